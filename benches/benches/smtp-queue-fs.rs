@@ -0,0 +1,68 @@
+use std::{fs, os::unix::fs::symlink, path::Path, sync::Arc};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::StreamExt;
+
+use smtp_queue::{ScheduleInfo, Storage};
+use smtp_queue_fs::{FsLayout, FsStorage, FsStorageConfig};
+
+/// Creates a queue with `mails` mails in `<queue>/queue`, by writing directly
+/// the on-disk format, as only the startup scan is being measured
+fn make_queue(path: &Path, layout: FsLayout, mails: usize) {
+    for dir in &["data", "queue", "inflight", "cleanup"] {
+        fs::create_dir(path.join(dir)).unwrap();
+    }
+    let schedule = serde_json::to_vec(&ScheduleInfo {
+        at: chrono::Utc::now(),
+        last_attempt: None,
+    })
+    .unwrap();
+    for _ in 0..mails {
+        let uuid = uuid::Uuid::new_v4().to_hyphenated().to_string();
+        let (mail_path, target) = match layout {
+            FsLayout::Flat => (uuid.clone(), format!("../data/{}", uuid)),
+            FsLayout::Sharded => {
+                let mail_path = format!("{}/{}", &uuid[..2], uuid);
+                let target = format!("../../data/{}", mail_path);
+                (mail_path, target)
+            }
+        };
+        let mail_dir = path.join("data").join(&mail_path);
+        fs::create_dir_all(&mail_dir).unwrap();
+        fs::write(mail_dir.join("schedule"), &schedule).unwrap();
+        fs::write(mail_dir.join("metadata"), b"{}").unwrap();
+        fs::write(mail_dir.join("contents"), b"").unwrap();
+        let link = path.join("queue").join(&mail_path);
+        fs::create_dir_all(link.parent().unwrap()).unwrap();
+        symlink(target, link).unwrap();
+    }
+}
+
+pub fn startup_scan(c: &mut Criterion) {
+    let mut g = c.benchmark_group("FsStorage::list_queue");
+    g.sample_size(10);
+
+    for &mails in &[1_000, 10_000, 100_000] {
+        g.throughput(Throughput::Elements(mails as u64));
+        for &(layout, name) in &[(FsLayout::Flat, "flat"), (FsLayout::Sharded, "sharded")] {
+            let dir = tempfile::tempdir().unwrap();
+            make_queue(dir.path(), layout, mails);
+            let config = FsStorageConfig {
+                layout,
+                ..FsStorageConfig::default()
+            };
+            let path = Arc::new(dir.path().to_owned());
+            let storage: FsStorage<()> = smol::run(FsStorage::with_config(path, config)).unwrap();
+
+            g.bench_with_input(BenchmarkId::new(name, mails), &storage, |b, storage| {
+                b.iter(|| {
+                    let found = smol::run(async { storage.list_queue().await.count().await });
+                    assert_eq!(found, mails);
+                })
+            });
+        }
+    }
+}
+
+criterion_group!(benches, startup_scan);
+criterion_main!(benches);
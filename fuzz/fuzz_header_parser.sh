@@ -0,0 +1,7 @@
+#!/bin/sh
+
+exec env \
+    CRATE=smtp-message \
+    TARGET=fuzz_header_parser \
+    DICT=smtp-header.dict \
+    ./run-fuzzer.sh "$0" "$@"
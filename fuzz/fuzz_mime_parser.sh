@@ -0,0 +1,7 @@
+#!/bin/sh
+
+exec env \
+    CRATE=smtp-message \
+    TARGET=fuzz_mime_parser \
+    DICT=smtp-mime.dict \
+    ./run-fuzzer.sh "$0" "$@"
@@ -0,0 +1,7 @@
+#!/bin/sh
+
+exec env \
+    CRATE=smtp-message \
+    TARGET=fuzz_transfer_decoding \
+    DICT=smtp-mime.dict \
+    ./run-fuzzer.sh "$0" "$@"
@@ -0,0 +1,425 @@
+use std::{io, sync::Arc, time::SystemTime};
+
+use async_trait::async_trait;
+use ring::digest;
+use rustls::{
+    Certificate, ClientConfig, RootCertStore, ServerCertVerified, ServerCertVerifier, TLSError,
+};
+use webpki::{DNSNameRef, EndEntityCert, SignatureAlgorithm, TLSServerTrustAnchors, Time};
+
+static SIG_ALGS: &[&SignatureAlgorithm] = &[
+    &webpki::ECDSA_P256_SHA256,
+    &webpki::ECDSA_P256_SHA384,
+    &webpki::ECDSA_P384_SHA256,
+    &webpki::ECDSA_P384_SHA384,
+    &webpki::ED25519,
+    &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA384_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA512_LEGACY_KEY,
+    &webpki::RSA_PKCS1_2048_8192_SHA256,
+    &webpki::RSA_PKCS1_2048_8192_SHA384,
+    &webpki::RSA_PKCS1_2048_8192_SHA512,
+    &webpki::RSA_PKCS1_3072_8192_SHA384,
+];
+
+/// A TLSA record (RFC 6698)
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Tlsa {
+    pub usage: u8,
+    pub selector: u8,
+    pub matching_type: u8,
+    pub data: Vec<u8>,
+}
+
+impl Tlsa {
+    /// The certificate is the one of the server
+    pub const DANE_EE: u8 = 3;
+    /// The certificate is a trust anchor that the chain of the server must
+    /// lead to
+    pub const DANE_TA: u8 = 2;
+    /// The data is the selected content itself
+    pub const EXACT: u8 = 0;
+    /// The whole certificate is selected
+    pub const FULL_CERTIFICATE: u8 = 0;
+    /// The data is the SHA-256 digest of the selected content
+    pub const SHA256: u8 = 1;
+    /// The data is the SHA-512 digest of the selected content
+    pub const SHA512: u8 = 2;
+    /// Only the `SubjectPublicKeyInfo` of the certificate is selected
+    pub const SPKI: u8 = 1;
+
+    /// Whether the record can be used by SMTP clients, following RFC 7672
+    /// section 3.1, that excludes the usages relying on the web PKI
+    pub fn is_usable(&self) -> bool {
+        (self.usage == Tlsa::DANE_TA || self.usage == Tlsa::DANE_EE)
+            && self.selector <= Tlsa::SPKI
+            && self.matching_type <= Tlsa::SHA512
+    }
+
+    /// Whether `cert`, in DER form, matches the record, whatever its usage
+    fn matches(&self, cert: &[u8]) -> bool {
+        let selected = match self.selector {
+            Tlsa::FULL_CERTIFICATE => cert,
+            Tlsa::SPKI => match spki(cert) {
+                Some(spki) => spki,
+                None => return false,
+            },
+            _ => return false,
+        };
+        match self.matching_type {
+            Tlsa::EXACT => selected == &self.data[..],
+            Tlsa::SHA256 => digest::digest(&digest::SHA256, selected).as_ref() == &self.data[..],
+            Tlsa::SHA512 => digest::digest(&digest::SHA512, selected).as_ref() == &self.data[..],
+            _ => false,
+        }
+    }
+}
+
+/// Source of the TLSA records of mail exchangers
+///
+/// As DANE relies on the authenticity of the records, only records validated
+/// with DNSSEC may be returned. Like for `Resolver`, lookups of a name that
+/// does not exist must fail with an `io::ErrorKind::NotFound` error, and any
+/// other error is considered transient.
+#[async_trait]
+pub trait TlsaSource: 'static + Send + Sync {
+    /// Returns the TLSA records of the SMTP server on `port` of `host`, that
+    /// are published at `_<port>._tcp.<host>`
+    async fn tlsa(&self, host: &str, port: u16) -> io::Result<Vec<Tlsa>>;
+}
+
+/// Splits the DER element at the start of `input`, returning the whole
+/// element, its contents and what follows it
+pub(crate) fn der_element(input: &[u8]) -> Option<(&[u8], &[u8], &[u8])> {
+    let first = *input.get(1)? as usize;
+    let (header, len) = if first < 0x80 {
+        (2, first)
+    } else {
+        let n = first & 0x7F;
+        if n == 0 || n > 4 {
+            return None;
+        }
+        let len = input
+            .get(2..2 + n)?
+            .iter()
+            .fold(0, |len, b| (len << 8) | *b as usize);
+        (2 + n, len)
+    };
+    let element = input.get(..header.checked_add(len)?)?;
+    Some((element, &element[header..], &input[element.len()..]))
+}
+
+/// Returns the `SubjectPublicKeyInfo` of `cert`, in DER form
+fn spki(cert: &[u8]) -> Option<&[u8]> {
+    let (_, cert, _) = der_element(cert)?;
+    let (_, mut tbs, _) = der_element(cert)?;
+    // Skip the optional version, and then the serial number, signature
+    // algorithm, issuer, validity and subject
+    if tbs.first() == Some(&0xA0) {
+        tbs = der_element(tbs)?.2;
+    }
+    for _ in 0..5 {
+        tbs = der_element(tbs)?.2;
+    }
+    der_element(tbs).map(|(spki, _, _)| spki)
+}
+
+/// Returns whether `leaf` is valid for `name` and chains to `anchor` through
+/// `intermediates`, all of them being in DER form
+fn chains_to(leaf: &[u8], intermediates: &[&[u8]], anchor: &[u8], name: DNSNameRef) -> bool {
+    let anchor = match webpki::trust_anchor_util::cert_der_as_trust_anchor(anchor) {
+        Ok(anchor) => anchor,
+        Err(_) => return false,
+    };
+    let leaf = match EndEntityCert::from(leaf) {
+        Ok(leaf) => leaf,
+        Err(_) => return false,
+    };
+    let now = match Time::try_from(SystemTime::now()) {
+        Ok(now) => now,
+        Err(_) => return false,
+    };
+    let anchors = [anchor];
+    leaf.verify_is_valid_tls_server_cert(
+        SIG_ALGS,
+        &TLSServerTrustAnchors(&anchors),
+        intermediates,
+        now,
+    )
+    .is_ok()
+        && leaf.verify_is_valid_for_dns_name(name).is_ok()
+}
+
+/// Returns whether the chain `certs` presented by the server named `name` is
+/// authenticated by `records`, following RFC 7672 section 3.1
+///
+/// DANE-EE records only bind the certificate of the server, with neither
+/// name nor expiration checks. DANE-TA records bind a certificate of the
+/// chain, that the certificate of the server must be issued by and be valid
+/// for `name` with. Without any usable record, TLS is still mandatory but
+/// not authenticated.
+fn verify(records: &[Tlsa], certs: &[&[u8]], name: DNSNameRef) -> bool {
+    let (leaf, chain) = match certs.split_first() {
+        Some(split) => split,
+        None => return false,
+    };
+    let mut usable = records.iter().filter(|r| r.is_usable()).peekable();
+    if usable.peek().is_none() {
+        return true;
+    }
+    usable.any(|r| match r.usage {
+        Tlsa::DANE_EE => r.matches(leaf),
+        _ => {
+            let full = (r.selector, r.matching_type) == (Tlsa::FULL_CERTIFICATE, Tlsa::EXACT);
+            chain
+                .iter()
+                .copied()
+                .filter(|c| r.matches(c))
+                .chain(if full { Some(&r.data[..]) } else { None })
+                .any(|anchor| chains_to(leaf, chain, anchor, name))
+        }
+    })
+}
+
+struct DaneVerifier {
+    records: Vec<Tlsa>,
+}
+
+impl ServerCertVerifier for DaneVerifier {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        dns_name: DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        let certs = presented_certs.iter().map(|c| &c.0[..]).collect::<Vec<_>>();
+        if verify(&self.records, &certs, dns_name) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(TLSError::General(
+                "no TLSA record matches the certificate".to_owned(),
+            ))
+        }
+    }
+}
+
+/// TLS configuration authenticating servers with `records` rather than the
+/// web PKI
+pub(crate) fn dane_tls_config(records: Vec<Tlsa>) -> Arc<ClientConfig> {
+    let mut tls = ClientConfig::new();
+    tls.dangerous()
+        .set_certificate_verifier(Arc::new(DaneVerifier { records }));
+    Arc::new(tls)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::TcpListener;
+
+    use futures::io::Cursor;
+    use rustls::internal::pemfile::certs;
+    use smol::Async;
+    use smtp_queue::{Transport, TransportFailure, TransportSender};
+
+    use crate::{
+        mta_sts::tests::{mx_tls, serve_mx, MX_CERT},
+        relay::tests::{Log, CA_CERT},
+        test_helpers::{hostname, meta},
+        SmtpDestination, SmtpTransport, StubResolver,
+    };
+
+    const MX_SHA256: &str = "cc6061dd683a2841a1ebcd007307dd7ca69bf6ae72415836d7e7e694d8c4047f";
+    const MX_SPKI_SHA256: &str = "60f6d4dab01f7d6bb5cf26f573267cdef7f239ebde87b768e3a40b5784e77cdf";
+    const MX_SPKI_SHA512: &str = "93da7cb66a924fffe9b14ecf813f9a57570d4511722bf15b2de61ac2e57eaf85\
+                                  f8b93246819527d286645c921b4d352f877188553c9f55d562c906a4f6d48b2b";
+    const CA_SHA256: &str = "f22b9f115993ecfd390ce162f6dd2020f38ad2b02589458f555cd02177db8bec";
+    const CA_SPKI_SHA256: &str = "7b35fc99d7ec418ee2a57b8c0a98f0f5bdd69725698c87f5160da956fb83069c";
+
+    fn unhex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn der(pem: &[u8]) -> Vec<u8> {
+        certs(&mut &pem[..]).unwrap().remove(0).0
+    }
+
+    fn tlsa(usage: u8, selector: u8, matching_type: u8, data: &[u8]) -> Tlsa {
+        Tlsa {
+            usage,
+            selector,
+            matching_type,
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn spki_extraction() {
+        let mx = der(MX_CERT);
+        let spki = spki(&mx).unwrap();
+        assert_eq!(
+            digest::digest(&digest::SHA256, spki).as_ref(),
+            &unhex(MX_SPKI_SHA256)[..]
+        );
+        assert_eq!(super::spki(&mx[..mx.len() - 1]), None);
+        assert_eq!(super::spki(b"\x30\x85\x00\x00\x00\x00\x01"), None);
+    }
+
+    #[test]
+    fn tlsa_verify() {
+        let mx = der(MX_CERT);
+        let ca = der(CA_CERT);
+        let name = DNSNameRef::try_from_ascii_str("mx.example.org").unwrap();
+        let other = DNSNameRef::try_from_ascii_str("other.example.org").unwrap();
+        let tests: &[(Tlsa, &[&[u8]], DNSNameRef, bool)] = &[
+            (tlsa(3, 1, 1, &unhex(MX_SPKI_SHA256)), &[&mx], name, true),
+            (tlsa(3, 1, 2, &unhex(MX_SPKI_SHA512)), &[&mx], name, true),
+            (tlsa(3, 0, 1, &unhex(MX_SHA256)), &[&mx], name, true),
+            (tlsa(3, 0, 0, &mx), &[&mx], name, true),
+            // DANE-EE does not check the name
+            (tlsa(3, 1, 1, &unhex(MX_SPKI_SHA256)), &[&mx], other, true),
+            (
+                tlsa(3, 1, 1, &unhex(CA_SPKI_SHA256)),
+                &[&mx, &ca],
+                name,
+                false,
+            ),
+            (tlsa(3, 0, 1, &unhex(MX_SPKI_SHA256)), &[&mx], name, false),
+            (tlsa(2, 0, 1, &unhex(CA_SHA256)), &[&mx, &ca], name, true),
+            (
+                tlsa(2, 1, 1, &unhex(CA_SPKI_SHA256)),
+                &[&mx, &ca],
+                name,
+                true,
+            ),
+            (tlsa(2, 0, 1, &unhex(CA_SHA256)), &[&mx, &ca], other, false),
+            (tlsa(2, 0, 1, &unhex(CA_SHA256)), &[&mx], name, false),
+            // A full trust anchor does not need to be in the chain
+            (tlsa(2, 0, 0, &ca), &[&mx], name, true),
+            // The certificate of the server is not a trust anchor
+            (tlsa(2, 0, 1, &unhex(MX_SHA256)), &[&mx], name, false),
+            // Without usable records, TLS is not authenticated
+            (tlsa(1, 0, 1, &unhex(CA_SHA256)), &[&mx], name, true),
+            (tlsa(3, 2, 1, &unhex(MX_SHA256)), &[&mx], other, true),
+            (tlsa(3, 0, 1, &unhex(MX_SHA256)), &[], name, false),
+        ];
+        for (record, chain, name, res) in tests {
+            println!("Test: {:?}", record);
+            assert_eq!(verify(std::slice::from_ref(record), chain, *name), *res);
+        }
+        assert!(verify(
+            &[
+                tlsa(3, 1, 1, &unhex(CA_SPKI_SHA256)),
+                tlsa(3, 1, 1, &unhex(MX_SPKI_SHA256)),
+            ],
+            &[&mx],
+            name
+        ));
+    }
+
+    /// Returns a transport to mx.example.org on the port of `listener`, with
+    /// the TLSA records `records`, and MX records validated with DNSSEC if
+    /// `secure_mx` is set
+    fn transport(
+        listener: &Async<TcpListener>,
+        records: &[Tlsa],
+        secure_mx: bool,
+    ) -> SmtpTransport<StubResolver> {
+        let port = listener.get_ref().local_addr().unwrap().port();
+        let mut resolver = StubResolver::new()
+            .with_mx("example.org", 10, "mx.example.org")
+            .with_ip("mx.example.org", "127.0.0.1".parse().unwrap());
+        if !secure_mx {
+            resolver = resolver.with_insecure_mx("example.org");
+        }
+        for r in records {
+            resolver = resolver.with_tlsa("mx.example.org", port, r.clone());
+        }
+        SmtpTransport::with_port(resolver.clone(), hostname(), port).with_dane(resolver)
+    }
+
+    #[test]
+    fn dane_delivery() {
+        let listener = Async::new(TcpListener::bind("127.0.0.1:0").unwrap()).unwrap();
+        let transport = transport(&listener, &[tlsa(3, 1, 1, &unhex(MX_SPKI_SHA256))], true);
+        let log = Log::default();
+        let log_ref = log.clone();
+        smol::run(async move {
+            serve_mx(listener, Some(mx_tls()), log_ref);
+            let dest = SmtpDestination::Domain("example.org".to_owned());
+            let mut sender = Transport::<()>::connect(&transport, &dest)
+                .await
+                .unwrap_or_else(|_| panic!("failed connecting"));
+            let res = sender
+                .send(
+                    &meta(&["<foo@example.org>"]),
+                    Cursor::new(&b"Hello\r\n"[..]),
+                )
+                .await;
+            assert!(res.is_ok());
+        });
+        assert_eq!(*log.lock().unwrap(), vec![
+            "EHLO client.example.com",
+            "STARTTLS",
+            "EHLO client.example.com",
+            "MAIL FROM:<sender@example.com>",
+            "RCPT TO:<foo@example.org>",
+            "DATA",
+            "Hello\r\n.\r\n",
+        ]);
+    }
+
+    #[test]
+    fn dane_mismatch() {
+        let listener = Async::new(TcpListener::bind("127.0.0.1:0").unwrap()).unwrap();
+        let transport = transport(&listener, &[tlsa(3, 1, 1, &unhex(CA_SPKI_SHA256))], true);
+        let log = Log::default();
+        let log_ref = log.clone();
+        smol::run(async move {
+            serve_mx(listener, Some(mx_tls()), log_ref);
+            let dest = SmtpDestination::Domain("example.org".to_owned());
+            match Transport::<()>::connect(&transport, &dest).await {
+                Err(TransportFailure::Local(_)) => (),
+                _ => panic!("expected a local failure"),
+            }
+        });
+        assert_eq!(log.lock().unwrap()[..], [
+            "EHLO client.example.com",
+            "STARTTLS"
+        ]);
+    }
+
+    #[test]
+    fn insecure_mx() {
+        // The TLSA records do not match, but are not used as the MX records
+        // leading to them are not validated
+        let listener = Async::new(TcpListener::bind("127.0.0.1:0").unwrap()).unwrap();
+        let transport = transport(&listener, &[tlsa(3, 1, 1, &unhex(CA_SPKI_SHA256))], false);
+        let log = Log::default();
+        let log_ref = log.clone();
+        smol::run(async move {
+            serve_mx(listener, Some(mx_tls()), log_ref);
+            let dest = SmtpDestination::Domain("example.org".to_owned());
+            let mut sender = Transport::<()>::connect(&transport, &dest)
+                .await
+                .unwrap_or_else(|_| panic!("failed connecting"));
+            let res = sender
+                .send(
+                    &meta(&["<foo@example.org>"]),
+                    Cursor::new(&b"Hello\r\n"[..]),
+                )
+                .await;
+            assert!(res.is_ok());
+        });
+        assert_eq!(log.lock().unwrap()[..3], [
+            "EHLO client.example.com",
+            "STARTTLS",
+            "EHLO client.example.com",
+        ]);
+    }
+}
@@ -0,0 +1,406 @@
+use std::{
+    fs, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use futures::{
+    future::{self, Either},
+    io::{AsyncReadExt, AsyncWriteExt},
+    pin_mut,
+};
+use smol::{Async, Timer};
+use trust_dns_proto::{
+    op::{Message, MessageType, OpCode, Query, ResponseCode},
+    rr::{Name, RData, RecordType},
+};
+
+use crate::{Resolver, Tlsa, TlsaSource};
+
+const DNS_PORT: u16 = 53;
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+/// Resolver that sends queries to recursive DNS servers, over UDP and
+/// falling back to TCP for answers that do not fit in a datagram
+///
+/// DNSSEC validation, if any, is left to the recursive servers, that must be
+/// trusted and reached over a secure channel for TLSA records to be used.
+#[derive(Clone)]
+pub struct DnsResolver {
+    nameservers: Vec<SocketAddr>,
+    timeout: Duration,
+    attempts: usize,
+}
+
+impl DnsResolver {
+    pub fn new(nameservers: Vec<SocketAddr>) -> DnsResolver {
+        DnsResolver {
+            nameservers,
+            timeout: Duration::from_secs(5),
+            attempts: 2,
+        }
+    }
+
+    /// Uses the nameservers configured in `/etc/resolv.conf`
+    pub fn from_system() -> io::Result<DnsResolver> {
+        let nameservers = parse_resolv_conf(&fs::read_to_string(RESOLV_CONF)?);
+        if nameservers.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no nameserver configured in /etc/resolv.conf",
+            ));
+        }
+        Ok(DnsResolver::new(nameservers))
+    }
+
+    /// Sets how long to wait for each answer
+    pub fn with_timeout(mut self, timeout: Duration) -> DnsResolver {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets how many times each nameserver is tried
+    pub fn with_attempts(mut self, attempts: usize) -> DnsResolver {
+        self.attempts = attempts;
+        self
+    }
+
+    /// Returns the data of the records of type `rtype` for `name`
+    pub(crate) async fn query(&self, name: &str, rtype: RecordType) -> io::Result<Vec<RData>> {
+        Ok(self.query_authenticated(name, rtype).await?.0)
+    }
+
+    /// Returns the data of the records of type `rtype` for `name`, and
+    /// whether the nameserver validated them with DNSSEC
+    async fn query_authenticated(
+        &self,
+        name: &str,
+        rtype: RecordType,
+    ) -> io::Result<(Vec<RData>, bool)> {
+        let name = Name::from_ascii(name)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        let mut last_err = io::Error::new(io::ErrorKind::NotFound, "no nameserver configured");
+        for _ in 0..self.attempts {
+            for server in self.nameservers.iter() {
+                let id = rand::random::<u16>();
+                let mut query = Message::new();
+                query
+                    .set_id(id)
+                    .set_message_type(MessageType::Query)
+                    .set_op_code(OpCode::Query)
+                    .set_recursion_desired(true)
+                    .set_authentic_data(true)
+                    .add_query(Query::query(name.clone(), rtype));
+                let query = query
+                    .to_vec()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+
+                let answer = match self.exchange(*server, id, &query).await {
+                    Ok(answer) => answer,
+                    Err(e) => {
+                        last_err = e;
+                        continue;
+                    }
+                };
+                match answer.response_code() {
+                    ResponseCode::NoError => {
+                        let records = answer
+                            .answers()
+                            .iter()
+                            .filter(|r| r.rr_type() == rtype)
+                            .map(|r| r.rdata().clone())
+                            .collect();
+                        return Ok((records, answer.authentic_data()));
+                    }
+                    ResponseCode::NXDomain => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::NotFound,
+                            format!("{} does not exist", name),
+                        ));
+                    }
+                    code => {
+                        last_err = io::Error::new(
+                            io::ErrorKind::Other,
+                            format!("nameserver {} answered {} for {}", server, code, name),
+                        );
+                    }
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    /// Sends `query` to `server` and returns its answer
+    async fn exchange(&self, server: SocketAddr, id: u16, query: &[u8]) -> io::Result<Message> {
+        let exchange = async {
+            let answer = udp_exchange(server, id, query).await?;
+            if answer.truncated() {
+                tcp_exchange(server, id, query).await
+            } else {
+                Ok(answer)
+            }
+        };
+        pin_mut!(exchange);
+        match future::select(exchange, Timer::new(self.timeout)).await {
+            Either::Left((res, _)) => res,
+            Either::Right(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("nameserver {} did not answer in time", server),
+            )),
+        }
+    }
+}
+
+fn parse_answer(buf: &[u8], id: u16) -> Option<Message> {
+    match Message::from_vec(buf) {
+        Ok(msg) if msg.id() == id && msg.message_type() == MessageType::Response => Some(msg),
+        _ => None,
+    }
+}
+
+async fn udp_exchange(server: SocketAddr, id: u16, query: &[u8]) -> io::Result<Message> {
+    let local: IpAddr = match server {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = Async::<UdpSocket>::bind(SocketAddr::new(local, 0))?;
+    socket.send_to(query, server).await?;
+    let mut buf = vec![0; 4096];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        // Ignore stray datagrams, that could be spoofing attempts
+        if from != server {
+            continue;
+        }
+        if let Some(answer) = parse_answer(&buf[..len], id) {
+            return Ok(answer);
+        }
+    }
+}
+
+async fn tcp_exchange(server: SocketAddr, id: u16, query: &[u8]) -> io::Result<Message> {
+    let mut stream = Async::<TcpStream>::connect(server).await?;
+    let mut buf = Vec::with_capacity(query.len() + 2);
+    buf.extend_from_slice(&(query.len() as u16).to_be_bytes());
+    buf.extend_from_slice(query);
+    stream.write_all(&buf).await?;
+    let mut len = [0; 2];
+    stream.read_exact(&mut len).await?;
+    let mut buf = vec![0; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut buf).await?;
+    parse_answer(&buf, id).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("nameserver {} sent an invalid answer", server),
+        )
+    })
+}
+
+fn parse_resolv_conf(conf: &str) -> Vec<SocketAddr> {
+    conf.lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some("nameserver"), Some(addr)) => {
+                    // Drop the zone index of link-local IPv6 addresses
+                    let addr = addr.split('%').next().unwrap();
+                    addr.parse::<IpAddr>()
+                        .ok()
+                        .map(|ip| SocketAddr::new(ip, DNS_PORT))
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+fn fqdn(name: &str) -> String {
+    if name.ends_with('.') {
+        name.to_owned()
+    } else {
+        format!("{}.", name)
+    }
+}
+
+#[async_trait]
+impl Resolver for DnsResolver {
+    async fn mx(&self, domain: &str) -> io::Result<Vec<(u16, String)>> {
+        Ok(self.mx_authenticated(domain).await?.0)
+    }
+
+    async fn mx_authenticated(&self, domain: &str) -> io::Result<(Vec<(u16, String)>, bool)> {
+        let (records, authenticated) = self
+            .query_authenticated(&fqdn(domain), RecordType::MX)
+            .await?;
+        let mxs = records
+            .into_iter()
+            .filter_map(|r| match r {
+                RData::MX(mx) => Some((mx.preference(), mx.exchange().to_ascii())),
+                _ => None,
+            })
+            .collect();
+        Ok((mxs, authenticated))
+    }
+
+    async fn ips(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        let host = fqdn(host);
+        let mut res = Vec::new();
+        for rtype in &[RecordType::A, RecordType::AAAA] {
+            for r in self.query(&host, *rtype).await? {
+                match r {
+                    RData::A(ip) => res.push(IpAddr::V4(ip)),
+                    RData::AAAA(ip) => res.push(IpAddr::V6(ip)),
+                    _ => (),
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    async fn txt(&self, name: &str) -> io::Result<Vec<String>> {
+        let records = self.query(&fqdn(name), RecordType::TXT).await?;
+        Ok(records
+            .into_iter()
+            .filter_map(|r| match r {
+                RData::TXT(txt) => Some(
+                    txt.txt_data()
+                        .iter()
+                        .map(|s| String::from_utf8_lossy(s))
+                        .collect(),
+                ),
+                _ => None,
+            })
+            .collect())
+    }
+}
+
+/// Only the TLSA records that the nameserver validated with DNSSEC are
+/// returned. Checking that the MX records leading to `host` were validated
+/// too is left to the transport, through `NextHop::authenticated`.
+#[async_trait]
+impl TlsaSource for DnsResolver {
+    async fn tlsa(&self, host: &str, port: u16) -> io::Result<Vec<Tlsa>> {
+        let name = format!("_{}._tcp.{}", port, fqdn(host));
+        let (records, authenticated) = self.query_authenticated(&name, RecordType::TLSA).await?;
+        if !authenticated {
+            return Ok(Vec::new());
+        }
+        Ok(records
+            .into_iter()
+            .filter_map(|r| match r {
+                RData::TLSA(tlsa) => Some(Tlsa {
+                    usage: tlsa.cert_usage().into(),
+                    selector: tlsa.selector().into(),
+                    matching_type: tlsa.matching().into(),
+                    data: tlsa.cert_data().to_vec(),
+                }),
+                _ => None,
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use trust_dns_proto::rr::{rdata::MX, Record};
+
+    #[test]
+    fn resolv_conf() {
+        let conf = "# comment\ndomain example.org\nnameserver 192.0.2.53\nnameserver \
+                    fe80::1%eth0\nnameserver invalid\noptions edns0\n";
+        assert_eq!(parse_resolv_conf(conf), vec![
+            "192.0.2.53:53".parse().unwrap(),
+            "[fe80::1]:53".parse().unwrap(),
+        ]);
+    }
+
+    /// Answers the queries sent to `socket` with an MX record, or with a
+    /// truncated answer for the first query if `truncate` is set
+    async fn serve(socket: Async<UdpSocket>, tcp: Async<std::net::TcpListener>, truncate: bool) {
+        let answer = |query: &Message, truncated: bool| {
+            let mut answer = Message::new();
+            answer
+                .set_id(query.id())
+                .set_message_type(MessageType::Response)
+                .set_op_code(OpCode::Query)
+                .add_queries(query.queries().to_vec());
+            let name = query.queries()[0].name().clone();
+            if name.to_ascii() == "nonexistent.example.org." {
+                answer.set_response_code(ResponseCode::NXDomain);
+            } else if !truncated {
+                let mx = MX::new(10, Name::from_ascii("mx.example.org.").unwrap());
+                answer.add_answer(Record::from_rdata(name, 300, RData::MX(mx)));
+            }
+            let mut answer = answer.to_vec().unwrap();
+            if truncated {
+                // The encoder computes the TC bit itself, so set it by hand
+                answer[2] |= 0x02;
+            }
+            answer
+        };
+        let mut buf = vec![0; 4096];
+        loop {
+            let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+            let query = Message::from_vec(&buf[..len]).unwrap();
+            socket
+                .send_to(&answer(&query, truncate), from)
+                .await
+                .unwrap();
+            if truncate {
+                let (mut stream, _) = tcp.accept().await.unwrap();
+                let mut len = [0; 2];
+                stream.read_exact(&mut len).await.unwrap();
+                let mut buf = vec![0; u16::from_be_bytes(len) as usize];
+                stream.read_exact(&mut buf).await.unwrap();
+                let answer = answer(&Message::from_vec(&buf).unwrap(), false);
+                stream
+                    .write_all(&(answer.len() as u16).to_be_bytes())
+                    .await
+                    .unwrap();
+                stream.write_all(&answer).await.unwrap();
+            }
+        }
+    }
+
+    fn check_server(truncate: bool) {
+        let socket =
+            Async::<UdpSocket>::bind("127.0.0.1:0".parse::<SocketAddr>().unwrap()).unwrap();
+        let addr = socket.get_ref().local_addr().unwrap();
+        let tcp = Async::<std::net::TcpListener>::bind(addr).unwrap();
+        smol::run(async move {
+            smol::Task::spawn(serve(socket, tcp, truncate)).detach();
+            let resolver = DnsResolver::new(vec![addr]);
+            assert_eq!(resolver.mx("example.org").await.unwrap(), vec![(
+                10,
+                "mx.example.org.".to_owned()
+            )]);
+            let err = resolver.mx("nonexistent.example.org").await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        });
+    }
+
+    #[test]
+    fn udp_query() {
+        check_server(false);
+    }
+
+    #[test]
+    fn tcp_fallback() {
+        check_server(true);
+    }
+
+    #[test]
+    fn timeout() {
+        // Nothing answers on this socket
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let resolver = DnsResolver::new(vec![addr])
+            .with_timeout(Duration::from_millis(50))
+            .with_attempts(1);
+        let err = smol::run(resolver.mx("example.org")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
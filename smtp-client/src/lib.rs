@@ -0,0 +1,485 @@
+use std::{
+    io,
+    net::TcpStream,
+    ops::Range,
+    os::unix::net::UnixStream,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use async_tls::client::TlsStream;
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use rustls::{
+    Certificate, ClientConfig, RootCertStore, ServerCertVerified, ServerCertVerifier, TLSError,
+};
+use smol::Async;
+use smtp_message::{
+    nom, Command, Email, EscapingDataWriter, Parameters, Reply, ReplyCode, ReplyCodeKind,
+};
+use smtp_queue::{MailMetadata, TransportFailure};
+
+mod dane;
+mod dkim;
+mod dns;
+mod lmtp;
+mod mta_sts;
+mod relay;
+mod resolver;
+mod smtp;
+#[cfg(test)]
+mod test_helpers;
+mod tlsrpt;
+
+pub use rustls;
+
+pub use dane::{Tlsa, TlsaSource};
+pub use dkim::{verify_dkim, Canonicalization, DkimKey, DkimResult, DkimSigner, DkimVerification};
+pub use dns::DnsResolver;
+pub use lmtp::{LmtpDestination, LmtpSender, LmtpTransport};
+pub use mta_sts::{HttpsFetcher, MtaSts, PolicyFetcher, StsMode, StsPolicy};
+pub use relay::{Credentials, RelayConfig, Smarthost};
+pub use resolver::{next_hops, NextHop, Resolver, StubResolver};
+pub use smtp::{SmtpDestination, SmtpSender, SmtpTransport};
+pub use tlsrpt::{
+    enqueue_reports, generate_reports, FailureDetails, FailureType, PolicyType, ReportOrganization,
+    TlsEvent, TlsPolicy, TlsReport, TlsRptRecorder,
+};
+
+const RDBUF_SIZE: usize = 16 * 1024;
+
+/// TLS configuration checking certificates against the usual web PKI roots
+pub(crate) fn webpki_tls_config() -> Arc<ClientConfig> {
+    let mut tls = ClientConfig::new();
+    tls.root_store
+        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    Arc::new(tls)
+}
+
+struct OpportunisticVerifier;
+
+impl ServerCertVerifier for OpportunisticVerifier {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        _presented_certs: &[Certificate],
+        _dns_name: webpki::DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// TLS configuration for opportunistic TLS (RFC 7435), that accepts any
+/// certificate and thus only protects against passive attackers
+///
+/// SNI is only sent if `sni` is set, so that a placeholder name can be used
+/// for servers without a name.
+pub(crate) fn opportunistic_tls_config(sni: bool) -> Arc<ClientConfig> {
+    let mut tls = ClientConfig::new();
+    tls.enable_sni = sni;
+    tls.dangerous()
+        .set_certificate_verifier(Arc::new(OpportunisticVerifier));
+    Arc::new(tls)
+}
+
+pub(crate) enum Stream {
+    Unix(Async<UnixStream>),
+    Tcp(Async<TcpStream>),
+    Tls(Box<TlsStream<Stream>>),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Unix(s) => Pin::new(s).poll_read(cx, buf),
+            Stream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            Stream::Tls(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Unix(s) => Pin::new(s).poll_write(cx, buf),
+            Stream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            Stream::Tls(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Unix(s) => Pin::new(s).poll_flush(cx),
+            Stream::Tcp(s) => Pin::new(s).poll_flush(cx),
+            Stream::Tls(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Unix(s) => Pin::new(s).poll_close(cx),
+            Stream::Tcp(s) => Pin::new(s).poll_close(cx),
+            Stream::Tls(s) => Pin::new(s).poll_close(cx),
+        }
+    }
+}
+
+/// A connection to an SMTP or LMTP server, that sends commands and reads the
+/// replies to them
+pub(crate) struct Connection<IO> {
+    io: IO,
+    rdbuf: Box<[u8]>,
+    unhandled: Range<usize>,
+    broken: bool,
+    pipelining: bool,
+    mail_accepted: bool,
+}
+
+impl<IO> Connection<IO>
+where
+    IO: Unpin + AsyncRead + AsyncWrite,
+{
+    pub(crate) fn new(io: IO) -> Connection<IO> {
+        Connection {
+            io,
+            rdbuf: vec![0; RDBUF_SIZE].into_boxed_slice(),
+            unhandled: 0..0,
+            broken: false,
+            pipelining: false,
+            mail_accepted: false,
+        }
+    }
+
+    /// Sets whether the server supports pipelining (RFC 2920), in which case
+    /// the commands of a mail transaction are sent in a single write
+    pub(crate) fn set_pipelining(&mut self, pipelining: bool) {
+        self.pipelining = pipelining;
+    }
+
+    /// Whether the session is in an unknown state, eg. after an I/O error,
+    /// or was closed by the server, and must not be used for any more mails
+    pub(crate) fn is_broken(&self) -> bool {
+        self.broken
+    }
+
+    /// Whether the server accepted the `MAIL` command of the last mail
+    /// transaction, before which the mail is never read
+    pub(crate) fn mail_accepted(&self) -> bool {
+        self.mail_accepted
+    }
+
+    /// Returns the underlying stream, eg. to start TLS on it
+    ///
+    /// This fails if the server sent data that was not handled yet, which
+    /// could otherwise be injected in a session that is later protected.
+    pub(crate) fn into_io(self) -> io::Result<IO> {
+        if !self.unhandled.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "received unexpected data before a change of stream",
+            ));
+        }
+        Ok(self.io)
+    }
+
+    pub(crate) async fn read_reply(&mut self) -> io::Result<Reply<String>> {
+        let res = self.read_reply_unchecked().await;
+        // A 421 reply means that the server is closing the session
+        self.broken |= match res {
+            Ok(ref reply) => reply.code == ReplyCode::SERVICE_NOT_AVAILABLE,
+            Err(_) => true,
+        };
+        res
+    }
+
+    async fn read_reply_unchecked(&mut self) -> io::Result<Reply<String>> {
+        loop {
+            match Reply::<&str>::parse(&self.rdbuf[self.unhandled.clone()]) {
+                Ok((rem, reply)) => {
+                    let reply = reply.to_owned();
+                    self.unhandled.start = self.unhandled.end - rem.len();
+                    return Ok(reply);
+                }
+                Err(nom::Err::Incomplete(_)) => (),
+                Err(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "received a syntactically invalid reply",
+                    ));
+                }
+            }
+
+            // Don't have a full reply yet, let's fetch more
+            if self.unhandled.start != 0 {
+                self.rdbuf.copy_within(self.unhandled.clone(), 0);
+                self.unhandled = 0..self.unhandled.len();
+            }
+            if self.unhandled.end == self.rdbuf.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "received a reply too long for the buffer",
+                ));
+            }
+            let read = self.io.read(&mut self.rdbuf[self.unhandled.end..]).await?;
+            if read == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "connection shutdown while waiting for a reply",
+                ));
+            }
+            self.unhandled.end += read;
+        }
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        let res = async {
+            self.io.write_all(buf).await?;
+            self.io.flush().await
+        }
+        .await;
+        self.broken |= res.is_err();
+        res
+    }
+
+    pub(crate) async fn send_command<S>(&mut self, cmd: &Command<S>) -> io::Result<()>
+    where
+        S: AsRef<str>,
+    {
+        let mut buf = Vec::with_capacity(128);
+        push_command(&mut buf, cmd);
+        self.write(&buf).await
+    }
+
+    pub(crate) async fn command<S>(&mut self, cmd: &Command<S>) -> io::Result<Reply<String>>
+    where
+        S: AsRef<str>,
+    {
+        self.send_command(cmd).await?;
+        self.read_reply().await
+    }
+
+    /// Sends the contents of a mail after the `DATA` command has been
+    /// accepted, escaping them and adding the final dot
+    pub(crate) async fn send_data<R>(&mut self, mail: R) -> io::Result<()>
+    where
+        R: AsyncRead,
+    {
+        let res = async {
+            let mut writer = EscapingDataWriter::new(&mut self.io);
+            futures::io::copy(mail, &mut writer).await?;
+            writer.finish().await?;
+            self.io.flush().await
+        }
+        .await;
+        self.broken |= res.is_err();
+        res
+    }
+
+    /// Aborts the current mail, marking the session as broken if the server
+    /// does not accept it
+    async fn reset(&mut self) {
+        match self.command(&Command::<&str>::Rset).await {
+            Ok(reply) if reply.code.kind() == ReplyCodeKind::PositiveCompletion => (),
+            _ => self.broken = true,
+        }
+    }
+
+    /// Sends a whole mail transaction
+    ///
+    /// With `per_recipient`, the server is expected to reply for each
+    /// accepted recipient after the end of the data, as LMTP servers do.
+    pub(crate) async fn send_mail<U, R>(
+        &mut self,
+        meta: &MailMetadata<U>,
+        mail: R,
+        per_recipient: bool,
+    ) -> Result<(), TransportFailure>
+    where
+        R: AsyncRead,
+    {
+        self.mail_accepted = false;
+        if self.pipelining {
+            return self.send_mail_pipelined(meta, mail, per_recipient).await;
+        }
+
+        let reply = self
+            .command(&mail_from(meta))
+            .await
+            .map_err(TransportFailure::Local)?;
+        expect(&reply, ReplyCodeKind::PositiveCompletion)?;
+        self.mail_accepted = true;
+
+        let mut results = Vec::with_capacity(meta.to.len());
+        for to in meta.to.iter() {
+            let reply = self
+                .command(&rcpt_to(to))
+                .await
+                .map_err(TransportFailure::Local)?;
+            results.push(expect(&reply, ReplyCodeKind::PositiveCompletion));
+        }
+        if !results.iter().any(|r| r.is_ok()) {
+            self.reset().await;
+            return TransportFailure::combine(results);
+        }
+
+        let reply = self
+            .command(&Command::<&str>::Data)
+            .await
+            .map_err(TransportFailure::Local)?;
+        if let Err(e) = expect(&reply, ReplyCodeKind::PositiveIntermediate) {
+            self.reset().await;
+            return Err(e);
+        }
+        self.finish_data(results, mail, per_recipient).await
+    }
+
+    /// Sends a whole mail transaction, writing the `MAIL`, `RCPT` and `DATA`
+    /// commands at once and then matching the replies to them in order
+    async fn send_mail_pipelined<U, R>(
+        &mut self,
+        meta: &MailMetadata<U>,
+        mail: R,
+        per_recipient: bool,
+    ) -> Result<(), TransportFailure>
+    where
+        R: AsyncRead,
+    {
+        let mut buf = Vec::with_capacity(128 * (meta.to.len() + 2));
+        push_command(&mut buf, &mail_from(meta));
+        for to in meta.to.iter() {
+            push_command(&mut buf, &rcpt_to(to));
+        }
+        push_command(&mut buf, &Command::<&str>::Data);
+        self.write(&buf).await.map_err(TransportFailure::Local)?;
+
+        let reply = self.read_reply().await.map_err(TransportFailure::Local)?;
+        let mail_res = expect(&reply, ReplyCodeKind::PositiveCompletion);
+        self.mail_accepted = mail_res.is_ok();
+        let mut results = Vec::with_capacity(meta.to.len());
+        for _ in meta.to.iter() {
+            let reply = self.read_reply().await.map_err(TransportFailure::Local)?;
+            results.push(expect(&reply, ReplyCodeKind::PositiveCompletion));
+        }
+        let reply = self.read_reply().await.map_err(TransportFailure::Local)?;
+        let data_res = expect(&reply, ReplyCodeKind::PositiveIntermediate);
+
+        let accepted = mail_res.is_ok() && results.iter().any(|r| r.is_ok());
+        match (accepted, data_res) {
+            (true, Ok(())) => self.finish_data(results, mail, per_recipient).await,
+            (false, Ok(())) => {
+                // The server accepted DATA even though there is no recipient,
+                // so the transaction has to be completed with empty data
+                // (RFC 2920 section 3.1)
+                self.send_data(&b""[..])
+                    .await
+                    .map_err(TransportFailure::Local)?;
+                self.read_reply().await.map_err(TransportFailure::Local)?;
+                mail_res?;
+                TransportFailure::combine(results)
+            }
+            (_, Err(e)) => {
+                if mail_res.is_ok() {
+                    self.reset().await;
+                }
+                mail_res?;
+                if accepted {
+                    Err(e)
+                } else {
+                    TransportFailure::combine(results)
+                }
+            }
+        }
+    }
+
+    /// Sends the mail once the `DATA` command has been accepted, and returns
+    /// the results of its delivery to the accepted recipients
+    async fn finish_data<R>(
+        &mut self,
+        mut results: Vec<Result<(), TransportFailure>>,
+        mail: R,
+        per_recipient: bool,
+    ) -> Result<(), TransportFailure>
+    where
+        R: AsyncRead,
+    {
+        self.send_data(mail)
+            .await
+            .map_err(TransportFailure::Local)?;
+
+        if per_recipient {
+            // There is one reply per accepted recipient, in the order of the
+            // RCPT commands. Failing to read one means that the mail may or
+            // may not have been delivered to this recipient, so it is
+            // retried.
+            for res in results.iter_mut().filter(|r| r.is_ok()) {
+                *res = match self.read_reply().await {
+                    Ok(reply) => expect(&reply, ReplyCodeKind::PositiveCompletion),
+                    Err(e) => Err(TransportFailure::Local(e)),
+                };
+            }
+        } else {
+            let reply = self.read_reply().await.map_err(TransportFailure::Local)?;
+            for res in results.iter_mut().filter(|r| r.is_ok()) {
+                *res = expect(&reply, ReplyCodeKind::PositiveCompletion);
+            }
+        }
+        TransportFailure::combine(results)
+    }
+}
+
+fn push_command<S>(buf: &mut Vec<u8>, cmd: &Command<S>)
+where
+    S: AsRef<str>,
+{
+    for s in cmd.as_io_slices() {
+        buf.extend_from_slice(&s);
+    }
+}
+
+fn mail_from<U>(meta: &MailMetadata<U>) -> Command<String> {
+    Command::Mail {
+        path: None,
+        email: meta.from.clone(),
+        params: Parameters::from(Vec::new()),
+    }
+}
+
+fn rcpt_to(to: &Email) -> Command<String> {
+    Command::Rcpt {
+        path: None,
+        email: to.clone(),
+        params: Parameters::from(Vec::new()),
+    }
+}
+
+fn reply_text(reply: &Reply<String>) -> String {
+    let mut text = Vec::new();
+    for s in reply.as_io_slices() {
+        text.extend_from_slice(&s);
+    }
+    String::from_utf8_lossy(&text)
+        .trim_end()
+        .replace("\r\n", "\n")
+}
+
+/// Converts a reply that is not positive into the matching failure
+pub(crate) fn reply_failure(reply: &Reply<String>) -> TransportFailure {
+    let err = io::Error::new(io::ErrorKind::Other, reply_text(reply));
+    match reply.code.kind() {
+        ReplyCodeKind::PermanentNegative => TransportFailure::RemotePermanent(reply.code, err),
+        _ => TransportFailure::RemoteTransient(reply.code, err),
+    }
+}
+
+pub(crate) fn expect(reply: &Reply<String>, kind: ReplyCodeKind) -> Result<(), TransportFailure> {
+    if reply.code.kind() == kind {
+        Ok(())
+    } else {
+        Err(reply_failure(reply))
+    }
+}
@@ -0,0 +1,379 @@
+use std::{
+    net::{SocketAddr, TcpStream},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+};
+
+use async_trait::async_trait;
+use futures::{io::AsyncRead, pin_mut};
+use smol::Async;
+use smtp_message::{Capabilities, Command, Hostname, ReplyCodeKind};
+use smtp_queue::{MailMetadata, Transport, TransportFailure, TransportSender};
+
+use crate::{expect, Connection, Stream};
+
+/// Address an LMTP server listens on
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum LmtpDestination {
+    Unix(PathBuf),
+    Tcp(SocketAddr),
+}
+
+/// Transport that hands all mails over to an LMTP server (RFC 2033),
+/// usually the MDA
+///
+/// The LMTP server replies for each recipient after the end of the data, so
+/// that the queue only retries or bounces the mail for the recipients it
+/// failed for.
+pub struct LmtpTransport {
+    dest: LmtpDestination,
+    hostname: Hostname,
+}
+
+impl LmtpTransport {
+    /// `hostname` is the name given to the server in the `LHLO` command
+    pub fn new(dest: LmtpDestination, hostname: Hostname) -> LmtpTransport {
+        LmtpTransport { dest, hostname }
+    }
+}
+
+#[async_trait]
+impl<U> Transport<U> for LmtpTransport
+where
+    U: 'static + Send + Sync,
+{
+    type Destination = LmtpDestination;
+    type Sender = LmtpSender;
+
+    async fn destination(
+        &self,
+        _meta: &MailMetadata<U>,
+    ) -> Result<LmtpDestination, TransportFailure> {
+        Ok(self.dest.clone())
+    }
+
+    async fn connect(&self, dest: &LmtpDestination) -> Result<LmtpSender, TransportFailure> {
+        let mut sender = LmtpSender {
+            dest: dest.clone(),
+            hostname: self.hostname.clone(),
+            conn: None,
+            reused: false,
+        };
+        sender.connection().await?;
+        Ok(sender)
+    }
+}
+
+/// An LMTP session, that is opened again if the connection was lost while
+/// sending a mail
+pub struct LmtpSender {
+    dest: LmtpDestination,
+    hostname: Hostname,
+    conn: Option<Connection<Stream>>,
+
+    /// Whether `conn` was already used for a previous mail
+    reused: bool,
+}
+
+impl LmtpSender {
+    async fn connection(&mut self) -> Result<&mut Connection<Stream>, TransportFailure> {
+        if self.conn.is_none() {
+            let stream = match self.dest {
+                LmtpDestination::Unix(ref path) => Stream::Unix(
+                    Async::<UnixStream>::connect(path)
+                        .await
+                        .map_err(TransportFailure::Local)?,
+                ),
+                LmtpDestination::Tcp(addr) => Stream::Tcp(
+                    Async::<TcpStream>::connect(addr)
+                        .await
+                        .map_err(TransportFailure::Local)?,
+                ),
+            };
+            let mut conn = Connection::new(stream);
+            let reply = conn.read_reply().await.map_err(TransportFailure::Local)?;
+            expect(&reply, ReplyCodeKind::PositiveCompletion)?;
+            let lhlo = Command::Lhlo {
+                hostname: self.hostname.clone(),
+            };
+            let reply = conn.command(&lhlo).await.map_err(TransportFailure::Local)?;
+            expect(&reply, ReplyCodeKind::PositiveCompletion)?;
+            conn.set_pipelining(Capabilities::<&str>::from_reply(&reply).pipelining);
+            self.conn = Some(conn);
+            self.reused = false;
+        }
+        Ok(self.conn.as_mut().unwrap())
+    }
+}
+
+#[async_trait]
+impl<U> TransportSender<U> for LmtpSender
+where
+    U: 'static + Send + Sync,
+{
+    async fn send<Reader>(
+        &mut self,
+        meta: &MailMetadata<U>,
+        mail: Reader,
+    ) -> Result<(), TransportFailure>
+    where
+        Reader: Send + AsyncRead,
+    {
+        pin_mut!(mail);
+        loop {
+            let conn = self.connection().await?;
+            let res = conn.send_mail(meta, mail.as_mut(), true).await;
+            if !conn.is_broken() {
+                self.reused = true;
+                return res;
+            }
+            // The connection is in an unknown state, open a new one. If it was
+            // kept open since a previous mail, it may just have been closed
+            // by the server in-between, so the mail is sent again over the new
+            // one as long as it was not read yet.
+            let retry = !conn.mail_accepted() && self.reused;
+            self.conn = None;
+            if !retry {
+                return res;
+            }
+        }
+    }
+
+    fn is_broken(&self) -> bool {
+        self.conn.is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        borrow::Cow,
+        net::TcpListener,
+        os::unix::net::UnixListener,
+        sync::{Arc, Mutex},
+    };
+
+    use futures::io::{AsyncReadExt, Cursor};
+    use smtp_message::{Email, EscapedDataReader, Reply, ReplyCode};
+    use smtp_server::{ConnectionInfo, ConnectionMetadata, Decision};
+
+    use crate::test_helpers::{email, hostname, meta};
+
+    type Mails = Arc<Mutex<Vec<(Vec<Email>, Vec<u8>)>>>;
+
+    struct TestMda {
+        mails: Mails,
+    }
+
+    #[async_trait]
+    impl smtp_server::Config for TestMda {
+        type ConnectionUserMeta = ();
+        type MailUserMeta = ();
+
+        fn hostname(&self) -> Cow<'static, str> {
+            "mda.example.org".into()
+        }
+
+        fn is_lmtp(&self) -> bool {
+            true
+        }
+
+        async fn new_mail(&self, _conn_meta: &mut ConnectionMetadata<()>) {}
+
+        async fn filter_from(
+            &self,
+            _from: &mut Option<Email<&str>>,
+            _meta: &mut smtp_server::MailMetadata<()>,
+            _conn_meta: &mut ConnectionMetadata<()>,
+        ) -> Decision {
+            Decision::Accept
+        }
+
+        async fn filter_to(
+            &self,
+            to: &mut Email<&str>,
+            _meta: &mut smtp_server::MailMetadata<()>,
+            _conn_meta: &mut ConnectionMetadata<()>,
+        ) -> Decision {
+            if *to.localpart.raw() == "nobody" {
+                Decision::Reject(Reply {
+                    code: ReplyCode::MAILBOX_UNAVAILABLE,
+                    ecode: None,
+                    text: vec!["No such user".into()],
+                })
+            } else {
+                Decision::Accept
+            }
+        }
+
+        async fn handle_mail<'a, R>(
+            &self,
+            _reader: &mut EscapedDataReader<'a, R>,
+            _meta: smtp_server::MailMetadata<()>,
+            _conn_meta: &mut ConnectionMetadata<()>,
+        ) -> Decision
+        where
+            R: Send + Unpin + AsyncRead,
+        {
+            unreachable!("handle_mail_per_recipient is always used in LMTP mode")
+        }
+
+        async fn handle_mail_per_recipient<'a, R>(
+            &self,
+            reader: &mut EscapedDataReader<'a, R>,
+            meta: smtp_server::MailMetadata<()>,
+            _conn_meta: &mut ConnectionMetadata<()>,
+        ) -> Vec<Decision>
+        where
+            R: Send + Unpin + AsyncRead,
+        {
+            let mut text = Vec::new();
+            if reader.read_to_end(&mut text).await.is_err() {
+                return meta
+                    .to
+                    .iter()
+                    .map(|_| Decision::Reject(self.handle_mail_did_not_call_complete()))
+                    .collect();
+            }
+            reader.complete();
+            let mut delivered = Vec::new();
+            let decisions = meta
+                .to
+                .into_iter()
+                .map(|to| match *to.localpart.raw() == "full" {
+                    true => Decision::Reject(self.insufficient_storage()),
+                    false => {
+                        delivered.push(to);
+                        Decision::Accept
+                    }
+                })
+                .collect();
+            self.mails.lock().unwrap().push((delivered, text));
+            decisions
+        }
+    }
+
+    fn failure_code(res: &Result<(), TransportFailure>) -> Option<(bool, ReplyCode)> {
+        match res {
+            Err(TransportFailure::RemotePermanent(c, _)) => Some((true, *c)),
+            Err(TransportFailure::RemoteTransient(c, _)) => Some((false, *c)),
+            _ => None,
+        }
+    }
+
+    async fn check_delivery(transport: LmtpTransport, dest: LmtpDestination, mails: Mails) {
+        let mut sender = Transport::<()>::connect(&transport, &dest)
+            .await
+            .unwrap_or_else(|_| panic!("failed connecting"));
+
+        let contents: &[u8] = b"Subject: test\r\n\r\n.hidden\r\nHello\r\n";
+        let res = sender
+            .send(&meta(&["<foo@example.org>"]), Cursor::new(contents))
+            .await;
+        assert!(res.is_ok());
+
+        let res = sender
+            .send(
+                &meta(&[
+                    "<foo@example.org>",
+                    "<nobody@example.org>",
+                    "<full@example.org>",
+                    "<bar@example.org>",
+                ]),
+                Cursor::new(contents),
+            )
+            .await;
+        match res {
+            Err(TransportFailure::PerRecipient(results)) => {
+                let codes = results.iter().map(failure_code).collect::<Vec<_>>();
+                assert_eq!(codes, vec![
+                    None,
+                    Some((true, ReplyCode::MAILBOX_UNAVAILABLE)),
+                    Some((false, ReplyCode::INSUFFICIENT_STORAGE)),
+                    None,
+                ]);
+                assert!(results[0].is_ok() && results[3].is_ok());
+            }
+            _ => panic!("expected per-recipient results"),
+        }
+
+        let res = sender
+            .send(&meta(&["<nobody@example.org>"]), Cursor::new(contents))
+            .await;
+        assert_eq!(
+            failure_code(&res),
+            Some((true, ReplyCode::MAILBOX_UNAVAILABLE))
+        );
+
+        // The server gets the mail still escaped
+        let escaped: &[u8] = b"Subject: test\r\n\r\n..hidden\r\nHello\r\n.\r\n";
+        let mails = mails.lock().unwrap();
+        let expected = vec![
+            (vec![email("<foo@example.org>")], escaped.to_vec()),
+            (
+                vec![email("<foo@example.org>"), email("<bar@example.org>")],
+                escaped.to_vec(),
+            ),
+        ];
+        assert_eq!(*mails, expected);
+    }
+
+    #[test]
+    fn lmtp_over_unix_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lmtp");
+        let listener = Async::new(UnixListener::bind(&path).unwrap()).unwrap();
+        let mails = Mails::default();
+        let cfg = Arc::new(TestMda {
+            mails: mails.clone(),
+        });
+        smol::run(async move {
+            smol::Task::spawn(async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let cfg = cfg.clone();
+                    smol::Task::spawn(async move {
+                        let _ = smtp_server::interact(stream, ConnectionInfo::default(), (), &*cfg)
+                            .await;
+                    })
+                    .detach();
+                }
+            })
+            .detach();
+
+            let dest = LmtpDestination::Unix(path);
+            let transport = LmtpTransport::new(dest.clone(), hostname());
+            check_delivery(transport, dest, mails).await;
+        });
+    }
+
+    #[test]
+    fn lmtp_over_tcp() {
+        let listener = Async::new(TcpListener::bind("127.0.0.1:0").unwrap()).unwrap();
+        let addr = listener.get_ref().local_addr().unwrap();
+        let mails = Mails::default();
+        let cfg = Arc::new(TestMda {
+            mails: mails.clone(),
+        });
+        smol::run(async move {
+            smol::Task::spawn(async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let info = ConnectionInfo::from_tcp(stream.get_ref()).unwrap();
+                    let cfg = cfg.clone();
+                    smol::Task::spawn(async move {
+                        let _ = smtp_server::interact(stream, info, (), &*cfg).await;
+                    })
+                    .detach();
+                }
+            })
+            .detach();
+
+            let dest = LmtpDestination::Tcp(addr);
+            let transport = LmtpTransport::new(dest.clone(), hostname());
+            check_delivery(transport, dest, mails).await;
+        });
+    }
+}
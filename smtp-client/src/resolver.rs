@@ -0,0 +1,413 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::IpAddr,
+};
+
+use async_trait::async_trait;
+use rand::seq::SliceRandom;
+use smtp_message::ReplyCode;
+use smtp_queue::TransportFailure;
+
+use crate::{SmtpDestination, Tlsa, TlsaSource};
+
+/// Resolves the names needed for finding where to deliver mails
+///
+/// Lookups of a name that does not exist must fail with an
+/// `io::ErrorKind::NotFound` error, while lookups of a name that exists but
+/// has no record of the requested type must return an empty list. Any other
+/// error is considered transient.
+#[async_trait]
+pub trait Resolver: 'static + Send + Sync {
+    /// Returns the MX records of `domain`, as `(preference, exchange)` pairs
+    async fn mx(&self, domain: &str) -> io::Result<Vec<(u16, String)>>;
+
+    /// Returns the MX records of `domain` like `mx`, and whether they (or
+    /// their absence) were validated with DNSSEC
+    ///
+    /// The default implementation never considers them validated, which
+    /// disables DANE.
+    async fn mx_authenticated(&self, domain: &str) -> io::Result<(Vec<(u16, String)>, bool)> {
+        Ok((self.mx(domain).await?, false))
+    }
+
+    /// Returns the IPv4 and IPv6 addresses of `host`
+    async fn ips(&self, host: &str) -> io::Result<Vec<IpAddr>>;
+
+    /// Returns the TXT records of `name`, the strings of each record being
+    /// concatenated
+    async fn txt(&self, name: &str) -> io::Result<Vec<String>>;
+}
+
+/// A host mails can be handed over to
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct NextHop {
+    /// Name of the host, or `None` if the destination was an address literal
+    pub hostname: Option<String>,
+
+    /// Addresses of the host, to be tried in order
+    pub addrs: Vec<IpAddr>,
+
+    /// Whether the hostname comes from MX records (or their absence)
+    /// validated with DNSSEC, so that the host may be authenticated with DANE
+    pub authenticated: bool,
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn tlsa_name(host: &str, port: u16) -> String {
+    format!("_{}._tcp.{}", port, normalize(host))
+}
+
+/// Shuffles the runs of MX records of equal preference in `mxs`, that must be
+/// sorted by preference
+fn shuffle_equal_preferences(mxs: &mut [(u16, String)]) {
+    let mut rng = rand::thread_rng();
+    let mut start = 0;
+    while start < mxs.len() {
+        let pref = mxs[start].0;
+        let len = mxs[start..].iter().take_while(|(p, _)| *p == pref).count();
+        mxs[start..start + len].shuffle(&mut rng);
+        start += len;
+    }
+}
+
+/// Finds the hosts that mails to `dest` are to be handed over to, in the
+/// order in which they are to be tried
+///
+/// This follows RFC 5321 section 5.1: the MX records are sorted by
+/// preference, records of equal preference being shuffled, and a domain
+/// without MX records is its own mail exchanger. A domain that publishes a
+/// null MX (RFC 7505) does not accept mail, which is a permanent failure.
+pub async fn next_hops<R>(
+    resolver: &R,
+    dest: &SmtpDestination,
+) -> Result<Vec<NextHop>, TransportFailure>
+where
+    R: ?Sized + Resolver,
+{
+    let domain = match dest {
+        SmtpDestination::Ip(ip) => {
+            return Ok(vec![NextHop {
+                hostname: None,
+                addrs: vec![*ip],
+                authenticated: false,
+            }]);
+        }
+        SmtpDestination::Domain(domain) => domain,
+        SmtpDestination::Relay => {
+            return Err(TransportFailure::Local(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "relayed mails have no next hop of their own",
+            )));
+        }
+    };
+
+    let (mut mxs, authenticated) = match resolver.mx_authenticated(domain).await {
+        Ok(res) => res,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(TransportFailure::RemotePermanent(
+                ReplyCode::MAILBOX_UNAVAILABLE,
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("domain {} does not exist", domain),
+                ),
+            ));
+        }
+        Err(e) => return Err(TransportFailure::Local(e)),
+    };
+    if mxs.iter().any(|(_, host)| normalize(host).is_empty()) {
+        return Err(TransportFailure::RemotePermanent(
+            ReplyCode::DOMAIN_DOES_NOT_ACCEPT_MAIL,
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("domain {} does not accept mail (null MX)", domain),
+            ),
+        ));
+    }
+    if mxs.is_empty() {
+        mxs.push((0, domain.clone()));
+    }
+
+    mxs.sort_by_key(|(pref, _)| *pref);
+    shuffle_equal_preferences(&mut mxs);
+
+    let mut hops = Vec::with_capacity(mxs.len());
+    let mut transient = None;
+    for (_, host) in mxs {
+        let host = normalize(&host);
+        if hops
+            .iter()
+            .any(|h: &NextHop| h.hostname.as_ref() == Some(&host))
+        {
+            continue;
+        }
+        match resolver.ips(&host).await {
+            Ok(addrs) if !addrs.is_empty() => hops.push(NextHop {
+                hostname: Some(host),
+                addrs,
+                authenticated,
+            }),
+            Ok(_) => (),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => transient = Some(e),
+        }
+    }
+    if hops.is_empty() {
+        return Err(match transient {
+            Some(e) => TransportFailure::Local(e),
+            None => TransportFailure::RemotePermanent(
+                ReplyCode::MAILBOX_UNAVAILABLE,
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no mail exchanger of domain {} has an address", domain),
+                ),
+            ),
+        });
+    }
+    Ok(hops)
+}
+
+/// Resolver answering from a fixed set of records, eg. for tests
+///
+/// A name is considered to exist as soon as it has a record of any type.
+#[derive(Clone, Debug, Default)]
+pub struct StubResolver {
+    mx: HashMap<String, Vec<(u16, String)>>,
+    ips: HashMap<String, Vec<IpAddr>>,
+    txt: HashMap<String, Vec<String>>,
+    tlsa: HashMap<String, Vec<Tlsa>>,
+    insecure_mx: HashSet<String>,
+    failing: HashSet<String>,
+}
+
+impl StubResolver {
+    pub fn new() -> StubResolver {
+        StubResolver::default()
+    }
+
+    pub fn with_mx(mut self, domain: &str, preference: u16, exchange: &str) -> StubResolver {
+        self.mx
+            .entry(normalize(domain))
+            .or_default()
+            .push((preference, exchange.to_owned()));
+        self
+    }
+
+    pub fn with_ip(mut self, host: &str, ip: IpAddr) -> StubResolver {
+        self.ips.entry(normalize(host)).or_default().push(ip);
+        self
+    }
+
+    pub fn with_txt(mut self, name: &str, txt: &str) -> StubResolver {
+        self.txt
+            .entry(normalize(name))
+            .or_default()
+            .push(txt.to_owned());
+        self
+    }
+
+    /// Adds a TLSA record for the SMTP server on `port` of `host`, that is
+    /// considered validated with DNSSEC
+    pub fn with_tlsa(mut self, host: &str, port: u16, tlsa: Tlsa) -> StubResolver {
+        self.tlsa
+            .entry(tlsa_name(host, port))
+            .or_default()
+            .push(tlsa);
+        self
+    }
+
+    /// Makes the MX records of `domain` (or their absence) not validated with
+    /// DNSSEC, while all other records are
+    pub fn with_insecure_mx(mut self, domain: &str) -> StubResolver {
+        self.insecure_mx.insert(normalize(domain));
+        self
+    }
+
+    /// Makes all lookups of `name` fail with a transient error
+    pub fn with_failure(mut self, name: &str) -> StubResolver {
+        self.failing.insert(normalize(name));
+        self
+    }
+
+    fn lookup<T: Clone>(
+        &self,
+        name: &str,
+        records: &HashMap<String, Vec<T>>,
+    ) -> io::Result<Vec<T>> {
+        let name = normalize(name);
+        if self.failing.contains(&name) {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("lookup of {} timed out", name),
+            ));
+        }
+        if let Some(res) = records.get(&name) {
+            return Ok(res.clone());
+        }
+        if self.mx.contains_key(&name)
+            || self.ips.contains_key(&name)
+            || self.txt.contains_key(&name)
+            || self.tlsa.contains_key(&name)
+        {
+            return Ok(Vec::new());
+        }
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} does not exist", name),
+        ))
+    }
+}
+
+#[async_trait]
+impl Resolver for StubResolver {
+    async fn mx(&self, domain: &str) -> io::Result<Vec<(u16, String)>> {
+        self.lookup(domain, &self.mx)
+    }
+
+    async fn mx_authenticated(&self, domain: &str) -> io::Result<(Vec<(u16, String)>, bool)> {
+        let mxs = self.lookup(domain, &self.mx)?;
+        Ok((mxs, !self.insecure_mx.contains(&normalize(domain))))
+    }
+
+    async fn ips(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        self.lookup(host, &self.ips)
+    }
+
+    async fn txt(&self, name: &str) -> io::Result<Vec<String>> {
+        self.lookup(name, &self.txt)
+    }
+}
+
+#[async_trait]
+impl TlsaSource for StubResolver {
+    async fn tlsa(&self, host: &str, port: u16) -> io::Result<Vec<Tlsa>> {
+        self.lookup(&tlsa_name(host, port), &self.tlsa)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn hops(resolver: &StubResolver, domain: &str) -> Result<Vec<NextHop>, TransportFailure> {
+        let dest = SmtpDestination::Domain(domain.to_owned());
+        smol::block_on(next_hops(resolver, &dest))
+    }
+
+    fn ok(res: Result<Vec<NextHop>, TransportFailure>) -> Vec<NextHop> {
+        res.unwrap_or_else(|_| panic!("expected next hops"))
+    }
+
+    fn hostnames(hops: &[NextHop]) -> Vec<&str> {
+        hops.iter()
+            .map(|h| h.hostname.as_deref().unwrap())
+            .collect()
+    }
+
+    fn failure(res: Result<Vec<NextHop>, TransportFailure>) -> (bool, Option<ReplyCode>) {
+        match res {
+            Ok(_) => panic!("expected a failure"),
+            Err(TransportFailure::RemotePermanent(c, _)) => (true, Some(c)),
+            Err(TransportFailure::RemoteTransient(c, _)) => (false, Some(c)),
+            Err(TransportFailure::Local(_)) => (false, None),
+            Err(TransportFailure::PerRecipient(_)) => panic!("unexpected per-recipient failure"),
+        }
+    }
+
+    #[test]
+    fn mx_preference() {
+        let resolver = StubResolver::new()
+            .with_mx("example.org", 20, "mx3.example.org.")
+            .with_mx("example.org", 10, "mx1.example.org.")
+            .with_mx("example.org", 10, "mx2.example.org.")
+            .with_mx("example.org", 30, "MX4.example.org")
+            .with_ip("mx1.example.org", ip("192.0.2.1"))
+            .with_ip("mx2.example.org", ip("192.0.2.2"))
+            .with_ip("mx2.example.org", ip("2001:db8::2"))
+            .with_ip("mx3.example.org", ip("192.0.2.3"))
+            .with_ip("mx4.example.org", ip("192.0.2.4"));
+        let hops = ok(hops(&resolver, "example.org"));
+        let names = hostnames(&hops);
+        assert!(
+            names[..2] == ["mx1.example.org", "mx2.example.org"]
+                || names[..2] == ["mx2.example.org", "mx1.example.org"]
+        );
+        assert_eq!(names[2..], ["mx3.example.org", "mx4.example.org"]);
+        let mx2 = hops.iter().find(|h| h.addrs.len() == 2).unwrap();
+        assert_eq!(mx2.addrs, vec![ip("192.0.2.2"), ip("2001:db8::2")]);
+    }
+
+    #[test]
+    fn implicit_mx() {
+        let resolver = StubResolver::new().with_ip("example.org", ip("192.0.2.1"));
+        assert_eq!(ok(hops(&resolver, "example.org")), vec![NextHop {
+            hostname: Some("example.org".to_owned()),
+            addrs: vec![ip("192.0.2.1")],
+            authenticated: true,
+        }]);
+
+        let resolver = resolver.with_insecure_mx("example.org");
+        assert!(!ok(hops(&resolver, "example.org"))[0].authenticated);
+    }
+
+    #[test]
+    fn null_mx() {
+        let resolver = StubResolver::new()
+            .with_mx("example.org", 0, ".")
+            .with_ip("example.org", ip("192.0.2.1"));
+        assert_eq!(
+            failure(hops(&resolver, "example.org")),
+            (true, Some(ReplyCode::DOMAIN_DOES_NOT_ACCEPT_MAIL))
+        );
+    }
+
+    #[test]
+    fn unresolvable() {
+        let resolver = StubResolver::new()
+            .with_mx("example.org", 10, "mx1.example.org")
+            .with_mx("example.org", 20, "mx2.example.org")
+            .with_ip("mx2.example.org", ip("192.0.2.2"))
+            .with_mx("nohost.example.org", 10, "mx1.example.org")
+            .with_mx("failing.example.org", 10, "mx1.example.org")
+            .with_mx("failing.example.org", 20, "down.example.org")
+            .with_failure("down.example.org")
+            .with_failure("down.example.com");
+
+        // MX hosts without addresses are skipped
+        let res = ok(hops(&resolver, "example.org"));
+        assert_eq!(hostnames(&res), ["mx2.example.org"]);
+
+        assert_eq!(
+            failure(hops(&resolver, "nonexistent.example.org")),
+            (true, Some(ReplyCode::MAILBOX_UNAVAILABLE))
+        );
+        assert_eq!(
+            failure(hops(&resolver, "nohost.example.org")),
+            (true, Some(ReplyCode::MAILBOX_UNAVAILABLE))
+        );
+        assert_eq!(
+            failure(hops(&resolver, "failing.example.org")),
+            (false, None)
+        );
+        assert_eq!(failure(hops(&resolver, "down.example.com")), (false, None));
+    }
+
+    #[test]
+    fn ip_literal() {
+        let resolver = StubResolver::new();
+        let dest = SmtpDestination::Ip(ip("2001:db8::1"));
+        let res = smol::block_on(next_hops(&resolver, &dest));
+        assert_eq!(ok(res), vec![NextHop {
+            hostname: None,
+            addrs: vec![ip("2001:db8::1")],
+            authenticated: false,
+        }]);
+    }
+}
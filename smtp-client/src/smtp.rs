@@ -0,0 +1,1197 @@
+use std::{
+    io,
+    net::{IpAddr, SocketAddr, TcpStream},
+    sync::Arc,
+};
+
+use async_tls::TlsConnector;
+use async_trait::async_trait;
+use futures::{io::AsyncRead, pin_mut};
+use rustls::ClientConfig;
+use smol::Async;
+use smtp_message::{Capabilities, Command, Hostname, MaybeUtf8, ReplyCode, ReplyCodeKind};
+use smtp_queue::{MailMetadata, Transport, TransportFailure, TransportSender};
+
+use crate::{
+    dane::dane_tls_config,
+    expect, next_hops, opportunistic_tls_config,
+    tlsrpt::{failure_type, StarttlsUnsupported},
+    Connection, FailureDetails, FailureType, MtaSts, NextHop, RelayConfig, Resolver, Smarthost,
+    Stream, StsMode, StsPolicy, TlsPolicy, TlsRptRecorder, TlsaSource,
+};
+
+pub const SMTP_PORT: u16 = 25;
+
+/// Where mails to a given recipient domain are sent
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum SmtpDestination {
+    /// A domain, lowercased and in its ASCII form
+    Domain(String),
+
+    /// An address literal
+    Ip(IpAddr),
+
+    /// The smarthosts of the transport
+    Relay,
+}
+
+impl SmtpDestination {
+    pub fn from_hostname(hostname: &Hostname) -> SmtpDestination {
+        match hostname {
+            Hostname::Utf8Domain { punycode, .. } => {
+                SmtpDestination::Domain(punycode.to_ascii_lowercase())
+            }
+            Hostname::AsciiDomain { raw } => SmtpDestination::Domain(raw.to_ascii_lowercase()),
+            Hostname::Ipv4 { ip, .. } => SmtpDestination::Ip(IpAddr::V4(*ip)),
+            Hostname::Ipv6 { ip, .. } => SmtpDestination::Ip(IpAddr::V6(*ip)),
+        }
+    }
+}
+
+struct Connector<R> {
+    resolver: R,
+    hostname: Hostname,
+    port: u16,
+    relay: Option<RelayConfig>,
+    mta_sts: Option<MtaSts>,
+    dane: Option<Box<dyn TlsaSource>>,
+    tls_reports: Option<Arc<TlsRptRecorder>>,
+}
+
+/// How the sessions with a next hop must be protected
+struct HopPolicy {
+    tls: Arc<ClientConfig>,
+
+    /// The policy that the sessions are reported against
+    report: TlsPolicy,
+
+    /// Whether failing to establish TLS fails the session, rather than only
+    /// being reported, which is the case for MTA-STS policies in testing mode
+    enforce: bool,
+}
+
+impl<R> Connector<R>
+where
+    R: Resolver,
+{
+    /// Opens a session with the first next hop of `dest` that accepts it
+    ///
+    /// The next hops are tried in order as long as they fail transiently, eg.
+    /// because they cannot be reached or reply with a 4xx code.
+    async fn connect(
+        &self,
+        dest: &SmtpDestination,
+    ) -> Result<Connection<Stream>, TransportFailure> {
+        if let (SmtpDestination::Relay, Some(relay)) = (dest, &self.relay) {
+            return self.connect_relay(relay).await;
+        }
+        let domain = match dest {
+            SmtpDestination::Domain(domain) => Some(domain as &str),
+            _ => None,
+        };
+        let sts = match (domain, &self.mta_sts) {
+            (Some(domain), Some(sts)) => {
+                let (policy, failure) = sts.lookup(&self.resolver, domain).await;
+                if let Some(failure) = failure {
+                    let report = match policy {
+                        Some(ref policy) => TlsPolicy::sts(domain, policy),
+                        None => TlsPolicy::no_policy_found(domain),
+                    };
+                    self.report(&report, None, None, Err(failure));
+                }
+                match policy {
+                    Some(policy) if policy.mode != StsMode::None => Some((sts, policy)),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        let mut last_err = None;
+        for hop in next_hops(&self.resolver, dest).await? {
+            let policy = match self.tls_config(domain, &hop, sts.as_ref()).await {
+                Ok(policy) => policy,
+                Err(e) => {
+                    last_err = Some(e);
+                    continue;
+                }
+            };
+            for ip in hop.addrs.iter() {
+                let addr = SocketAddr::new(*ip, self.port);
+                let res = match (&policy, &hop.hostname) {
+                    (Some(policy), Some(host)) => {
+                        let res = self.open_tls(addr, host, policy.tls.clone()).await;
+                        // Failures unrelated to TLS, like the server being
+                        // unreachable, are not reported
+                        let outcome = match res {
+                            Ok(_) => Some(Ok(())),
+                            Err(ref e) => failure_type(e).map(Err),
+                        };
+                        if let Some(outcome) = outcome {
+                            self.report(&policy.report, Some(host), Some(addr), outcome);
+                        }
+                        match outcome {
+                            Some(Err(_)) if !policy.enforce => self.open(addr, Some(host)).await,
+                            _ => res,
+                        }
+                    }
+                    _ => self.open(addr, hop.hostname.as_deref()).await,
+                };
+                match res {
+                    Ok(conn) => return Ok(conn),
+                    Err(e @ TransportFailure::RemotePermanent(..)) => return Err(e),
+                    Err(e) => last_err = Some(e),
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            TransportFailure::Local(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no next hop to connect to",
+            ))
+        }))
+    }
+
+    /// Returns how the sessions with `hop`, a next hop of `domain`, must be
+    /// protected, if at all, `sts` being the MTA-STS policy to apply
+    ///
+    /// DANE takes precedence over MTA-STS, following RFC 8461 section 2. It
+    /// only applies to mail exchangers found through MX records validated
+    /// with DNSSEC (RFC 7672 section 2.2.1). The TLSA records of a mail
+    /// exchanger failing to be retrieved, or it not being allowed by an
+    /// MTA-STS policy in enforce mode, is a transient failure.
+    async fn tls_config(
+        &self,
+        domain: Option<&str>,
+        hop: &NextHop,
+        sts: Option<&(&MtaSts, StsPolicy)>,
+    ) -> Result<Option<HopPolicy>, TransportFailure> {
+        let host = match hop.hostname {
+            Some(ref host) => host,
+            None => return Ok(None),
+        };
+        if let (Some(dane), true) = (&self.dane, hop.authenticated) {
+            match dane.tlsa(host, self.port).await {
+                Ok(records) if !records.is_empty() => {
+                    return Ok(Some(HopPolicy {
+                        report: TlsPolicy::tlsa(domain.unwrap_or(host), host, &records),
+                        tls: dane_tls_config(records),
+                        enforce: true,
+                    }));
+                }
+                Ok(_) => (),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(TransportFailure::Local(e)),
+            }
+        }
+        let (sts, policy, report) = match (domain, sts) {
+            (Some(domain), Some((sts, policy))) => (sts, policy, TlsPolicy::sts(domain, policy)),
+            _ => return Ok(None),
+        };
+        let enforce = policy.mode == StsMode::Enforce;
+        if !policy.matches(host) {
+            let addr = hop.addrs.first().map(|ip| SocketAddr::new(*ip, self.port));
+            self.report(
+                &report,
+                Some(host),
+                addr,
+                Err(FailureType::ValidationFailure),
+            );
+            if !enforce {
+                return Ok(None);
+            }
+            return Err(TransportFailure::Local(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "mail exchanger {} is not allowed by the MTA-STS policy",
+                    host
+                ),
+            )));
+        }
+        Ok(Some(HopPolicy {
+            tls: sts.tls.clone(),
+            report,
+            enforce,
+        }))
+    }
+
+    /// Records a session with `host` at `addr` under `policy`, that failed
+    /// if `res` is an error, if TLS reporting is enabled
+    fn report(
+        &self,
+        policy: &TlsPolicy,
+        host: Option<&str>,
+        addr: Option<SocketAddr>,
+        res: Result<(), FailureType>,
+    ) {
+        if let Some(ref recorder) = self.tls_reports {
+            let failure = res.err().map(|result_type| FailureDetails {
+                result_type,
+                sending_mta_ip: None,
+                receiving_mx_hostname: host.map(str::to_owned),
+                receiving_ip: addr.map(|a| a.ip()),
+            });
+            recorder.record(policy.clone(), failure);
+        }
+    }
+
+    /// Opens a session with the first smarthost that accepts it
+    ///
+    /// Failures to do so are never permanent, so that mails stay queued until
+    /// the smarthosts or their configuration are fixed.
+    async fn connect_relay(
+        &self,
+        relay: &RelayConfig,
+    ) -> Result<Connection<Stream>, TransportFailure> {
+        let mut last_err = None;
+        for host in relay.smarthosts.iter() {
+            let ips = match host.hostname.parse::<IpAddr>() {
+                Ok(ip) => vec![ip],
+                Err(_) => match self.resolver.ips(&host.hostname).await {
+                    Ok(ips) => ips,
+                    Err(e) => {
+                        last_err = Some(TransportFailure::Local(e));
+                        continue;
+                    }
+                },
+            };
+            for ip in ips {
+                let addr = SocketAddr::new(ip, host.port);
+                match self.open_relay(relay, host, addr).await {
+                    Ok(conn) => return Ok(conn),
+                    Err(TransportFailure::RemotePermanent(_, e)) => {
+                        last_err = Some(TransportFailure::Local(e))
+                    }
+                    Err(e) => last_err = Some(e),
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            TransportFailure::Local(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no smarthost to connect to",
+            ))
+        }))
+    }
+
+    async fn open_relay(
+        &self,
+        relay: &RelayConfig,
+        host: &Smarthost,
+        addr: SocketAddr,
+    ) -> Result<Connection<Stream>, TransportFailure> {
+        let conn = self.greet(addr).await?;
+        let mut conn = self
+            .starttls(conn, &host.hostname, relay.tls.clone())
+            .await?;
+        if let Some(ref creds) = host.credentials {
+            let mut plain = Vec::new();
+            plain.push(0);
+            plain.extend_from_slice(creds.username.as_bytes());
+            plain.push(0);
+            plain.extend_from_slice(creds.password.as_bytes());
+            let auth = Command::Auth {
+                mechanism: MaybeUtf8::Ascii("PLAIN"),
+                initial_response: Some(MaybeUtf8::Ascii(&base64::encode(&plain) as &str)),
+            };
+            let reply = conn.command(&auth).await.map_err(TransportFailure::Local)?;
+            expect(&reply, ReplyCodeKind::PositiveCompletion)?;
+        }
+        Ok(conn)
+    }
+
+    /// Opens a session with `addr` that is protected with STARTTLS, the
+    /// certificate of the server having to be valid for `host`
+    async fn open_tls(
+        &self,
+        addr: SocketAddr,
+        host: &str,
+        tls: Arc<ClientConfig>,
+    ) -> Result<Connection<Stream>, TransportFailure> {
+        let conn = self.greet(addr).await?;
+        self.starttls(conn, host, tls).await
+    }
+
+    /// Upgrades `conn` to TLS, the certificate of the server having to be
+    /// valid for `host`
+    ///
+    /// The server refusing to do so is never a permanent failure, as it
+    /// could be fixed or be an attack.
+    async fn starttls(
+        &self,
+        mut conn: Connection<Stream>,
+        host: &str,
+        tls: Arc<ClientConfig>,
+    ) -> Result<Connection<Stream>, TransportFailure> {
+        if !self.ehlo(&mut conn).await?.starttls {
+            return Err(TransportFailure::Local(io::Error::new(
+                io::ErrorKind::Other,
+                StarttlsUnsupported(host.to_owned()),
+            )));
+        }
+        let reply = conn
+            .command(&Command::<&str>::Starttls)
+            .await
+            .map_err(TransportFailure::Local)?;
+        if reply.code.kind() != ReplyCodeKind::PositiveCompletion {
+            return Err(TransportFailure::Local(io::Error::new(
+                io::ErrorKind::Other,
+                StarttlsUnsupported(host.to_owned()),
+            )));
+        }
+        let io = conn.into_io().map_err(TransportFailure::Local)?;
+        let io = TlsConnector::from(tls)
+            .connect(host, io)
+            .await
+            .map_err(TransportFailure::Local)?;
+
+        // The session restarts from scratch once TLS is established
+        let mut conn = Connection::new(Stream::Tls(Box::new(io)));
+        self.ehlo(&mut conn).await?;
+        Ok(conn)
+    }
+
+    /// Connects to `addr` and waits for the greeting of the server
+    async fn greet(&self, addr: SocketAddr) -> Result<Connection<Stream>, TransportFailure> {
+        let stream = Async::<TcpStream>::connect(addr)
+            .await
+            .map_err(TransportFailure::Local)?;
+        let mut conn = Connection::new(Stream::Tcp(stream));
+        let reply = conn.read_reply().await.map_err(TransportFailure::Local)?;
+        expect(&reply, ReplyCodeKind::PositiveCompletion)?;
+        Ok(conn)
+    }
+
+    async fn ehlo(&self, conn: &mut Connection<Stream>) -> Result<Capabilities, TransportFailure> {
+        let ehlo = Command::Ehlo {
+            hostname: self.hostname.clone(),
+        };
+        let reply = conn.command(&ehlo).await.map_err(TransportFailure::Local)?;
+        expect(&reply, ReplyCodeKind::PositiveCompletion)?;
+        let capabilities = Capabilities::from_reply(&reply);
+        conn.set_pipelining(capabilities.pipelining);
+        Ok(capabilities)
+    }
+
+    /// Says EHLO, or HELO to servers that do not know about ESMTP, returning
+    /// the capabilities the server advertised if it accepted EHLO
+    async fn hello(
+        &self,
+        conn: &mut Connection<Stream>,
+    ) -> Result<Option<Capabilities>, TransportFailure> {
+        let ehlo = Command::Ehlo {
+            hostname: self.hostname.clone(),
+        };
+        let reply = conn.command(&ehlo).await.map_err(TransportFailure::Local)?;
+        if reply.code.kind() == ReplyCodeKind::PositiveCompletion {
+            let capabilities = Capabilities::from_reply(&reply);
+            conn.set_pipelining(capabilities.pipelining);
+            return Ok(Some(capabilities));
+        }
+        let helo = Command::Helo {
+            hostname: self.hostname.clone(),
+        };
+        let reply = conn.command(&helo).await.map_err(TransportFailure::Local)?;
+        expect(&reply, ReplyCodeKind::PositiveCompletion)?;
+        Ok(None)
+    }
+
+    /// Opens a session with `addr`, that is upgraded with STARTTLS whenever
+    /// the server supports it, `host` being the name of the server if known
+    ///
+    /// This is opportunistic TLS (RFC 7435), so the certificate of the server
+    /// is not checked, and the session is opened again without TLS if TLS
+    /// fails to be established after the server accepted `STARTTLS`.
+    async fn open(
+        &self,
+        addr: SocketAddr,
+        host: Option<&str>,
+    ) -> Result<Connection<Stream>, TransportFailure> {
+        let mut conn = self.greet(addr).await?;
+        match self.hello(&mut conn).await? {
+            Some(ref capabilities) if capabilities.starttls => (),
+            _ => return Ok(conn),
+        }
+        let reply = conn
+            .command(&Command::<&str>::Starttls)
+            .await
+            .map_err(TransportFailure::Local)?;
+        if reply.code.kind() != ReplyCodeKind::PositiveCompletion {
+            // The session goes on without TLS
+            return Ok(conn);
+        }
+        // Address literals have no name to send with SNI
+        let (name, tls) = match host {
+            Some(host) => (host, opportunistic_tls_config(true)),
+            None => ("address-literal.invalid", opportunistic_tls_config(false)),
+        };
+        let res = match conn.into_io() {
+            Ok(io) => TlsConnector::from(tls).connect(name, io).await,
+            Err(e) => Err(e),
+        };
+        match res {
+            Ok(io) => {
+                // The session restarts from scratch once TLS is established
+                let mut conn = Connection::new(Stream::Tls(Box::new(io)));
+                self.ehlo(&mut conn).await?;
+                Ok(conn)
+            }
+            Err(_) => {
+                let mut conn = self.greet(addr).await?;
+                self.hello(&mut conn).await?;
+                Ok(conn)
+            }
+        }
+    }
+}
+
+/// Transport that delivers mails to the mail exchangers of their recipients'
+/// domain, over SMTP
+///
+/// All the recipients of a mail must be in the same domain, which is the case
+/// for mails enqueued with `Queue::enqueue_split_by_domain`. When relaying
+/// through smarthosts, all the recipients must instead be either relayed or
+/// in the same directly delivered domain. Other mails are bounced.
+///
+/// Sessions with mail exchangers that are not under a DANE or MTA-STS policy
+/// use opportunistic TLS when the server supports STARTTLS.
+pub struct SmtpTransport<R> {
+    connector: Arc<Connector<R>>,
+}
+
+impl<R> SmtpTransport<R>
+where
+    R: Resolver,
+{
+    /// `hostname` is the name given to the servers in the `EHLO` command
+    pub fn new(resolver: R, hostname: Hostname) -> SmtpTransport<R> {
+        SmtpTransport::with_port(resolver, hostname, SMTP_PORT)
+    }
+
+    pub fn with_port(resolver: R, hostname: Hostname, port: u16) -> SmtpTransport<R> {
+        SmtpTransport {
+            connector: Arc::new(Connector {
+                resolver,
+                hostname,
+                port,
+                relay: None,
+                mta_sts: None,
+                dane: None,
+                tls_reports: None,
+            }),
+        }
+    }
+
+    /// Relays mails through the smarthosts of `relay`, except for the
+    /// domains it delivers directly to
+    pub fn relay(resolver: R, hostname: Hostname, relay: RelayConfig) -> SmtpTransport<R> {
+        SmtpTransport {
+            connector: Arc::new(Connector {
+                resolver,
+                hostname,
+                port: SMTP_PORT,
+                relay: Some(relay),
+                mta_sts: None,
+                dane: None,
+                tls_reports: None,
+            }),
+        }
+    }
+
+    /// Enforces the MTA-STS policies of the domains mails are delivered to
+    /// directly
+    ///
+    /// This must be called before the transport is used.
+    pub fn with_mta_sts(mut self, mta_sts: MtaSts) -> SmtpTransport<R> {
+        Arc::get_mut(&mut self.connector)
+            .expect("MTA-STS configured on a transport already in use")
+            .mta_sts = Some(mta_sts);
+        self
+    }
+
+    /// Authenticates the mail exchangers that have TLSA records in `source`
+    /// with DANE (RFC 7672)
+    ///
+    /// This must be called before the transport is used.
+    pub fn with_dane<T>(mut self, source: T) -> SmtpTransport<R>
+    where
+        T: TlsaSource,
+    {
+        Arc::get_mut(&mut self.connector)
+            .expect("DANE configured on a transport already in use")
+            .dane = Some(Box::new(source));
+        self
+    }
+
+    /// Records the outcome of the sessions opened under a DANE or MTA-STS
+    /// policy into `recorder`, for TLS reports to be generated from them
+    pub fn with_tls_reporting(mut self, recorder: Arc<TlsRptRecorder>) -> SmtpTransport<R> {
+        Arc::get_mut(&mut self.connector)
+            .expect("TLS reporting configured on a transport already in use")
+            .tls_reports = Some(recorder);
+        self
+    }
+}
+
+#[async_trait]
+impl<U, R> Transport<U> for SmtpTransport<R>
+where
+    U: 'static + Send + Sync,
+    R: Resolver,
+{
+    type Destination = SmtpDestination;
+    type Sender = SmtpSender<R>;
+
+    async fn destination(
+        &self,
+        meta: &MailMetadata<U>,
+    ) -> Result<SmtpDestination, TransportFailure> {
+        let relay = self.connector.relay.as_ref();
+        let mut dests = meta.to.iter().map(|to| match to.hostname {
+            Some(ref h) => {
+                let dest = SmtpDestination::from_hostname(h);
+                Ok(match relay {
+                    Some(relay) => relay.route(dest),
+                    None => dest,
+                })
+            }
+            None => Err(TransportFailure::RemotePermanent(
+                ReplyCode::MAILBOX_UNAVAILABLE,
+                io::Error::new(io::ErrorKind::InvalidInput, "recipient without a domain"),
+            )),
+        });
+        let dest = match dests.next() {
+            Some(dest) => dest?,
+            None => {
+                return Err(TransportFailure::Local(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "mail without recipients",
+                )));
+            }
+        };
+        for d in dests {
+            if d? != dest {
+                // Retrying would not help, as the recipients of a queued mail
+                // never change
+                return Err(TransportFailure::RemotePermanent(
+                    ReplyCode::TRANSACTION_FAILED,
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "recipients of a mail to deliver over SMTP must be in a single domain",
+                    ),
+                ));
+            }
+        }
+        Ok(dest)
+    }
+
+    async fn connect(&self, dest: &SmtpDestination) -> Result<SmtpSender<R>, TransportFailure> {
+        let conn = self.connector.connect(dest).await?;
+        Ok(SmtpSender {
+            connector: self.connector.clone(),
+            dest: dest.clone(),
+            conn: Some(conn),
+            reused: false,
+        })
+    }
+}
+
+/// An SMTP session with one of the next hops of a destination, that is
+/// opened again if it broke while sending a mail
+pub struct SmtpSender<R> {
+    connector: Arc<Connector<R>>,
+    dest: SmtpDestination,
+    conn: Option<Connection<Stream>>,
+
+    /// Whether `conn` was already used for a previous mail
+    reused: bool,
+}
+
+#[async_trait]
+impl<U, R> TransportSender<U> for SmtpSender<R>
+where
+    U: 'static + Send + Sync,
+    R: Resolver,
+{
+    async fn send<Reader>(
+        &mut self,
+        meta: &MailMetadata<U>,
+        mail: Reader,
+    ) -> Result<(), TransportFailure>
+    where
+        Reader: Send + AsyncRead,
+    {
+        pin_mut!(mail);
+        loop {
+            if self.conn.is_none() {
+                self.conn = Some(self.connector.connect(&self.dest).await?);
+                self.reused = false;
+            }
+            let conn = self.conn.as_mut().unwrap();
+            let res = conn.send_mail(meta, mail.as_mut(), false).await;
+            if !conn.is_broken() {
+                self.reused = true;
+                return res;
+            }
+            // A session kept open since a previous mail may have been closed
+            // by the server in-between, so it is opened again once, as long as
+            // the mail was not read yet
+            let retry = self.reused && !conn.mail_accepted();
+            self.conn = None;
+            if !retry {
+                return res;
+            }
+        }
+    }
+
+    fn is_broken(&self) -> bool {
+        self.conn.is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        borrow::Cow,
+        net::TcpListener,
+        sync::{Arc, Mutex},
+    };
+
+    use async_tls::TlsAcceptor;
+    use futures::io::{
+        AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, Cursor,
+    };
+    use smtp_message::{Email, EscapedDataReader};
+    use smtp_server::{ConnectionInfo, ConnectionMetadata, Decision};
+
+    use crate::{
+        relay::tests::{read_command, reply, server_tls, Log},
+        test_helpers::{email, hostname, meta},
+        StubResolver,
+    };
+
+    type Mails = Arc<Mutex<Vec<(Vec<Email>, Vec<u8>)>>>;
+
+    struct TestMx {
+        mails: Mails,
+    }
+
+    #[async_trait]
+    impl smtp_server::Config for TestMx {
+        type ConnectionUserMeta = ();
+        type MailUserMeta = ();
+
+        fn hostname(&self) -> Cow<'static, str> {
+            "mx.example.org".into()
+        }
+
+        async fn new_mail(&self, _conn_meta: &mut ConnectionMetadata<()>) {}
+
+        async fn filter_from(
+            &self,
+            _from: &mut Option<Email<&str>>,
+            _meta: &mut smtp_server::MailMetadata<()>,
+            _conn_meta: &mut ConnectionMetadata<()>,
+        ) -> Decision {
+            Decision::Accept
+        }
+
+        async fn filter_to(
+            &self,
+            to: &mut Email<&str>,
+            _meta: &mut smtp_server::MailMetadata<()>,
+            _conn_meta: &mut ConnectionMetadata<()>,
+        ) -> Decision {
+            if *to.localpart.raw() == "nobody" {
+                Decision::Reject(smtp_message::Reply {
+                    code: ReplyCode::MAILBOX_UNAVAILABLE,
+                    ecode: None,
+                    text: vec!["No such user".into()],
+                })
+            } else {
+                Decision::Accept
+            }
+        }
+
+        async fn handle_mail<'a, R>(
+            &self,
+            reader: &mut EscapedDataReader<'a, R>,
+            meta: smtp_server::MailMetadata<()>,
+            _conn_meta: &mut ConnectionMetadata<()>,
+        ) -> Decision
+        where
+            R: Send + Unpin + AsyncRead,
+        {
+            let mut text = Vec::new();
+            if reader.read_to_end(&mut text).await.is_err() {
+                return Decision::Reject(self.handle_mail_did_not_call_complete());
+            }
+            reader.complete();
+            self.mails.lock().unwrap().push((meta.to, text));
+            Decision::Accept
+        }
+    }
+
+    /// Serves as an MX accepting all recipients but "nobody"
+    fn serve(listener: Async<TcpListener>, mails: Mails) {
+        let cfg = Arc::new(TestMx { mails });
+        smol::Task::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let cfg = cfg.clone();
+                smol::Task::spawn(async move {
+                    let _ =
+                        smtp_server::interact(stream, ConnectionInfo::default(), (), &*cfg).await;
+                })
+                .detach();
+            }
+        })
+        .detach();
+    }
+
+    /// Serves a server that is temporarily unavailable
+    fn serve_busy(listener: Async<TcpListener>) {
+        smol::Task::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let _ = stream.write_all(b"421 Busy, try later\r\n").await;
+            }
+        })
+        .detach();
+    }
+
+    #[test]
+    fn destination() {
+        let transport = SmtpTransport::new(StubResolver::new(), hostname());
+        let dest =
+            |to: &[&str]| smol::block_on(Transport::<()>::destination(&transport, &meta(to))).ok();
+        assert_eq!(
+            dest(&["<foo@Example.ORG>", "<bar@example.org>"]),
+            Some(SmtpDestination::Domain("example.org".to_owned()))
+        );
+        assert_eq!(
+            dest(&["<foo@[192.0.2.1]>"]),
+            Some(SmtpDestination::Ip("192.0.2.1".parse().unwrap()))
+        );
+        assert_eq!(dest(&["<foo@example.org>", "<bar@example.com>"]), None);
+        assert_eq!(dest(&[]), None);
+
+        let m = meta(&["<foo@example.org>", "<bar@example.com>"]);
+        match smol::block_on(Transport::<()>::destination(&transport, &m)) {
+            Err(TransportFailure::RemotePermanent(..)) => (),
+            _ => panic!("expected a permanent failure for multiple domains"),
+        }
+    }
+
+    #[test]
+    fn walks_next_hops() {
+        let listener = Async::new(TcpListener::bind("127.0.0.1:0").unwrap()).unwrap();
+        let port = listener.get_ref().local_addr().unwrap().port();
+        let busy = Async::new(TcpListener::bind(("127.0.0.3", port)).unwrap()).unwrap();
+        let resolver = StubResolver::new()
+            .with_mx("example.org", 10, "unreachable.example.org")
+            .with_mx("example.org", 20, "busy.example.org")
+            .with_mx("example.org", 30, "mx.example.org")
+            // Nothing listens there, so that the connection is refused
+            .with_ip("unreachable.example.org", "127.0.0.2".parse().unwrap())
+            .with_ip("busy.example.org", "127.0.0.3".parse().unwrap())
+            .with_ip("mx.example.org", "127.0.0.1".parse().unwrap());
+        let transport = SmtpTransport::with_port(resolver, hostname(), port);
+        let mails = Mails::default();
+
+        let mails_ref = mails.clone();
+        smol::run(async move {
+            serve(listener, mails_ref);
+            serve_busy(busy);
+            let m = meta(&["<foo@example.org>", "<nobody@example.org>"]);
+            let dest = Transport::<()>::destination(&transport, &m)
+                .await
+                .unwrap_or_else(|_| panic!("failed getting the destination"));
+            let mut sender = Transport::<()>::connect(&transport, &dest)
+                .await
+                .unwrap_or_else(|_| panic!("failed connecting"));
+
+            let res = sender.send(&m, Cursor::new(&b"Hello\r\n"[..])).await;
+            match res {
+                Err(TransportFailure::PerRecipient(results)) => {
+                    assert!(results[0].is_ok());
+                    match results[1] {
+                        Err(TransportFailure::RemotePermanent(c, _)) => {
+                            assert_eq!(c, ReplyCode::MAILBOX_UNAVAILABLE)
+                        }
+                        _ => panic!("expected a permanent failure for nobody"),
+                    }
+                }
+                _ => panic!("expected per-recipient results"),
+            }
+
+            let res = sender
+                .send(
+                    &meta(&["<bar@example.org>"]),
+                    Cursor::new(&b"Hello again\r\n"[..]),
+                )
+                .await;
+            assert!(res.is_ok());
+        });
+
+        assert_eq!(*mails.lock().unwrap(), vec![
+            (vec![email("<foo@example.org>")], b"Hello\r\n.\r\n".to_vec()),
+            (
+                vec![email("<bar@example.org>")],
+                b"Hello again\r\n.\r\n".to_vec()
+            ),
+        ]);
+    }
+
+    #[test]
+    fn all_hops_failing() {
+        let busy = Async::new(TcpListener::bind("127.0.0.1:0").unwrap()).unwrap();
+        let port = busy.get_ref().local_addr().unwrap().port();
+        let resolver = StubResolver::new()
+            .with_mx("example.org", 10, "busy.example.org")
+            .with_ip("busy.example.org", "127.0.0.1".parse().unwrap());
+        let transport = SmtpTransport::with_port(resolver, hostname(), port);
+        smol::run(async move {
+            serve_busy(busy);
+            let dest = SmtpDestination::Domain("example.org".to_owned());
+            match Transport::<()>::connect(&transport, &dest).await {
+                Err(TransportFailure::RemoteTransient(c, _)) => {
+                    assert_eq!(c, ReplyCode::SERVICE_NOT_AVAILABLE)
+                }
+                _ => panic!("expected a transient failure"),
+            }
+        });
+    }
+
+    /// Serves sessions that each accept a single mail, and then close with a
+    /// 421 reply to the next `MAIL` command, as servers timing out idle
+    /// sessions do, returning the number of sessions and of mails
+    fn serve_one_mail_per_session(listener: Async<TcpListener>) -> Arc<Mutex<(usize, usize)>> {
+        let counts = Arc::new(Mutex::new((0, 0)));
+        let counts_ref = counts.clone();
+        smol::Task::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                counts_ref.lock().unwrap().0 += 1;
+                let counts = counts_ref.clone();
+                smol::Task::spawn(async move {
+                    let mut io = BufReader::new(stream);
+                    let mut line = String::new();
+                    let mut sent = false;
+                    io.get_mut().write_all(b"220 mx\r\n").await.unwrap();
+                    while io.read_line(&mut line).await.unwrap() != 0 {
+                        let reply: &[u8] = match &line[..4] {
+                            "MAIL" if sent => b"421 Idle for too long\r\n",
+                            "DATA" => {
+                                io.get_mut().write_all(b"354 Go ahead\r\n").await.unwrap();
+                                let mut data = String::new();
+                                while !data.ends_with("\r\n.\r\n") {
+                                    io.read_line(&mut data).await.unwrap();
+                                }
+                                sent = true;
+                                counts.lock().unwrap().1 += 1;
+                                b"250 Queued\r\n"
+                            }
+                            _ => b"250 Ok\r\n",
+                        };
+                        io.get_mut().write_all(reply).await.unwrap();
+                        if reply.starts_with(b"421") {
+                            return;
+                        }
+                        line.clear();
+                    }
+                })
+                .detach();
+            }
+        })
+        .detach();
+        counts
+    }
+
+    #[test]
+    fn reconnects_closed_sessions() {
+        let listener = Async::new(TcpListener::bind("127.0.0.1:0").unwrap()).unwrap();
+        let port = listener.get_ref().local_addr().unwrap().port();
+        let resolver = StubResolver::new().with_ip("example.org", "127.0.0.1".parse().unwrap());
+        let transport = SmtpTransport::with_port(resolver, hostname(), port);
+        smol::run(async move {
+            let counts = serve_one_mail_per_session(listener);
+            let dest = SmtpDestination::Domain("example.org".to_owned());
+            let mut sender = Transport::<()>::connect(&transport, &dest)
+                .await
+                .unwrap_or_else(|_| panic!("failed connecting"));
+            let m = meta(&["<foo@example.org>"]);
+            for _ in 0..3 {
+                let res = sender.send(&m, Cursor::new(&b"Hello\r\n"[..])).await;
+                assert!(res.is_ok());
+                assert!(!TransportSender::<()>::is_broken(&sender));
+            }
+            assert_eq!(*counts.lock().unwrap(), (3, 3));
+        });
+    }
+
+    /// Serves a session advertising PIPELINING, that only replies to the
+    /// commands of a transaction once it received all of them up to `DATA`,
+    /// and always accepts `DATA` even without valid recipients
+    async fn serve_pipelined(listener: Async<TcpListener>, log: Arc<Mutex<Vec<String>>>) {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut io = BufReader::new(stream);
+        let mut line = String::new();
+        io.get_mut()
+            .write_all(b"220 mx.example.org\r\n")
+            .await
+            .unwrap();
+        io.read_line(&mut line).await.unwrap();
+        io.get_mut()
+            .write_all(b"250-mx.example.org\r\n250 PIPELINING\r\n")
+            .await
+            .unwrap();
+
+        let mut replies = Vec::new();
+        let mut accepted = false;
+        loop {
+            line.clear();
+            if io.read_line(&mut line).await.unwrap() == 0 {
+                return;
+            }
+            log.lock().unwrap().push(line.trim_end().to_owned());
+            if line.starts_with("MAIL") {
+                replies.extend_from_slice(b"250 Ok\r\n");
+            } else if line.starts_with("RCPT") && line.contains("nobody") {
+                replies.extend_from_slice(b"550 No such user\r\n");
+            } else if line.starts_with("RCPT") {
+                accepted = true;
+                replies.extend_from_slice(b"250 Ok\r\n");
+            } else if line.starts_with("DATA") {
+                replies.extend_from_slice(b"354 Go ahead\r\n");
+                io.get_mut().write_all(&replies).await.unwrap();
+                replies.clear();
+                let mut data = String::new();
+                while data != ".\r\n" && !data.ends_with("\r\n.\r\n") {
+                    io.read_line(&mut data).await.unwrap();
+                }
+                log.lock().unwrap().push(data);
+                let reply: &[u8] = match accepted {
+                    true => b"250 Queued\r\n",
+                    false => b"554 No valid recipients\r\n",
+                };
+                io.get_mut().write_all(reply).await.unwrap();
+                accepted = false;
+            } else {
+                io.get_mut()
+                    .write_all(b"502 Unimplemented\r\n")
+                    .await
+                    .unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn pipelining() {
+        let listener = Async::new(TcpListener::bind("127.0.0.1:0").unwrap()).unwrap();
+        let port = listener.get_ref().local_addr().unwrap().port();
+        let resolver = StubResolver::new().with_ip("example.org", "127.0.0.1".parse().unwrap());
+        let transport = SmtpTransport::with_port(resolver, hostname(), port);
+        let log = Arc::new(Mutex::new(Vec::new()));
+
+        let log_ref = log.clone();
+        smol::run(async move {
+            smol::Task::spawn(serve_pipelined(listener, log_ref)).detach();
+            let dest = SmtpDestination::Domain("example.org".to_owned());
+            let mut sender = Transport::<()>::connect(&transport, &dest)
+                .await
+                .unwrap_or_else(|_| panic!("failed connecting"));
+
+            let m = meta(&["<foo@example.org>", "<nobody@example.org>"]);
+            match sender.send(&m, Cursor::new(&b"Hello\r\n"[..])).await {
+                Err(TransportFailure::PerRecipient(results)) => {
+                    assert!(results[0].is_ok());
+                    assert!(matches!(
+                        results[1],
+                        Err(TransportFailure::RemotePermanent(..))
+                    ));
+                }
+                _ => panic!("expected per-recipient results"),
+            }
+
+            let m = meta(&["<nobody@example.org>"]);
+            match sender.send(&m, Cursor::new(&b"Hello\r\n"[..])).await {
+                Err(TransportFailure::RemotePermanent(c, _)) => {
+                    assert_eq!(c, ReplyCode::MAILBOX_UNAVAILABLE)
+                }
+                _ => panic!("expected a permanent failure"),
+            }
+        });
+
+        assert_eq!(*log.lock().unwrap(), vec![
+            "MAIL FROM:<sender@example.com>",
+            "RCPT TO:<foo@example.org>",
+            "RCPT TO:<nobody@example.org>",
+            "DATA",
+            "Hello\r\n.\r\n",
+            "MAIL FROM:<sender@example.com>",
+            "RCPT TO:<nobody@example.org>",
+            "DATA",
+            // Only the end of the data is sent, as there is no recipient
+            ".\r\n",
+        ]);
+    }
+
+    #[test]
+    fn ehlo_capabilities() {
+        let listener = Async::new(TcpListener::bind("127.0.0.1:0").unwrap()).unwrap();
+        let addr = listener.get_ref().local_addr().unwrap();
+        let transport = SmtpTransport::new(StubResolver::new(), hostname());
+        let log = Log::default();
+
+        let log_ref = log.clone();
+        smol::run(async move {
+            smol::Task::spawn(async move {
+                let (stream, _) = listener.accept().await?;
+                let mut io = BufReader::new(stream);
+                reply(&mut io, b"220 mx.example.org ESMTP\r\n").await?;
+                read_command(&mut io, &log_ref).await?;
+                reply(
+                    &mut io,
+                    b"250-mx.example.org greets client.example.com\r\n\
+                      250-SIZE 10240000\r\n\
+                      250-pipelining\r\n\
+                      250-AUTH PLAIN LOGIN\r\n\
+                      250 STARTTLS\r\n",
+                )
+                .await
+            })
+            .detach();
+
+            let connector = &transport.connector;
+            let mut conn = connector
+                .greet(addr)
+                .await
+                .unwrap_or_else(|_| panic!("failed connecting"));
+            let capabilities = connector
+                .ehlo(&mut conn)
+                .await
+                .unwrap_or_else(|_| panic!("failed saying EHLO"));
+            assert!(capabilities.pipelining);
+            assert!(capabilities.starttls);
+            assert!(!capabilities.eightbitmime);
+            assert_eq!(capabilities.size, Some(10240000));
+            assert_eq!(capabilities.auth, vec!["PLAIN", "LOGIN"]);
+            assert!(conn.pipelining);
+        });
+
+        assert_eq!(*log.lock().unwrap(), vec!["EHLO client.example.com"]);
+    }
+
+    /// Serves commands up to `STARTTLS`, that is only advertised if
+    /// `starttls` is set, returning whether it was received
+    async fn serve_commands<IO>(
+        io: &mut BufReader<IO>,
+        starttls: bool,
+        log: &Log,
+    ) -> io::Result<bool>
+    where
+        IO: Unpin + AsyncRead + AsyncWrite,
+    {
+        loop {
+            match &read_command(io, log).await? as &str {
+                "EHLO" if starttls => reply(io, b"250-mx.example.org\r\n250 STARTTLS\r\n").await?,
+                "EHLO" => reply(io, b"250 mx.example.org\r\n").await?,
+                "STARTTLS" if starttls => {
+                    reply(io, b"220 2.0.0 Ready to start TLS\r\n").await?;
+                    return Ok(true);
+                }
+                "MAIL" | "RCPT" => reply(io, b"250 2.0.0 Ok\r\n").await?,
+                "DATA" => {
+                    reply(io, b"354 Go ahead\r\n").await?;
+                    let mut data = String::new();
+                    while !data.ends_with("\r\n.\r\n") {
+                        if io.read_line(&mut data).await? == 0 {
+                            return Ok(false);
+                        }
+                    }
+                    log.lock().unwrap().push(data);
+                    reply(io, b"250 2.0.0 Queued\r\n").await?
+                }
+                "QUIT" | "" => return Ok(false),
+                _ => reply(io, b"502 5.5.1 Unrecognized command\r\n").await?,
+            }
+        }
+    }
+
+    /// Serves as an MX advertising STARTTLS, whose TLS handshakes fail if
+    /// `tls` is not set, logging each new session as `CONNECT`
+    fn serve_starttls(listener: Async<TcpListener>, tls: bool, log: Log) {
+        smol::Task::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                log.lock().unwrap().push("CONNECT".to_owned());
+                let log = log.clone();
+                smol::Task::spawn(async move {
+                    let mut io = BufReader::new(stream);
+                    reply(&mut io, b"220 mx.example.org ESMTP\r\n").await?;
+                    if !serve_commands(&mut io, true, &log).await? {
+                        return Ok(());
+                    }
+                    if !tls {
+                        return Ok(());
+                    }
+                    let acceptor: TlsAcceptor = server_tls();
+                    let mut io = BufReader::new(acceptor.accept(io.into_inner()).await?);
+                    serve_commands(&mut io, false, &log).await.map(|_| ())
+                })
+                .detach();
+            }
+        })
+        .detach();
+    }
+
+    #[test]
+    fn opportunistic_starttls() {
+        let tests: &[(bool, &[&str])] = &[
+            (true, &[
+                "CONNECT",
+                "EHLO client.example.com",
+                "STARTTLS",
+                "EHLO client.example.com",
+                "MAIL FROM:<sender@example.com>",
+                "RCPT TO:<foo@example.org>",
+                "DATA",
+                "Hello\r\n.\r\n",
+            ]),
+            // The session is opened again without TLS
+            (false, &[
+                "CONNECT",
+                "EHLO client.example.com",
+                "STARTTLS",
+                "CONNECT",
+                "EHLO client.example.com",
+                "MAIL FROM:<sender@example.com>",
+                "RCPT TO:<foo@example.org>",
+                "DATA",
+                "Hello\r\n.\r\n",
+            ]),
+        ];
+        for &(tls, out) in tests {
+            println!("Test: {:?}", tls);
+            let listener = Async::new(TcpListener::bind("127.0.0.1:0").unwrap()).unwrap();
+            let port = listener.get_ref().local_addr().unwrap().port();
+            let resolver = StubResolver::new().with_ip("example.org", "127.0.0.1".parse().unwrap());
+            let transport = SmtpTransport::with_port(resolver, hostname(), port);
+            let log = Log::default();
+            let log_ref = log.clone();
+            smol::run(async move {
+                serve_starttls(listener, tls, log_ref);
+                let dest = SmtpDestination::Domain("example.org".to_owned());
+                let mut sender = Transport::<()>::connect(&transport, &dest)
+                    .await
+                    .unwrap_or_else(|_| panic!("failed connecting"));
+                let m = meta(&["<foo@example.org>"]);
+                let res = sender.send(&m, Cursor::new(&b"Hello\r\n"[..])).await;
+                assert!(res.is_ok());
+            });
+            assert_eq!(log.lock().unwrap()[..], *out);
+        }
+    }
+}
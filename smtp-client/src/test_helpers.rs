@@ -0,0 +1,24 @@
+//! Fixtures shared by the tests of all the modules
+
+use smtp_message::{Email, Hostname};
+use smtp_queue::MailMetadata;
+
+pub(crate) fn email(s: &str) -> Email {
+    Email::parse_bracketed(s.as_bytes()).unwrap().to_owned()
+}
+
+/// Metadata of a mail from sender@example.com to `to`
+pub(crate) fn meta(to: &[&str]) -> MailMetadata<()> {
+    MailMetadata {
+        from: Some(email("<sender@example.com>")),
+        to: to.iter().map(|to| email(to)).collect(),
+        metadata: (),
+    }
+}
+
+/// Name the client gives in `EHLO` and `LHLO`
+pub(crate) fn hostname() -> Hostname {
+    Hostname::parse_until(b">")(b"client.example.com>")
+        .unwrap()
+        .1
+}
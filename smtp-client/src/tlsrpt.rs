@@ -0,0 +1,898 @@
+use std::{collections::BTreeMap, fmt, io, net::IpAddr, sync::Mutex};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use futures::io::AsyncWriteExt;
+use rustls::TLSError;
+use serde::Serialize;
+use smtp_message::Email;
+use smtp_queue::{MailMetadata, Queue, ScheduleInfo, Storage, Transport, TransportFailure};
+
+use crate::{Resolver, StsMode, StsPolicy, Tlsa};
+
+/// Kind of policy applied to a TLS session (RFC 8460 section 4.3)
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum PolicyType {
+    Tlsa,
+    Sts,
+    NoPolicyFound,
+}
+
+impl PolicyType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            PolicyType::Tlsa => "tlsa",
+            PolicyType::Sts => "sts",
+            PolicyType::NoPolicyFound => "no-policy-found",
+        }
+    }
+}
+
+/// A policy that TLS sessions are reported against
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct TlsPolicy {
+    pub policy_type: PolicyType,
+
+    /// The policy, as lines of the MTA-STS policy or TLSA records in
+    /// presentation format
+    pub policy_string: Vec<String>,
+
+    /// The recipient domain the policy is for
+    pub policy_domain: String,
+
+    /// The mail exchangers the policy applies to
+    pub mx_host: Vec<String>,
+}
+
+impl TlsPolicy {
+    /// The MTA-STS policy `policy` of `domain`
+    pub fn sts(domain: &str, policy: &StsPolicy) -> TlsPolicy {
+        let mode = match policy.mode {
+            StsMode::Enforce => "enforce",
+            StsMode::Testing => "testing",
+            StsMode::None => "none",
+        };
+        let mut lines = vec!["version: STSv1".to_owned(), format!("mode: {}", mode)];
+        lines.extend(policy.mx.iter().map(|mx| format!("mx: {}", mx)));
+        lines.push(format!("max_age: {}", policy.max_age.as_secs()));
+        TlsPolicy {
+            policy_type: PolicyType::Sts,
+            policy_string: lines,
+            policy_domain: domain.to_owned(),
+            mx_host: policy.mx.clone(),
+        }
+    }
+
+    /// The absence of a usable policy for `domain`
+    pub fn no_policy_found(domain: &str) -> TlsPolicy {
+        TlsPolicy {
+            policy_type: PolicyType::NoPolicyFound,
+            policy_string: Vec::new(),
+            policy_domain: domain.to_owned(),
+            mx_host: Vec::new(),
+        }
+    }
+
+    /// The TLSA records `records` of `host`, a mail exchanger of `domain`
+    pub fn tlsa(domain: &str, host: &str, records: &[Tlsa]) -> TlsPolicy {
+        let lines = records.iter().map(|r| {
+            let data = r.data.iter().map(|b| format!("{:02X}", b));
+            format!(
+                "{} {} {} {}",
+                r.usage,
+                r.selector,
+                r.matching_type,
+                data.collect::<String>()
+            )
+        });
+        TlsPolicy {
+            policy_type: PolicyType::Tlsa,
+            policy_string: lines.collect(),
+            policy_domain: domain.to_owned(),
+            mx_host: vec![host.to_owned()],
+        }
+    }
+}
+
+/// Reason of a failed TLS session (RFC 8460 section 4.3)
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum FailureType {
+    StarttlsNotSupported,
+    CertificateHostMismatch,
+    CertificateExpired,
+    CertificateNotTrusted,
+    ValidationFailure,
+    TlsaInvalid,
+    DnssecInvalid,
+    DaneRequired,
+    StsPolicyFetchError,
+    StsPolicyInvalid,
+    StsWebpkiInvalid,
+}
+
+impl FailureType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            FailureType::StarttlsNotSupported => "starttls-not-supported",
+            FailureType::CertificateHostMismatch => "certificate-host-mismatch",
+            FailureType::CertificateExpired => "certificate-expired",
+            FailureType::CertificateNotTrusted => "certificate-not-trusted",
+            FailureType::ValidationFailure => "validation-failure",
+            FailureType::TlsaInvalid => "tlsa-invalid",
+            FailureType::DnssecInvalid => "dnssec-invalid",
+            FailureType::DaneRequired => "dane-required",
+            FailureType::StsPolicyFetchError => "sts-policy-fetch-error",
+            FailureType::StsPolicyInvalid => "sts-policy-invalid",
+            FailureType::StsWebpkiInvalid => "sts-webpki-invalid",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct FailureDetails {
+    pub result_type: FailureType,
+    pub sending_mta_ip: Option<IpAddr>,
+    pub receiving_mx_hostname: Option<String>,
+    pub receiving_ip: Option<IpAddr>,
+}
+
+/// The outcome of TLS sessions on a given day, under a given policy
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct TlsEvent {
+    pub date: NaiveDate,
+    pub policy: TlsPolicy,
+
+    /// Why the sessions failed, or `None` if they succeeded
+    pub failure: Option<FailureDetails>,
+}
+
+/// Records the outcome of the TLS sessions that delivery attempts opened
+/// under a policy, for them to be reported later on
+#[derive(Default)]
+pub struct TlsRptRecorder {
+    events: Mutex<BTreeMap<TlsEvent, u64>>,
+}
+
+impl TlsRptRecorder {
+    pub fn new() -> TlsRptRecorder {
+        TlsRptRecorder::default()
+    }
+
+    /// Records a session of today under `policy`, that failed if `failure` is
+    /// set
+    pub fn record(&self, policy: TlsPolicy, failure: Option<FailureDetails>) {
+        let event = TlsEvent {
+            date: Utc::now().naive_utc().date(),
+            policy,
+            failure,
+        };
+        *self.events.lock().unwrap().entry(event).or_insert(0) += 1;
+    }
+
+    /// Removes and returns the events of the days before `date`, with the
+    /// number of sessions they happened in
+    pub fn take_before(&self, date: NaiveDate) -> Vec<(TlsEvent, u64)> {
+        let mut events = self.events.lock().unwrap();
+        let (taken, kept): (BTreeMap<_, _>, _) = std::mem::take(&mut *events)
+            .into_iter()
+            .partition(|(e, _)| e.date < date);
+        *events = kept;
+        taken.into_iter().collect()
+    }
+}
+
+/// The organization that reports are sent by
+#[derive(Clone, Debug)]
+pub struct ReportOrganization {
+    pub organization_name: String,
+
+    /// How to contact the organization, eg. an email address
+    pub contact_info: String,
+
+    /// Hostname of the sending MTA, identifying the submitter of reports
+    pub submitter: String,
+
+    /// Address reports are sent from
+    pub from: Email,
+}
+
+/// An aggregate report for one policy domain and day
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TlsReport {
+    pub policy_domain: String,
+    pub date: NaiveDate,
+    pub report_id: String,
+
+    /// The report, in JSON
+    pub json: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct JsonReport<'a> {
+    organization_name: &'a str,
+    date_range: JsonDateRange,
+    contact_info: &'a str,
+    report_id: &'a str,
+    policies: Vec<JsonPolicyResult<'a>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct JsonDateRange {
+    start_datetime: String,
+    end_datetime: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct JsonPolicyResult<'a> {
+    policy: JsonPolicy<'a>,
+    summary: JsonSummary,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    failure_details: Vec<JsonFailureDetails<'a>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct JsonPolicy<'a> {
+    policy_type: &'static str,
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    policy_string: &'a [String],
+    policy_domain: &'a str,
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    mx_host: &'a [String],
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct JsonSummary {
+    total_successful_session_count: u64,
+    total_failure_session_count: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct JsonFailureDetails<'a> {
+    result_type: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sending_mta_ip: Option<IpAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    receiving_mx_hostname: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    receiving_ip: Option<IpAddr>,
+    failed_session_count: u64,
+}
+
+/// Generates the reports of `date` from `events`, with the number of sessions
+/// they happened in, one report per policy domain
+///
+/// The result only depends on the arguments: reports are sorted by domain,
+/// and policies and failures inside of them are sorted too.
+pub fn generate_reports(
+    org: &ReportOrganization,
+    date: NaiveDate,
+    events: &[(TlsEvent, u64)],
+) -> Vec<TlsReport> {
+    type Results<'a> = (u64, BTreeMap<&'a FailureDetails, u64>);
+    let mut domains = BTreeMap::<&str, BTreeMap<&TlsPolicy, Results>>::new();
+    for (event, count) in events.iter().filter(|(e, _)| e.date == date) {
+        let (successes, failures) = domains
+            .entry(&event.policy.policy_domain)
+            .or_default()
+            .entry(&event.policy)
+            .or_default();
+        match event.failure {
+            None => *successes += count,
+            Some(ref f) => *failures.entry(f).or_insert(0) += count,
+        }
+    }
+
+    let date_range = || JsonDateRange {
+        start_datetime: date.format("%Y-%m-%dT00:00:00Z").to_string(),
+        end_datetime: date.format("%Y-%m-%dT23:59:59Z").to_string(),
+    };
+    domains
+        .into_iter()
+        .map(|(domain, policies)| {
+            let report_id = format!("{}.{}@{}", date, domain, org.submitter);
+            let policies = policies
+                .into_iter()
+                .map(|(policy, (successes, failures))| JsonPolicyResult {
+                    policy: JsonPolicy {
+                        policy_type: policy.policy_type.as_str(),
+                        policy_string: &policy.policy_string,
+                        policy_domain: &policy.policy_domain,
+                        mx_host: &policy.mx_host,
+                    },
+                    summary: JsonSummary {
+                        total_successful_session_count: successes,
+                        total_failure_session_count: failures.values().sum(),
+                    },
+                    failure_details: failures
+                        .into_iter()
+                        .map(|(f, count)| JsonFailureDetails {
+                            result_type: f.result_type.as_str(),
+                            sending_mta_ip: f.sending_mta_ip,
+                            receiving_mx_hostname: f.receiving_mx_hostname.as_deref(),
+                            receiving_ip: f.receiving_ip,
+                            failed_session_count: count,
+                        })
+                        .collect(),
+                })
+                .collect();
+            let json = serde_json::to_string(&JsonReport {
+                organization_name: &org.organization_name,
+                date_range: date_range(),
+                contact_info: &org.contact_info,
+                report_id: &report_id,
+                policies,
+            })
+            .expect("serializing a report to JSON failed");
+            TlsReport {
+                policy_domain: domain.to_owned(),
+                date,
+                report_id,
+                json,
+            }
+        })
+        .collect()
+}
+
+fn email_to_string(email: &Email) -> String {
+    let mut bytes = Vec::new();
+    for s in email.as_io_slices() {
+        bytes.extend_from_slice(&s);
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+impl TlsReport {
+    /// Builds the mail carrying the report (RFC 8460 section 5.3), sent by
+    /// `org` to `to` at `now`
+    pub fn to_mail(&self, org: &ReportOrganization, to: &[Email], now: DateTime<Utc>) -> Vec<u8> {
+        let epoch = NaiveDate::from_ymd_opt(1970, 1, 1).unwrap();
+        let start = self.date.signed_duration_since(epoch).num_seconds();
+        let filename = format!(
+            "{}!{}!{}!{}.json",
+            org.submitter,
+            self.policy_domain,
+            start,
+            start + 86399
+        );
+        let mut body = base64::encode(&self.json);
+        let mut attachment = String::with_capacity(body.len() + body.len() / 38);
+        while !body.is_empty() {
+            let rest = body.split_off(body.len().min(76));
+            attachment.push_str(&body);
+            attachment.push_str("\r\n");
+            body = rest;
+        }
+        let boundary = format!("tlsrpt-{}", self.report_id);
+        let to = to
+            .iter()
+            .map(|e| format!("<{}>", email_to_string(e)))
+            .collect::<Vec<_>>();
+        let lines = [
+            format!("From: <{}>", email_to_string(&org.from)),
+            format!("To: {}", to.join(", ")),
+            format!("Date: {}", now.to_rfc2822()),
+            format!(
+                "Subject: Report Domain: {} Submitter: {} Report-ID: <{}>",
+                self.policy_domain, org.submitter, self.report_id
+            ),
+            format!("Message-ID: <{}>", self.report_id),
+            format!("TLS-Report-Domain: {}", self.policy_domain),
+            format!("TLS-Report-Submitter: {}", org.submitter),
+            "MIME-Version: 1.0".to_owned(),
+            "Content-Type: multipart/report; report-type=\"tlsrpt\";".to_owned(),
+            format!("\tboundary=\"{}\"", boundary),
+            String::new(),
+            format!("--{}", boundary),
+            "Content-Type: text/plain; charset=utf-8".to_owned(),
+            String::new(),
+            format!(
+                "This is an aggregate TLS report from {} for {}.",
+                org.submitter, self.policy_domain
+            ),
+            String::new(),
+            format!("--{}", boundary),
+            "Content-Type: application/tlsrpt+json".to_owned(),
+            "Content-Transfer-Encoding: base64".to_owned(),
+            format!("Content-Disposition: attachment; filename=\"{}\"", filename),
+            String::new(),
+            attachment,
+        ];
+        let mut mail = lines.join("\r\n");
+        mail.push_str(&format!("--{}--\r\n", boundary));
+        mail.into_bytes()
+    }
+}
+
+/// Decodes the `%XX` escapes of a URI
+fn percent_decode(s: &str) -> Option<String> {
+    let mut res = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'%' {
+            res.push(b);
+            continue;
+        }
+        let hex = [bytes.next()?, bytes.next()?];
+        res.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+    }
+    String::from_utf8(res).ok()
+}
+
+/// Returns the addresses that reports are to be mailed to, from the TXT
+/// records of `_smtp._tls.<domain>`
+///
+/// Only `mailto:` URIs are supported, and the domain is considered not to
+/// want reports unless exactly one record is a TLSRPT one.
+fn rua(records: &[String]) -> Vec<Email> {
+    let mut records = records
+        .iter()
+        .filter(|r| r.split(';').next().map(str::trim) == Some("v=TLSRPTv1"));
+    let record = match (records.next(), records.next()) {
+        (Some(record), None) => record,
+        _ => return Vec::new(),
+    };
+    let uris = record.split(';').skip(1).find_map(|field| {
+        let mut kv = field.splitn(2, '=');
+        match (kv.next(), kv.next()) {
+            (Some(k), Some(v)) if k.trim() == "rua" => Some(v),
+            _ => None,
+        }
+    });
+    uris.unwrap_or("")
+        .split(',')
+        .filter_map(|uri| {
+            let uri = uri.trim();
+            if uri.len() < 7 || !uri[..7].eq_ignore_ascii_case("mailto:") {
+                return None;
+            }
+            let addr = percent_decode(uri[7..].split('?').next().unwrap())?;
+            let email = Email::<&str>::parse_bracketed(format!("<{}>", addr).as_bytes())
+                .ok()?
+                .to_owned();
+            Some(email)
+        })
+        .collect()
+}
+
+/// Enqueues each of `reports` to the `mailto:` addresses that its policy
+/// domain publishes, scheduling it for `now`
+///
+/// The reports of domains that do not want any are dropped. Failing to
+/// retrieve the addresses of a domain does not prevent the other reports
+/// from being enqueued, but the last such error is returned.
+pub async fn enqueue_reports<U, C, S, T, R>(
+    queue: &Queue<U, C, S, T>,
+    resolver: &R,
+    org: &ReportOrganization,
+    metadata: U,
+    now: DateTime<Utc>,
+    reports: &[TlsReport],
+) -> io::Result<()>
+where
+    U: 'static + Send + Sync + Clone,
+    C: smtp_queue::Config<U>,
+    S: Storage<U>,
+    T: Transport<U>,
+    R: ?Sized + Resolver,
+{
+    let mut last_err = None;
+    for report in reports {
+        let name = format!("_smtp._tls.{}", report.policy_domain);
+        let to = match resolver.txt(&name).await {
+            Ok(records) => rua(&records),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => {
+                last_err = Some(e);
+                continue;
+            }
+        };
+        if to.is_empty() {
+            continue;
+        }
+        let mail = report.to_mail(org, &to, now);
+        let meta = MailMetadata {
+            from: Some(org.from.clone()),
+            to,
+            metadata: metadata.clone(),
+        };
+        let schedule = ScheduleInfo {
+            at: now,
+            last_attempt: None,
+        };
+        let mut enqueuer = queue.enqueue_split_by_domain(meta, schedule).await?;
+        enqueuer.write_all(&mail).await?;
+        enqueuer.commit().await?;
+    }
+    match last_err {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Error of a mail exchanger not supporting STARTTLS, or refusing to start
+/// it, that names the mail exchanger
+#[derive(Debug)]
+pub(crate) struct StarttlsUnsupported(pub(crate) String);
+
+impl fmt::Display for StarttlsUnsupported {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} does not support STARTTLS", self.0)
+    }
+}
+
+impl std::error::Error for StarttlsUnsupported {}
+
+/// Returns why a session opened under a policy failed with `err`, or `None`
+/// if the failure is unrelated to TLS
+pub(crate) fn failure_type(err: &TransportFailure) -> Option<FailureType> {
+    let err = match err {
+        TransportFailure::Local(e) => e.get_ref()?,
+        _ => return None,
+    };
+    if err.is::<StarttlsUnsupported>() {
+        return Some(FailureType::StarttlsNotSupported);
+    }
+    Some(match err.downcast_ref::<TLSError>()? {
+        TLSError::WebPKIError(webpki::Error::CertExpired)
+        | TLSError::WebPKIError(webpki::Error::CertNotValidYet) => FailureType::CertificateExpired,
+        TLSError::WebPKIError(webpki::Error::CertNotValidForName) => {
+            FailureType::CertificateHostMismatch
+        }
+        TLSError::WebPKIError(webpki::Error::UnknownIssuer) => FailureType::CertificateNotTrusted,
+        _ => FailureType::ValidationFailure,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{sync::Arc, time::Duration};
+
+    use chrono::TimeZone;
+    use serde_json::{json, Value};
+
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2020, 4, day).unwrap()
+    }
+
+    fn org() -> ReportOrganization {
+        ReportOrganization {
+            organization_name: "Example Inc.".to_owned(),
+            contact_info: "postmaster@example.com".to_owned(),
+            submitter: "mail.example.com".to_owned(),
+            from: Email::parse_bracketed(b"<tlsrpt@example.com>")
+                .unwrap()
+                .to_owned(),
+        }
+    }
+
+    fn sts_policy() -> TlsPolicy {
+        TlsPolicy::sts("example.org", &StsPolicy {
+            mode: StsMode::Enforce,
+            mx: vec!["mx.example.org".to_owned(), "*.example.net".to_owned()],
+            max_age: Duration::from_secs(86400),
+        })
+    }
+
+    fn tlsa_policy() -> TlsPolicy {
+        TlsPolicy::tlsa("example.com", "mx.example.com", &[Tlsa {
+            usage: 3,
+            selector: 1,
+            matching_type: 1,
+            data: vec![0x01, 0xAB],
+        }])
+    }
+
+    fn failure(result_type: FailureType, host: &str) -> Option<FailureDetails> {
+        Some(FailureDetails {
+            result_type,
+            sending_mta_ip: None,
+            receiving_mx_hostname: Some(host.to_owned()),
+            receiving_ip: Some("192.0.2.1".parse().unwrap()),
+        })
+    }
+
+    fn event(day: u32, policy: TlsPolicy, failure: Option<FailureDetails>) -> TlsEvent {
+        TlsEvent {
+            date: date(day),
+            policy,
+            failure,
+        }
+    }
+
+    #[test]
+    fn policies() {
+        assert_eq!(sts_policy().policy_string, vec![
+            "version: STSv1",
+            "mode: enforce",
+            "mx: mx.example.org",
+            "mx: *.example.net",
+            "max_age: 86400",
+        ]);
+        assert_eq!(tlsa_policy().policy_string, vec!["3 1 1 01AB"]);
+    }
+
+    #[test]
+    fn reports_generation() {
+        let events = vec![
+            (event(2, sts_policy(), None), 1000),
+            (event(1, sts_policy(), None), 12),
+            (
+                event(
+                    1,
+                    sts_policy(),
+                    failure(FailureType::StarttlsNotSupported, "mx.example.org"),
+                ),
+                2,
+            ),
+            (event(1, tlsa_policy(), None), 3),
+            (
+                event(
+                    1,
+                    sts_policy(),
+                    failure(FailureType::CertificateExpired, "mx1.example.net"),
+                ),
+                1,
+            ),
+        ];
+        let reports = generate_reports(&org(), date(1), &events);
+        let ids = reports.iter().map(|r| &r.report_id as &str);
+        assert_eq!(ids.collect::<Vec<_>>(), vec![
+            "2020-04-01.example.com@mail.example.com",
+            "2020-04-01.example.org@mail.example.com",
+        ]);
+        let date_range = json!({
+            "start-datetime": "2020-04-01T00:00:00Z",
+            "end-datetime": "2020-04-01T23:59:59Z",
+        });
+        let json = |r: &TlsReport| serde_json::from_str::<Value>(&r.json).unwrap();
+        assert_eq!(
+            json(&reports[0]),
+            json!({
+                "organization-name": "Example Inc.",
+                "date-range": date_range,
+                "contact-info": "postmaster@example.com",
+                "report-id": "2020-04-01.example.com@mail.example.com",
+                "policies": [{
+                    "policy": {
+                        "policy-type": "tlsa",
+                        "policy-string": ["3 1 1 01AB"],
+                        "policy-domain": "example.com",
+                        "mx-host": ["mx.example.com"],
+                    },
+                    "summary": {
+                        "total-successful-session-count": 3,
+                        "total-failure-session-count": 0,
+                    },
+                }],
+            })
+        );
+        assert_eq!(
+            json(&reports[1]),
+            json!({
+                "organization-name": "Example Inc.",
+                "date-range": date_range,
+                "contact-info": "postmaster@example.com",
+                "report-id": "2020-04-01.example.org@mail.example.com",
+                "policies": [{
+                    "policy": {
+                        "policy-type": "sts",
+                        "policy-string": [
+                            "version: STSv1",
+                            "mode: enforce",
+                            "mx: mx.example.org",
+                            "mx: *.example.net",
+                            "max_age: 86400",
+                        ],
+                        "policy-domain": "example.org",
+                        "mx-host": ["mx.example.org", "*.example.net"],
+                    },
+                    "summary": {
+                        "total-successful-session-count": 12,
+                        "total-failure-session-count": 3,
+                    },
+                    "failure-details": [
+                        {
+                            "result-type": "starttls-not-supported",
+                            "receiving-mx-hostname": "mx.example.org",
+                            "receiving-ip": "192.0.2.1",
+                            "failed-session-count": 2,
+                        },
+                        {
+                            "result-type": "certificate-expired",
+                            "receiving-mx-hostname": "mx1.example.net",
+                            "receiving-ip": "192.0.2.1",
+                            "failed-session-count": 1,
+                        },
+                    ],
+                }],
+            })
+        );
+
+        // The order of the events does not matter
+        let mut reversed = events.clone();
+        reversed.reverse();
+        assert_eq!(generate_reports(&org(), date(1), &reversed), reports);
+
+        assert_eq!(generate_reports(&org(), date(3), &events), vec![]);
+    }
+
+    #[test]
+    fn recorder() {
+        let recorder = TlsRptRecorder::new();
+        recorder.record(sts_policy(), None);
+        recorder.record(tlsa_policy(), None);
+        recorder.record(sts_policy(), None);
+        recorder.record(
+            sts_policy(),
+            failure(FailureType::ValidationFailure, "mx.example.org"),
+        );
+        let today = Utc::now().naive_utc().date();
+        assert_eq!(recorder.take_before(today), vec![]);
+        let events = recorder.take_before(today.succ_opt().unwrap());
+        assert_eq!(events.len(), 3);
+        assert!(events.contains(&(
+            TlsEvent {
+                date: today,
+                policy: sts_policy(),
+                failure: None,
+            },
+            2
+        )));
+        let reports = generate_reports(&org(), today, &events);
+        assert_eq!(reports.len(), 2);
+        assert_eq!(recorder.take_before(today.succ_opt().unwrap()), vec![]);
+    }
+
+    #[test]
+    fn report_mail() {
+        let report = TlsReport {
+            policy_domain: "example.org".to_owned(),
+            date: date(1),
+            report_id: "2020-04-01.example.org@mail.example.com".to_owned(),
+            json: "{\"organization-name\":\"Example Inc.\"}".repeat(3),
+        };
+        let now = Utc.with_ymd_and_hms(2020, 4, 2, 0, 10, 0).unwrap();
+        let to = [
+            Email::parse_bracketed(b"<a@example.org>")
+                .unwrap()
+                .to_owned(),
+            Email::parse_bracketed(b"<b@example.org>")
+                .unwrap()
+                .to_owned(),
+        ];
+        let mail = String::from_utf8(report.to_mail(&org(), &to, now)).unwrap();
+        let expected = [
+            "From: <tlsrpt@example.com>",
+            "To: <a@example.org>, <b@example.org>",
+            "Date: Thu, 2 Apr 2020 00:10:00 +0000",
+            "Subject: Report Domain: example.org Submitter: mail.example.com Report-ID: \
+             <2020-04-01.example.org@mail.example.com>",
+            "Message-ID: <2020-04-01.example.org@mail.example.com>",
+            "TLS-Report-Domain: example.org",
+            "TLS-Report-Submitter: mail.example.com",
+            "MIME-Version: 1.0",
+            "Content-Type: multipart/report; report-type=\"tlsrpt\";",
+            "\tboundary=\"tlsrpt-2020-04-01.example.org@mail.example.com\"",
+            "",
+            "--tlsrpt-2020-04-01.example.org@mail.example.com",
+            "Content-Type: text/plain; charset=utf-8",
+            "",
+            "This is an aggregate TLS report from mail.example.com for example.org.",
+            "",
+            "--tlsrpt-2020-04-01.example.org@mail.example.com",
+            "Content-Type: application/tlsrpt+json",
+            "Content-Transfer-Encoding: base64",
+            "Content-Disposition: attachment; \
+             filename=\"mail.example.com!example.org!1585699200!1585785599.json\"",
+            "",
+            "",
+        ]
+        .join("\r\n");
+        let expected = &expected as &str;
+        assert!(mail.starts_with(expected), "{}", mail);
+        let attachment = &mail[expected.len()..];
+        let (attachment, end) = attachment.split_at(attachment.find("--").unwrap());
+        assert_eq!(
+            end,
+            "--tlsrpt-2020-04-01.example.org@mail.example.com--\r\n"
+        );
+        assert!(attachment.split("\r\n").all(|l| l.len() <= 76));
+        let decoded = base64::decode(attachment.replace("\r\n", "")).unwrap();
+        assert_eq!(decoded, report.json.as_bytes());
+    }
+
+    #[test]
+    fn rua_record() {
+        let tests: &[(&[&str], &[&str])] = &[
+            (&["v=TLSRPTv1; rua=mailto:reports@example.org"], &[
+                "<reports@example.org>",
+            ]),
+            (
+                &[
+                    "v=spf1 -all",
+                    "v=TLSRPTv1;rua=MAILTO:a@example.org,https://example.org/tlsrpt, \
+                     mailto:b%2Bc@example.net?subject=tlsrpt",
+                ],
+                &["<a@example.org>", "<b+c@example.net>"],
+            ),
+            (&["v=TLSRPTv1; rua=https://example.org/tlsrpt"], &[]),
+            (&["v=TLSRPTv1; rua=mailto:not an address"], &[]),
+            (
+                &[
+                    "v=TLSRPTv1; rua=mailto:a@example.org",
+                    "v=TLSRPTv1; rua=mailto:b@example.org",
+                ],
+                &[],
+            ),
+            (&["v=TLSRPTv1;"], &[]),
+            (&[], &[]),
+        ];
+        for (inp, out) in tests {
+            println!("Test: {:?}", inp);
+            let records = inp.iter().map(|r| r.to_string()).collect::<Vec<_>>();
+            let expected = out
+                .iter()
+                .map(|e| Email::parse_bracketed(e.as_bytes()).unwrap().to_owned())
+                .collect::<Vec<Email>>();
+            assert_eq!(rua(&records), expected);
+        }
+    }
+
+    #[test]
+    fn records_sessions() {
+        use std::net::TcpListener;
+
+        use smol::Async;
+
+        use crate::{
+            mta_sts::tests::{mx_tls, serve_mx},
+            relay::tests::Log,
+            test_helpers::hostname,
+            SmtpDestination, SmtpTransport, StubResolver,
+        };
+
+        let listener = Async::new(TcpListener::bind("127.0.0.1:0").unwrap()).unwrap();
+        let port = listener.get_ref().local_addr().unwrap().port();
+        let tlsa = Tlsa {
+            usage: Tlsa::DANE_EE,
+            selector: Tlsa::SPKI,
+            matching_type: Tlsa::SHA256,
+            data: vec![0; 32],
+        };
+        let resolver = StubResolver::new()
+            .with_mx("example.org", 10, "mx.example.org")
+            .with_ip("mx.example.org", "127.0.0.1".parse().unwrap())
+            .with_tlsa("mx.example.org", port, tlsa.clone());
+        let recorder = Arc::new(TlsRptRecorder::new());
+        let transport = SmtpTransport::with_port(resolver.clone(), hostname(), port)
+            .with_dane(resolver)
+            .with_tls_reporting(recorder.clone());
+        smol::run(async move {
+            serve_mx(listener, Some(mx_tls()), Log::default());
+            let dest = SmtpDestination::Domain("example.org".to_owned());
+            assert!(Transport::<()>::connect(&transport, &dest).await.is_err());
+        });
+
+        let today = Utc::now().naive_utc().date();
+        assert_eq!(recorder.take_before(today.succ_opt().unwrap()), vec![(
+            TlsEvent {
+                date: today,
+                policy: TlsPolicy::tlsa("example.org", "mx.example.org", &[tlsa]),
+                failure: Some(FailureDetails {
+                    result_type: FailureType::ValidationFailure,
+                    sending_mta_ip: None,
+                    receiving_mx_hostname: Some("mx.example.org".to_owned()),
+                    receiving_ip: Some("127.0.0.1".parse().unwrap()),
+                }),
+            },
+            1
+        )]);
+    }
+}
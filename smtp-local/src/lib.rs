@@ -0,0 +1,20 @@
+use smtp_message::Email;
+
+mod maildir;
+mod pipe;
+#[cfg(test)]
+mod test_helpers;
+
+pub use maildir::{
+    LocalpartMapping, MaildirConfig, MaildirMapping, MaildirSender, MaildirTransport,
+};
+pub use pipe::{PipeConfig, PipeSender, PipeTransport};
+
+/// Formats `email` the way it is given to local delivery agents, without the
+/// angle brackets
+fn email_string(email: &Email) -> String {
+    match email.hostname {
+        Some(ref host) => format!("{}@{}", email.localpart.raw(), host.raw()),
+        None => email.localpart.raw().clone(),
+    }
+}
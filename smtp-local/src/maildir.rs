@@ -0,0 +1,561 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io,
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use futures::io::{AsyncRead, AsyncWriteExt};
+use smol::Unblock;
+use smtp_message::{Email, MaybeUtf8, ReplyCode};
+use smtp_queue::{MailMetadata, Transport, TransportFailure, TransportSender};
+
+const MAILDIR_SUBDIRS: &[&str] = &["tmp", "new", "cur"];
+
+/// Gives the Maildir each recipient's mails are to be delivered to
+pub trait MaildirMapping: 'static + Send + Sync {
+    /// Returns the path of the Maildir for `to`, or `None` if there is no
+    /// such mailbox, in which case the mail is bounced for this recipient
+    fn maildir(&self, to: &Email) -> Option<PathBuf>;
+}
+
+impl<F> MaildirMapping for F
+where
+    F: 'static + Send + Sync + Fn(&Email) -> Option<PathBuf>,
+{
+    fn maildir(&self, to: &Email) -> Option<PathBuf> {
+        self(to)
+    }
+}
+
+/// Delivers to `<root>/<localpart>/Maildir`, whatever the domain of the
+/// recipient
+///
+/// The localpart is lowercased, and recipients whose localpart could designate
+/// another directory (eg. containing a `/` or starting with a `.`) are
+/// refused.
+pub struct LocalpartMapping {
+    pub root: PathBuf,
+}
+
+impl MaildirMapping for LocalpartMapping {
+    fn maildir(&self, to: &Email) -> Option<PathBuf> {
+        let localpart = match to.localpart.unquote() {
+            MaybeUtf8::Ascii(l) | MaybeUtf8::Utf8(l) => l.to_lowercase(),
+        };
+        if localpart.is_empty()
+            || localpart.starts_with('.')
+            || localpart.contains(&['/', '\0'][..])
+        {
+            return None;
+        }
+        Some(self.root.join(localpart).join("Maildir"))
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct MaildirConfig {
+    /// Bounce the mail for recipients that are over their disk quota,
+    /// instead of retrying the delivery later
+    pub permanent_quota_errors: bool,
+
+    /// Create the Maildir and its `tmp`, `new` and `cur` directories if
+    /// they do not exist yet
+    pub create_dirs: bool,
+}
+
+/// Transport that delivers mails into Maildirs
+///
+/// Each mail is written into the `tmp` directory under a unique name, synced
+/// to disk and only then moved into `new`, so that readers of the Maildir
+/// never see partially written mails. A mail to multiple recipients is
+/// written once, and then copied for the other recipients.
+pub struct MaildirTransport<M> {
+    mapping: Arc<M>,
+    config: Arc<MaildirConfig>,
+    hostname: Arc<String>,
+}
+
+impl<M> MaildirTransport<M>
+where
+    M: MaildirMapping,
+{
+    pub fn new(mapping: M, config: MaildirConfig) -> MaildirTransport<M> {
+        MaildirTransport {
+            mapping: Arc::new(mapping),
+            config: Arc::new(config),
+            hostname: Arc::new(maildir_hostname()),
+        }
+    }
+}
+
+#[async_trait]
+impl<U, M> Transport<U> for MaildirTransport<M>
+where
+    U: 'static + Send + Sync,
+    M: MaildirMapping,
+{
+    type Destination = ();
+    type Sender = MaildirSender<M>;
+
+    async fn destination(&self, _meta: &MailMetadata<U>) -> Result<(), TransportFailure> {
+        Ok(())
+    }
+
+    async fn connect(&self, _dest: &()) -> Result<MaildirSender<M>, TransportFailure> {
+        Ok(MaildirSender {
+            mapping: self.mapping.clone(),
+            config: self.config.clone(),
+            hostname: self.hostname.clone(),
+        })
+    }
+}
+
+pub struct MaildirSender<M> {
+    mapping: Arc<M>,
+    config: Arc<MaildirConfig>,
+    hostname: Arc<String>,
+}
+
+#[async_trait]
+impl<U, M> TransportSender<U> for MaildirSender<M>
+where
+    U: 'static + Send + Sync,
+    M: MaildirMapping,
+{
+    async fn send<Reader>(
+        &mut self,
+        meta: &MailMetadata<U>,
+        mail: Reader,
+    ) -> Result<(), TransportFailure>
+    where
+        Reader: Send + AsyncRead,
+    {
+        let maildirs = meta
+            .to
+            .iter()
+            .map(|to| self.mapping.maildir(to))
+            .collect::<Vec<_>>();
+        let mut results = maildirs
+            .iter()
+            .map(|m| match m {
+                Some(_) => Ok(()),
+                None => Err(TransportFailure::RemotePermanent(
+                    ReplyCode::MAILBOX_UNAVAILABLE,
+                    io::Error::new(io::ErrorKind::NotFound, "no such mailbox"),
+                )),
+            })
+            .collect::<Vec<_>>();
+
+        // Write the mail once, into the tmp directory of the first recipient
+        // for which this works
+        let mut spool = None;
+        for (i, maildir) in maildirs.iter().enumerate() {
+            let maildir = match maildir {
+                Some(m) => m.clone(),
+                None => continue,
+            };
+            let config = self.config.clone();
+            let hostname = self.hostname.clone();
+            match smol::unblock(move || create_tmp(&maildir, &config, &hostname)).await {
+                Ok((tmp, file)) => {
+                    spool = Some((i, tmp, file));
+                    break;
+                }
+                Err(e) => results[i] = Err(failure(&self.config, e)),
+            }
+        }
+        let (spool_idx, spool, file) = match spool {
+            Some(s) => s,
+            None => return TransportFailure::combine(results),
+        };
+        let written = write_spool(file, mail).await;
+        if let Err(e) = written {
+            let _ = smol::unblock(move || fs::remove_file(&spool.path)).await;
+            return match e {
+                SpoolError::Read(e) => Err(TransportFailure::Local(e)),
+                SpoolError::Write(e) => {
+                    // All recipients would get the same error, the mail not
+                    // having been written anywhere
+                    let failures = results
+                        .into_iter()
+                        .map(|r| {
+                            r.and_then(|()| {
+                                let e = io::Error::new(e.kind(), e.to_string());
+                                Err(failure(&self.config, e))
+                            })
+                        })
+                        .collect();
+                    TransportFailure::combine(failures)
+                }
+            };
+        }
+
+        // Then copy it for the other recipients, and only move it into place
+        // for the first recipient at the end
+        let config = self.config.clone();
+        let hostname = self.hostname.clone();
+        let maildirs = maildirs
+            .into_iter()
+            .zip(results.iter())
+            .map(|(m, r)| m.filter(|_| r.is_ok()))
+            .collect::<Vec<_>>();
+        let delivered = smol::unblock(move || {
+            let mut delivered = Vec::with_capacity(maildirs.len());
+            for (i, maildir) in maildirs.iter().enumerate() {
+                delivered.push(match maildir {
+                    None => Ok(()),
+                    Some(_) if i == spool_idx => Ok(()),
+                    Some(m) => copy_into(&spool.path, m, &config, &hostname),
+                });
+            }
+            delivered[spool_idx] = spool.commit();
+            delivered
+        })
+        .await;
+        for (res, d) in results.iter_mut().zip(delivered) {
+            if let Err(e) = d {
+                *res = Err(failure(&self.config, e));
+            }
+        }
+        TransportFailure::combine(results)
+    }
+}
+
+fn failure(config: &MaildirConfig, err: io::Error) -> TransportFailure {
+    match err.raw_os_error() {
+        Some(libc::EDQUOT) if config.permanent_quota_errors => {
+            TransportFailure::RemotePermanent(ReplyCode::EXCEEDED_STORAGE, err)
+        }
+        Some(libc::EDQUOT) | Some(libc::ENOSPC) => {
+            TransportFailure::RemoteTransient(ReplyCode::INSUFFICIENT_STORAGE, err)
+        }
+        _ => TransportFailure::RemoteTransient(ReplyCode::LOCAL_ERROR, err),
+    }
+}
+
+enum SpoolError {
+    Read(io::Error),
+    Write(io::Error),
+}
+
+async fn write_spool<R>(file: File, mail: R) -> Result<(), SpoolError>
+where
+    R: AsyncRead,
+{
+    futures::pin_mut!(mail);
+    let mut file = Unblock::new(file);
+    let mut buf = vec![0; 16 * 1024];
+    loop {
+        let read = futures::io::AsyncReadExt::read(&mut mail, &mut buf)
+            .await
+            .map_err(SpoolError::Read)?;
+        if read == 0 {
+            break;
+        }
+        file.write_all(&buf[..read])
+            .await
+            .map_err(SpoolError::Write)?;
+    }
+    file.flush().await.map_err(SpoolError::Write)?;
+    let file = file.into_inner().await;
+    smol::unblock(move || file.sync_all())
+        .await
+        .map_err(SpoolError::Write)
+}
+
+/// A mail being written in the `tmp` directory of a Maildir
+struct TmpMail {
+    maildir: PathBuf,
+    name: String,
+    path: PathBuf,
+}
+
+impl TmpMail {
+    /// Moves the mail into `new`, making it visible to the readers of the
+    /// Maildir
+    fn commit(&self) -> io::Result<()> {
+        let new = self.maildir.join("new");
+        let res = fs::rename(&self.path, new.join(&self.name)).and_then(|()| sync_dir(&new));
+        if res.is_err() {
+            let _ = fs::remove_file(&self.path);
+        }
+        res
+    }
+}
+
+fn create_tmp(
+    maildir: &Path,
+    config: &MaildirConfig,
+    hostname: &str,
+) -> io::Result<(TmpMail, File)> {
+    if config.create_dirs {
+        create_maildir(maildir)?;
+    }
+    let name = unique_name(hostname);
+    let path = maildir.join("tmp").join(&name);
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)?;
+    let tmp = TmpMail {
+        maildir: maildir.to_owned(),
+        name,
+        path,
+    };
+    Ok((tmp, file))
+}
+
+fn copy_into(
+    spool: &Path,
+    maildir: &Path,
+    config: &MaildirConfig,
+    hostname: &str,
+) -> io::Result<()> {
+    let (tmp, mut file) = create_tmp(maildir, config, hostname)?;
+    let res = File::open(spool)
+        .and_then(|mut spool| io::copy(&mut spool, &mut file))
+        .and_then(|_| file.sync_all());
+    match res {
+        Ok(()) => tmp.commit(),
+        Err(e) => {
+            let _ = fs::remove_file(&tmp.path);
+            Err(e)
+        }
+    }
+}
+
+fn create_maildir(maildir: &Path) -> io::Result<()> {
+    let mut created = false;
+    for sub in MAILDIR_SUBDIRS {
+        let path = maildir.join(sub);
+        if !path.is_dir() {
+            fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(&path)?;
+            created = true;
+        }
+    }
+    if created {
+        sync_dir(maildir)?;
+    }
+    Ok(())
+}
+
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+/// Returns a file name unique across all the deliveries, following the
+/// `<time>.M<usec>P<pid>Q<counter>.<hostname>` convention
+fn unique_name(hostname: &str) -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    format!(
+        "{}.M{}P{}Q{}.{}",
+        now.as_secs(),
+        now.subsec_micros(),
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed),
+        hostname
+    )
+}
+
+/// Returns the hostname, with the characters that are not allowed in Maildir
+/// file names escaped
+fn maildir_hostname() -> String {
+    let mut buf = [0u8; 256];
+    let res = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    let len = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+    let hostname = match res {
+        0 => String::from_utf8_lossy(&buf[..len]).into_owned(),
+        _ => String::new(),
+    };
+    if hostname.is_empty() {
+        return "localhost".to_owned();
+    }
+    hostname.replace('/', "\\057").replace(':', "\\072")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::io::Cursor;
+
+    use crate::test_helpers::{email, meta};
+
+    fn send<M: MaildirMapping>(
+        transport: &MaildirTransport<M>,
+        meta: &MailMetadata<()>,
+        mail: &[u8],
+    ) -> Result<(), TransportFailure> {
+        smol::run(async {
+            let mut sender = Transport::<()>::connect(transport, &())
+                .await
+                .unwrap_or_else(|_| panic!("failed connecting"));
+            sender.send(meta, Cursor::new(mail)).await
+        })
+    }
+
+    fn list(dir: &Path) -> Vec<PathBuf> {
+        let mut res = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect::<Vec<_>>();
+        res.sort();
+        res
+    }
+
+    #[test]
+    fn localpart_mapping() {
+        let mapping = LocalpartMapping {
+            root: "/var/mail".into(),
+        };
+        let tests: &[(&str, Option<&str>)] = &[
+            ("<Foo@example.org>", Some("/var/mail/foo/Maildir")),
+            (
+                "<\"foo bar\"@example.org>",
+                Some("/var/mail/foo bar/Maildir"),
+            ),
+            ("<\"../foo\"@example.org>", None),
+            ("<\"foo/bar\"@example.org>", None),
+            ("<.foo@example.org>", None),
+        ];
+        for (to, expected) in tests {
+            assert_eq!(
+                mapping.maildir(&email(to)),
+                expected.map(PathBuf::from),
+                "for {}",
+                to
+            );
+        }
+    }
+
+    #[test]
+    fn delivers_to_each_recipient() {
+        let root = tempfile::tempdir().unwrap();
+        let transport = MaildirTransport::new(
+            LocalpartMapping {
+                root: root.path().to_owned(),
+            },
+            MaildirConfig {
+                permanent_quota_errors: false,
+                create_dirs: true,
+            },
+        );
+        let mail: &[u8] = b"Subject: test\r\n\r\nHello\r\n";
+        let res = send(
+            &transport,
+            &meta(&[
+                "<foo@example.org>",
+                "<bar@example.org>",
+                "<foo@example.org>",
+            ]),
+            mail,
+        );
+        assert!(res.is_ok());
+
+        let foo = list(&root.path().join("foo/Maildir/new"));
+        let bar = list(&root.path().join("bar/Maildir/new"));
+        assert_eq!(foo.len(), 2);
+        assert_eq!(bar.len(), 1);
+        assert_ne!(foo[0], foo[1]);
+        for path in foo.iter().chain(bar.iter()) {
+            assert_eq!(fs::read(path).unwrap(), mail);
+        }
+        assert!(list(&root.path().join("foo/Maildir/tmp")).is_empty());
+        assert!(list(&root.path().join("bar/Maildir/tmp")).is_empty());
+        assert!(list(&root.path().join("foo/Maildir/cur")).is_empty());
+    }
+
+    #[test]
+    fn fails_per_recipient() {
+        let root = tempfile::tempdir().unwrap();
+        let existing = root.path().join("existing");
+        let missing = root.path().join("missing");
+        create_maildir(&existing).unwrap();
+        let transport = MaildirTransport::new(
+            move |to: &Email| match to.localpart.raw().as_str() {
+                "foo" => Some(existing.clone()),
+                "bar" => Some(missing.clone()),
+                _ => None,
+            },
+            MaildirConfig::default(),
+        );
+        let res = send(
+            &transport,
+            &meta(&[
+                "<bar@example.org>",
+                "<nobody@example.org>",
+                "<foo@example.org>",
+            ]),
+            b"Hello\r\n",
+        );
+        match res {
+            Err(TransportFailure::PerRecipient(results)) => {
+                assert_eq!(results.len(), 3);
+                assert!(results[2].is_ok());
+                match results[1] {
+                    Err(TransportFailure::RemotePermanent(c, _)) => {
+                        assert_eq!(c, ReplyCode::MAILBOX_UNAVAILABLE)
+                    }
+                    _ => panic!("expected a permanent failure for nobody"),
+                }
+                match results[0] {
+                    Err(TransportFailure::RemoteTransient(c, _)) => {
+                        assert_eq!(c, ReplyCode::LOCAL_ERROR)
+                    }
+                    _ => panic!("expected a transient failure for bar"),
+                }
+            }
+            _ => panic!("expected per-recipient results"),
+        }
+        assert_eq!(list(&root.path().join("existing/new")).len(), 1);
+        assert!(!root.path().join("missing").exists());
+    }
+
+    #[test]
+    fn quota_errors() {
+        let code = |permanent_quota_errors, errno| {
+            let config = MaildirConfig {
+                permanent_quota_errors,
+                create_dirs: false,
+            };
+            match failure(&config, io::Error::from_raw_os_error(errno)) {
+                TransportFailure::RemotePermanent(c, _) => (true, c),
+                TransportFailure::RemoteTransient(c, _) => (false, c),
+                _ => panic!("unexpected failure kind"),
+            }
+        };
+        assert_eq!(
+            code(true, libc::EDQUOT),
+            (true, ReplyCode::EXCEEDED_STORAGE)
+        );
+        assert_eq!(
+            code(false, libc::EDQUOT),
+            (false, ReplyCode::INSUFFICIENT_STORAGE)
+        );
+        assert_eq!(
+            code(true, libc::ENOSPC),
+            (false, ReplyCode::INSUFFICIENT_STORAGE)
+        );
+        assert_eq!(code(true, libc::EACCES), (false, ReplyCode::LOCAL_ERROR));
+    }
+
+    #[test]
+    fn hostname_is_escaped() {
+        let name = unique_name(&maildir_hostname());
+        assert!(!name.contains('/') && !name.contains(':'));
+    }
+}
@@ -0,0 +1,667 @@
+use std::{
+    cmp, io,
+    os::unix::process::CommandExt,
+    path::PathBuf,
+    process::{Child, ChildStdin, Command, ExitStatus, Stdio},
+    sync::Arc,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use futures::{
+    future::{self, Either},
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt, Cursor},
+    pin_mut,
+};
+use smol::{Task, Timer, Unblock};
+use smtp_message::ReplyCode;
+use smtp_queue::{MailMetadata, Transport, TransportFailure, TransportSender};
+
+use crate::email_string;
+
+const BUF_SIZE: usize = 16 * 1024;
+
+/// `PATH` the program is run with, as it does not inherit the environment
+pub const DEFAULT_PATH: &str = "/usr/local/bin:/usr/bin:/bin";
+
+// Exit codes from sysexits.h
+const EX_USAGE: i32 = 64;
+const EX_DATAERR: i32 = 65;
+const EX_NOINPUT: i32 = 66;
+const EX_NOUSER: i32 = 67;
+const EX_NOHOST: i32 = 68;
+const EX_UNAVAILABLE: i32 = 69;
+const EX_SOFTWARE: i32 = 70;
+const EX_OSERR: i32 = 71;
+const EX_OSFILE: i32 = 72;
+const EX_CANTCREAT: i32 = 73;
+const EX_IOERR: i32 = 74;
+const EX_TEMPFAIL: i32 = 75;
+const EX_PROTOCOL: i32 = 76;
+const EX_NOPERM: i32 = 77;
+const EX_CONFIG: i32 = 78;
+
+#[derive(Clone, Debug)]
+pub struct PipeConfig {
+    /// Program to run for delivering the mails
+    pub program: PathBuf,
+
+    /// Arguments given to the program
+    ///
+    /// `{sender}` is replaced with the sender of the mail, or the empty
+    /// string for bounces. An argument that is exactly `{recipient}` is
+    /// replaced with one argument per recipient, and `{recipient}` in any
+    /// other argument with the recipients separated by commas.
+    ///
+    /// The program also gets them in the `SENDER` and `RECIPIENT`
+    /// environment variables, the latter being comma-separated too.
+    pub args: Vec<String>,
+
+    /// Run the program once for each recipient of the mail, instead of once
+    /// for all of them
+    ///
+    /// The mail is then kept in memory for the time of the deliveries if it
+    /// has multiple recipients.
+    pub per_recipient: bool,
+
+    /// Convert the CRLF line endings of the mail to LF, as most Unix programs
+    /// expect
+    pub convert_crlf: bool,
+
+    /// Kill the program if it has not exited after this long, and retry the
+    /// delivery later
+    pub timeout: Duration,
+
+    /// Number of bytes of the program's standard output, and then of its
+    /// standard error, that are kept for the error message if it fails
+    ///
+    /// The rest of its output is discarded.
+    pub max_output: usize,
+
+    /// User ID to run the program as
+    ///
+    /// This requires the server to be running as root. The program is run
+    /// with the supplementary groups of this user from the user database,
+    /// instead of the ones of the server.
+    pub uid: Option<u32>,
+
+    /// Group ID to run the program as
+    ///
+    /// This defaults to the primary group of `uid` when it is set. Without
+    /// `uid`, the supplementary groups of the server are dropped.
+    pub gid: Option<u32>,
+}
+
+/// Transport that delivers the mails by piping them into a program
+///
+/// The exit status of the program is interpreted as per `sysexits.h`:
+/// `EX_TEMPFAIL`, `EX_OSERR`, `EX_IOERR` and `EX_CONFIG` make the delivery
+/// be retried later, while any other non-zero status bounces the mail. The
+/// delivery is also retried if the program was killed by a signal.
+///
+/// The program is run with an empty environment except for the envelope
+/// variables and `PATH`, and in the `/` directory.
+pub struct PipeTransport {
+    config: Arc<PipeConfig>,
+}
+
+impl PipeTransport {
+    pub fn new(config: PipeConfig) -> PipeTransport {
+        PipeTransport {
+            config: Arc::new(config),
+        }
+    }
+}
+
+#[async_trait]
+impl<U> Transport<U> for PipeTransport
+where
+    U: 'static + Send + Sync,
+{
+    type Destination = ();
+    type Sender = PipeSender;
+
+    async fn destination(&self, _meta: &MailMetadata<U>) -> Result<(), TransportFailure> {
+        Ok(())
+    }
+
+    async fn connect(&self, _dest: &()) -> Result<PipeSender, TransportFailure> {
+        Ok(PipeSender {
+            config: self.config.clone(),
+        })
+    }
+}
+
+pub struct PipeSender {
+    config: Arc<PipeConfig>,
+}
+
+#[async_trait]
+impl<U> TransportSender<U> for PipeSender
+where
+    U: 'static + Send + Sync,
+{
+    async fn send<Reader>(
+        &mut self,
+        meta: &MailMetadata<U>,
+        mail: Reader,
+    ) -> Result<(), TransportFailure>
+    where
+        Reader: Send + AsyncRead,
+    {
+        let sender = meta.from.as_ref().map(email_string).unwrap_or_default();
+        let recipients = meta.to.iter().map(email_string).collect::<Vec<_>>();
+        if !self.config.per_recipient || recipients.len() == 1 {
+            return run(&self.config, &sender, &recipients, mail).await;
+        }
+
+        let mut contents = Vec::new();
+        pin_mut!(mail);
+        mail.read_to_end(&mut contents)
+            .await
+            .map_err(TransportFailure::Local)?;
+        let mut results = Vec::with_capacity(recipients.len());
+        for to in recipients {
+            let res = run(&self.config, &sender, &[to], Cursor::new(&contents[..])).await;
+            results.push(res);
+        }
+        TransportFailure::combine(results)
+    }
+}
+
+fn args(config: &PipeConfig, sender: &str, recipients: &[String]) -> Vec<String> {
+    let joined = recipients.join(",");
+    let mut res = Vec::with_capacity(config.args.len());
+    for arg in config.args.iter() {
+        if arg == "{recipient}" {
+            res.extend(recipients.iter().cloned());
+        } else {
+            res.push(
+                arg.replace("{sender}", sender)
+                    .replace("{recipient}", &joined),
+            );
+        }
+    }
+    res
+}
+
+async fn run<R>(
+    config: &PipeConfig,
+    sender: &str,
+    recipients: &[String],
+    mail: R,
+) -> Result<(), TransportFailure>
+where
+    R: AsyncRead,
+{
+    let mut cmd = Command::new(&config.program);
+    cmd.args(args(config, sender, recipients))
+        .env_clear()
+        .env("PATH", DEFAULT_PATH)
+        .env("SENDER", sender)
+        .env("RECIPIENT", recipients.join(","))
+        .current_dir("/")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let (uid, gid) = (config.uid, config.gid);
+    let creds = smol::unblock(move || Credentials::lookup(uid, gid))
+        .await
+        .map_err(TransportFailure::Local)?;
+    if let Some(creds) = creds {
+        // Safety: `apply` only does async-signal-safe calls
+        unsafe {
+            cmd.pre_exec(move || creds.apply());
+        }
+    }
+    let mut child = cmd.spawn().map_err(TransportFailure::Local)?;
+    let pid = child.id() as libc::id_t;
+    let stdin = Unblock::new(child.stdin.take().unwrap());
+    let stdout = Unblock::new(child.stdout.take().unwrap());
+    let stderr = Unblock::new(child.stderr.take().unwrap());
+    let mut exited = Task::spawn(smol::unblock(move || wait_exit(pid)));
+
+    let io = future::join3(
+        write_input(mail, stdin, config.convert_crlf),
+        read_output(stdout, config.max_output),
+        read_output(stderr, config.max_output),
+    );
+    pin_mut!(io);
+    let mut timer = Timer::new(config.timeout);
+    let (input, stdout, stderr) = match future::select(io, &mut timer).await {
+        Either::Left((res, _)) => res,
+        Either::Right(_) => return Err(kill(child).await),
+    };
+    if let Err(e) = input {
+        // Failed reading the mail, so the program must not consider it
+        // delivered
+        kill(child).await;
+        return Err(TransportFailure::Local(e));
+    }
+    match future::select(&mut exited, &mut timer).await {
+        Either::Left((res, _)) => res.map_err(TransportFailure::Local)?,
+        Either::Right(_) => return Err(kill(child).await),
+    }
+    let status = smol::unblock(move || child.wait())
+        .await
+        .map_err(TransportFailure::Local)?;
+    exit_result(status, &stdout, &stderr)
+}
+
+/// User, group and supplementary groups the program is run with
+#[derive(Debug, PartialEq)]
+struct Credentials {
+    uid: Option<libc::uid_t>,
+    gid: libc::gid_t,
+    groups: Vec<libc::gid_t>,
+}
+
+impl Credentials {
+    /// Looks up the primary group, unless `gid` is set, and the supplementary
+    /// groups of `uid` in the user database
+    fn lookup(uid: Option<u32>, gid: Option<u32>) -> io::Result<Option<Credentials>> {
+        let uid = match (uid, gid) {
+            (None, None) => return Ok(None),
+            (None, Some(gid)) => {
+                return Ok(Some(Credentials {
+                    uid: None,
+                    gid,
+                    groups: vec![gid],
+                }))
+            }
+            (Some(uid), _) => uid,
+        };
+
+        let mut pwd = unsafe { std::mem::zeroed::<libc::passwd>() };
+        let mut buf = vec![0 as libc::c_char; 1024];
+        let mut found = std::ptr::null_mut();
+        loop {
+            let res =
+                unsafe { libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut found) };
+            match res {
+                0 => break,
+                libc::ERANGE => buf.resize(buf.len() * 2, 0),
+                e => return Err(io::Error::from_raw_os_error(e)),
+            }
+        }
+        if found.is_null() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no user with uid {} to run the delivery program as", uid),
+            ));
+        }
+
+        let gid = gid.unwrap_or(pwd.pw_gid);
+        let mut groups = vec![0 as libc::gid_t; 64];
+        loop {
+            let mut len = groups.len() as libc::c_int;
+            let res =
+                unsafe { libc::getgrouplist(pwd.pw_name, gid, groups.as_mut_ptr(), &mut len) };
+            if res >= 0 {
+                groups.truncate(len as usize);
+                break;
+            }
+            // `len` is set to the number of groups of the user
+            let len = cmp::max(len as usize, groups.len() * 2);
+            groups.resize(len, 0);
+        }
+
+        Ok(Some(Credentials {
+            uid: Some(uid),
+            gid,
+            groups,
+        }))
+    }
+
+    /// Switches the current process to these credentials
+    ///
+    /// This runs between `fork` and `exec`, so it must only do
+    /// async-signal-safe calls.
+    fn apply(&self) -> io::Result<()> {
+        if unsafe { libc::setgroups(self.groups.len(), self.groups.as_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if unsafe { libc::setgid(self.gid) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if let Some(uid) = self.uid {
+            if unsafe { libc::setuid(uid) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Waits for the program to exit, without reaping it so that it can still
+/// be killed without risking to hit another process with the same pid
+fn wait_exit(pid: libc::id_t) -> io::Result<()> {
+    loop {
+        let mut info = unsafe { std::mem::zeroed::<libc::siginfo_t>() };
+        let flags = libc::WEXITED | libc::WNOWAIT;
+        if unsafe { libc::waitid(libc::P_PID, pid, &mut info, flags) } == 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+/// Kills the program and reaps it, returning the failure for a program that
+/// timed out
+async fn kill(mut child: Child) -> TransportFailure {
+    let _ = child.kill();
+    let _ = smol::unblock(move || child.wait()).await;
+    TransportFailure::RemoteTransient(
+        ReplyCode::LOCAL_ERROR,
+        io::Error::new(io::ErrorKind::TimedOut, "the delivery program timed out"),
+    )
+}
+
+/// Copies the mail to the program's input, returning an error only if
+/// reading the mail failed
+async fn write_input<R>(
+    mail: R,
+    mut stdin: Unblock<ChildStdin>,
+    convert_crlf: bool,
+) -> io::Result<()>
+where
+    R: AsyncRead,
+{
+    pin_mut!(mail);
+    let mut buf = vec![0; BUF_SIZE];
+    let mut converted = Vec::with_capacity(BUF_SIZE);
+    let mut pending_cr = false;
+    loop {
+        let read = mail.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        let chunk = if convert_crlf {
+            converted.clear();
+            for &c in &buf[..read] {
+                if pending_cr && c != b'\n' {
+                    converted.push(b'\r');
+                }
+                pending_cr = c == b'\r';
+                if !pending_cr {
+                    converted.push(c);
+                }
+            }
+            &converted[..]
+        } else {
+            &buf[..read]
+        };
+        if stdin.write_all(chunk).await.is_err() {
+            // The program stopped reading its input, which is up to it: its
+            // exit status tells whether it handled the mail
+            return Ok(());
+        }
+    }
+    if pending_cr {
+        let _ = stdin.write_all(b"\r").await;
+    }
+    let _ = stdin.close().await;
+    Ok(())
+}
+
+async fn read_output<R>(mut output: Unblock<R>, max: usize) -> Vec<u8>
+where
+    R: 'static + Send + io::Read,
+{
+    let mut res = Vec::new();
+    let mut buf = vec![0; BUF_SIZE];
+    loop {
+        match output.read(&mut buf).await {
+            Ok(0) | Err(_) => return res,
+            Ok(read) => {
+                let kept = cmp::min(read, max - res.len());
+                res.extend_from_slice(&buf[..kept]);
+            }
+        }
+    }
+}
+
+fn exit_result(status: ExitStatus, stdout: &[u8], stderr: &[u8]) -> Result<(), TransportFailure> {
+    let code = match status.code() {
+        Some(0) => return Ok(()),
+        Some(code) => code,
+        None => {
+            return Err(TransportFailure::RemoteTransient(
+                ReplyCode::LOCAL_ERROR,
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    "the delivery program was killed by a signal",
+                ),
+            ));
+        }
+    };
+    let (name, code_kind) = match code {
+        EX_USAGE => ("EX_USAGE", Err(ReplyCode::TRANSACTION_FAILED)),
+        EX_DATAERR => ("EX_DATAERR", Err(ReplyCode::TRANSACTION_FAILED)),
+        EX_NOINPUT => ("EX_NOINPUT", Err(ReplyCode::MAILBOX_UNAVAILABLE)),
+        EX_NOUSER => ("EX_NOUSER", Err(ReplyCode::MAILBOX_UNAVAILABLE)),
+        EX_NOHOST => ("EX_NOHOST", Err(ReplyCode::MAILBOX_UNAVAILABLE)),
+        EX_UNAVAILABLE => ("EX_UNAVAILABLE", Err(ReplyCode::TRANSACTION_FAILED)),
+        EX_SOFTWARE => ("EX_SOFTWARE", Err(ReplyCode::TRANSACTION_FAILED)),
+        EX_OSERR => ("EX_OSERR", Ok(ReplyCode::LOCAL_ERROR)),
+        EX_OSFILE => ("EX_OSFILE", Err(ReplyCode::TRANSACTION_FAILED)),
+        EX_CANTCREAT => ("EX_CANTCREAT", Err(ReplyCode::MAILBOX_UNAVAILABLE)),
+        EX_IOERR => ("EX_IOERR", Ok(ReplyCode::LOCAL_ERROR)),
+        EX_TEMPFAIL => ("EX_TEMPFAIL", Ok(ReplyCode::LOCAL_ERROR)),
+        EX_PROTOCOL => ("EX_PROTOCOL", Err(ReplyCode::TRANSACTION_FAILED)),
+        EX_NOPERM => ("EX_NOPERM", Err(ReplyCode::MAILBOX_UNAVAILABLE)),
+        EX_CONFIG => ("EX_CONFIG", Ok(ReplyCode::LOCAL_ERROR)),
+        _ => ("unknown", Err(ReplyCode::TRANSACTION_FAILED)),
+    };
+    let mut msg = format!(
+        "the delivery program exited with status {} ({})",
+        code, name
+    );
+    for output in &[stdout, stderr] {
+        let output = String::from_utf8_lossy(output);
+        let output = output.trim();
+        if !output.is_empty() {
+            msg.push_str(": ");
+            msg.push_str(output);
+        }
+    }
+    let err = io::Error::new(io::ErrorKind::Other, msg);
+    // Ok for transient failures, Err for permanent ones
+    match code_kind {
+        Ok(c) => Err(TransportFailure::RemoteTransient(c, err)),
+        Err(c) => Err(TransportFailure::RemotePermanent(c, err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_helpers::meta;
+
+    fn config(script: &str) -> PipeConfig {
+        PipeConfig {
+            program: "/bin/sh".into(),
+            args: vec![
+                "-c".into(),
+                script.into(),
+                "sh".into(),
+                "{sender}".into(),
+                "{recipient}".into(),
+            ],
+            per_recipient: false,
+            convert_crlf: false,
+            timeout: Duration::from_secs(10),
+            max_output: 1024,
+            uid: None,
+            gid: None,
+        }
+    }
+
+    fn send(
+        config: PipeConfig,
+        meta: &MailMetadata<()>,
+        mail: &[u8],
+    ) -> Result<(), TransportFailure> {
+        let transport = PipeTransport::new(config);
+        smol::run(async {
+            let mut sender = Transport::<()>::connect(&transport, &())
+                .await
+                .unwrap_or_else(|_| panic!("failed connecting"));
+            sender.send(meta, Cursor::new(mail)).await
+        })
+    }
+
+    fn failure(res: &Result<(), TransportFailure>) -> Option<(bool, ReplyCode, String)> {
+        match res {
+            Ok(()) => None,
+            Err(TransportFailure::RemotePermanent(c, e)) => Some((true, *c, e.to_string())),
+            Err(TransportFailure::RemoteTransient(c, e)) => Some((false, *c, e.to_string())),
+            Err(TransportFailure::Local(e)) => panic!("unexpected local failure: {}", e),
+            Err(TransportFailure::PerRecipient(_)) => panic!("unexpected per-recipient failure"),
+        }
+    }
+
+    #[test]
+    fn delivers_mail() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        let mut cfg = config(&format!(
+            "{{ printf '%s|%s|%s|%s|%s\\n' \"$SENDER\" \"$RECIPIENT\" \"$@\" && cat; }} > {}",
+            out.display()
+        ));
+        cfg.convert_crlf = true;
+        let res = send(
+            cfg,
+            &meta(&["<foo@example.org>", "<bar@example.org>"]),
+            b"Subject: test\r\n\r\nHello\r\nworld\r\n",
+        );
+        assert!(failure(&res).is_none());
+        assert_eq!(
+            std::fs::read(&out).unwrap(),
+            &b"sender@example.org|foo@example.org,bar@example.org|\
+               sender@example.org|foo@example.org|bar@example.org\n\
+               Subject: test\n\nHello\nworld\n"[..]
+        );
+    }
+
+    #[test]
+    fn maps_exit_status() {
+        let tests: &[(&str, Option<(bool, ReplyCode)>)] = &[
+            ("exit 0", None),
+            ("exit 75", Some((false, ReplyCode::LOCAL_ERROR))),
+            ("exit 74", Some((false, ReplyCode::LOCAL_ERROR))),
+            ("exit 67", Some((true, ReplyCode::MAILBOX_UNAVAILABLE))),
+            ("exit 1", Some((true, ReplyCode::TRANSACTION_FAILED))),
+            ("kill -9 $$", Some((false, ReplyCode::LOCAL_ERROR))),
+        ];
+        for (script, expected) in tests {
+            let res = send(config(script), &meta(&["<foo@example.org>"]), b"Hello\r\n");
+            let res = failure(&res).map(|(p, c, _)| (p, c));
+            assert_eq!(res, *expected, "for script {}", script);
+        }
+    }
+
+    #[test]
+    fn keeps_limited_output() {
+        let mut cfg =
+            config("echo 'No such user'; echo oops >&2; head -c 10000 /dev/zero; exit 67");
+        cfg.max_output = 13;
+        let res = send(cfg, &meta(&["<foo@example.org>"]), b"Hello\r\n");
+        assert_eq!(
+            failure(&res),
+            Some((
+                true,
+                ReplyCode::MAILBOX_UNAVAILABLE,
+                "the delivery program exited with status 67 (EX_NOUSER): No such user: oops"
+                    .to_owned()
+            ))
+        );
+    }
+
+    #[test]
+    fn kills_on_timeout() {
+        let mut cfg = config("sleep 10");
+        cfg.timeout = Duration::from_millis(100);
+        let res = send(cfg, &meta(&["<foo@example.org>"]), b"Hello\r\n");
+        let (permanent, code, _) = failure(&res).unwrap();
+        assert!(!permanent);
+        assert_eq!(code, ReplyCode::LOCAL_ERROR);
+    }
+
+    #[test]
+    fn program_not_reading_input() {
+        let mail = vec![b'a'; 1024 * 1024];
+        let res = send(config("exit 0"), &meta(&["<foo@example.org>"]), &mail);
+        assert!(failure(&res).is_none());
+    }
+
+    #[test]
+    fn runs_per_recipient() {
+        let mut cfg =
+            config("cat > /dev/null; [ \"$RECIPIENT\" != nobody@example.org ] || exit 67");
+        cfg.per_recipient = true;
+        let res = send(
+            cfg,
+            &meta(&["<foo@example.org>", "<nobody@example.org>"]),
+            b"Hello\r\n",
+        );
+        match res {
+            Err(TransportFailure::PerRecipient(results)) => {
+                let results = results
+                    .iter()
+                    .map(|r| failure(r).map(|(p, c, _)| (p, c)))
+                    .collect::<Vec<_>>();
+                assert_eq!(results, vec![
+                    None,
+                    Some((true, ReplyCode::MAILBOX_UNAVAILABLE))
+                ]);
+            }
+            _ => panic!("expected per-recipient results"),
+        }
+    }
+
+    #[test]
+    fn looks_up_credentials() {
+        let creds = |uid, gid, groups: &[u32]| {
+            Some(Credentials {
+                uid,
+                gid,
+                groups: groups.to_vec(),
+            })
+        };
+        let tests = &[
+            ((None, None), None),
+            ((None, Some(42)), creds(None, 42, &[42])),
+            ((Some(0), None), creds(Some(0), 0, &[0])),
+            ((Some(0), Some(42)), creds(Some(0), 42, &[42])),
+        ];
+        for &((uid, gid), ref out) in tests {
+            println!("Test: {:?}", (uid, gid));
+            let mut res = Credentials::lookup(uid, gid).unwrap();
+            // The supplementary groups of root depend on the system
+            if let Some(ref mut res) = res {
+                let gid = res.gid;
+                res.groups.retain(|&g| g == gid);
+            }
+            assert_eq!(res, *out);
+        }
+        let err = Credentials::lookup(Some(4_242_424), None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn runs_as_user() {
+        if unsafe { libc::geteuid() } != 0 {
+            println!("Not running as root, skipping");
+            return;
+        }
+        let mut cfg = config("[ \"$(id -u) $(id -G)\" = \"65534 65534\" ] || exit 67");
+        cfg.uid = Some(65534);
+        let res = send(cfg, &meta(&["<foo@example.org>"]), b"Hello\r\n");
+        assert!(failure(&res).is_none());
+    }
+}
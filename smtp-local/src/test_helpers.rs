@@ -0,0 +1,17 @@
+//! Fixtures shared by the tests of all the modules
+
+use smtp_message::Email;
+use smtp_queue::MailMetadata;
+
+pub(crate) fn email(s: &str) -> Email {
+    Email::parse_bracketed(s.as_bytes()).unwrap().to_owned()
+}
+
+/// Metadata of a mail from sender@example.org to `to`
+pub(crate) fn meta(to: &[&str]) -> MailMetadata<()> {
+    MailMetadata {
+        from: Some(email("<sender@example.org>")),
+        to: to.iter().map(|to| email(to)).collect(),
+        metadata: (),
+    }
+}
@@ -0,0 +1,7 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|inp: (Vec<u8>, Vec<usize>)| {
+    smtp_message::fuzz::header_parsing(inp.0, inp.1);
+});
@@ -0,0 +1,380 @@
+use crate::*;
+
+/// The extensions a server advertises in its reply to `EHLO` (or `LHLO`)
+///
+/// Extensions are listed in alphabetical order when building a reply, which
+/// makes it deterministic.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Capabilities<S = String> {
+    /// 8BITMIME (RFC 6152)
+    pub eightbitmime: bool,
+
+    /// AUTH (RFC 4954), with the supported mechanisms, none meaning that the
+    /// extension is not advertised
+    pub auth: Vec<S>,
+
+    /// CHUNKING (RFC 3030)
+    pub chunking: bool,
+
+    /// DSN (RFC 3461)
+    pub dsn: bool,
+
+    /// ENHANCEDSTATUSCODES (RFC 2034)
+    pub enhanced_status_codes: bool,
+
+    /// PIPELINING (RFC 2920)
+    pub pipelining: bool,
+
+    /// REQUIRETLS (RFC 8689)
+    pub requiretls: bool,
+
+    /// SIZE (RFC 1870), with the maximum size of a message, `0` meaning that
+    /// there is no fixed maximum
+    pub size: Option<u64>,
+
+    /// SMTPUTF8 (RFC 6531)
+    pub smtputf8: bool,
+
+    /// STARTTLS (RFC 3207)
+    pub starttls: bool,
+
+    /// Other extensions, as their keyword and parameters
+    pub other: Vec<(S, Option<S>)>,
+}
+
+impl<S> Default for Capabilities<S> {
+    fn default() -> Capabilities<S> {
+        Capabilities::new()
+    }
+}
+
+impl<S> Capabilities<S> {
+    pub fn new() -> Capabilities<S> {
+        Capabilities {
+            eightbitmime: false,
+            auth: Vec::new(),
+            chunking: false,
+            dsn: false,
+            enhanced_status_codes: false,
+            pipelining: false,
+            requiretls: false,
+            size: None,
+            smtputf8: false,
+            starttls: false,
+            other: Vec::new(),
+        }
+    }
+
+    /// Parses the extensions advertised in `reply`, that must be the reply to
+    /// an `EHLO` command
+    ///
+    /// The first line of the reply is the greeting, and is ignored. Keywords
+    /// are matched case-insensitively, and extensions with invalid parameters
+    /// are kept as unknown ones.
+    pub fn from_reply<'a, T>(reply: &'a Reply<T>) -> Capabilities<S>
+    where
+        S: From<&'a str>,
+        T: AsRef<str>,
+    {
+        let mut res = Capabilities::new();
+        for line in reply.text.iter().skip(1) {
+            let line = match line {
+                MaybeUtf8::Ascii(l) | MaybeUtf8::Utf8(l) => l.as_ref().trim(),
+            };
+            let mut words = line.splitn(2, &[' ', '\t'][..]);
+            let keyword = match words.next() {
+                Some(k) if !k.is_empty() => k,
+                _ => continue,
+            };
+            let params = words.next().map(|p| p.trim()).filter(|p| !p.is_empty());
+            let is_flag = |name: &str| keyword.eq_ignore_ascii_case(name) && params.is_none();
+            if is_flag("8BITMIME") {
+                res.eightbitmime = true;
+            } else if keyword.eq_ignore_ascii_case("AUTH") {
+                let mechanisms = params.unwrap_or("").split_whitespace();
+                res.auth.extend(mechanisms.map(S::from));
+            } else if keyword.get(..5).map(|k| k.eq_ignore_ascii_case("AUTH=")) == Some(true) {
+                // Obsolete syntax still advertised by some servers
+                let mechanisms = keyword[5..]
+                    .split(',')
+                    .chain(params.unwrap_or("").split_whitespace());
+                res.auth
+                    .extend(mechanisms.filter(|m| !m.is_empty()).map(S::from));
+            } else if is_flag("CHUNKING") {
+                res.chunking = true;
+            } else if is_flag("DSN") {
+                res.dsn = true;
+            } else if is_flag("ENHANCEDSTATUSCODES") {
+                res.enhanced_status_codes = true;
+            } else if is_flag("PIPELINING") {
+                res.pipelining = true;
+            } else if is_flag("REQUIRETLS") {
+                res.requiretls = true;
+            } else if is_flag("SIZE") {
+                res.size = Some(0);
+            } else if let (true, Some(Ok(size))) = (
+                keyword.eq_ignore_ascii_case("SIZE"),
+                params.map(|p| p.parse::<u64>()),
+            ) {
+                res.size = Some(size);
+            } else if is_flag("SMTPUTF8") {
+                res.smtputf8 = true;
+            } else if is_flag("STARTTLS") {
+                res.starttls = true;
+            } else {
+                res.other.push((S::from(keyword), params.map(S::from)));
+            }
+        }
+        res
+    }
+
+    pub fn with_eightbitmime(mut self) -> Capabilities<S> {
+        self.eightbitmime = true;
+        self
+    }
+
+    pub fn with_auth(mut self, mechanism: S) -> Capabilities<S> {
+        self.auth.push(mechanism);
+        self
+    }
+
+    pub fn with_chunking(mut self) -> Capabilities<S> {
+        self.chunking = true;
+        self
+    }
+
+    pub fn with_dsn(mut self) -> Capabilities<S> {
+        self.dsn = true;
+        self
+    }
+
+    pub fn with_enhanced_status_codes(mut self) -> Capabilities<S> {
+        self.enhanced_status_codes = true;
+        self
+    }
+
+    pub fn with_pipelining(mut self) -> Capabilities<S> {
+        self.pipelining = true;
+        self
+    }
+
+    pub fn with_requiretls(mut self) -> Capabilities<S> {
+        self.requiretls = true;
+        self
+    }
+
+    pub fn with_size(mut self, size: u64) -> Capabilities<S> {
+        self.size = Some(size);
+        self
+    }
+
+    pub fn with_smtputf8(mut self) -> Capabilities<S> {
+        self.smtputf8 = true;
+        self
+    }
+
+    pub fn with_starttls(mut self) -> Capabilities<S> {
+        self.starttls = true;
+        self
+    }
+
+    pub fn with_other(mut self, keyword: S, params: Option<S>) -> Capabilities<S> {
+        self.other.push((keyword, params));
+        self
+    }
+}
+
+impl<S> Capabilities<S>
+where
+    S: AsRef<str>,
+{
+    /// Returns whether the extension `keyword` is advertised, `keyword`
+    /// being matched case-insensitively
+    pub fn has(&self, keyword: &str) -> bool {
+        self.lines().any(|l| {
+            let k = l.split(' ').next().unwrap();
+            k.eq_ignore_ascii_case(keyword)
+        })
+    }
+
+    fn lines(&self) -> impl '_ + Iterator<Item = String> {
+        let flag = |set: bool, keyword: &str| {
+            if set {
+                Some(keyword.to_owned())
+            } else {
+                None
+            }
+        };
+        let auth = if self.auth.is_empty() {
+            None
+        } else {
+            let mechanisms = self.auth.iter().map(|m| m.as_ref()).collect::<Vec<_>>();
+            Some(format!("AUTH {}", mechanisms.join(" ")))
+        };
+        let size = self.size.map(|s| match s {
+            0 => "SIZE".to_owned(),
+            s => format!("SIZE {}", s),
+        });
+        let known = vec![
+            flag(self.eightbitmime, "8BITMIME"),
+            auth,
+            flag(self.chunking, "CHUNKING"),
+            flag(self.dsn, "DSN"),
+            flag(self.enhanced_status_codes, "ENHANCEDSTATUSCODES"),
+            flag(self.pipelining, "PIPELINING"),
+            flag(self.requiretls, "REQUIRETLS"),
+            size,
+            flag(self.smtputf8, "SMTPUTF8"),
+            flag(self.starttls, "STARTTLS"),
+        ];
+        known
+            .into_iter()
+            .flatten()
+            .chain(self.other.iter().map(|(keyword, params)| match params {
+                None => keyword.as_ref().to_owned(),
+                Some(p) => format!("{} {}", keyword.as_ref(), p.as_ref()),
+            }))
+    }
+
+    /// Builds the reply to an `EHLO` command advertising these extensions,
+    /// after the `greeting` line
+    pub fn to_reply<T>(&self, greeting: MaybeUtf8<T>) -> Reply<T>
+    where
+        T: From<String>,
+    {
+        let mut text = vec![greeting];
+        text.extend(self.lines().map(|l| MaybeUtf8::Ascii(T::from(l))));
+        Reply {
+            code: ReplyCode::OKAY,
+            ecode: None,
+            text,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capabilities_parse() {
+        let tests: &[(&[u8], Capabilities<&str>)] = &[
+            (b"250 mx.example.org Hello\r\n", Capabilities::new()),
+            (
+                b"250-mx.example.org greets client.example.com\r\n\
+                  250-8bitmime\r\n\
+                  250-SIZE 35882577\r\n\
+                  250-AUTH PLAIN LOGIN\r\n\
+                  250-AUTH=CRAM-MD5\r\n\
+                  250-STARTTLS\r\n\
+                  250-PIPELINING\r\n\
+                  250-CHUNKING\r\n\
+                  250-DSN\r\n\
+                  250-ENHANCEDSTATUSCODES\r\n\
+                  250-REQUIRETLS\r\n\
+                  250-SMTPUTF8\r\n\
+                  250-XCLIENT NAME ADDR\r\n\
+                  250 ETRN\r\n",
+                Capabilities::new()
+                    .with_eightbitmime()
+                    .with_size(35882577)
+                    .with_auth("PLAIN")
+                    .with_auth("LOGIN")
+                    .with_auth("CRAM-MD5")
+                    .with_starttls()
+                    .with_pipelining()
+                    .with_chunking()
+                    .with_dsn()
+                    .with_enhanced_status_codes()
+                    .with_requiretls()
+                    .with_smtputf8()
+                    .with_other("XCLIENT", Some("NAME ADDR"))
+                    .with_other("ETRN", None),
+            ),
+            (
+                b"250-mx.example.org\r\n250-SIZE\r\n250 SIZE nope\r\n",
+                Capabilities::new()
+                    .with_size(0)
+                    .with_other("SIZE", Some("nope")),
+            ),
+            (
+                b"250-mx.example.org\r\n250-STARTTLS now\r\n250 PIPELINING\r\n",
+                Capabilities::new()
+                    .with_other("STARTTLS", Some("now"))
+                    .with_pipelining(),
+            ),
+        ];
+        for (inp, out) in tests {
+            println!("Test: {:?}", show_bytes(inp));
+            let (rem, reply) = Reply::<&str>::parse(inp).unwrap();
+            assert_eq!(rem, b"");
+            assert_eq!(Capabilities::from_reply(&reply), *out);
+        }
+    }
+
+    #[test]
+    fn capabilities_build() {
+        let tests: &[(Capabilities<&str>, &[u8])] = &[
+            (Capabilities::new(), b"250 mx.example.org\r\n"),
+            (
+                Capabilities::new()
+                    .with_starttls()
+                    .with_smtputf8()
+                    .with_pipelining()
+                    .with_size(0)
+                    .with_auth("PLAIN")
+                    .with_auth("LOGIN")
+                    .with_other("XCLIENT", Some("NAME ADDR"))
+                    .with_eightbitmime(),
+                b"250-mx.example.org\r\n\
+                  250-8BITMIME\r\n\
+                  250-AUTH PLAIN LOGIN\r\n\
+                  250-PIPELINING\r\n\
+                  250-SIZE\r\n\
+                  250-SMTPUTF8\r\n\
+                  250-STARTTLS\r\n\
+                  250 XCLIENT NAME ADDR\r\n",
+            ),
+            (
+                Capabilities::new()
+                    .with_size(1000)
+                    .with_dsn()
+                    .with_chunking()
+                    .with_requiretls()
+                    .with_enhanced_status_codes(),
+                b"250-mx.example.org\r\n\
+                  250-CHUNKING\r\n\
+                  250-DSN\r\n\
+                  250-ENHANCEDSTATUSCODES\r\n\
+                  250-REQUIRETLS\r\n\
+                  250 SIZE 1000\r\n",
+            ),
+        ];
+        for (inp, out) in tests {
+            println!("Test: {:?}", inp);
+            let reply = inp.to_reply(MaybeUtf8::Ascii(String::from("mx.example.org")));
+            let mut res = Vec::new();
+            for s in reply.as_io_slices() {
+                res.extend_from_slice(&s);
+            }
+            println!("Result  : {:?}", show_bytes(&res));
+            println!("Expected: {:?}", show_bytes(out));
+            assert_eq!(&res, out);
+            let (_, reply) = Reply::<&str>::parse(out).unwrap();
+            assert_eq!(Capabilities::from_reply(&reply), *inp);
+        }
+    }
+
+    #[test]
+    fn capabilities_has() {
+        let caps = Capabilities::<&str>::new()
+            .with_pipelining()
+            .with_size(10)
+            .with_other("X-FOO", None);
+        assert!(caps.has("pipelining"));
+        assert!(caps.has("SIZE"));
+        assert!(caps.has("x-foo"));
+        assert!(!caps.has("STARTTLS"));
+        assert!(!caps.has("X"));
+    }
+}
@@ -4,7 +4,7 @@ use auto_enums::auto_enum;
 use lazy_static::lazy_static;
 use nom::{
     branch::alt,
-    bytes::streaming::{is_a, tag, tag_no_case, take_until},
+    bytes::streaming::{is_a, is_not, tag, tag_no_case, take_until},
     character::streaming::one_of,
     combinator::{map, map_res, opt, value},
     multi::{many0, many1_count},
@@ -121,6 +121,18 @@ impl<S> Parameters<S> {
             |v| Parameters(v),
         )
     }
+
+    #[inline]
+    pub fn iter(&self) -> impl Iterator<Item = &(ParameterName<S>, Option<MaybeUtf8<S>>)> {
+        self.0.iter()
+    }
+}
+
+impl<S> From<Vec<(ParameterName<S>, Option<MaybeUtf8<S>>)>> for Parameters<S> {
+    #[inline]
+    fn from(v: Vec<(ParameterName<S>, Option<MaybeUtf8<S>>)>) -> Parameters<S> {
+        Parameters(v)
+    }
 }
 
 impl<S> Parameters<S>
@@ -146,6 +158,14 @@ where
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Command<S> {
+    /// AUTH <mechanism> [SP <initial-response>] <CRLF>
+    ///
+    /// Starts an authentication exchange (RFC 4954)
+    Auth {
+        mechanism: MaybeUtf8<S>,
+        initial_response: Option<MaybeUtf8<S>>,
+    },
+
     /// DATA <CRLF>
     Data,
 
@@ -161,6 +181,11 @@ pub enum Command<S> {
     /// HELP [<subject>] <CRLF>
     Help { subject: MaybeUtf8<S> },
 
+    /// LHLO <hostname> <CRLF>
+    ///
+    /// The LMTP replacement for EHLO (RFC 2033)
+    Lhlo { hostname: Hostname<S> },
+
     /// MAIL FROM:<@ONE,@TWO:JOE@THREE> [SP <mail-parameters>] <CRLF>
     Mail {
         path: Option<Path<S>>,
@@ -184,8 +209,25 @@ pub enum Command<S> {
     /// RSET <CRLF>
     Rset,
 
+    /// STARTTLS <CRLF>
+    ///
+    /// Upgrades the connection to TLS (RFC 3207)
+    Starttls,
+
     /// VRFY <name> <CRLF>
     Vrfy { name: MaybeUtf8<S> },
+
+    /// XCLIENT <attribute=value> [SP <attribute=value>]... <CRLF>
+    ///
+    /// Postfix extension, for a proxy to give the attributes of its client.
+    /// The values are xtext-encoded.
+    Xclient { attributes: Parameters<S> },
+
+    /// XFORWARD <attribute=value> [SP <attribute=value>]... <CRLF>
+    ///
+    /// Postfix extension, for a content filter to give the attributes of
+    /// the client that sent the mail. The values are xtext-encoded.
+    Xforward { attributes: Parameters<S> },
 }
 
 impl<S> Command<S> {
@@ -194,6 +236,27 @@ impl<S> Command<S> {
         S: From<&'a str>,
     {
         alt((
+            map_res(
+                tuple((
+                    tag_no_case(b"AUTH"),
+                    is_a(" \t"),
+                    is_not(" \t\r"),
+                    opt(preceded(is_a(" \t"), is_not(" \t\r"))),
+                    opt(is_a(" \t")),
+                    tag(b"\r\n"),
+                )),
+                |(_, _, mechanism, initial_response, _, _)| {
+                    let mechanism = MaybeUtf8::from(str::from_utf8(mechanism)?);
+                    let initial_response = match initial_response {
+                        None => None,
+                        Some(r) => Some(MaybeUtf8::from(str::from_utf8(r)?)),
+                    };
+                    Ok::<_, str::Utf8Error>(Command::Auth {
+                        mechanism,
+                        initial_response,
+                    })
+                },
+            ),
             map(
                 tuple((tag_no_case(b"DATA"), opt(is_a(" \t")), tag(b"\r\n"))),
                 |_| Command::Data,
@@ -245,6 +308,16 @@ impl<S> Command<S> {
                     })
                 },
             ),
+            map(
+                tuple((
+                    tag_no_case(b"LHLO"),
+                    is_a(" \t"),
+                    Hostname::parse_until(b" \t\r"),
+                    opt(is_a(" \t")),
+                    tag(b"\r\n"),
+                )),
+                |(_, _, hostname, _, _)| Command::Lhlo { hostname },
+            ),
             map(
                 tuple((
                     tag_no_case(b"MAIL FROM:"),
@@ -310,6 +383,10 @@ impl<S> Command<S> {
                 tuple((tag_no_case(b"RSET"), opt(is_a(" \t")), tag(b"\r\n"))),
                 |_| Command::Rset,
             ),
+            map(
+                tuple((tag_no_case(b"STARTTLS"), opt(is_a(" \t")), tag(b"\r\n"))),
+                |_| Command::Starttls,
+            ),
             map_res(
                 tuple((
                     tag_no_case(b"VRFY"),
@@ -323,6 +400,24 @@ impl<S> Command<S> {
                     })
                 },
             ),
+            map(
+                tuple((
+                    tag_no_case(b"XCLIENT"),
+                    Parameters::parse_until(b" \t\r"),
+                    opt(is_a(" \t")),
+                    tag(b"\r\n"),
+                )),
+                |(_, attributes, _, _)| Command::Xclient { attributes },
+            ),
+            map(
+                tuple((
+                    tag_no_case(b"XFORWARD"),
+                    Parameters::parse_until(b" \t\r"),
+                    opt(is_a(" \t")),
+                    tag(b"\r\n"),
+                )),
+                |(_, attributes, _, _)| Command::Xforward { attributes },
+            ),
         ))(buf)
     }
 }
@@ -334,6 +429,20 @@ where
     #[auto_enum(Iterator)]
     pub fn as_io_slices(&self) -> impl Iterator<Item = IoSlice> {
         match self {
+            Command::Auth {
+                mechanism,
+                initial_response,
+            } => iter::once(IoSlice::new(b"AUTH "))
+                .chain(mechanism.as_io_slices())
+                .chain(
+                    #[auto_enum(Iterator)]
+                    match initial_response {
+                        None => iter::empty(),
+                        Some(r) => iter::once(IoSlice::new(b" ")).chain(r.as_io_slices()),
+                    },
+                )
+                .chain(iter::once(IoSlice::new(b"\r\n"))),
+
             Command::Data => iter::once(IoSlice::new(b"DATA\r\n")),
 
             Command::Ehlo { hostname } => iter::once(IoSlice::new(b"EHLO "))
@@ -352,6 +461,10 @@ where
                 .chain(subject.as_io_slices())
                 .chain(iter::once(IoSlice::new(b"\r\n"))),
 
+            Command::Lhlo { hostname } => iter::once(IoSlice::new(b"LHLO "))
+                .chain(hostname.as_io_slices())
+                .chain(iter::once(IoSlice::new(b"\r\n"))),
+
             Command::Mail {
                 path,
                 email,
@@ -400,9 +513,19 @@ where
 
             Command::Rset => iter::once(IoSlice::new(b"RSET\r\n")),
 
+            Command::Starttls => iter::once(IoSlice::new(b"STARTTLS\r\n")),
+
             Command::Vrfy { name } => iter::once(IoSlice::new(b"VRFY "))
                 .chain(name.as_io_slices())
                 .chain(iter::once(IoSlice::new(b"\r\n"))),
+
+            Command::Xclient { attributes } => iter::once(IoSlice::new(b"XCLIENT"))
+                .chain(attributes.as_io_slices())
+                .chain(iter::once(IoSlice::new(b"\r\n"))),
+
+            Command::Xforward { attributes } => iter::once(IoSlice::new(b"XFORWARD"))
+                .chain(attributes.as_io_slices())
+                .chain(iter::once(IoSlice::new(b"\r\n"))),
         }
     }
 }
@@ -482,6 +605,14 @@ mod tests {
     #[test]
     fn command_valid() {
         let tests: &[(&[u8], Command<&str>)] = &[
+            (b"AUTH PLAIN\r\n", Command::Auth {
+                mechanism: MaybeUtf8::Ascii("PLAIN"),
+                initial_response: None,
+            }),
+            (b"auth \tplain dGVzdAB0ZXN0ADEyMzQ= \r\n", Command::Auth {
+                mechanism: MaybeUtf8::Ascii("plain"),
+                initial_response: Some(MaybeUtf8::Ascii("dGVzdAB0ZXN0ADEyMzQ=")),
+            }),
             (b"DATA \t  \t \r\n", Command::Data),
             (b"daTa\r\n", Command::Data),
             (b"eHlO \t hello.world \t \r\n", Command::Ehlo {
@@ -508,6 +639,9 @@ mod tests {
             (b"hElP \r\n", Command::Help {
                 subject: MaybeUtf8::Ascii(""),
             }),
+            (b"LHLO hello.world\r\n", Command::Lhlo {
+                hostname: Hostname::AsciiDomain { raw: "hello.world" },
+            }),
             (b"Mail FROM:<@one,@two:foo@bar.baz>\r\n", Command::Mail {
                 path: Some(Path {
                     domains: vec![
@@ -603,9 +737,36 @@ mod tests {
             }),
             (b"RSET \t  \t \r\n", Command::Rset),
             (b"rSet\r\n", Command::Rset),
+            (b"STARTTLS\r\n", Command::Starttls),
+            (b"starttls \r\n", Command::Starttls),
             (b"VrFY \t hello.world \t \r\n", Command::Vrfy {
                 name: MaybeUtf8::Ascii("\t hello.world \t "),
             }),
+            (
+                b"XCLIENT ADDR=IPV6:2001:db8::1 LOGIN=[UNAVAILABLE] HELO=foo+2Bbar\r\n",
+                Command::Xclient {
+                    attributes: Parameters(vec![
+                        (
+                            ParameterName::Other("ADDR"),
+                            Some(MaybeUtf8::Ascii("IPV6:2001:db8::1")),
+                        ),
+                        (
+                            ParameterName::Other("LOGIN"),
+                            Some(MaybeUtf8::Ascii("[UNAVAILABLE]")),
+                        ),
+                        (
+                            ParameterName::Other("HELO"),
+                            Some(MaybeUtf8::Ascii("foo+2Bbar")),
+                        ),
+                    ]),
+                },
+            ),
+            (b"xforward\tname=mx.example.org \r\n", Command::Xforward {
+                attributes: Parameters(vec![(
+                    ParameterName::Other("name"),
+                    Some(MaybeUtf8::Ascii("mx.example.org")),
+                )]),
+            }),
         ];
         for (inp, out) in tests {
             println!("Test: {:?}", show_bytes(inp));
@@ -634,7 +795,12 @@ mod tests {
 
     #[test]
     fn command_invalid() {
-        let tests: &[&[u8]] = &[b"HELPfoo"];
+        let tests: &[&[u8]] = &[
+            b"HELPfoo",
+            b"XCLIENTADDR=192.0.2.1\r\n",
+            b"AUTH\r\n",
+            b"STARTTLSfoo\r\n",
+        ];
         for inp in tests {
             let r = Command::<&str>::parse(inp);
             println!("{:?}:  {:?}", show_bytes(inp), r);
@@ -645,6 +811,20 @@ mod tests {
     #[test]
     fn command_build() {
         let tests: &[(Command<&str>, &[u8])] = &[
+            (
+                Command::Auth {
+                    mechanism: MaybeUtf8::Ascii("PLAIN"),
+                    initial_response: Some(MaybeUtf8::Ascii("AHVzZXIAcGFzcw==")),
+                },
+                b"AUTH PLAIN AHVzZXIAcGFzcw==\r\n",
+            ),
+            (
+                Command::Auth {
+                    mechanism: MaybeUtf8::Ascii("LOGIN"),
+                    initial_response: None,
+                },
+                b"AUTH LOGIN\r\n",
+            ),
             (Command::Data, b"DATA\r\n"),
             (
                 Command::Ehlo {
@@ -674,6 +854,14 @@ mod tests {
                 },
                 b"HELP topic\r\n",
             ),
+            (
+                Command::Lhlo {
+                    hostname: Hostname::AsciiDomain {
+                        raw: "test.foo.bar",
+                    },
+                },
+                b"LHLO test.foo.bar\r\n",
+            ),
             (
                 Command::Mail {
                     path: None,
@@ -759,18 +947,40 @@ mod tests {
                 b"RCPT TO:<Postmaster>\r\n",
             ),
             (Command::Rset, b"RSET\r\n"),
+            (Command::Starttls, b"STARTTLS\r\n"),
             (
                 Command::Vrfy {
                     name: MaybeUtf8::Ascii("postmaster"),
                 },
                 b"VRFY postmaster\r\n",
             ),
+            (
+                Command::Xclient {
+                    attributes: Parameters(vec![
+                        (
+                            ParameterName::Other("ADDR"),
+                            Some(MaybeUtf8::Ascii("192.0.2.1")),
+                        ),
+                        (ParameterName::Other("PORT"), Some(MaybeUtf8::Ascii("25"))),
+                    ]),
+                },
+                b"XCLIENT ADDR=192.0.2.1 PORT=25\r\n",
+            ),
+            (
+                Command::Xforward {
+                    attributes: Parameters(vec![(
+                        ParameterName::Other("IDENT"),
+                        Some(MaybeUtf8::Ascii("abcd")),
+                    )]),
+                },
+                b"XFORWARD IDENT=abcd\r\n",
+            ),
         ];
         for (inp, out) in tests {
             println!("Test: {:?}", inp);
             let res = inp
                 .as_io_slices()
-                .flat_map(|s| s.to_owned().into_iter())
+                .flat_map(|s| s.to_vec().into_iter())
                 .collect::<Vec<u8>>();
             println!("Result  : {:?}", show_bytes(&res));
             println!("Expected: {:?}", show_bytes(out));
@@ -39,6 +39,10 @@ pub struct EscapedDataReader<'a, R> {
 
     state: EscapedDataReaderState,
 
+    // Data returned before the stream, and how much of it was already read
+    prefix: Vec<u8>,
+    prefix_read: usize,
+
     #[pin]
     read: R,
 }
@@ -53,10 +57,23 @@ where
             buf,
             unhandled,
             state: EscapedDataReaderState::CrLf,
+            prefix: Vec::new(),
+            prefix_read: 0,
             read,
         }
     }
 
+    /// Makes this reader return `prefix` before the contents of the stream,
+    /// eg. to prepend trace headers to the mail
+    ///
+    /// `prefix` must be escaped like the rest of the stream and end with a
+    /// CRLF, as it is returned as-is.
+    #[inline]
+    pub fn with_prefix(mut self, prefix: Vec<u8>) -> Self {
+        self.prefix = prefix;
+        self
+    }
+
     /// Returns `true` iff the message has been successfully streamed
     /// to completion
     #[inline]
@@ -116,6 +133,19 @@ where
 
         let this = self.project();
 
+        // Return the prefix before anything else, it cannot contain the end
+        if *this.prefix_read < this.prefix.len() {
+            let mut size = 0;
+            for buf in bufs.iter_mut() {
+                let prefix = &this.prefix[*this.prefix_read..];
+                let copy_len = cmp::min(buf.len(), prefix.len());
+                buf[..copy_len].copy_from_slice(&prefix[..copy_len]);
+                *this.prefix_read += copy_len;
+                size += copy_len;
+            }
+            return Poll::Ready(Ok(size));
+        }
+
         // First, fill the bufs with incoming data
         let raw_size = {
             let unhandled_len_start = this.unhandled.end - this.unhandled.start;
@@ -339,6 +369,94 @@ impl DataUnescaper {
     }
 }
 
+/// Size of the buffer an [`UnescapedDataReader`](UnescapedDataReader) reads
+/// into, that is below the buffer size of an
+/// [`EscapedDataReader`](EscapedDataReader) used by `smtp-server`
+const UNESCAPE_BUF_SIZE: usize = 4096;
+
+/// `AsyncRead` instance that unescapes the `DATA` stream returned by an
+/// [`EscapedDataReader`](EscapedDataReader), returning the actual contents of
+/// the message.
+///
+/// The stream read from is assumed to end with the end-of-data marker, which
+/// `EscapedDataReader` makes sure of.
+#[pin_project]
+pub struct UnescapedDataReader<R> {
+    unescaper: DataUnescaper,
+
+    buf: Box<[u8]>,
+
+    // Unescaped data that has not been returned yet
+    ready: Range<usize>,
+
+    // Data that could not be unescaped yet, for lack of what follows it
+    pending: Range<usize>,
+
+    #[pin]
+    read: R,
+}
+
+impl<R> UnescapedDataReader<R>
+where
+    R: AsyncRead,
+{
+    #[inline]
+    pub fn new(read: R) -> Self {
+        UnescapedDataReader {
+            unescaper: DataUnescaper::new(true),
+            buf: vec![0; UNESCAPE_BUF_SIZE].into_boxed_slice(),
+            ready: 0..0,
+            pending: 0..0,
+            read,
+        }
+    }
+}
+
+impl<R> AsyncRead for UnescapedDataReader<R>
+where
+    R: AsyncRead,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut this = self.project();
+        loop {
+            if this.ready.start != this.ready.end {
+                let copy_len = cmp::min(buf.len(), this.ready.end - this.ready.start);
+                let next_start = this.ready.start + copy_len;
+                buf[..copy_len].copy_from_slice(&this.buf[this.ready.start..next_start]);
+                this.ready.start = next_start;
+                return Poll::Ready(Ok(copy_len));
+            }
+
+            let start = this.pending.end - this.pending.start;
+            this.buf.copy_within(this.pending.clone(), 0);
+            let read = match this.read.as_mut().poll_read(cx, &mut this.buf[start..]) {
+                Poll::Ready(Ok(s)) => s,
+                Poll::Ready(Err(e)) => {
+                    *this.pending = 0..start;
+                    return Poll::Ready(Err(e));
+                }
+                Poll::Pending => {
+                    *this.pending = 0..start;
+                    return Poll::Pending;
+                }
+            };
+            if read == 0 {
+                // What is still pending can only be (part of) the end-of-data
+                // marker of an empty message
+                *this.pending = 0..0;
+                return Poll::Ready(Ok(0));
+            }
+            let res = this.unescaper.unescape(&mut this.buf[..start + read]);
+            *this.ready = 0..res.written;
+            *this.pending = res.unhandled_idx..start + read;
+        }
+    }
+}
+
 #[derive(Clone, Copy)]
 enum EscapingDataWriterState {
     Start,
@@ -573,6 +691,42 @@ mod tests {
         }
     }
 
+    #[test]
+    fn escaped_data_reader_prefix() {
+        let tests: &[(&[u8], &[u8], &[u8])] = &[
+            (b"Foo: bar\r\n", b"baz\r\n.\r\n", b""),
+            (
+                b"Received: by example.org\r\n",
+                b".\r\nQUIT\r\n",
+                b"QUIT\r\n",
+            ),
+        ];
+        for &(prefix, inp, rem) in tests {
+            println!("Test: {:?}", (show_bytes(prefix), show_bytes(inp)));
+            let mut surrounding_buf: [u8; 16] = [0; 16];
+            let mut enclosed_buf: [u8; 8] = [0; 8];
+            surrounding_buf[..inp.len()].copy_from_slice(inp);
+            let mut data_reader =
+                EscapedDataReader::new(&mut surrounding_buf, 0..inp.len(), futures::io::empty())
+                    .with_prefix(prefix.to_vec());
+
+            let mut res_out = Vec::<u8>::new();
+            loop {
+                let r = executor::block_on(data_reader.read(&mut enclosed_buf)).unwrap();
+                if r == 0 {
+                    break;
+                }
+                res_out.extend_from_slice(&enclosed_buf[..r]);
+            }
+            data_reader.complete();
+            let out = [prefix, &inp[..inp.len() - rem.len()]].concat();
+            assert_eq!(show_bytes(&res_out), show_bytes(&out));
+
+            let unhandled = data_reader.get_unhandled().unwrap();
+            assert_eq!(&surrounding_buf[unhandled], rem);
+        }
+    }
+
     #[test]
     fn data_unescaper() {
         let tests: &[(&[&[u8]], &[u8])] = &[
@@ -611,6 +765,39 @@ mod tests {
         }
     }
 
+    #[test]
+    fn unescaped_data_reader() {
+        let tests: &[(&[&[u8]], &[u8])] = &[
+            (&[b"foo", b" bar", b"\r\n", b".\r", b"\n"], b"foo bar\r\n"),
+            (&[b".\r\n"], b""),
+            (&[b".", b"\r", b"\n"], b""),
+            (&[b"..\r\n.\r\n"], b".\r\n"),
+            (&[b"foo\r\n.", b". bar\r\n.", b"\r\n"], b"foo\r\n. bar\r\n"),
+            (&[b"\r\r\n.\r\n"], b"\r\r\n"),
+        ];
+        let mut surrounding_buf: [u8; 16] = [0; 16];
+        let mut out_buf: [u8; 3] = [0; 3];
+        for &(inp, out) in tests {
+            println!("Expected result: {:?}", show_bytes(out));
+            let reader = inp.iter().map(Cursor::new).fold(
+                Box::pin(futures::io::empty()) as Pin<Box<dyn 'static + AsyncRead>>,
+                |a, b| Box::pin(AsyncReadExt::chain(a, b)),
+            );
+            let mut data_reader = EscapedDataReader::new(&mut surrounding_buf, 0..0, reader);
+            let mut unescaped = UnescapedDataReader::new(&mut data_reader);
+            let mut res = Vec::new();
+            loop {
+                let r = executor::block_on(unescaped.read(&mut out_buf)).unwrap();
+                if r == 0 {
+                    break;
+                }
+                res.extend_from_slice(&out_buf[..r]);
+            }
+            data_reader.complete();
+            assert_eq!(&res[..], out);
+        }
+    }
+
     #[test]
     fn escaping_data_writer() {
         let tests: &[(&[&[&[u8]]], &[u8])] = &[
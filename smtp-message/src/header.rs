@@ -0,0 +1,488 @@
+use std::{borrow::Cow, io, ops::Range, str};
+
+use futures::{AsyncRead, AsyncReadExt};
+
+/// Size of the chunks [`read_headers`](read_headers) reads
+const READ_BUF_SIZE: usize = 4096;
+
+#[inline]
+pub(crate) fn is_wsp(b: u8) -> bool {
+    b == b' ' || b == b'\t'
+}
+
+/// A header field of a message (RFC 5322 section 2.2), borrowed from its
+/// header section
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct HeaderField<'a> {
+    /// The field name, as it appears in the message
+    pub name: &'a str,
+
+    /// The field body, from after the colon to before the final CRLF, still
+    /// folded
+    pub raw_value: &'a [u8],
+}
+
+impl<'a> HeaderField<'a> {
+    /// Returns `true` iff the field is named `name`, which is case-insensitive
+    #[inline]
+    pub fn is(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name)
+    }
+
+    /// Returns the field body with the folding CRLFs removed (RFC 5322
+    /// section 2.2.3)
+    pub fn unfolded(&self) -> Cow<'a, [u8]> {
+        if !self.raw_value.contains(&b'\n') {
+            return Cow::Borrowed(self.raw_value);
+        }
+        let mut res = Vec::with_capacity(self.raw_value.len());
+        let mut rest = self.raw_value;
+        while let Some(i) = rest.windows(2).position(|w| w == b"\r\n") {
+            res.extend_from_slice(&rest[..i]);
+            rest = &rest[i + 2..];
+        }
+        res.extend_from_slice(rest);
+        Cow::Owned(res)
+    }
+
+    /// Returns the field body as text: unfolded, without surrounding
+    /// whitespace and with its encoded words decoded
+    ///
+    /// This is meant for unstructured fields like `Subject:`, but also gives
+    /// a readable version of structured ones like `From:`.
+    pub fn text(&self) -> String {
+        let unfolded = self.unfolded();
+        let start = unfolded.iter().position(|&b| !is_wsp(b));
+        let end = unfolded.iter().rposition(|&b| !is_wsp(b));
+        match (start, end) {
+            (Some(start), Some(end)) => decode_encoded_words(&unfolded[start..=end]),
+            _ => String::new(),
+        }
+    }
+}
+
+/// The header section of a message, that gives access to its fields without
+/// copying them
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Headers {
+    raw: Vec<u8>,
+
+    // The ranges of the name and body of each field in `raw`
+    fields: Vec<(Range<usize>, Range<usize>)>,
+}
+
+impl Headers {
+    /// Parses `raw`, the header section of a message along with the empty
+    /// line that ends it, if any
+    ///
+    /// Lines that are neither a field nor the continuation of one are
+    /// ignored, along with their continuation lines. They are still part of
+    /// the raw header section.
+    pub fn parse(raw: Vec<u8>) -> Headers {
+        let mut fields: Vec<(Range<usize>, Range<usize>)> = Vec::new();
+        let mut in_field = false;
+        let mut pos = 0;
+        while pos < raw.len() {
+            let (content_end, line_end) = match raw[pos..].windows(2).position(|w| w == b"\r\n") {
+                Some(i) => (pos + i, pos + i + 2),
+                None => (raw.len(), raw.len()),
+            };
+            let line = &raw[pos..content_end];
+            if line.is_empty() && line_end != content_end {
+                // The empty line ending the header section
+                break;
+            }
+            if is_wsp(line[0]) {
+                if in_field {
+                    fields.last_mut().unwrap().1.end = content_end;
+                }
+            } else {
+                let colon = line.iter().position(|&b| b == b':');
+                let name_end = colon.map(|c| {
+                    line[..c]
+                        .iter()
+                        .rposition(|&b| !is_wsp(b))
+                        .map(|i| i + 1)
+                        .unwrap_or(0)
+                });
+                in_field = match (colon, name_end) {
+                    (Some(colon), Some(name_end))
+                        if name_end > 0 && line[..name_end].iter().all(|&b| b > 32 && b < 127) =>
+                    {
+                        fields.push((pos..pos + name_end, pos + colon + 1..content_end));
+                        true
+                    }
+                    _ => false,
+                };
+            }
+            pos = line_end;
+        }
+        Headers { raw, fields }
+    }
+
+    /// Returns the raw header section, as it was parsed
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.raw
+    }
+
+    /// Returns the offset of the body in the message, which is the size of
+    /// the header section
+    #[inline]
+    pub fn body_offset(&self) -> usize {
+        self.raw.len()
+    }
+
+    /// Returns the fields, in the order they appear in the message
+    pub fn iter(&self) -> impl '_ + Iterator<Item = HeaderField<'_>> {
+        self.fields.iter().map(move |(name, value)| HeaderField {
+            // The name is made only of printable ASCII characters
+            name: str::from_utf8(&self.raw[name.clone()]).unwrap(),
+            raw_value: &self.raw[value.clone()],
+        })
+    }
+
+    /// Returns the first field named `name`, if any
+    pub fn get(&self, name: &str) -> Option<HeaderField<'_>> {
+        self.iter().find(|f| f.is(name))
+    }
+
+    /// Returns all the fields named `name`, in the order they appear in the
+    /// message
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl 'a + Iterator<Item = HeaderField<'a>> {
+        self.iter().filter(move |f| f.is(name))
+    }
+}
+
+/// Incremental parser for the header section of a message
+///
+/// It is fed the unescaped contents of the message chunk by chunk, until it
+/// finds the end of the header section.
+pub struct HeaderParser {
+    buf: Vec<u8>,
+    max_size: usize,
+}
+
+impl HeaderParser {
+    /// Creates a `HeaderParser` that rejects header sections longer than
+    /// `max_size`
+    pub fn new(max_size: usize) -> HeaderParser {
+        HeaderParser {
+            buf: Vec::new(),
+            max_size,
+        }
+    }
+
+    /// Feeds the next chunk of the message to the parser
+    ///
+    /// Once the end of the header section is reached, this returns the
+    /// headers along with the index in `data` at which the body starts.
+    /// Otherwise, more data is needed, or [`finish`](HeaderParser::finish)
+    /// is to be called if the message has ended.
+    pub fn feed(&mut self, data: &[u8]) -> io::Result<Option<(Headers, usize)>> {
+        let old_len = self.buf.len();
+        self.buf.extend_from_slice(data);
+        let end = if self.buf.starts_with(b"\r\n") {
+            Some(2)
+        } else {
+            let from = old_len.saturating_sub(3);
+            self.buf[from..]
+                .windows(4)
+                .position(|w| w == b"\r\n\r\n")
+                .map(|i| from + i + 4)
+        };
+        match end {
+            Some(end) if end <= self.max_size => {
+                let mut raw = std::mem::take(&mut self.buf);
+                raw.truncate(end);
+                Ok(Some((Headers::parse(raw), end - old_len)))
+            }
+            None if self.buf.len() <= self.max_size => Ok(None),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the header section of the message is too long",
+            )),
+        }
+    }
+
+    /// Returns the headers of a message that ended before the end of its
+    /// header section, ie. without a body
+    pub fn finish(self) -> Headers {
+        Headers::parse(self.buf)
+    }
+}
+
+/// Reads the header section of a message from `reader`, that returns the
+/// unescaped contents of the message, eg. an
+/// [`UnescapedDataReader`](crate::UnescapedDataReader)
+///
+/// This returns the headers along with the beginning of the body that was
+/// read past them, the rest of the body being left to be read from `reader`.
+/// Header sections longer than `max_size` are rejected.
+pub async fn read_headers<R>(reader: &mut R, max_size: usize) -> io::Result<(Headers, Vec<u8>)>
+where
+    R: Unpin + AsyncRead,
+{
+    let mut parser = HeaderParser::new(max_size);
+    let mut buf = vec![0; READ_BUF_SIZE];
+    loop {
+        let read = reader.read(&mut buf).await?;
+        if read == 0 {
+            return Ok((parser.finish(), Vec::new()));
+        }
+        if let Some((headers, body_start)) = parser.feed(&buf[..read])? {
+            buf.truncate(read);
+            buf.drain(..body_start);
+            return Ok((headers, buf));
+        }
+    }
+}
+
+/// Decodes the `Q` encoding of an encoded word (RFC 2047 section 4.2)
+fn decode_q(text: &[u8]) -> Option<Vec<u8>> {
+    let mut res = Vec::with_capacity(text.len());
+    let mut bytes = text.iter();
+    while let Some(&b) = bytes.next() {
+        match b {
+            b'_' => res.push(b' '),
+            b'=' => {
+                let hex = [*bytes.next()?, *bytes.next()?];
+                res.push(u8::from_str_radix(str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            b => res.push(b),
+        }
+    }
+    Some(res)
+}
+
+/// Parses and decodes the encoded word `input` starts with, returning it
+/// along with its length
+///
+/// Encoded words in unknown charsets are not decoded.
+fn encoded_word(input: &[u8]) -> Option<(String, usize)> {
+    let mut parts = input.strip_prefix(b"=?")?.splitn(3, |&b| b == b'?');
+    let charset = parts.next()?;
+    let encoding = parts.next()?;
+    let rest = parts.next()?;
+    let text_len = rest.iter().position(|&b| b == b'?')?;
+    if rest.get(text_len + 1) != Some(&b'=')
+        || rest[..text_len].iter().any(|&b| b <= b' ' || b >= 127)
+    {
+        return None;
+    }
+    let text = &rest[..text_len];
+    let len = 2 + charset.len() + 1 + encoding.len() + 1 + text_len + 2;
+    let decoded = match encoding {
+        b"B" | b"b" => base64::decode(text).ok()?,
+        b"Q" | b"q" => decode_q(text)?,
+        _ => return None,
+    };
+
+    // The charset may be followed by a language (RFC 2231 section 5)
+    let charset = charset.split(|&b| b == b'*').next().unwrap();
+    Some((decode_charset(str::from_utf8(charset).ok()?, decoded)?, len))
+}
+
+/// Converts `bytes` from `charset` to a string, if the charset is known
+///
+/// Only UTF-8, US-ASCII and ISO-8859-1 are supported, invalid UTF-8 being
+/// replaced with U+FFFD.
+pub(crate) fn decode_charset(charset: &str, bytes: Vec<u8>) -> Option<String> {
+    match &charset.to_ascii_lowercase() as &str {
+        "utf-8" | "us-ascii" => Some(String::from_utf8_lossy(&bytes).into_owned()),
+        "iso-8859-1" | "latin1" => Some(bytes.into_iter().map(char::from).collect()),
+        _ => None,
+    }
+}
+
+/// Decodes the encoded words (RFC 2047) of `value`, an unfolded field body
+///
+/// Encoded words in the UTF-8, US-ASCII and ISO-8859-1 charsets are decoded,
+/// the whitespace between adjacent ones being dropped. Anything else is kept
+/// as is, invalid UTF-8 being replaced with U+FFFD.
+pub fn decode_encoded_words(value: &[u8]) -> String {
+    let mut res = String::with_capacity(value.len());
+    let mut rest = value;
+    let mut after_word = false;
+    while let Some(i) = rest.windows(2).position(|w| w == b"=?") {
+        let (before, word) = rest.split_at(i);
+        match encoded_word(word) {
+            Some((decoded, len)) => {
+                if !after_word || !before.iter().all(|&b| is_wsp(b)) {
+                    res.push_str(&String::from_utf8_lossy(before));
+                }
+                res.push_str(&decoded);
+                rest = &word[len..];
+                after_word = true;
+            }
+            None => {
+                res.push_str(&String::from_utf8_lossy(&rest[..i + 2]));
+                rest = &rest[i + 2..];
+                after_word = false;
+            }
+        }
+    }
+    res.push_str(&String::from_utf8_lossy(rest));
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    use std::pin::Pin;
+
+    use futures::{executor, io::Cursor};
+
+    #[test]
+    fn headers_parse() {
+        let raw: &[u8] = b"From: John Doe <jdoe@example.org>\r\n\
+              Subject: Saying\r\n \tHello\r\n\
+              not a field\r\n \r\n\
+              Received : from example.net\r\n\
+              X-Empty:\r\n\
+              : no name\r\n\
+              subject: again\r\n\
+              \r\n";
+        let headers = Headers::parse(raw.to_vec());
+        let fields = headers
+            .iter()
+            .map(|f| (f.name, f.raw_value))
+            .collect::<Vec<_>>();
+        assert_eq!(fields, vec![
+            ("From", &b" John Doe <jdoe@example.org>"[..]),
+            ("Subject", b" Saying\r\n \tHello"),
+            ("Received", b" from example.net"),
+            ("X-Empty", b""),
+            ("subject", b" again"),
+        ]);
+        assert_eq!(headers.as_bytes(), raw);
+        assert_eq!(headers.body_offset(), raw.len());
+        assert_eq!(headers.get("SUBJECT").unwrap().text(), "Saying \tHello");
+        assert_eq!(
+            headers
+                .get_all("Subject")
+                .map(|f| f.text())
+                .collect::<Vec<_>>(),
+            vec!["Saying \tHello", "again"]
+        );
+        assert_eq!(headers.get("Message-ID"), None);
+        assert_eq!(
+            headers.get("subject").unwrap().unfolded(),
+            &b" Saying \tHello"[..]
+        );
+
+        // Header sections without an empty line or a final CRLF
+        let headers = Headers::parse(b"To: a@example.org\r\nCc: b@example.org".to_vec());
+        let fields = headers.iter().map(|f| f.name).collect::<Vec<_>>();
+        assert_eq!(fields, vec!["To", "Cc"]);
+    }
+
+    #[test]
+    fn header_parser() {
+        // The chunks fed to the parser, the header section and the body
+        type Test<'a> = (&'a [&'a [u8]], &'a [u8], &'a [u8]);
+        let tests: &[Test] = &[
+            (
+                &[b"Subject: foo\r\n\r\nbody\r\n"],
+                b"Subject: foo\r\n\r\n",
+                b"body\r\n",
+            ),
+            (
+                &[b"Subject: foo\r", b"\n", b"\r", b"\nbody"],
+                b"Subject: foo\r\n\r\n",
+                b"body",
+            ),
+            (&[b"\r", b"\nbody\r\n"], b"\r\n", b"body\r\n"),
+            (&[b"Subject: foo\r\n"], b"Subject: foo\r\n", b""),
+        ];
+        for &(inp, raw, body) in tests {
+            println!(
+                "Test: {:?}",
+                inp.iter().map(|i| show_bytes(i)).collect::<Vec<_>>()
+            );
+            let mut parser = HeaderParser::new(1024);
+            let mut res = None;
+            for (i, chunk) in inp.iter().enumerate() {
+                if let Some((headers, body_start)) = parser.feed(chunk).unwrap() {
+                    let mut body = chunk[body_start..].to_vec();
+                    body.extend(inp[i + 1..].iter().flat_map(|c| c.iter()));
+                    res = Some((headers, body));
+                    break;
+                }
+            }
+            let (headers, res_body) = res.unwrap_or_else(|| (parser.finish(), Vec::new()));
+            assert_eq!(headers.as_bytes(), raw);
+            assert_eq!(res_body, body);
+        }
+
+        let mut parser = HeaderParser::new(16);
+        assert!(parser.feed(b"Subject: 1234567\r\n").is_err());
+    }
+
+    #[test]
+    fn encoded_words() {
+        let tests: &[(&[u8], &str)] = &[
+            // Examples from RFC 2047 section 8
+            (b"=?ISO-8859-1?Q?a?=", "a"),
+            (b"=?ISO-8859-1?Q?a?= b", "a b"),
+            (b"=?ISO-8859-1?Q?a?= =?ISO-8859-1?Q?b?=", "ab"),
+            (b"=?ISO-8859-1?Q?a?=  =?ISO-8859-1?Q?b?=", "ab"),
+            (b"=?ISO-8859-1?Q?a?=\t =?ISO-8859-1?Q?b?=", "ab"),
+            (b"=?ISO-8859-1?Q?a_b?=", "a b"),
+            (
+                b"=?ISO-8859-1?Q?a?= =?ISO-8859-2?Q?_b?=",
+                "a =?ISO-8859-2?Q?_b?=",
+            ),
+            (
+                b"=?ISO-8859-1?Q?Keld_J=F8rn_Simonsen?= <keld@dkuug.dk>",
+                "Keld J\u{f8}rn Simonsen <keld@dkuug.dk>",
+            ),
+            (
+                b"=?ISO-8859-1?B?SWYgeW91IGNhbiByZWFkIHRoaXMgeW8=?=\
+                  =?ISO-8859-2?B?dSB1bmRlcnN0YW5kIHRoZSBleGFtcGxlLg==?=",
+                "If you can read this yo=?ISO-8859-2?B?dSB1bmRlcnN0YW5kIHRoZSBleGFtcGxlLg==?=",
+            ),
+            (
+                b"=?utf-8?b?w6lsw6h2ZQ==?= =?UTF-8*fr?q?d=C3=A9j=C3=A0?= vu",
+                "\u{e9}l\u{e8}vedéjà vu",
+            ),
+            // Invalid encoded words are kept as is
+            (b"=?utf-8?x?abc?=", "=?utf-8?x?abc?="),
+            (b"=?utf-8?q?a b?=", "=?utf-8?q?a b?="),
+            (b"=?utf-8?q?a=Z?=", "=?utf-8?q?a=Z?="),
+            (b"=?utf-8?q?abc", "=?utf-8?q?abc"),
+            (b"=?=?utf-8?q?a?=", "=?a"),
+            (b"caf\xc3\xa9 \xff", "caf\u{e9} \u{fffd}"),
+        ];
+        for &(inp, out) in tests {
+            println!("Test: {:?}", show_bytes(inp));
+            assert_eq!(decode_encoded_words(inp), out);
+        }
+    }
+
+    #[test]
+    fn read_headers_from_data() {
+        let data: &[u8] = b"Subject: =?utf-8?q?caf=C3=A9?=\r\n\
+               Message-ID: <foo@example.org>\r\n\
+               \r\n\
+               ..hidden\r\n\
+               .\r\n";
+        let reader = Box::pin(Cursor::new(data)) as Pin<Box<dyn AsyncRead>>;
+        let mut buf = [0; 16];
+        let mut data_reader = EscapedDataReader::new(&mut buf, 0..0, reader);
+        let mut unescaped = UnescapedDataReader::new(&mut data_reader);
+        let (headers, mut body) = executor::block_on(read_headers(&mut unescaped, 1024)).unwrap();
+        executor::block_on(unescaped.read_to_end(&mut body)).unwrap();
+        data_reader.complete();
+
+        assert_eq!(headers.get("subject").unwrap().text(), "caf\u{e9}");
+        assert_eq!(
+            headers.get("message-id").unwrap().text(),
+            "<foo@example.org>"
+        );
+        assert_eq!(headers.body_offset(), 65);
+        assert_eq!(body, b".hidden\r\n");
+    }
+}
@@ -2,8 +2,11 @@
 
 pub use nom;
 
+mod capabilities;
 mod command;
 mod data;
+mod header;
+mod mime;
 mod misc;
 mod reply;
 
@@ -12,8 +15,15 @@ mod reply;
 use misc::*;
 // use reply::*;
 
+pub use capabilities::Capabilities;
 pub use command::{Command, ParameterName, Parameters};
-pub use data::{DataUnescapeRes, DataUnescaper, EscapedDataReader, EscapingDataWriter};
+pub use data::{
+    DataUnescapeRes, DataUnescaper, EscapedDataReader, EscapingDataWriter, UnescapedDataReader,
+};
+pub use header::{decode_encoded_words, read_headers, HeaderField, HeaderParser, Headers};
+pub use mime::{
+    read_mime, ContentType, MimeHandler, MimeParser, MimePart, TransferDecoder, TransferEncoding,
+};
 pub use misc::{next_crlf, Email, Hostname, Localpart, MaybeUtf8, NextCrLfState, Path};
 pub use reply::{
     EnhancedReplyCode, EnhancedReplyCodeClass, EnhancedReplyCodeSubject, Reply, ReplyCode,
@@ -39,7 +49,7 @@ pub(crate) fn show_bytes(b: &[u8]) -> String {
 pub mod fuzz {
     use super::*;
 
-    use std::{cmp, io::IoSlice};
+    use std::{cmp, io::IoSlice, iter};
 
     use futures::{
         executor,
@@ -180,6 +190,160 @@ pub mod fuzz {
             assert_eq!(read, expected);
         }
     }
+
+    pub fn header_parsing(data: Vec<u8>, chunks: Vec<usize>) {
+        // Parse the whole message at once
+        let mut parser = HeaderParser::new(usize::MAX);
+        let headers = match parser.feed(&data).unwrap() {
+            Some((headers, body_start)) => {
+                assert_eq!(body_start, headers.body_offset());
+                headers
+            }
+            None => parser.finish(),
+        };
+        assert_eq!(&data[..headers.body_offset()], headers.as_bytes());
+
+        // Then chunk by chunk, which should give the same result
+        let mut parser = HeaderParser::new(usize::MAX);
+        let mut pos = 0;
+        let mut i = 0;
+        let chunked = loop {
+            if pos == data.len() {
+                break parser.finish();
+            }
+            let len = cmp::min(
+                cmp::max(1, chunks.get(i).cloned().unwrap_or(1)),
+                data.len() - pos,
+            );
+            if let Some((headers, body_start)) = parser.feed(&data[pos..pos + len]).unwrap() {
+                assert_eq!(pos + body_start, headers.body_offset());
+                break headers;
+            }
+            pos += len;
+            i += 1;
+        };
+        assert_eq!(chunked, headers);
+
+        for field in headers.iter() {
+            assert!(!field.name.is_empty() && !field.name.contains(':'));
+            assert_eq!(
+                headers.get(field.name).map(|f| f.is(field.name)),
+                Some(true)
+            );
+            field.text();
+        }
+    }
+
+    /// Splits `data` in chunks of the lengths in `chunks`, repeated as needed
+    fn chunked<'a>(data: &'a [u8], chunks: &'a [usize]) -> impl Iterator<Item = &'a [u8]> {
+        let mut rest = data;
+        let mut i = 0;
+        iter::from_fn(move || {
+            if rest.is_empty() {
+                return None;
+            }
+            let len = match chunks.len() {
+                0 => rest.len(),
+                n => cmp::min(cmp::max(1, chunks[i % n]), rest.len()),
+            };
+            let (chunk, next) = rest.split_at(len);
+            rest = next;
+            i += 1;
+            Some(chunk)
+        })
+    }
+
+    #[derive(Debug, Default, Eq, PartialEq)]
+    struct MimeRecorder {
+        events: Vec<(bool, u64)>,
+        bodies: Vec<(u64, Vec<u8>)>,
+    }
+
+    impl MimeHandler for MimeRecorder {
+        fn part_start(&mut self, part: &MimePart) {
+            self.events.push((true, part.offset));
+        }
+
+        fn part_body(&mut self, part: &MimePart, data: &[u8]) {
+            assert!(!data.is_empty());
+            match self.bodies.last_mut() {
+                Some((offset, body)) if *offset == part.offset => body.extend_from_slice(data),
+                _ => self.bodies.push((part.offset, data.to_vec())),
+            }
+        }
+
+        fn part_end(&mut self, part: &MimePart) {
+            self.events.push((false, part.offset));
+        }
+    }
+
+    fn check_part(part: &MimePart, start: u64, end: u64) {
+        assert!(start <= part.offset);
+        assert!(part.offset <= part.body.start);
+        assert!(part.body.start <= part.body.end);
+        assert!(part.body.end <= end);
+        let mut prev = part.body.start;
+        for p in part.parts.iter() {
+            check_part(p, prev, part.body.end);
+            prev = p.body.end;
+        }
+    }
+
+    pub fn mime_parsing(data: Vec<u8>, chunks: Vec<usize>) {
+        let mut recorder = MimeRecorder::default();
+        let mut parser = MimeParser::new();
+        parser.feed(&data, &mut recorder);
+        let root = parser.finish(&mut recorder);
+        check_part(&root, 0, data.len() as u64);
+
+        // Feeding the message chunk by chunk should give the same result
+        let mut chunked_recorder = MimeRecorder::default();
+        let mut parser = MimeParser::new();
+        for chunk in chunked(&data, &chunks) {
+            parser.feed(chunk, &mut chunked_recorder);
+        }
+        assert_eq!(parser.finish(&mut chunked_recorder), root);
+        assert_eq!(chunked_recorder, recorder);
+    }
+
+    pub fn transfer_decoding(data: Vec<u8>, chunks: Vec<usize>) {
+        let decode = |encoding: &TransferEncoding, data: &[u8], chunks: &[usize]| {
+            let mut decoder = TransferDecoder::new(encoding);
+            let mut res = Vec::new();
+            for chunk in chunked(data, chunks) {
+                decoder.decode(chunk, &mut res);
+            }
+            decoder.finish(&mut res);
+            res
+        };
+
+        // Decoding chunk by chunk should give the same result
+        for encoding in &[TransferEncoding::Base64, TransferEncoding::QuotedPrintable] {
+            assert_eq!(
+                decode(encoding, &data, &[]),
+                decode(encoding, &data, &chunks)
+            );
+        }
+
+        // And encoded data should decode back to the original
+        let mut base64 = Vec::new();
+        for line in base64::encode(&data).as_bytes().chunks(76) {
+            base64.extend_from_slice(line);
+            base64.extend_from_slice(b"\r\n");
+        }
+        assert_eq!(decode(&TransferEncoding::Base64, &base64, &chunks), data);
+        let mut qp = Vec::new();
+        for (i, b) in data.iter().enumerate() {
+            if i > 0 && i % 25 == 0 {
+                qp.extend_from_slice(b"=\r\n");
+            }
+            qp.extend_from_slice(format!("={:02X}", b).as_bytes());
+        }
+        assert_eq!(
+            decode(&TransferEncoding::QuotedPrintable, &qp, &chunks),
+            data
+        );
+    }
 }
 
 #[cfg(test)]
@@ -197,4 +361,19 @@ mod tests {
     ) {
         fuzz::escaping_then_unescaping(data, maxread, initread, readlen)
     }
+
+    #[quickcheck]
+    pub fn header_parsing(data: Vec<u8>, chunks: Vec<usize>) {
+        fuzz::header_parsing(data, chunks)
+    }
+
+    #[quickcheck]
+    pub fn mime_parsing(data: Vec<u8>, chunks: Vec<usize>) {
+        fuzz::mime_parsing(data, chunks)
+    }
+
+    #[quickcheck]
+    pub fn transfer_decoding(data: Vec<u8>, chunks: Vec<usize>) {
+        fuzz::transfer_decoding(data, chunks)
+    }
 }
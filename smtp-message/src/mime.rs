@@ -0,0 +1,1193 @@
+use std::{collections::BTreeMap, io, mem, ops::Range, str};
+
+use futures::{AsyncRead, AsyncReadExt};
+
+use crate::{
+    header::{decode_charset, decode_encoded_words, is_wsp},
+    Headers,
+};
+
+/// Maximum size of the header section of a part, past which the rest of it is
+/// handled as the body of the part
+const MAX_PART_HEADER_SIZE: usize = 64 * 1024;
+
+/// Maximum length of a line that can still be a boundary delimiter: two
+/// dashes, a boundary of up to 70 characters, two more dashes and some
+/// transport padding
+const MAX_DELIMITER_LEN: usize = 128;
+
+/// Maximum nesting of multipart parts, deeper ones being handled as if they
+/// were not multipart
+const MAX_DEPTH: usize = 32;
+
+/// Size of the chunks [`read_mime`](read_mime) reads
+const READ_BUF_SIZE: usize = 4096;
+
+/// Maximum length of a line (RFC 5322 section 2.1.1), past which whitespace
+/// held back in quoted-printable bodies can no longer be padding
+const MAX_LINE_LEN: usize = 998;
+
+fn trim_wsp(s: &[u8]) -> &[u8] {
+    let start = s.iter().position(|&b| !is_wsp(b)).unwrap_or(s.len());
+    let end = s.iter().rposition(|&b| !is_wsp(b)).map(|i| i + 1);
+    &s[start..end.unwrap_or(start)]
+}
+
+fn lossy(s: &[u8]) -> String {
+    String::from_utf8_lossy(s).into_owned()
+}
+
+/// Splits a structured field body at its semicolons, removing the comments
+/// and unquoting the quoted strings
+fn split_params(value: &[u8]) -> Vec<Vec<u8>> {
+    let mut res = vec![Vec::new()];
+    let mut quoted = false;
+    let mut comment = 0usize;
+    let mut bytes = value.iter();
+    while let Some(&b) = bytes.next() {
+        let cur = res.last_mut().unwrap();
+        match (quoted, comment, b) {
+            (true, _, b'\\') => cur.extend(bytes.next()),
+            (true, _, b'"') => quoted = false,
+            (true, _, b) => cur.push(b),
+            (false, 0, b'"') => quoted = true,
+            (false, 0, b';') => res.push(Vec::new()),
+            (false, 0, b')') => (),
+            (false, 0, b) if b != b'(' => cur.push(b),
+            (false, _, b'\\') => {
+                bytes.next();
+            }
+            (false, _, b'(') => comment += 1,
+            (false, _, b')') => comment -= 1,
+            (false, _, _) => (),
+        }
+    }
+    res
+}
+
+/// Decodes the `%XX` escapes of an extended parameter value, keeping invalid
+/// ones as is
+fn percent_decode(value: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(value.len());
+    let mut i = 0;
+    while i < value.len() {
+        let hex = value
+            .get(i + 1..i + 3)
+            .and_then(|h| str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (value[i], hex) {
+            (b'%', Some(b)) => {
+                res.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                res.push(b);
+                i += 1;
+            }
+        }
+    }
+    res
+}
+
+/// Combines the parameters split in sections or with a charset (RFC 2231)
+///
+/// Combined parameters take precedence over regular ones of the same name.
+fn combine_params(raw: Vec<(String, Vec<u8>)>) -> Vec<(String, String)> {
+    let mut res = Vec::<(String, String)>::new();
+    let mut sections = BTreeMap::<String, BTreeMap<u32, (bool, Vec<u8>)>>::new();
+    for (name, value) in raw {
+        let (name, extended) = match name.strip_suffix('*') {
+            Some(name) => (name.to_owned(), true),
+            None => (name, false),
+        };
+        let section = name
+            .rfind('*')
+            .and_then(|i| Some((i, name[i + 1..].parse::<u32>().ok()?)));
+        match (section, extended) {
+            (None, false) => res.push((name, lossy(&value))),
+            (None, true) => {
+                sections.entry(name).or_default().insert(0, (true, value));
+            }
+            (Some((i, n)), _) => {
+                let sections = sections.entry(name[..i].to_owned()).or_default();
+                sections.insert(n, (extended, value));
+            }
+        }
+    }
+    for (name, sections) in sections {
+        let mut bytes = Vec::new();
+        let mut charset = None;
+        for (i, (n, (extended, value))) in sections.into_iter().enumerate() {
+            // Sections must be numbered from 0 without gaps
+            if n as usize != i {
+                break;
+            }
+            let mut value = &value[..];
+            if extended && n == 0 {
+                let mut parts = value.splitn(3, |&b| b == b'\'');
+                if let (Some(c), Some(_), Some(v)) = (parts.next(), parts.next(), parts.next()) {
+                    charset = Some(lossy(c));
+                    value = v;
+                }
+            }
+            match extended {
+                true => bytes.extend(percent_decode(value)),
+                false => bytes.extend_from_slice(value),
+            }
+        }
+        let value = charset
+            .and_then(|c| decode_charset(&c, bytes.clone()))
+            .unwrap_or_else(|| lossy(&bytes));
+        res.retain(|(n, _)| *n != name);
+        res.push((name, value));
+    }
+    res
+}
+
+/// Parses a field body made of a value and parameters, like the one of
+/// `Content-Type:` or `Content-Disposition:` (RFC 2045 section 5.1)
+///
+/// The value and the parameter names are lowercased.
+fn parse_structured(value: &[u8]) -> (String, Vec<(String, String)>) {
+    let mut segments = split_params(value).into_iter();
+    let main = segments.next().unwrap_or_default();
+    let main = main
+        .into_iter()
+        .filter(|&b| !is_wsp(b))
+        .collect::<Vec<u8>>();
+    let params = segments.filter_map(|seg| {
+        let eq = seg.iter().position(|&b| b == b'=')?;
+        let name = lossy(trim_wsp(&seg[..eq])).to_ascii_lowercase();
+        match name.is_empty() {
+            true => None,
+            false => Some((name, trim_wsp(&seg[eq + 1..]).to_vec())),
+        }
+    });
+    let params = combine_params(params.collect());
+    (lossy(&main).to_ascii_lowercase(), params)
+}
+
+/// The media type of a part (RFC 2045 section 5)
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ContentType {
+    /// The type and subtype, lowercased, eg. `text/plain`
+    pub mime_type: String,
+
+    /// The parameters, with lowercased names, those split in sections or with
+    /// a charset (RFC 2231) being combined
+    pub params: Vec<(String, String)>,
+}
+
+impl ContentType {
+    /// Parses the body of a `Content-Type:` field, returning `None` if it is
+    /// not a valid media type
+    pub fn parse(value: &[u8]) -> Option<ContentType> {
+        let (mime_type, params) = parse_structured(value);
+        let mut parts = mime_type.split('/');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(t), Some(s), None) if !t.is_empty() && !s.is_empty() => {
+                Some(ContentType { mime_type, params })
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the value of the parameter `name`, if any
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v as &str)
+    }
+
+    #[inline]
+    pub fn is_multipart(&self) -> bool {
+        self.mime_type.starts_with("multipart/")
+    }
+}
+
+/// The encoding of the body of a part (RFC 2045 section 6)
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum TransferEncoding {
+    SevenBit,
+    EightBit,
+    Binary,
+    QuotedPrintable,
+    Base64,
+
+    /// An unknown encoding, lowercased
+    Other(String),
+}
+
+impl TransferEncoding {
+    /// Parses the body of a `Content-Transfer-Encoding:` field
+    pub fn parse(value: &[u8]) -> TransferEncoding {
+        match &parse_structured(value).0 as &str {
+            "7bit" => TransferEncoding::SevenBit,
+            "8bit" => TransferEncoding::EightBit,
+            "binary" => TransferEncoding::Binary,
+            "quoted-printable" => TransferEncoding::QuotedPrintable,
+            "base64" => TransferEncoding::Base64,
+            other => TransferEncoding::Other(other.to_owned()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum QpState {
+    Normal,
+    Cr,
+    Eq,
+    Hex(u8),
+    EqWsp,
+    EqCr,
+}
+
+enum DecoderState {
+    Identity,
+    Base64 { acc: u32, bits: u32 },
+    QuotedPrintable { state: QpState, wsp: Vec<u8> },
+}
+
+/// Streaming decoder for the body of a part
+///
+/// Decoding is lenient: characters outside of the base64 alphabet are
+/// ignored, invalid quoted-printable escapes are kept as is, and bodies in
+/// other encodings are returned unchanged.
+pub struct TransferDecoder {
+    state: DecoderState,
+}
+
+fn base64_value(b: u8) -> Option<u32> {
+    match b {
+        b'A'..=b'Z' => Some((b - b'A') as u32),
+        b'a'..=b'z' => Some((b - b'a') as u32 + 26),
+        b'0'..=b'9' => Some((b - b'0') as u32 + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    }
+}
+
+fn hex_value(b: u8) -> u8 {
+    match b {
+        b'0'..=b'9' => b - b'0',
+        b'a'..=b'f' => b - b'a' + 10,
+        _ => b - b'A' + 10,
+    }
+}
+
+impl TransferDecoder {
+    pub fn new(encoding: &TransferEncoding) -> TransferDecoder {
+        let state = match encoding {
+            TransferEncoding::Base64 => DecoderState::Base64 { acc: 0, bits: 0 },
+            TransferEncoding::QuotedPrintable => DecoderState::QuotedPrintable {
+                state: QpState::Normal,
+                wsp: Vec::new(),
+            },
+            _ => DecoderState::Identity,
+        };
+        TransferDecoder { state }
+    }
+
+    /// Decodes the next chunk `data` of the body, appending the result to
+    /// `out`
+    pub fn decode(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        match self.state {
+            DecoderState::Identity => out.extend_from_slice(data),
+            DecoderState::Base64 {
+                ref mut acc,
+                ref mut bits,
+            } => {
+                for &b in data {
+                    if let Some(v) = base64_value(b) {
+                        *acc = ((*acc << 6) | v) & 0xFFFF;
+                        *bits += 6;
+                        if *bits >= 8 {
+                            *bits -= 8;
+                            out.push((*acc >> *bits) as u8);
+                        }
+                    } else if b == b'=' {
+                        // Padding ends a quantum, and possibly a stream that
+                        // could be followed by another one
+                        *bits = 0;
+                    }
+                }
+            }
+            DecoderState::QuotedPrintable {
+                ref mut state,
+                ref mut wsp,
+            } => {
+                for &b in data {
+                    Self::decode_qp(state, wsp, b, out);
+                }
+            }
+        }
+    }
+
+    fn decode_qp(state: &mut QpState, wsp: &mut Vec<u8>, b: u8, out: &mut Vec<u8>) {
+        use QpState::*;
+        // Whitespace at the end of lines is padding, so it is only output
+        // once something else follows it, or once there is too much of it
+        loop {
+            match (*state, b) {
+                (Normal, b' ') | (Normal, b'\t') => {
+                    wsp.push(b);
+                    if wsp.len() > MAX_LINE_LEN {
+                        out.append(wsp);
+                    }
+                }
+                (Normal, b'\r') => *state = Cr,
+                (Normal, b'=') => {
+                    out.append(wsp);
+                    *state = Eq;
+                }
+                (Normal, b) => {
+                    out.append(wsp);
+                    out.push(b);
+                }
+                (Cr, b'\n') => {
+                    wsp.clear();
+                    out.extend_from_slice(b"\r\n");
+                    *state = Normal;
+                }
+                (Cr, _) => {
+                    out.append(wsp);
+                    out.push(b'\r');
+                    *state = Normal;
+                    continue;
+                }
+                (Eq, b) if b.is_ascii_hexdigit() => *state = Hex(b),
+                (Eq, b'\r') | (EqWsp, b'\r') => *state = EqCr,
+                (Eq, b'\n') | (EqWsp, b'\n') | (EqCr, b'\n') => *state = Normal,
+                (Eq, b' ') | (Eq, b'\t') | (EqWsp, b' ') | (EqWsp, b'\t') => *state = EqWsp,
+                (Hex(h), b) if b.is_ascii_hexdigit() => {
+                    out.push(hex_value(h) * 16 + hex_value(b));
+                    *state = Normal;
+                }
+                (Hex(h), _) => {
+                    out.push(b'=');
+                    out.push(h);
+                    *state = Normal;
+                    continue;
+                }
+                (EqCr, _) => {
+                    out.extend_from_slice(b"=\r");
+                    *state = Normal;
+                    continue;
+                }
+                (Eq, _) | (EqWsp, _) => {
+                    out.push(b'=');
+                    *state = Normal;
+                    continue;
+                }
+            }
+            break;
+        }
+    }
+
+    /// Ends decoding the body, appending what is left to `out`
+    pub fn finish(&mut self, out: &mut Vec<u8>) {
+        match self.state {
+            DecoderState::Identity => (),
+            DecoderState::Base64 {
+                ref mut acc,
+                ref mut bits,
+            } => {
+                *acc = 0;
+                *bits = 0;
+            }
+            DecoderState::QuotedPrintable {
+                ref mut state,
+                ref mut wsp,
+            } => {
+                match *state {
+                    QpState::Cr => {
+                        out.append(wsp);
+                        out.push(b'\r');
+                    }
+                    QpState::Hex(h) => {
+                        out.push(b'=');
+                        out.push(h);
+                    }
+                    _ => (),
+                }
+                wsp.clear();
+                *state = QpState::Normal;
+            }
+        }
+    }
+}
+
+/// A part of a message (RFC 2045), the message itself being the root part
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MimePart {
+    pub headers: Headers,
+
+    /// The media type of the part, which defaults to `text/plain`, or to
+    /// `message/rfc822` in a `multipart/digest`
+    pub content_type: ContentType,
+
+    pub transfer_encoding: TransferEncoding,
+
+    /// The disposition type of the part, lowercased, if any
+    pub disposition: Option<String>,
+
+    /// The name of the file the part is, if any, from its disposition or
+    /// media type
+    pub filename: Option<String>,
+
+    /// Offset of the part, ie. of its header section, in the message
+    pub offset: u64,
+
+    /// Range of the body of the part in the message
+    pub body: Range<u64>,
+
+    /// The subparts of the part, if it is multipart
+    pub parts: Vec<MimePart>,
+}
+
+impl MimePart {
+    fn new(headers: Headers, default_type: &str, offset: u64) -> MimePart {
+        let content_type = headers
+            .get("content-type")
+            .and_then(|f| ContentType::parse(&f.unfolded()))
+            .unwrap_or_else(|| ContentType {
+                mime_type: default_type.to_owned(),
+                params: Vec::new(),
+            });
+        let transfer_encoding = headers
+            .get("content-transfer-encoding")
+            .map(|f| TransferEncoding::parse(&f.unfolded()))
+            .unwrap_or(TransferEncoding::SevenBit);
+        let (disposition, params) = headers
+            .get("content-disposition")
+            .map(|f| parse_structured(&f.unfolded()))
+            .unwrap_or_default();
+        let filename = params
+            .into_iter()
+            .find(|(n, _)| n == "filename")
+            .map(|(_, v)| v)
+            .or_else(|| content_type.param("name").map(str::to_owned))
+            .map(|f| decode_encoded_words(f.as_bytes()));
+        let body_start = offset + headers.body_offset() as u64;
+        MimePart {
+            headers,
+            content_type,
+            transfer_encoding,
+            disposition: Some(disposition).filter(|d| !d.is_empty()),
+            filename,
+            offset,
+            body: body_start..body_start,
+            parts: Vec::new(),
+        }
+    }
+}
+
+/// Receives the parts of a message as a [`MimeParser`](MimeParser) finds them
+pub trait MimeHandler {
+    /// Called once the header section of `part` has been parsed
+    fn part_start(&mut self, _part: &MimePart) {}
+
+    /// Called with the next chunk of the body of `part`, decoded according to
+    /// its transfer encoding, unless it is multipart
+    fn part_body(&mut self, _part: &MimePart, _data: &[u8]) {}
+
+    /// Called once `part` has been parsed, along with its subparts
+    fn part_end(&mut self, _part: &MimePart) {}
+}
+
+impl MimeHandler for () {}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum MimeParserState {
+    Headers,
+    Body,
+    Preamble,
+    Epilogue,
+}
+
+struct OpenPart {
+    part: MimePart,
+
+    // The delimiter of the subparts, if the part is multipart
+    delimiter: Option<Vec<u8>>,
+
+    decoder: TransferDecoder,
+}
+
+/// Incremental parser for the MIME structure of a message
+///
+/// It is fed the unescaped contents of the message chunk by chunk, and
+/// passes the parts to a [`MimeHandler`](MimeHandler) as it finds them. Only
+/// the current line, as long as it may be a boundary delimiter, and the
+/// header section of the current part are buffered.
+///
+/// Encapsulated messages (`message/rfc822`) are not parsed further.
+pub struct MimeParser {
+    // The parts being parsed, each one being a subpart of the previous one
+    stack: Vec<OpenPart>,
+
+    root: Option<MimePart>,
+
+    state: MimeParserState,
+
+    // The header section of the part being parsed, and its offset
+    headers: Vec<u8>,
+    headers_offset: u64,
+
+    // The current line, or what is left to handle of it
+    line: Vec<u8>,
+
+    // Whether the beginning of the current line has already been handled, it
+    // then not being a delimiter
+    line_streamed: bool,
+
+    // Whether the previous line ended with a CRLF, that is part of the body
+    // only if no delimiter follows
+    pending_crlf: bool,
+
+    // Offset in the message of the end of the data fed so far
+    offset: u64,
+
+    decoded: Vec<u8>,
+}
+
+impl Default for MimeParser {
+    fn default() -> MimeParser {
+        MimeParser::new()
+    }
+}
+
+impl MimeParser {
+    /// Creates a parser for a message, starting with its header section
+    pub fn new() -> MimeParser {
+        MimeParser {
+            stack: Vec::new(),
+            root: None,
+            state: MimeParserState::Headers,
+            headers: Vec::new(),
+            headers_offset: 0,
+            line: Vec::new(),
+            line_streamed: false,
+            pending_crlf: false,
+            offset: 0,
+            decoded: Vec::new(),
+        }
+    }
+
+    /// Creates a parser for the body of a message whose header section,
+    /// `headers`, has already been read, eg. with
+    /// [`read_headers`](crate::read_headers)
+    pub fn after_headers<H>(headers: Headers, handler: &mut H) -> MimeParser
+    where
+        H: ?Sized + MimeHandler,
+    {
+        let mut parser = MimeParser::new();
+        parser.offset = headers.body_offset() as u64;
+        parser.start_part(headers, handler);
+        parser
+    }
+
+    /// Feeds the next chunk of the message to the parser
+    pub fn feed<H>(&mut self, mut data: &[u8], handler: &mut H)
+    where
+        H: ?Sized + MimeHandler,
+    {
+        while !data.is_empty() {
+            let len = data
+                .iter()
+                .position(|&b| b == b'\n')
+                .map(|i| i + 1)
+                .unwrap_or(data.len());
+            self.line.extend_from_slice(&data[..len]);
+            self.offset += len as u64;
+            data = &data[len..];
+            if self.line.ends_with(b"\r\n") {
+                let mut line = mem::take(&mut self.line);
+                self.handle_line(&line[..line.len() - 2], true, handler);
+                line.clear();
+                self.line = line;
+                self.line_streamed = false;
+            } else {
+                self.handle_partial_line(handler);
+            }
+        }
+    }
+
+    /// Ends parsing the message, returning its root part
+    pub fn finish<H>(mut self, handler: &mut H) -> MimePart
+    where
+        H: ?Sized + MimeHandler,
+    {
+        if !self.line.is_empty() {
+            let line = mem::take(&mut self.line);
+            self.handle_line(&line, false, handler);
+        }
+        if self.state == MimeParserState::Headers {
+            self.end_headers(handler);
+        }
+        self.body_data(&[], handler);
+        self.close_parts(0, self.offset, handler);
+        self.root.take().unwrap()
+    }
+
+    fn handle_partial_line<H>(&mut self, handler: &mut H)
+    where
+        H: ?Sized + MimeHandler,
+    {
+        if self.state == MimeParserState::Headers {
+            if self.headers.len() + self.line.len() <= MAX_PART_HEADER_SIZE {
+                return;
+            }
+            self.end_headers(handler);
+        }
+        let may_be_delimiter = !self.line_streamed
+            && self.line.len() <= MAX_DELIMITER_LEN
+            && b"--".starts_with(&self.line[..self.line.len().min(2)])
+            && self.stack.iter().any(|p| p.delimiter.is_some());
+        if may_be_delimiter {
+            return;
+        }
+
+        // Keep a final CR, that may start the CRLF ending the line
+        let len = self.line.len() - self.line.ends_with(b"\r") as usize;
+        let mut line = mem::take(&mut self.line);
+        self.body_data(&line[..len], handler);
+        line.drain(..len);
+        self.line = line;
+        self.line_streamed = true;
+    }
+
+    fn handle_line<H>(&mut self, line: &[u8], crlf: bool, handler: &mut H)
+    where
+        H: ?Sized + MimeHandler,
+    {
+        if !self.line_streamed {
+            if let Some((depth, close)) = self.delimiter(line) {
+                let line_start = self.offset - line.len() as u64 - 2 * crlf as u64;
+                let end = line_start - 2 * mem::take(&mut self.pending_crlf) as u64;
+                if self.state == MimeParserState::Headers {
+                    self.end_headers(handler);
+                }
+                self.close_parts(depth + 1, end, handler);
+                // The CRLF after a close delimiter is in the epilogue
+                self.pending_crlf = close && crlf;
+                if close {
+                    self.state = MimeParserState::Epilogue;
+                } else {
+                    self.state = MimeParserState::Headers;
+                    self.headers_offset = self.offset;
+                }
+                return;
+            }
+        }
+        if self.state == MimeParserState::Headers {
+            if line.is_empty() && crlf {
+                self.headers.extend_from_slice(b"\r\n");
+                self.end_headers(handler);
+                return;
+            }
+            if self.headers.len() + line.len() + 2 <= MAX_PART_HEADER_SIZE {
+                self.headers.extend_from_slice(line);
+                if crlf {
+                    self.headers.extend_from_slice(b"\r\n");
+                }
+                return;
+            }
+            self.end_headers(handler);
+        }
+        self.body_data(line, handler);
+        self.pending_crlf = crlf;
+    }
+
+    /// Returns the depth of the part whose delimiter `line` is, and whether
+    /// it is the close delimiter, if it is a delimiter
+    fn delimiter(&self, line: &[u8]) -> Option<(usize, bool)> {
+        for (i, open) in self.stack.iter().enumerate().rev() {
+            let rest = match open.delimiter {
+                Some(ref d) if line.starts_with(d) => &line[d.len()..],
+                _ => continue,
+            };
+            let (close, rest) = match rest.strip_prefix(b"--") {
+                Some(rest) => (true, rest),
+                None => (false, rest),
+            };
+            if rest.iter().all(|&b| is_wsp(b)) {
+                return Some((i, close));
+            }
+        }
+        None
+    }
+
+    fn body_data<H>(&mut self, data: &[u8], handler: &mut H)
+    where
+        H: ?Sized + MimeHandler,
+    {
+        if self.state != MimeParserState::Body {
+            return;
+        }
+        let open = self.stack.last_mut().unwrap();
+        self.decoded.clear();
+        if mem::take(&mut self.pending_crlf) {
+            open.decoder.decode(b"\r\n", &mut self.decoded);
+        }
+        open.decoder.decode(data, &mut self.decoded);
+        if !self.decoded.is_empty() {
+            handler.part_body(&open.part, &self.decoded);
+        }
+    }
+
+    fn end_headers<H>(&mut self, handler: &mut H)
+    where
+        H: ?Sized + MimeHandler,
+    {
+        let headers = Headers::parse(mem::take(&mut self.headers));
+        self.start_part(headers, handler);
+    }
+
+    fn start_part<H>(&mut self, headers: Headers, handler: &mut H)
+    where
+        H: ?Sized + MimeHandler,
+    {
+        let default_type = match self.stack.last() {
+            Some(p) if p.part.content_type.mime_type == "multipart/digest" => "message/rfc822",
+            _ => "text/plain",
+        };
+        let part = MimePart::new(headers, default_type, self.headers_offset);
+        handler.part_start(&part);
+        let delimiter = match part.content_type.param("boundary") {
+            Some(b) if part.content_type.is_multipart() && self.stack.len() < MAX_DEPTH => {
+                Some(format!("--{}", b).into_bytes())
+            }
+            _ => None,
+        };
+        self.state = match delimiter {
+            Some(_) => MimeParserState::Preamble,
+            None => MimeParserState::Body,
+        };
+        self.pending_crlf = false;
+        let decoder = TransferDecoder::new(&part.transfer_encoding);
+        self.stack.push(OpenPart {
+            part,
+            delimiter,
+            decoder,
+        });
+    }
+
+    /// Ends the parts deeper than `depth`, that end at offset `end`
+    fn close_parts<H>(&mut self, depth: usize, end: u64, handler: &mut H)
+    where
+        H: ?Sized + MimeHandler,
+    {
+        while self.stack.len() > depth {
+            let mut open = self.stack.pop().unwrap();
+            if open.delimiter.is_none() {
+                self.decoded.clear();
+                open.decoder.finish(&mut self.decoded);
+                if !self.decoded.is_empty() {
+                    handler.part_body(&open.part, &self.decoded);
+                }
+            }
+            open.part.body.end = end;
+            handler.part_end(&open.part);
+            match self.stack.last_mut() {
+                Some(parent) => parent.part.parts.push(open.part),
+                None => self.root = Some(open.part),
+            }
+        }
+    }
+}
+
+/// Parses the MIME structure of a message from `reader`, that returns the
+/// unescaped contents of the message, eg. an
+/// [`UnescapedDataReader`](crate::UnescapedDataReader)
+///
+/// The parts are passed to `handler` as they are found, and the root part is
+/// returned once the message has been read in full.
+pub async fn read_mime<R, H>(reader: &mut R, handler: &mut H) -> io::Result<MimePart>
+where
+    R: Unpin + AsyncRead,
+    H: ?Sized + MimeHandler,
+{
+    let mut parser = MimeParser::new();
+    let mut buf = vec![0; READ_BUF_SIZE];
+    loop {
+        let read = reader.read(&mut buf).await?;
+        if read == 0 {
+            return Ok(parser.finish(handler));
+        }
+        parser.feed(&buf[..read], handler);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    use std::pin::Pin;
+
+    use futures::{executor, io::Cursor};
+
+    #[derive(Debug, Default, Eq, PartialEq)]
+    struct Recorder {
+        events: Vec<(&'static str, u64)>,
+        bodies: BTreeMap<u64, Vec<u8>>,
+    }
+
+    impl MimeHandler for Recorder {
+        fn part_start(&mut self, part: &MimePart) {
+            self.events.push(("start", part.offset));
+        }
+
+        fn part_body(&mut self, part: &MimePart, data: &[u8]) {
+            let body = self.bodies.entry(part.offset).or_default();
+            body.extend_from_slice(data);
+        }
+
+        fn part_end(&mut self, part: &MimePart) {
+            self.events.push(("end", part.offset));
+        }
+    }
+
+    #[test]
+    fn content_type() {
+        // The field body, and the expected type and parameters if it is valid
+        type Test<'a> = (&'a [u8], Option<(&'a str, &'a [(&'a str, &'a str)])>);
+        let tests: &[Test] = &[
+            (
+                b"text/plain; charset=\"us-ascii\" (Plain text)",
+                Some(("text/plain", &[("charset", "us-ascii")])),
+            ),
+            (
+                b" Multipart/Mixed ;\tBoundary=\"a;b\\\"c\"; foo",
+                Some(("multipart/mixed", &[("boundary", "a;b\"c")])),
+            ),
+            (
+                b"message/external-body; access-type=URL; URL*0=\"ftp://\"; \
+                  URL*1=\"cs.utk.edu/pub/moore/bulk-mailer/bulk-mailer.tar\"",
+                Some(("message/external-body", &[
+                    ("access-type", "URL"),
+                    (
+                        "url",
+                        "ftp://cs.utk.edu/pub/moore/bulk-mailer/bulk-mailer.tar",
+                    ),
+                ])),
+            ),
+            (
+                b"application/x-stuff; title*=us-ascii'en-us'This%20is%20%2A%2A%2Afun%2A%2A%2A",
+                Some(("application/x-stuff", &[("title", "This is ***fun***")])),
+            ),
+            (
+                b"application/x-stuff; title*0*=us-ascii'en'This%20is%20even%20more%20; \
+                  title*1*=%2A%2A%2Afun%2A%2A%2A%20; title*2=\"isn't it!\"",
+                Some(("application/x-stuff", &[(
+                    "title",
+                    "This is even more ***fun*** isn't it!",
+                )])),
+            ),
+            (
+                b"text/plain; name=\"fallback\"; name*=iso-8859-1''caf%E9",
+                Some(("text/plain", &[("name", "caf\u{e9}")])),
+            ),
+            (b"text", None),
+            (b"text/plain/extra", None),
+            (b"/plain", None),
+        ];
+        for &(inp, out) in tests {
+            println!("Test: {:?}", show_bytes(inp));
+            let res = ContentType::parse(inp);
+            println!("Result: {:?}", res);
+            match (res, out) {
+                (None, None) => (),
+                (Some(res), Some((mime_type, params))) => {
+                    assert_eq!(res.mime_type, mime_type);
+                    let res_params = res
+                        .params
+                        .iter()
+                        .map(|(n, v)| (n as &str, v as &str))
+                        .collect::<Vec<_>>();
+                    assert_eq!(res_params, params);
+                }
+                _ => panic!("Unexpected result"),
+            }
+        }
+        let ct = ContentType::parse(b"multipart/alternative; BOUNDARY=foo").unwrap();
+        assert!(ct.is_multipart());
+        assert_eq!(ct.param("boundary"), Some("foo"));
+        assert_eq!(ct.param("charset"), None);
+    }
+
+    #[test]
+    fn transfer_decoding() {
+        let tests: &[(TransferEncoding, &[u8], &[u8])] = &[
+            (
+                TransferEncoding::QuotedPrintable,
+                b"=3D is an equal sign =\r\nand a soft break",
+                b"= is an equal sign and a soft break",
+            ),
+            (
+                TransferEncoding::QuotedPrintable,
+                b"trailing   \r\nspace\t\r\nkept  here  ",
+                b"trailing\r\nspace\r\nkept  here",
+            ),
+            (
+                TransferEncoding::QuotedPrintable,
+                b"bad =ZZ escape =\r lone\rcr =4",
+                b"bad =ZZ escape =\r lone\rcr =4",
+            ),
+            (
+                TransferEncoding::QuotedPrintable,
+                b"padded soft break= \t\r\nend=\n!",
+                b"padded soft breakend!",
+            ),
+            (
+                TransferEncoding::Base64,
+                b"aGVsbG8g\r\nd29ybGQ=\r\n",
+                b"hello world",
+            ),
+            (TransferEncoding::Base64, b"aGVs!bG8=", b"hello"),
+            (TransferEncoding::Base64, b"QQ==QQ==Q", b"AA"),
+            (TransferEncoding::SevenBit, b"a=3D \r\n", b"a=3D \r\n"),
+            (
+                TransferEncoding::Other("x-uuencode".into()),
+                b"begin",
+                b"begin",
+            ),
+        ];
+        for &(ref encoding, inp, out) in tests {
+            println!("Test: {:?} {:?}", encoding, show_bytes(inp));
+            let mut decoder = TransferDecoder::new(encoding);
+            let mut res = Vec::new();
+            decoder.decode(inp, &mut res);
+            decoder.finish(&mut res);
+            println!("Result: {:?}", show_bytes(&res));
+            assert_eq!(res, out);
+
+            let mut decoder = TransferDecoder::new(encoding);
+            let mut res = Vec::new();
+            for b in inp.chunks(1) {
+                decoder.decode(b, &mut res);
+            }
+            decoder.finish(&mut res);
+            assert_eq!(res, out);
+        }
+
+        // Whitespace is not held back past the maximum line length
+        let mut decoder = TransferDecoder::new(&TransferEncoding::QuotedPrintable);
+        let mut res = Vec::new();
+        decoder.decode(&[b' '; 2000], &mut res);
+        assert_eq!(res.len(), 2 * (MAX_LINE_LEN + 1));
+        decoder.decode(b"\r\n", &mut res);
+        assert_eq!(res.len(), 2 * (MAX_LINE_LEN + 1) + 2);
+
+        assert_eq!(
+            TransferEncoding::parse(b" Base64 (encoded)"),
+            TransferEncoding::Base64
+        );
+        assert_eq!(
+            TransferEncoding::parse(b"X-UUEncode"),
+            TransferEncoding::Other("x-uuencode".into())
+        );
+    }
+
+    #[test]
+    fn mime_tree() {
+        let msg = [
+            "From: a@example.org",
+            "Content-Type: multipart/mixed; boundary=\"outer\"",
+            "",
+            "preamble",
+            "--outer",
+            "Content-Type: text/plain; charset=utf-8",
+            "Content-Transfer-Encoding: quoted-printable",
+            "",
+            "caf=C3=A9 =",
+            "au lait",
+            "--outer",
+            "Content-Type: multipart/alternative; boundary=inner",
+            "",
+            "--inner",
+            "",
+            "implicit text/plain",
+            "",
+            "--inner ",
+            "Content-Type: text/html",
+            "",
+            "<p>hi</p>",
+            "--inner--",
+            "--outer",
+            "Content-Type: application/octet-stream; name=\"ignored.bin\"",
+            "Content-Disposition: attachment;",
+            " filename*=utf-8''r%C3%A9sum%C3%A9.pdf",
+            "Content-Transfer-Encoding: base64",
+            "",
+            "aGVsbG8g",
+            "d29ybGQ=",
+            "--outer--",
+            "epilogue",
+            "",
+        ]
+        .join("\r\n");
+        let msg = msg.as_bytes();
+        let body = |part: &MimePart| &msg[part.body.start as usize..part.body.end as usize];
+
+        let mut recorder = Recorder::default();
+        let mut parser = MimeParser::new();
+        parser.feed(msg, &mut recorder);
+        let root = parser.finish(&mut recorder);
+
+        assert_eq!(root.content_type.mime_type, "multipart/mixed");
+        assert_eq!(root.offset, 0);
+        assert!(body(&root).starts_with(b"preamble\r\n"));
+        assert!(body(&root).ends_with(b"epilogue\r\n"));
+        assert_eq!(root.parts.len(), 3);
+
+        let text = &root.parts[0];
+        assert_eq!(text.content_type.param("charset"), Some("utf-8"));
+        assert_eq!(text.transfer_encoding, TransferEncoding::QuotedPrintable);
+        assert!(msg[text.offset as usize..].starts_with(b"Content-Type: text/plain"));
+        assert_eq!(body(text), b"caf=C3=A9 =\r\nau lait");
+
+        let alternative = &root.parts[1];
+        assert_eq!(alternative.content_type.mime_type, "multipart/alternative");
+        assert!(body(alternative).ends_with(b"<p>hi</p>\r\n--inner--"));
+        let implicit = &alternative.parts[0];
+        assert_eq!(implicit.content_type.mime_type, "text/plain");
+        assert_eq!(implicit.headers.iter().count(), 0);
+        assert_eq!(body(implicit), b"implicit text/plain\r\n");
+        let html = &alternative.parts[1];
+        assert_eq!(html.content_type.mime_type, "text/html");
+        assert_eq!(body(html), b"<p>hi</p>");
+
+        let attachment = &root.parts[2];
+        assert_eq!(attachment.disposition.as_deref(), Some("attachment"));
+        assert_eq!(attachment.filename.as_deref(), Some("r\u{e9}sum\u{e9}.pdf"));
+        assert_eq!(body(attachment), b"aGVsbG8g\r\nd29ybGQ=");
+
+        assert_eq!(recorder.events, vec![
+            ("start", root.offset),
+            ("start", text.offset),
+            ("end", text.offset),
+            ("start", alternative.offset),
+            ("start", implicit.offset),
+            ("end", implicit.offset),
+            ("start", html.offset),
+            ("end", html.offset),
+            ("end", alternative.offset),
+            ("start", attachment.offset),
+            ("end", attachment.offset),
+            ("end", root.offset),
+        ]);
+        let bodies = recorder.bodies.iter().collect::<Vec<_>>();
+        assert_eq!(bodies, vec![
+            (&text.offset, &"caf\u{e9} au lait".as_bytes().to_vec()),
+            (&implicit.offset, &b"implicit text/plain\r\n".to_vec()),
+            (&html.offset, &b"<p>hi</p>".to_vec()),
+            (&attachment.offset, &b"hello world".to_vec()),
+        ]);
+
+        // Feeding the message byte by byte gives the same result
+        let mut chunked_recorder = Recorder::default();
+        let mut parser = MimeParser::new();
+        for b in msg.chunks(1) {
+            parser.feed(b, &mut chunked_recorder);
+        }
+        assert_eq!(parser.finish(&mut chunked_recorder), root);
+        assert_eq!(chunked_recorder, recorder);
+
+        // And so does starting after the header section
+        let (headers, rest) =
+            executor::block_on(read_headers(&mut Cursor::new(msg), 1024)).unwrap();
+        let mut recorder_after = Recorder::default();
+        let mut parser = MimeParser::after_headers(headers, &mut recorder_after);
+        parser.feed(&rest, &mut recorder_after);
+        assert_eq!(parser.finish(&mut recorder_after), root);
+        assert_eq!(recorder_after, recorder);
+    }
+
+    #[test]
+    fn mime_edge_cases() {
+        // Not a MIME message
+        let mut parser = MimeParser::new();
+        parser.feed(b"Subject: hi\r\n\r\nhello\r\n", &mut ());
+        let root = parser.finish(&mut ());
+        assert_eq!(root.content_type.mime_type, "text/plain");
+        assert_eq!(root.transfer_encoding, TransferEncoding::SevenBit);
+        assert_eq!(root.body, 15..22);
+        assert!(root.parts.is_empty());
+
+        // Unclosed multipart/digest, whose parts are messages by default
+        let msg = [
+            "Content-Type: multipart/digest; boundary=d",
+            "",
+            "--d",
+            "",
+            "Subject: first",
+            "",
+            "--d",
+            "Content-Type: text/plain",
+            "",
+            "truncated",
+        ]
+        .join("\r\n");
+        let mut recorder = Recorder::default();
+        let mut parser = MimeParser::new();
+        parser.feed(msg.as_bytes(), &mut recorder);
+        let root = parser.finish(&mut recorder);
+        let types = root
+            .parts
+            .iter()
+            .map(|p| &p.content_type.mime_type as &str)
+            .collect::<Vec<_>>();
+        assert_eq!(types, vec!["message/rfc822", "text/plain"]);
+        assert!(root.parts[0].parts.is_empty());
+        assert_eq!(root.parts[1].body.end, msg.len() as u64);
+        assert_eq!(recorder.bodies[&root.parts[1].offset], b"truncated");
+
+        // Lines that only start like a delimiter are part of the body
+        let msg = [
+            "Content-Type: multipart/mixed; boundary=b",
+            "",
+            "--b",
+            "",
+            "--bad",
+            "--b-- not the end",
+            "--b--",
+            "",
+        ]
+        .join("\r\n");
+        let mut recorder = Recorder::default();
+        let mut parser = MimeParser::new();
+        parser.feed(msg.as_bytes(), &mut recorder);
+        let root = parser.finish(&mut recorder);
+        assert_eq!(root.parts.len(), 1);
+        assert_eq!(
+            recorder.bodies[&root.parts[0].offset],
+            b"--bad\r\n--b-- not the end"
+        );
+    }
+
+    #[test]
+    fn read_mime_from_data() {
+        let data: &[u8] = b"Content-Type: multipart/mixed; boundary=x\r\n\
+               \r\n\
+               --x\r\n\
+               \r\n\
+               ..hidden\r\n\
+               --x--\r\n\
+               .\r\n";
+        let reader = Box::pin(Cursor::new(data)) as Pin<Box<dyn AsyncRead>>;
+        let mut buf = [0; 16];
+        let mut data_reader = EscapedDataReader::new(&mut buf, 0..0, reader);
+        let mut unescaped = UnescapedDataReader::new(&mut data_reader);
+        let mut recorder = Recorder::default();
+        let root = executor::block_on(read_mime(&mut unescaped, &mut recorder)).unwrap();
+        data_reader.complete();
+
+        assert_eq!(root.parts.len(), 1);
+        assert_eq!(recorder.bodies[&root.parts[0].offset], b".hidden");
+        assert_eq!(root.body.end, data.len() as u64 - 4);
+    }
+}
@@ -96,10 +96,10 @@ impl ReplyCode {
     #[inline]
     pub fn kind(&self) -> ReplyCodeKind {
         match self.0[0] {
-            2 => ReplyCodeKind::PositiveCompletion,
-            3 => ReplyCodeKind::PositiveIntermediate,
-            4 => ReplyCodeKind::TransientNegative,
-            5 => ReplyCodeKind::PermanentNegative,
+            b'2' => ReplyCodeKind::PositiveCompletion,
+            b'3' => ReplyCodeKind::PositiveIntermediate,
+            b'4' => ReplyCodeKind::TransientNegative,
+            b'5' => ReplyCodeKind::PermanentNegative,
             _ => panic!("Asked kind of invalid reply code!"),
         }
     }
@@ -107,17 +107,18 @@ impl ReplyCode {
     #[inline]
     pub fn category(&self) -> ReplyCodeCategory {
         match self.0[1] {
-            0 => ReplyCodeCategory::Syntax,
-            1 => ReplyCodeCategory::Information,
-            2 => ReplyCodeCategory::Connection,
-            5 => ReplyCodeCategory::ReceiverStatus,
+            b'0' => ReplyCodeCategory::Syntax,
+            b'1' => ReplyCodeCategory::Information,
+            b'2' => ReplyCodeCategory::Connection,
+            b'5' => ReplyCodeCategory::ReceiverStatus,
             _ => ReplyCodeCategory::Unspecified,
         }
     }
 
     #[inline]
     pub fn code(&self) -> u16 {
-        self.0[0] as u16 * 100 + self.0[1] as u16 * 10 + self.0[2] as u16
+        let digit = |c: u8| (c - b'0') as u16;
+        digit(self.0[0]) * 100 + digit(self.0[1]) * 10 + digit(self.0[2])
     }
 
     #[inline]
@@ -487,6 +488,16 @@ impl<S> Reply<S> {
     }
 }
 
+impl Reply<&str> {
+    pub fn to_owned(&self) -> Reply<String> {
+        Reply {
+            code: self.code,
+            ecode: self.ecode.clone().map(|e| e.into()),
+            text: self.text.iter().map(|t| t.to_owned()).collect(),
+        }
+    }
+}
+
 impl<S> Reply<S>
 where
     S: AsRef<str>,
@@ -543,6 +554,41 @@ mod tests {
         }
     }
 
+    #[test]
+    fn reply_code_kind() {
+        let tests: &[(ReplyCode, ReplyCodeKind, ReplyCodeCategory, u16)] = &[
+            (
+                ReplyCode::SERVICE_READY,
+                ReplyCodeKind::PositiveCompletion,
+                ReplyCodeCategory::Connection,
+                220,
+            ),
+            (
+                ReplyCode::START_MAIL_INPUT,
+                ReplyCodeKind::PositiveIntermediate,
+                ReplyCodeCategory::ReceiverStatus,
+                354,
+            ),
+            (
+                ReplyCode::INSUFFICIENT_STORAGE,
+                ReplyCodeKind::TransientNegative,
+                ReplyCodeCategory::ReceiverStatus,
+                452,
+            ),
+            (
+                ReplyCode::SYNTAX_ERROR,
+                ReplyCodeKind::PermanentNegative,
+                ReplyCodeCategory::Syntax,
+                501,
+            ),
+        ];
+        for &(code, kind, category, num) in tests {
+            assert_eq!(code.kind(), kind);
+            assert_eq!(code.category(), category);
+            assert_eq!(code.code(), num);
+        }
+    }
+
     // TODO: test reply code builder
 
     #[test]
@@ -682,7 +728,7 @@ mod tests {
             println!("Test: {:?}", inp);
             let res = inp
                 .as_io_slices()
-                .flat_map(|s| s.to_owned().into_iter())
+                .flat_map(|s| s.to_vec().into_iter())
                 .collect::<Vec<u8>>();
             println!("Result  : {:?}", show_bytes(&res));
             println!("Expected: {:?}", show_bytes(out));
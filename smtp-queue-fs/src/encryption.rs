@@ -0,0 +1,614 @@
+use std::{
+    cmp,
+    collections::HashMap,
+    convert::TryInto,
+    fmt, io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use chacha20poly1305::{
+    aead::{Aead, NewAead, Payload},
+    Key, XChaCha20Poly1305, XNonce,
+};
+use futures::{prelude::*, ready};
+use rand::{rngs::OsRng, RngCore};
+
+// Encrypted files are made of a header followed by chunks encrypted with
+// XChaCha20-Poly1305, following the STREAM construction: the nonce of each
+// chunk is the nonce prefix from the header, followed by the big-endian
+// index of the chunk and a byte that is 1 only for the last chunk. All the
+// chunks are authenticated along with the header, the name of the file and
+// the UUID of the mail, so that they cannot be moved around.
+//
+// All the chunks but the last one hold exactly `CHUNK_LEN` bytes of
+// plaintext, and the last one holds strictly less, so that truncating the
+// file at a chunk boundary is detected.
+
+const MAGIC: &'static [u8] = b"yuubind";
+const FORMAT_VERSION: u8 = 1;
+const NONCE_PREFIX_LEN: usize = 19;
+const HEADER_LEN: usize = MAGIC.len() + 1 + 4 + NONCE_PREFIX_LEN;
+const CHUNK_LEN: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+
+/// Identifier of an encryption key, written in the header of the files it
+/// encrypted so that the right key can be used for decrypting them
+pub type KeyId = u32;
+
+/// The set of keys known to the storage
+///
+/// New mails are encrypted with the current key, while the other keys are
+/// only used for reading mails that were enqueued before a key rotation.
+#[derive(Clone)]
+pub struct EncryptionKeys {
+    current: KeyId,
+    keys: HashMap<KeyId, Arc<XChaCha20Poly1305>>,
+}
+
+impl EncryptionKeys {
+    pub fn new(id: KeyId, key: [u8; 32]) -> EncryptionKeys {
+        let mut keys = HashMap::new();
+        keys.insert(id, Arc::new(XChaCha20Poly1305::new(&Key::from(key))));
+        EncryptionKeys { current: id, keys }
+    }
+
+    /// Adds a key that will only be used for decrypting already-enqueued mails
+    ///
+    /// Adding a key with the same ID as the current key is ignored.
+    pub fn with_old_key(mut self, id: KeyId, key: [u8; 32]) -> EncryptionKeys {
+        if id != self.current {
+            let cipher = Arc::new(XChaCha20Poly1305::new(&Key::from(key)));
+            self.keys.insert(id, cipher);
+        }
+        self
+    }
+}
+
+impl fmt::Debug for EncryptionKeys {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Never print out the keys themselves
+        let mut ids = self.keys.keys().collect::<Vec<_>>();
+        ids.sort();
+        f.debug_struct("EncryptionKeys")
+            .field("current", &self.current)
+            .field("keys", &ids)
+            .finish()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct EncryptionConfig {
+    pub keys: EncryptionKeys,
+
+    /// Accept reading mails that were not encrypted
+    ///
+    /// This is required for migrating a queue to encryption, but allows an
+    /// attacker to replace an encrypted mail with an unencrypted one.
+    pub allow_unencrypted: bool,
+}
+
+/// Error returned (wrapped in an `io::Error` of kind `InvalidData`) when an
+/// encrypted mail cannot be trusted
+#[derive(Debug)]
+pub enum IntegrityError {
+    /// The mail was encrypted with a key that is not known to the storage
+    UnknownKey(KeyId),
+
+    /// The mail was encrypted with an unknown version of the format
+    UnknownVersion(u8),
+
+    /// The mail was not encrypted, and unencrypted mails are not allowed
+    NotEncrypted,
+
+    /// The mail was tampered with, truncated, or moved from another mail
+    Corrupted,
+}
+
+impl fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IntegrityError::UnknownKey(id) => {
+                write!(f, "mail is encrypted with unknown key {}", id)
+            }
+            IntegrityError::UnknownVersion(v) => {
+                write!(f, "mail is encrypted with unknown format version {}", v)
+            }
+            IntegrityError::NotEncrypted => write!(f, "mail is not encrypted"),
+            IntegrityError::Corrupted => write!(f, "encrypted mail failed integrity check"),
+        }
+    }
+}
+
+impl std::error::Error for IntegrityError {}
+
+impl From<IntegrityError> for io::Error {
+    fn from(e: IntegrityError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+pub(crate) struct StreamCipher {
+    cipher: Arc<XChaCha20Poly1305>,
+    nonce_prefix: [u8; NONCE_PREFIX_LEN],
+    aad: Vec<u8>,
+    counter: u32,
+    finished: bool,
+}
+
+impl StreamCipher {
+    /// Prepares encrypting file `file` of mail `uuid` with the current key
+    pub(crate) fn encryptor(
+        config: &EncryptionConfig,
+        file: &str,
+        uuid: &str,
+    ) -> io::Result<StreamCipher> {
+        let mut nonce_prefix = [0; NONCE_PREFIX_LEN];
+        OsRng
+            .try_fill_bytes(&mut nonce_prefix)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(MAGIC);
+        header.push(FORMAT_VERSION);
+        header.extend_from_slice(&config.keys.current.to_be_bytes());
+        header.extend_from_slice(&nonce_prefix);
+        let cipher = config.keys.keys[&config.keys.current].clone();
+        Ok(StreamCipher::new(cipher, nonce_prefix, header, file, uuid))
+    }
+
+    /// Prepares decrypting file `file` of mail `uuid`, that starts with
+    /// `header`
+    pub(crate) fn decryptor(
+        config: &EncryptionConfig,
+        header: &[u8; HEADER_LEN],
+        file: &str,
+        uuid: &str,
+    ) -> Result<StreamCipher, IntegrityError> {
+        if !header.starts_with(MAGIC) {
+            return Err(IntegrityError::Corrupted);
+        }
+        let version = header[MAGIC.len()];
+        if version != FORMAT_VERSION {
+            return Err(IntegrityError::UnknownVersion(version));
+        }
+        let key_id = &header[MAGIC.len() + 1..MAGIC.len() + 5];
+        let key_id = KeyId::from_be_bytes(key_id.try_into().unwrap());
+        let cipher = config
+            .keys
+            .keys
+            .get(&key_id)
+            .ok_or(IntegrityError::UnknownKey(key_id))?
+            .clone();
+        let nonce_prefix = header[MAGIC.len() + 5..].try_into().unwrap();
+        Ok(StreamCipher::new(
+            cipher,
+            nonce_prefix,
+            header.to_vec(),
+            file,
+            uuid,
+        ))
+    }
+
+    fn new(
+        cipher: Arc<XChaCha20Poly1305>,
+        nonce_prefix: [u8; NONCE_PREFIX_LEN],
+        header: Vec<u8>,
+        file: &str,
+        uuid: &str,
+    ) -> StreamCipher {
+        let mut aad = header;
+        aad.extend_from_slice(file.as_bytes());
+        aad.push(0);
+        aad.extend_from_slice(uuid.as_bytes());
+        StreamCipher {
+            cipher,
+            nonce_prefix,
+            aad,
+            counter: 0,
+            finished: false,
+        }
+    }
+
+    fn header(&self) -> &[u8] {
+        &self.aad[..HEADER_LEN]
+    }
+
+    fn next_nonce(&mut self, last: bool) -> Option<XNonce> {
+        if self.finished {
+            return None;
+        }
+        let mut nonce = XNonce::default();
+        nonce[..NONCE_PREFIX_LEN].copy_from_slice(&self.nonce_prefix);
+        nonce[NONCE_PREFIX_LEN..NONCE_PREFIX_LEN + 4].copy_from_slice(&self.counter.to_be_bytes());
+        nonce[NONCE_PREFIX_LEN + 4] = last as u8;
+        match self.counter.checked_add(1) {
+            Some(c) => self.counter = c,
+            None => self.finished = true,
+        }
+        self.finished |= last;
+        Some(nonce)
+    }
+
+    fn seal(&mut self, chunk: &[u8], last: bool) -> io::Result<Vec<u8>> {
+        let nonce = self.next_nonce(last).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::Other, "too much data for a single mail")
+        })?;
+        let payload = Payload {
+            msg: chunk,
+            aad: &self.aad,
+        };
+        self.cipher
+            .encrypt(&nonce, payload)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "failed encrypting mail"))
+    }
+
+    fn open(&mut self, chunk: &[u8], last: bool) -> Result<Vec<u8>, IntegrityError> {
+        let nonce = self.next_nonce(last).ok_or(IntegrityError::Corrupted)?;
+        let payload = Payload {
+            msg: chunk,
+            aad: &self.aad,
+        };
+        self.cipher
+            .decrypt(&nonce, payload)
+            .map_err(|_| IntegrityError::Corrupted)
+    }
+}
+
+/// Encrypts a whole file at once
+pub(crate) fn seal_file(
+    config: &EncryptionConfig,
+    file: &str,
+    uuid: &str,
+    data: &[u8],
+) -> io::Result<Vec<u8>> {
+    let mut cipher = StreamCipher::encryptor(config, file, uuid)?;
+    let mut res = Vec::with_capacity(HEADER_LEN + data.len() + TAG_LEN);
+    res.extend_from_slice(cipher.header());
+    let mut chunks = data.chunks_exact(CHUNK_LEN);
+    for chunk in &mut chunks {
+        res.extend_from_slice(&cipher.seal(chunk, false)?);
+    }
+    res.extend_from_slice(&cipher.seal(chunks.remainder(), true)?);
+    Ok(res)
+}
+
+/// Decrypts a whole file at once
+///
+/// Returns `None` if the file is not encrypted and this is allowed by the
+/// configuration.
+pub(crate) fn open_file(
+    config: Option<&EncryptionConfig>,
+    file: &str,
+    uuid: &str,
+    data: &[u8],
+) -> Result<Option<Vec<u8>>, IntegrityError> {
+    if !data.starts_with(MAGIC) {
+        return match config {
+            Some(c) if !c.allow_unencrypted => Err(IntegrityError::NotEncrypted),
+            _ => Ok(None),
+        };
+    }
+    if data.len() < HEADER_LEN {
+        return Err(IntegrityError::Corrupted);
+    }
+    let header = data[..HEADER_LEN].try_into().unwrap();
+    let mut cipher = match config {
+        Some(c) => StreamCipher::decryptor(c, header, file, uuid)?,
+        None => {
+            let key_id = &header[MAGIC.len() + 1..MAGIC.len() + 5];
+            let key_id = KeyId::from_be_bytes(key_id.try_into().unwrap());
+            return Err(IntegrityError::UnknownKey(key_id));
+        }
+    };
+    let mut res = Vec::with_capacity(data.len() - HEADER_LEN);
+    let mut chunks = data[HEADER_LEN..].chunks(CHUNK_LEN + TAG_LEN);
+    loop {
+        let chunk = chunks.next().unwrap_or(&[]);
+        let last = chunk.len() < CHUNK_LEN + TAG_LEN;
+        res.extend_from_slice(&cipher.open(chunk, last)?);
+        if last {
+            return Ok(Some(res));
+        }
+    }
+}
+
+/// Reads the header of an encrypted file, and prepares decrypting the rest
+pub(crate) fn read_header<R: io::Read>(
+    config: &EncryptionConfig,
+    file: &str,
+    uuid: &str,
+    reader: &mut R,
+) -> io::Result<StreamCipher> {
+    let mut header = [0; HEADER_LEN];
+    match reader.read_exact(&mut header) {
+        Ok(()) => (),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            return Err(IntegrityError::Corrupted.into())
+        }
+        Err(e) => return Err(e),
+    }
+    Ok(StreamCipher::decryptor(config, &header, file, uuid)?)
+}
+
+pub(crate) struct EncryptingWriter<W> {
+    inner: W,
+    cipher: StreamCipher,
+    plain: Vec<u8>,
+    out: Vec<u8>,
+    out_pos: usize,
+    closed: bool,
+}
+
+impl<W> EncryptingWriter<W> {
+    pub(crate) fn new(inner: W, cipher: StreamCipher) -> EncryptingWriter<W> {
+        let out = cipher.header().to_vec();
+        EncryptingWriter {
+            inner,
+            cipher,
+            plain: Vec::with_capacity(CHUNK_LEN),
+            out,
+            out_pos: 0,
+            closed: false,
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> EncryptingWriter<W> {
+    fn poll_write_out(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        while self.out_pos < self.out.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.out[self.out_pos..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.out_pos += n;
+        }
+        self.out.clear();
+        self.out_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for EncryptingWriter<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_out(cx))?;
+        if this.closed {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::Other,
+                "wrote to a closed mail",
+            )));
+        }
+        let len = cmp::min(buf.len(), CHUNK_LEN - this.plain.len());
+        this.plain.extend_from_slice(&buf[..len]);
+        if this.plain.len() == CHUNK_LEN {
+            this.out = this.cipher.seal(&this.plain, false)?;
+            this.plain.clear();
+        }
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_out(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_out(cx))?;
+        if !this.closed {
+            this.out = this.cipher.seal(&this.plain, true)?;
+            this.plain.clear();
+            this.closed = true;
+            ready!(this.poll_write_out(cx))?;
+        }
+        Pin::new(&mut this.inner).poll_close(cx)
+    }
+}
+
+pub(crate) struct DecryptingReader<R> {
+    inner: R,
+    cipher: StreamCipher,
+    buf: Vec<u8>,
+    filled: usize,
+    plain: Vec<u8>,
+    plain_pos: usize,
+    done: bool,
+}
+
+impl<R> DecryptingReader<R> {
+    /// `inner` must be positioned just after the header that was used for
+    /// building `cipher`
+    pub(crate) fn new(inner: R, cipher: StreamCipher) -> DecryptingReader<R> {
+        DecryptingReader {
+            inner,
+            cipher,
+            buf: vec![0; CHUNK_LEN + TAG_LEN],
+            filled: 0,
+            plain: Vec::new(),
+            plain_pos: 0,
+            done: false,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for DecryptingReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            if this.plain_pos < this.plain.len() {
+                let len = cmp::min(buf.len(), this.plain.len() - this.plain_pos);
+                buf[..len].copy_from_slice(&this.plain[this.plain_pos..this.plain_pos + len]);
+                this.plain_pos += len;
+                return Poll::Ready(Ok(len));
+            }
+            if this.done {
+                return Poll::Ready(Ok(0));
+            }
+
+            let mut eof = false;
+            while this.filled < this.buf.len() {
+                let buf = &mut this.buf[this.filled..];
+                match ready!(Pin::new(&mut this.inner).poll_read(cx, buf))? {
+                    0 => {
+                        eof = true;
+                        break;
+                    }
+                    n => this.filled += n,
+                }
+            }
+
+            // A full chunk is never the last one, see the format description
+            this.plain = this.cipher.open(&this.buf[..this.filled], eof)?;
+            this.plain_pos = 0;
+            this.filled = 0;
+            this.done = eof;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::{executor, io::Cursor};
+
+    fn config(allow_unencrypted: bool) -> EncryptionConfig {
+        EncryptionConfig {
+            keys: EncryptionKeys::new(2, [2; 32]).with_old_key(1, [1; 32]),
+            allow_unencrypted,
+        }
+    }
+
+    fn is_corrupted(r: Result<Option<Vec<u8>>, IntegrityError>) -> bool {
+        matches!(r, Err(IntegrityError::Corrupted))
+    }
+
+    #[test]
+    fn file_roundtrip() {
+        let cfg = config(false);
+        for len in &[0, 10, CHUNK_LEN, CHUNK_LEN + 1, 3 * CHUNK_LEN] {
+            let data = (0..*len).map(|i| i as u8).collect::<Vec<u8>>();
+            let sealed = seal_file(&cfg, "metadata", "uuid", &data).unwrap();
+            let opened = open_file(Some(&cfg), "metadata", "uuid", &sealed).unwrap();
+            assert_eq!(opened, Some(data));
+        }
+    }
+
+    #[test]
+    fn file_tampering() {
+        let cfg = config(true);
+        let data = vec![42; 2 * CHUNK_LEN + 12];
+        let sealed = seal_file(&cfg, "metadata", "uuid", &data).unwrap();
+
+        assert!(is_corrupted(open_file(
+            Some(&cfg),
+            "metadata",
+            "other-uuid",
+            &sealed
+        )));
+        assert!(is_corrupted(open_file(
+            Some(&cfg),
+            "contents",
+            "uuid",
+            &sealed
+        )));
+
+        let mut flipped = sealed.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        assert!(is_corrupted(open_file(
+            Some(&cfg),
+            "metadata",
+            "uuid",
+            &flipped
+        )));
+
+        for len in &[HEADER_LEN + CHUNK_LEN + TAG_LEN, sealed.len() - 1] {
+            assert!(is_corrupted(open_file(
+                Some(&cfg),
+                "metadata",
+                "uuid",
+                &sealed[..*len]
+            )));
+        }
+    }
+
+    #[test]
+    fn file_keys_and_unencrypted() {
+        let old = EncryptionConfig {
+            keys: EncryptionKeys::new(1, [1; 32]),
+            allow_unencrypted: false,
+        };
+        let sealed = seal_file(&old, "metadata", "uuid", b"hello").unwrap();
+        let opened = open_file(Some(&config(false)), "metadata", "uuid", &sealed).unwrap();
+        assert_eq!(opened, Some(b"hello".to_vec()));
+
+        let unknown = EncryptionConfig {
+            keys: EncryptionKeys::new(3, [3; 32]),
+            allow_unencrypted: false,
+        };
+        let sealed = seal_file(&unknown, "metadata", "uuid", b"hello").unwrap();
+        assert!(matches!(
+            open_file(Some(&config(false)), "metadata", "uuid", &sealed),
+            Err(IntegrityError::UnknownKey(3))
+        ));
+        assert!(matches!(
+            open_file(None, "metadata", "uuid", &sealed),
+            Err(IntegrityError::UnknownKey(3))
+        ));
+
+        assert!(matches!(
+            open_file(Some(&config(false)), "metadata", "uuid", b"{}"),
+            Err(IntegrityError::NotEncrypted)
+        ));
+        assert_eq!(
+            open_file(Some(&config(true)), "metadata", "uuid", b"{}").unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn stream_roundtrip() {
+        let cfg = config(false);
+        let data = (0..3 * CHUNK_LEN + 17)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<u8>>();
+        executor::block_on(async {
+            for len in &[0, 17, CHUNK_LEN, data.len()] {
+                let cipher = StreamCipher::encryptor(&cfg, "contents", "uuid").unwrap();
+                let mut writer = EncryptingWriter::new(Cursor::new(Vec::new()), cipher);
+                for chunk in data[..*len].chunks(1000) {
+                    writer.write_all(chunk).await.unwrap();
+                }
+                writer.close().await.unwrap();
+                let sealed = writer.inner.into_inner();
+
+                // Both ways of writing files are compatible
+                let opened = open_file(Some(&cfg), "contents", "uuid", &sealed).unwrap();
+                assert_eq!(opened.as_deref(), Some(&data[..*len]));
+
+                let mut sealed = io::Cursor::new(sealed);
+                let cipher = read_header(&cfg, "contents", "uuid", &mut sealed).unwrap();
+                let pos = sealed.position() as usize;
+                let sealed = sealed.into_inner();
+                let mut reader = DecryptingReader::new(Cursor::new(&sealed[pos..]), cipher);
+                let mut opened = Vec::new();
+                reader.read_to_end(&mut opened).await.unwrap();
+                assert_eq!(opened, &data[..*len]);
+
+                let mut truncated = io::Cursor::new(&sealed[..sealed.len() - 1]);
+                let cipher = read_header(&cfg, "contents", "uuid", &mut truncated).unwrap();
+                let mut reader =
+                    DecryptingReader::new(Cursor::new(&sealed[pos..sealed.len() - 1]), cipher);
+                let err = reader.read_to_end(&mut Vec::new()).await.unwrap_err();
+                let err = err.get_ref().unwrap().downcast_ref::<IntegrityError>();
+                assert!(matches!(err, Some(IntegrityError::Corrupted)));
+            }
+        });
+    }
+}
@@ -3,41 +3,312 @@ use std::{
     marker::PhantomData,
     path::{Path, PathBuf},
     pin::Pin,
-    sync::Arc,
+    sync::{Arc, PoisonError, RwLock, RwLockReadGuard},
     task::{Context, Poll},
 };
 
+use async_compression::futures::{bufread, write};
 use async_trait::async_trait;
 use futures::{io::IoSlice, prelude::*};
 use openat::Dir;
+use sha2::{Digest, Sha256};
 use smol::unblock;
-use smtp_queue::{MailMetadata, QueueId, ScheduleInfo};
+use smtp_message::Email;
+use smtp_queue::{MailMetadata, QueueFull, QueueId, ScheduleInfo};
 use uuid::Uuid;
 use walkdir::WalkDir;
 
+mod encryption;
+mod quota;
+
+use encryption::{DecryptingReader, EncryptingWriter, StreamCipher};
+pub use encryption::{EncryptionConfig, EncryptionKeys, IntegrityError, KeyId};
+use quota::{CountingWriter, QueueUsage, Reservation};
+
 pub const DATA_DIR: &'static str = "data";
 pub const QUEUE_DIR: &'static str = "queue";
 pub const INFLIGHT_DIR: &'static str = "inflight";
 pub const CLEANUP_DIR: &'static str = "cleanup";
+pub const BLOBS_DIR: &'static str = "blobs";
 
 pub const DATA_DIR_FROM_OTHER_QUEUE: &'static str = "../data";
 
 pub const CONTENTS_FILE: &'static str = "contents";
 pub const METADATA_FILE: &'static str = "metadata";
 pub const SCHEDULE_FILE: &'static str = "schedule";
+pub const BLOB_FILE: &'static str = "blob";
 pub const TMP_SCHEDULE_FILE_PREFIX: &'static str = "schedule.";
+pub const TMP_CONTENTS_FILE: &'static str = "contents.tmp";
+pub const TMP_SYMLINK_SUFFIX: &'static str = ".tmp";
+
+#[derive(Clone, Debug, Default)]
+pub struct FsStorageConfig {
+    /// Store the contents of the mails content-addressed in `<queue>/blobs`,
+    /// so that mails enqueued with the same contents share them on disk
+    pub deduplicate_contents: bool,
+
+    /// Compress the contents of the mails when enqueuing them
+    ///
+    /// The compression used is recorded in the metadata of each mail, so
+    /// changing this only affects newly-enqueued mails.
+    pub compression: Option<Compression>,
+
+    /// Encrypt the contents and metadata of the mails when enqueuing them
+    ///
+    /// This cannot be used along with contents deduplication.
+    pub encryption: Option<EncryptionConfig>,
+
+    /// Layout of the mails in the queue directories
+    ///
+    /// Mails stored with the other layout are still found, and can be moved
+    /// to this one with `FsStorage::migrate_layout`.
+    pub layout: FsLayout,
+
+    /// Refuse new mails once the contents of the mails in the queue take
+    /// this many bytes
+    ///
+    /// Contents shared between multiple mails are counted once per mail.
+    pub max_bytes: Option<u64>,
+
+    /// Refuse new mails once there are this many mails in the queue
+    pub max_mails: Option<u64>,
+
+    /// Refuse new mails once the filesystem holding the queue has less than
+    /// this many bytes available
+    pub min_free_bytes: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FsLayout {
+    /// Mails are directly in `data`, `queue`, `inflight` and `cleanup`
+    Flat,
+
+    /// Mails are in sub-directories named after the first two characters of
+    /// their (random) UUID, eg. `queue/ab/abcdef01-...`, so as to keep
+    /// directories small with lots of mails in the queue
+    Sharded,
+}
+
+impl Default for FsLayout {
+    fn default() -> FsLayout {
+        FsLayout::Flat
+    }
+}
+
+impl FsLayout {
+    fn other(self) -> FsLayout {
+        match self {
+            FsLayout::Flat => FsLayout::Sharded,
+            FsLayout::Sharded => FsLayout::Flat,
+        }
+    }
+
+    /// Path of the mail relative to any of the queue directories
+    fn mail_path(self, uuid: &str) -> String {
+        match self {
+            FsLayout::Flat => uuid.to_owned(),
+            FsLayout::Sharded => format!("{}/{}", &uuid[..2], uuid),
+        }
+    }
+
+    /// Target of the symlink to the data of a mail stored at `data_path` in
+    /// `<queue>/data`, for a symlink stored with this layout
+    fn symlink_target(self, data_path: &str) -> String {
+        match self {
+            FsLayout::Flat => format!("{}/{}", DATA_DIR_FROM_OTHER_QUEUE, data_path),
+            FsLayout::Sharded => format!("../{}/{}", DATA_DIR_FROM_OTHER_QUEUE, data_path),
+        }
+    }
+
+    /// Depth of the mails in the queue directories
+    fn depth(self) -> usize {
+        match self {
+            FsLayout::Flat => 1,
+            FsLayout::Sharded => 2,
+        }
+    }
+}
+
+/// Runs `f` on the path of mail `uuid` in `layout`, and then in the other
+/// layout if it was not found
+///
+/// Mails being moved from one layout to the other only while the layout
+/// lock is held for writing, this must be called with it held for reading.
+fn find_mail<T, F>(layout: FsLayout, uuid: &str, mut f: F) -> io::Result<T>
+where
+    F: FnMut(&str) -> io::Result<T>,
+{
+    match f(&layout.mail_path(uuid)) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => f(&layout.other().mail_path(uuid)),
+        r => r,
+    }
+}
+
+/// Moves mail `uuid`, whose symlink is in `dir`, from layout `from` to `to`
+///
+/// The symlink is first moved as-is, so that the mail is always in exactly
+/// one state, and then replaced with one that has the right target.
+fn migrate_mail(
+    lock: &RwLock<()>,
+    data: &Dir,
+    dir: &Dir,
+    from: FsLayout,
+    to: FsLayout,
+    uuid: &str,
+) -> io::Result<bool> {
+    let _lock = lock.write().unwrap_or_else(PoisonError::into_inner);
+    let from_path = from.mail_path(uuid);
+    let to_path = to.mail_path(uuid);
+    let tmp_path = format!("{}{}", to_path, TMP_SYMLINK_SUFFIX);
+
+    match openat::rename(data, &*from_path, data, &*to_path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => (),
+    }
+
+    match dir.remove_file(&*tmp_path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => (),
+    }
+    dir.symlink(&*tmp_path, to.symlink_target(&to_path))?;
+    match openat::rename(dir, &*from_path, dir, &*to_path) {
+        Ok(()) => (),
+        // The mail changed state since it was listed
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            dir.remove_file(&*tmp_path)?;
+            return Ok(false);
+        }
+        Err(e) => return Err(e),
+    }
+    dir.local_rename(&*tmp_path, &*to_path)?;
+    Ok(true)
+}
+
+/// Finishes moving mail `uuid` to layout `to`, if it was interrupted after
+/// the temporary symlink was created
+fn finish_migrating_mail(lock: &RwLock<()>, dir: &Dir, to: FsLayout, uuid: &str) -> io::Result<()> {
+    let _lock = lock.write().unwrap_or_else(PoisonError::into_inner);
+    let to_path = to.mail_path(uuid);
+    let tmp_path = format!("{}{}", to_path, TMP_SYMLINK_SUFFIX);
+    match dir.read_link(&*to_path) {
+        Ok(_) => dir.local_rename(&*tmp_path, &*to_path),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => match dir.remove_file(&*tmp_path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        },
+        Err(e) => Err(e),
+    }
+}
+
+/// Checks that the symlink to mail `uuid` at `path`, in any of the queue
+/// directories other than `<queue>/data`, points to the mail's data
+///
+/// The data may have been moved already if a layout migration was
+/// interrupted, so the data path of both layouts is accepted.
+fn check_symlink_target(path: &str, target: &Path, uuid: &str) -> io::Result<()> {
+    let layout = match path.contains('/') {
+        false => FsLayout::Flat,
+        true => FsLayout::Sharded,
+    };
+    let data_layouts = [FsLayout::Flat, FsLayout::Sharded];
+    match data_layouts
+        .iter()
+        .any(|l| Path::new(&layout.symlink_target(&l.mail_path(uuid))) == target)
+    {
+        true => Ok(()),
+        false => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "message symlink is outside the queue",
+        )),
+    }
+}
+
+fn read_lock(lock: &RwLock<()>) -> RwLockReadGuard<'_, ()> {
+    lock.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    fn name(&self) -> &'static str {
+        match self {
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        }
+    }
+}
+
+/// What is actually stored in the metadata file: the mail's metadata, along
+/// with the information needed to read its contents back
+///
+/// Mails enqueued before the storage information was added just do not have
+/// it, which is the same as storing the contents as-is.
+///
+/// The fields of `MailMetadata` are repeated here rather than flattened into
+/// it, as serde does not support all types of user metadata when flattened
+/// (eg. maps with non-string keys).
+#[derive(serde::Deserialize, serde::Serialize)]
+struct StoredMetadata<U> {
+    from: Option<Email>,
+    to: Vec<Email>,
+    metadata: U,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    compression: Option<Compression>,
+
+    /// UUID of the mail the encrypted contents are bound to, when they are
+    /// shared with other mails and this is not the one that wrote them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    contents_uuid: Option<String>,
+}
+
+impl<U> StoredMetadata<U> {
+    fn into_metadata(self) -> MailMetadata<U> {
+        MailMetadata {
+            from: self.from,
+            to: self.to,
+            metadata: self.metadata,
+        }
+    }
+}
 
+/// Storage of the queue in a directory of the local filesystem
+///
+/// The `QueueId` of a mail is its UUID, which does not depend on the layout
+/// it is stored with nor on its current state.
 pub struct FsStorage<U> {
     path: Arc<PathBuf>,
+    config: FsStorageConfig,
     data: Arc<Dir>,
     queue: Arc<Dir>,
     inflight: Arc<Dir>,
     cleanup: Arc<Dir>,
+    blobs: Option<Arc<Dir>>,
+    layout_lock: Arc<RwLock<()>>,
+    usage: Option<Arc<QueueUsage>>,
     phantom: PhantomData<U>,
 }
 
 impl<U> FsStorage<U> {
     pub async fn new(path: Arc<PathBuf>) -> io::Result<FsStorage<U>> {
+        FsStorage::with_config(path, FsStorageConfig::default()).await
+    }
+
+    pub async fn with_config(
+        path: Arc<PathBuf>,
+        config: FsStorageConfig,
+    ) -> io::Result<FsStorage<U>> {
+        if config.deduplicate_contents && config.encryption.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "contents deduplication cannot be used along with encryption",
+            ));
+        }
         let main_dir = {
             let path = path.clone();
             Arc::new(unblock!(Dir::open(&*path))?)
@@ -58,15 +329,139 @@ impl<U> FsStorage<U> {
             let main_dir = main_dir.clone();
             Arc::new(unblock!(main_dir.sub_dir(CLEANUP_DIR))?)
         };
+        // The blobs directory is opened even when not deduplicating, so that
+        // mails enqueued while deduplication was enabled still get cleaned up
+        let blobs = {
+            let main_dir = main_dir.clone();
+            let deduplicate = config.deduplicate_contents;
+            unblock!({
+                match main_dir.sub_dir(BLOBS_DIR) {
+                    Ok(d) => Ok(Some(Arc::new(d))),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound && !deduplicate => Ok(None),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                        main_dir.create_dir(BLOBS_DIR, 0o700)?;
+                        Ok(Some(Arc::new(main_dir.sub_dir(BLOBS_DIR)?)))
+                    }
+                    Err(e) => Err(e),
+                }
+            })?
+        };
+        if config.layout == FsLayout::Sharded {
+            let dirs = [
+                data.clone(),
+                queue.clone(),
+                inflight.clone(),
+                cleanup.clone(),
+            ];
+            unblock!({
+                for dir in dirs.iter() {
+                    for shard in 0..=0xFF {
+                        match dir.create_dir(&*format!("{:02x}", shard), 0o700) {
+                            Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+                            _ => (),
+                        }
+                    }
+                }
+                Ok::<_, io::Error>(())
+            })?;
+        }
+        // Computing the usage requires reading the whole queue, so it is only
+        // done when there are limits to enforce
+        let usage = match config.max_bytes.is_some() || config.max_mails.is_some() {
+            true => {
+                let data_path = path.join(DATA_DIR);
+                Some(Arc::new(unblock!(QueueUsage::scan(&data_path))?))
+            }
+            false => None,
+        };
         Ok(FsStorage {
             path,
+            config,
             data,
             queue,
             inflight,
             cleanup,
+            blobs,
+            layout_lock: Arc::new(RwLock::new(())),
+            usage,
             phantom: PhantomData,
         })
     }
+
+    /// Checks that `mails` more mails can be enqueued without going over the
+    /// configured limits
+    async fn check_limits(&self, mails: u64) -> io::Result<()> {
+        if let Some(ref usage) = self.usage {
+            if let Some(max_mails) = self.config.max_mails {
+                if usage.mails().saturating_add(mails) > max_mails {
+                    return Err(QueueFull.into());
+                }
+            }
+            if let Some(max_bytes) = self.config.max_bytes {
+                if usage.bytes() >= max_bytes {
+                    return Err(QueueFull.into());
+                }
+            }
+        }
+        if let Some(min_free_bytes) = self.config.min_free_bytes {
+            let data = self.data.clone();
+            if unblock!(quota::free_bytes(&data))? < min_free_bytes {
+                return Err(QueueFull.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves all the mails stored with the other layout to the configured one
+    ///
+    /// This can be run while this `FsStorage` is in use, and returns the
+    /// number of mails that were moved. Mails that change state while the
+    /// migration runs may be missed, and will be moved by the next migration.
+    ///
+    /// The lock that keeps other operations from seeing a mail while it is
+    /// being moved is only shared with the clones of this `FsStorage`: no
+    /// other process must be using the queue while this runs.
+    pub async fn migrate_layout(&self) -> io::Result<usize> {
+        let to = self.config.layout;
+        let from = to.other();
+        let mut migrated = 0;
+        let dirs = [
+            (QUEUE_DIR, &self.queue),
+            (INFLIGHT_DIR, &self.inflight),
+            (CLEANUP_DIR, &self.cleanup),
+        ];
+        for (name, dir) in dirs.iter() {
+            let path = self.path.join(name);
+            let symlinks = unblock!({
+                WalkDir::new(path)
+                    .min_depth(1)
+                    .max_depth(2)
+                    .into_iter()
+                    .filter(|e| e.as_ref().map(|e| e.path_is_symlink()).unwrap_or(true))
+                    .map(|e| {
+                        let e = e?;
+                        Ok((e.depth(), e.file_name().to_string_lossy().into_owned()))
+                    })
+                    .collect::<io::Result<Vec<(usize, String)>>>()
+            })?;
+            for (depth, name) in symlinks {
+                let data = self.data.clone();
+                let dir = (*dir).clone();
+                let lock = self.layout_lock.clone();
+                if depth == from.depth() && Uuid::parse_str(&name).is_ok() {
+                    let moved = unblock!(migrate_mail(&lock, &data, &dir, from, to, &name))?;
+                    migrated += moved as usize;
+                } else if depth == to.depth() && name.ends_with(TMP_SYMLINK_SUFFIX) {
+                    let uuid = &name[..name.len() - TMP_SYMLINK_SUFFIX.len()];
+                    if Uuid::parse_str(uuid).is_ok() {
+                        let uuid = uuid.to_owned();
+                        unblock!(finish_migrating_mail(&lock, &dir, to, &uuid))?;
+                    }
+                }
+            }
+        }
+        Ok(migrated)
+    }
 }
 
 #[async_trait]
@@ -92,9 +487,14 @@ where
     ) -> Pin<Box<dyn Send + Stream<Item = Result<FsQueuedMail, (io::Error, Option<QueueId>)>>>>
     {
         Box::pin(
-            scan_queue(self.path.join(QUEUE_DIR), self.queue.clone())
-                .await
-                .map(|r| r.map(FsQueuedMail::found)),
+            scan_queue(
+                self.path.join(QUEUE_DIR),
+                self.data.clone(),
+                self.config.layout,
+                self.layout_lock.clone(),
+            )
+            .await
+            .map(|r| r.map(FsQueuedMail::found)),
         )
     }
 
@@ -103,9 +503,14 @@ where
     ) -> Pin<Box<dyn Send + Stream<Item = Result<FsInflightMail, (io::Error, Option<QueueId>)>>>>
     {
         Box::pin(
-            scan_queue(self.path.join(INFLIGHT_DIR), self.inflight.clone())
-                .await
-                .map(|r| r.map(FsInflightMail::found)),
+            scan_queue(
+                self.path.join(INFLIGHT_DIR),
+                self.data.clone(),
+                self.config.layout,
+                self.layout_lock.clone(),
+            )
+            .await
+            .map(|r| r.map(FsInflightMail::found)),
         )
     }
 
@@ -126,54 +531,198 @@ where
         mail: &FsInflightMail,
     ) -> Result<(MailMetadata<U>, Self::Reader), io::Error> {
         let mail_dir = {
-            let inflight = self.inflight.clone();
-            let mail = mail.id.0.clone();
-            Arc::new(unblock!(inflight.sub_dir(&*mail))?)
+            let data = self.data.clone();
+            let id = mail.id.0.clone();
+            let (layout, lock) = (self.config.layout, self.layout_lock.clone());
+            Arc::new(unblock!({
+                let _lock = read_lock(&lock);
+                find_mail(layout, &id, |p| data.sub_dir(p))
+            })?)
         };
-        let metadata = {
+        let uuid = (*mail.id.0).clone();
+        let encryption = self.config.encryption.clone();
+        let (stored, decryptor) = {
             let mail_dir = mail_dir.clone();
-            unblock!(
-                mail_dir
-                    .open_file(METADATA_FILE)
-                    .and_then(|f| serde_json::from_reader(f).map_err(io::Error::from))
-            )?
+            unblock!({
+                let mut metadata = Vec::new();
+                io::Read::read_to_end(&mut mail_dir.open_file(METADATA_FILE)?, &mut metadata)?;
+                let decrypted =
+                    encryption::open_file(encryption.as_ref(), METADATA_FILE, &uuid, &metadata)?;
+                let stored: StoredMetadata<U> =
+                    serde_json::from_slice(decrypted.as_ref().unwrap_or(&metadata))?;
+                // Contents are encrypted iff the metadata is
+                let decryptor = match (decrypted, encryption) {
+                    (Some(_), Some(encryption)) => {
+                        let mut contents = mail_dir.open_file(CONTENTS_FILE)?;
+                        let contents_uuid = stored.contents_uuid.as_ref().unwrap_or(&uuid);
+                        let decryptor = encryption::read_header(
+                            &encryption,
+                            CONTENTS_FILE,
+                            contents_uuid,
+                            &mut contents,
+                        )?;
+                        Some((contents, decryptor))
+                    }
+                    _ => None,
+                };
+                Ok::<_, io::Error>((stored, decryptor))
+            })?
         };
-        let reader = {
-            let mail_dir = mail_dir.clone();
-            let contents = unblock!(mail_dir.open_file(CONTENTS_FILE))?;
-            Box::pin(smol::Async::new(contents)?)
+        let contents: Pin<Box<dyn Send + AsyncRead>> = match decryptor {
+            Some((contents, decryptor)) => Box::pin(DecryptingReader::new(
+                smol::Unblock::new(contents),
+                decryptor,
+            )),
+            None => {
+                let mail_dir = mail_dir.clone();
+                let contents = unblock!(mail_dir.open_file(CONTENTS_FILE))?;
+                Box::pin(smol::Unblock::new(contents))
+            }
+        };
+        let reader: Self::Reader = match stored.compression {
+            None => contents,
+            Some(Compression::Gzip) => Box::pin(bufread::GzipDecoder::new(
+                futures::io::BufReader::new(contents),
+            )),
+            Some(Compression::Zstd) => Box::pin(bufread::ZstdDecoder::new(
+                futures::io::BufReader::new(contents),
+            )),
         };
-        Ok((metadata, reader))
+        Ok((stored.into_metadata(), reader))
+    }
+
+    async fn check_space(&self) -> io::Result<()> {
+        self.check_limits(1).await
     }
 
     async fn enqueue(
         &self,
-        metadata: MailMetadata<U>,
+        metadatas: Vec<MailMetadata<U>>,
         schedule: ScheduleInfo,
     ) -> io::Result<FsEnqueuer> {
+        self.check_limits(metadatas.len() as u64).await?;
+        let mails = metadatas.len() as u64;
+        let reservation = self
+            .usage
+            .clone()
+            .map(|usage| Arc::new(Reservation::new(usage, mails)));
+        let max_bytes = self.config.max_bytes;
         let data = self.data.clone();
         let queue = self.queue.clone();
+        let layout = self.config.layout;
+        let compression = self.config.compression;
+        let encryption = self.config.encryption.clone();
+        let (blobs, hasher) = match self.config.deduplicate_contents {
+            true => {
+                // Mails stored differently must not share a blob, so the way
+                // they are stored is part of the hash. Uncompressed mails are
+                // hashed as-is, like before compression was supported.
+                let mut hasher = Sha256::new();
+                if let Some(c) = compression {
+                    hasher.update(c.name().as_bytes());
+                    hasher.update(b"\0");
+                }
+                (self.blobs.clone(), Some(hasher))
+            }
+            false => (None, None),
+        };
 
         unblock!({
-            let mut uuid_buf: [u8; 45] = Uuid::encode_buffer();
-            let uuid = Uuid::new_v4()
-                .to_hyphenated_ref()
-                .encode_lower(&mut uuid_buf);
+            // Removes the mails created so far if anything fails
+            let mut mails = PartialMails {
+                data,
+                blobs,
+                layout,
+                uuids: Vec::with_capacity(metadatas.len()),
+                reservation: reservation.clone(),
+                committed: false,
+            };
+            let mut contents_file = None;
+            for metadata in metadatas {
+                let mut uuid_buf: [u8; 45] = Uuid::encode_buffer();
+                let uuid = Uuid::new_v4()
+                    .to_hyphenated_ref()
+                    .encode_lower(&mut uuid_buf);
 
-            data.create_dir(&*uuid, 0600)?;
-            let mail_dir = data.sub_dir(&*uuid)?;
+                let data = &mails.data;
+                let mail_path = layout.mail_path(uuid);
+                data.create_dir(&*mail_path, 0600)?;
+                let first_uuid = mails.uuids.first().cloned();
+                mails.uuids.push(uuid.to_owned());
+                let mail_dir = data.sub_dir(&*mail_path)?;
 
-            let schedule_file = mail_dir.new_file(SCHEDULE_FILE, 0600)?;
-            serde_json::to_writer(schedule_file, &schedule)?;
+                let schedule_file = mail_dir.new_file(SCHEDULE_FILE, 0600)?;
+                serde_json::to_writer(schedule_file, &schedule)?;
 
-            let metadata_file = mail_dir.new_file(METADATA_FILE, 0600)?;
-            serde_json::to_writer(metadata_file, &metadata)?;
+                let mut metadata_file = mail_dir.new_file(METADATA_FILE, 0600)?;
+                let contents_uuid = match encryption {
+                    Some(_) => first_uuid.clone(),
+                    None => None,
+                };
+                let metadata = serde_json::to_vec(&StoredMetadata {
+                    from: metadata.from,
+                    to: metadata.to,
+                    metadata: metadata.metadata,
+                    compression,
+                    contents_uuid,
+                })?;
+                let metadata = match encryption {
+                    Some(ref e) => encryption::seal_file(e, METADATA_FILE, uuid, &metadata)?,
+                    None => metadata,
+                };
+                io::Write::write_all(&mut metadata_file, &metadata)?;
 
-            let contents_file = mail_dir.new_file(CONTENTS_FILE, 0600)?;
+                // All the mails share the same contents file, through hard
+                // links, so that the filesystem does the reference counting
+                // and cleanup can remove each mail independently
+                match first_uuid {
+                    None => contents_file = Some(mail_dir.new_file(CONTENTS_FILE, 0600)?),
+                    Some(first_uuid) => {
+                        let first_mail_dir = data.sub_dir(&*layout.mail_path(&first_uuid))?;
+                        openat::hardlink(
+                            &first_mail_dir,
+                            CONTENTS_FILE,
+                            &mail_dir,
+                            CONTENTS_FILE,
+                        )?;
+                    }
+                }
+            }
+
+            let contents_file = contents_file.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "enqueued a mail with no metadata",
+                )
+            })?;
+            let contents_file: Pin<Box<dyn 'static + Send + AsyncWrite + Unpin>> = match reservation
+            {
+                // Regular files cannot be polled, so they are written to from
+                // a thread pool
+                None => Box::pin(smol::Unblock::new(contents_file)),
+                Some(reservation) => {
+                    let contents_file = smol::Unblock::new(contents_file);
+                    Box::pin(CountingWriter::new(contents_file, reservation, max_bytes))
+                }
+            };
+            let contents_file: Pin<Box<dyn 'static + Send + AsyncWrite>> = match encryption {
+                None => contents_file,
+                Some(ref e) => {
+                    // The contents are bound to the first mail, that wrote them
+                    let encryptor = StreamCipher::encryptor(e, CONTENTS_FILE, &mails.uuids[0])?;
+                    Box::pin(EncryptingWriter::new(contents_file, encryptor))
+                }
+            };
+            let writer: Pin<Box<dyn 'static + Send + AsyncWrite>> = match compression {
+                None => contents_file,
+                Some(Compression::Gzip) => Box::pin(write::GzipEncoder::new(contents_file)),
+                Some(Compression::Zstd) => Box::pin(write::ZstdEncoder::new(contents_file)),
+            };
             Ok(FsEnqueuer {
                 queue,
-                uuid: uuid.to_owned(),
-                writer: Box::pin(smol::Async::new(contents_file)?),
+                mails,
+                writer,
+                hasher,
                 schedule,
             })
         })
@@ -183,9 +732,13 @@ where
         mail.schedule = schedule;
 
         let mail_dir = {
-            let queue = self.queue.clone();
+            let data = self.data.clone();
             let id = mail.id.0.clone();
-            unblock!(queue.sub_dir(&*id))?
+            let (layout, lock) = (self.config.layout, self.layout_lock.clone());
+            unblock!({
+                let _lock = read_lock(&lock);
+                find_mail(layout, &id, |p| data.sub_dir(p))
+            })?
         };
 
         unblock!({
@@ -212,8 +765,11 @@ where
     ) -> Result<Option<FsInflightMail>, (FsQueuedMail, io::Error)> {
         let queue = self.queue.clone();
         let inflight = self.inflight.clone();
+        let (layout, lock) = (self.config.layout, self.layout_lock.clone());
         unblock!({
-            match openat::rename(&*queue, &*mail.id.0, &*inflight, &*mail.id.0) {
+            let _lock = read_lock(&lock);
+            let rename = |p: &str| openat::rename(&*queue, p, &*inflight, p);
+            match find_mail(layout, &mail.id.0, rename) {
                 Ok(()) => Ok(Some(mail.into_inflight())),
                 Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
                 Err(e) => Err((mail, e)),
@@ -227,8 +783,11 @@ where
     ) -> Result<Option<FsPendingCleanupMail>, (FsInflightMail, io::Error)> {
         let inflight = self.inflight.clone();
         let cleanup = self.cleanup.clone();
+        let (layout, lock) = (self.config.layout, self.layout_lock.clone());
         unblock!({
-            match openat::rename(&*inflight, &*mail.id.0, &*cleanup, &*mail.id.0) {
+            let _lock = read_lock(&lock);
+            let rename = |p: &str| openat::rename(&*inflight, p, &*cleanup, p);
+            match find_mail(layout, &mail.id.0, rename) {
                 Ok(()) => Ok(Some(mail.into_pending_cleanup())),
                 Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
                 Err(e) => Err((mail, e)),
@@ -242,8 +801,11 @@ where
     ) -> Result<Option<FsQueuedMail>, (FsInflightMail, io::Error)> {
         let inflight = self.inflight.clone();
         let queue = self.queue.clone();
+        let (layout, lock) = (self.config.layout, self.layout_lock.clone());
         unblock!({
-            match openat::rename(&*inflight, &*mail.id.0, &*queue, &*mail.id.0) {
+            let _lock = read_lock(&lock);
+            let rename = |p: &str| openat::rename(&*inflight, p, &*queue, p);
+            match find_mail(layout, &mail.id.0, rename) {
                 Ok(()) => Ok(Some(mail.into_queued())),
                 Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
                 Err(e) => Err((mail, e)),
@@ -257,8 +819,11 @@ where
     ) -> Result<Option<FsPendingCleanupMail>, (FsQueuedMail, io::Error)> {
         let queue = self.queue.clone();
         let cleanup = self.cleanup.clone();
+        let (layout, lock) = (self.config.layout, self.layout_lock.clone());
         unblock!({
-            match openat::rename(&*queue, &*mail.id.0, &*cleanup, &*mail.id.0) {
+            let _lock = read_lock(&lock);
+            let rename = |p: &str| openat::rename(&*queue, p, &*cleanup, p);
+            match find_mail(layout, &mail.id.0, rename) {
                 Ok(()) => Ok(Some(mail.into_pending_cleanup())),
                 Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
                 Err(e) => Err((mail, e)),
@@ -272,53 +837,38 @@ where
     ) -> Result<bool, (FsPendingCleanupMail, io::Error)> {
         let cleanup = self.cleanup.clone();
         let data = self.data.clone();
+        let blobs = self.blobs.clone();
+        let usage = self.usage.clone();
+        let (layout, lock) = (self.config.layout, self.layout_lock.clone());
         unblock!({
-            match cleanup.sub_dir(&*mail.id.0) {
-                Err(e) if e.kind() == io::ErrorKind::NotFound => (), // already removed
-                Err(e) => return Err((mail, e)),
-                Ok(mail_dir) => {
-                    match mail_dir.remove_file(CONTENTS_FILE) {
-                        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err((mail, e)),
-                        _ => (),
-                    }
-
-                    match mail_dir.remove_file(METADATA_FILE) {
-                        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err((mail, e)),
-                        _ => (),
-                    }
-
-                    match mail_dir.remove_file(SCHEDULE_FILE) {
-                        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err((mail, e)),
-                        _ => (),
+            let _lock = read_lock(&lock);
+            // The data is looked up from the mail's UUID, but a symlink that
+            // does not point to it means the queue is not in the state it
+            // should be, so it is better not to touch anything
+            let read_link = |p: &str| cleanup.read_link(p).map(|t| (p.to_owned(), t));
+            match find_mail(layout, &mail.id.0, read_link) {
+                Ok((path, target)) => {
+                    if let Err(e) = check_symlink_target(&path, &target, &mail.id.0) {
+                        return Err((mail, e));
                     }
                 }
-            };
-
-            let symlink_target = match cleanup.read_link(&*mail.id.0) {
-                Ok(t) => t,
-                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
                 Err(e) => return Err((mail, e)),
-            };
+            }
 
-            let path_in_data_dir = match symlink_target.strip_prefix(DATA_DIR_FROM_OTHER_QUEUE) {
-                Ok(p) => p,
-                Err(_) => {
-                    return Err((
-                        mail,
-                        io::Error::new(
-                            io::ErrorKind::InvalidData,
-                            "message symlink is outside the queue",
-                        ),
-                    ));
+            let find_data = |p: &str| data.sub_dir(p).map(|_| p.to_owned());
+            match find_mail(layout, &mail.id.0, find_data) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (), // already removed
+                Err(e) => return Err((mail, e)),
+                Ok(data_path) => {
+                    let usage = usage.as_deref();
+                    if let Err(e) = remove_mail_data(&data, &data_path, blobs.as_deref(), usage) {
+                        return Err((mail, e));
+                    }
                 }
-            };
-
-            match data.remove_dir(path_in_data_dir) {
-                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err((mail, e)),
-                _ => (),
             }
 
-            match cleanup.remove_file(&*mail.id.0) {
+            match find_mail(layout, &mail.id.0, |p| cleanup.remove_file(p)) {
                 Ok(()) => Ok(true),
                 Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
                 Err(e) => Err((mail, e)),
@@ -327,6 +877,143 @@ where
     }
 }
 
+/// Removes the folder of the mail at `data_path` in `<queue>/data`, removing
+/// what it accounted for from `usage` along the way
+///
+/// This can be called again on a mail whose removal was interrupted.
+fn remove_mail_data(
+    data: &Dir,
+    data_path: &str,
+    blobs: Option<&Dir>,
+    usage: Option<&QueueUsage>,
+) -> io::Result<()> {
+    let mail_dir = match data.sub_dir(data_path) {
+        Ok(d) => d,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    let contents_len = match mail_dir.metadata(CONTENTS_FILE) {
+        Ok(m) => m.stat().st_size as u64,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(e),
+    };
+    match mail_dir.remove_file(CONTENTS_FILE) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => (),
+    }
+    if let Some(usage) = usage {
+        usage.remove(contents_len, 0);
+    }
+
+    match mail_dir.remove_file(TMP_CONTENTS_FILE) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => (),
+    }
+
+    // The blob file must be removed only after the blob has been
+    // garbage-collected, so that a crash in-between leads to the garbage
+    // collection being retried
+    cleanup_blob(&mail_dir, blobs)?;
+
+    match mail_dir.remove_file(BLOB_FILE) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => (),
+    }
+
+    match mail_dir.remove_file(METADATA_FILE) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => (),
+    }
+
+    match mail_dir.remove_file(SCHEDULE_FILE) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => (),
+    }
+
+    match data.remove_dir(data_path) {
+        Ok(()) => {
+            if let Some(usage) = usage {
+                usage.remove(0, 1);
+            }
+            Ok(())
+        }
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        Err(_) => Ok(()),
+    }
+}
+
+/// Removes the blob referenced by the mail in `mail_dir`, if the mail was
+/// its last user
+///
+/// This relies on the link count of the blob: each mail using it holds a
+/// hard link to it as its contents, so once the only remaining link is the
+/// one in `<queue>/blobs` it can be removed. If a mail starts using it again
+/// concurrently, it will just keep its own link to the contents and the
+/// deduplication will be lost for the next mails, but no data will be lost.
+fn cleanup_blob(mail_dir: &Dir, blobs: Option<&Dir>) -> io::Result<()> {
+    let mut hash = String::new();
+    match mail_dir.open_file(BLOB_FILE) {
+        Ok(mut f) => {
+            io::Read::read_to_string(&mut f, &mut hash)?;
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    }
+    let blobs = blobs.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            "mail references a blob but the blobs directory is missing",
+        )
+    })?;
+    if !is_valid_blob_name(&hash) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "mail references an invalid blob name",
+        ));
+    }
+    match blobs.metadata(&*hash) {
+        Ok(m) if m.stat().st_nlink <= 1 => match blobs.remove_file(&*hash) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        },
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+fn is_valid_blob_name(name: &str) -> bool {
+    name.len() == 64 && name.bytes().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Makes the contents of the mail in `mail_dir` a hard link to the blob
+/// `hash`, creating the blob from the mail's contents if it does not exist yet
+fn link_to_blob(blobs: &Dir, hash: &str, mail_dir: &Dir) -> io::Result<()> {
+    let blob_file = mail_dir.new_file(BLOB_FILE, 0o600)?;
+    io::Write::write_all(&mut &blob_file, hash.as_bytes())?;
+    loop {
+        match openat::hardlink(mail_dir, CONTENTS_FILE, blobs, hash) {
+            Ok(()) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+            Err(e) => return Err(e),
+        }
+        match openat::hardlink(blobs, hash, mail_dir, TMP_CONTENTS_FILE) {
+            Ok(()) => (),
+            // The blob was garbage-collected in-between, retry creating it
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        }
+        mail_dir.local_rename(TMP_CONTENTS_FILE, CONTENTS_FILE)?;
+        // If the contents already were the blob, rename(2) is a no-op that
+        // leaves the temporary link in place
+        return match mail_dir.remove_file(TMP_CONTENTS_FILE) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        };
+    }
+}
+
 struct FoundMail {
     id: QueueId,
     schedule: ScheduleInfo,
@@ -338,18 +1025,23 @@ async fn scan_folder<P>(
 where
     P: 'static + Send + AsRef<Path>,
 {
-    let it = unblock!(WalkDir::new(path).into_iter());
+    // Mails are at depth 1 with the flat layout, and 2 with the sharded one
+    let it = unblock!(WalkDir::new(path).min_depth(1).max_depth(2).into_iter());
     smol::stream::iter(it)
         .then(move |p| async move {
             let p = p.map_err(|e| (io::Error::from(e), None))?;
             if !p.path_is_symlink() {
                 Ok(None)
             } else {
-                let path = p.path().to_str().ok_or((
+                let name = p.file_name().to_str().ok_or((
                     io::Error::new(io::ErrorKind::InvalidData, "file path is not utf-8"),
                     None,
                 ))?;
-                Ok(Some(QueueId::new(path)))
+                // Skip the temporary symlinks of interrupted layout migrations
+                match Uuid::parse_str(name) {
+                    Ok(_) => Ok(Some(QueueId::new(name))),
+                    Err(_) => Ok(None),
+                }
             }
         })
         .filter_map(|r| async move { r.transpose() })
@@ -357,20 +1049,26 @@ where
 
 async fn scan_queue<P>(
     path: P,
-    dir: Arc<Dir>,
+    data: Arc<Dir>,
+    layout: FsLayout,
+    layout_lock: Arc<RwLock<()>>,
 ) -> impl 'static + Send + Stream<Item = Result<FoundMail, (io::Error, Option<QueueId>)>>
 where
     P: 'static + Send + AsRef<Path>,
 {
     scan_folder(path).await.then(move |id| {
-        let dir = dir.clone();
+        let data = data.clone();
+        let layout_lock = layout_lock.clone();
         async move {
             let id = id?;
-            let schedule_path = Path::new(&*id.0).join(SCHEDULE_FILE);
-            let schedule = unblock!(
-                dir.open_file(&schedule_path)
-                    .and_then(|f| serde_json::from_reader(f).map_err(io::Error::from))
-            )
+            let uuid = id.0.clone();
+            let schedule = unblock!({
+                let _lock = read_lock(&layout_lock);
+                find_mail(layout, &uuid, |p| {
+                    data.open_file(&Path::new(p).join(SCHEDULE_FILE))
+                })
+                .and_then(|f| serde_json::from_reader(f).map_err(io::Error::from))
+            })
             .map_err(|e| (e, Some(id.clone())))?;
             Ok(FoundMail { id, schedule })
         }
@@ -459,33 +1157,104 @@ impl smtp_queue::PendingCleanupMail for FsPendingCleanupMail {
     }
 }
 
+/// Mails that are being enqueued, which are removed from `<queue>/data` and
+/// from the usage of the queue if dropped before being committed
+struct PartialMails {
+    data: Arc<Dir>,
+    blobs: Option<Arc<Dir>>,
+    layout: FsLayout,
+    uuids: Vec<String>,
+    reservation: Option<Arc<Reservation>>,
+    committed: bool,
+}
+
+impl PartialMails {
+    fn commit(&mut self) {
+        self.committed = true;
+    }
+}
+
+impl Drop for PartialMails {
+    fn drop(&mut self) {
+        if self.committed {
+            return;
+        }
+        for uuid in self.uuids.iter() {
+            let mail_path = self.layout.mail_path(uuid);
+            // There is no one to report the error to, and the mail not being
+            // referenced from any other queue directory will not be sent
+            let _ = remove_mail_data(&self.data, &mail_path, self.blobs.as_deref(), None);
+        }
+        if let Some(ref reservation) = self.reservation {
+            reservation.release();
+        }
+    }
+}
+
 pub struct FsEnqueuer {
     queue: Arc<Dir>,
-    uuid: String,
+    mails: PartialMails,
     writer: Pin<Box<dyn 'static + Send + AsyncWrite>>,
+    hasher: Option<Sha256>,
     schedule: ScheduleInfo,
 }
 
 #[async_trait]
 impl smtp_queue::StorageEnqueuer<FsQueuedMail> for FsEnqueuer {
-    async fn commit(mut self) -> io::Result<FsQueuedMail> {
-        self.flush().await?;
+    async fn commit(mut self) -> io::Result<Vec<FsQueuedMail>> {
+        // Closing is required for the compressed formats to write their final
+        // frames, and only flushes uncompressed contents
+        self.close().await?;
+        let (queue, mut partial, hasher) = (self.queue, self.mails, self.hasher);
+        let schedule = self.schedule;
         unblock!({
-            let mut symlink_value = String::from(DATA_DIR_FROM_OTHER_QUEUE);
-            symlink_value.push_str(&self.uuid);
-            self.queue.symlink(&self.uuid, symlink_value)?;
-
-            Ok(FsQueuedMail::found(FoundMail {
-                id: QueueId(Arc::new(self.uuid)),
-                schedule: self.schedule,
-            }))
+            let layout = partial.layout;
+            if let (Some(blobs), Some(hasher)) = (&partial.blobs, hasher) {
+                let hash = hasher
+                    .finalize()
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect::<String>();
+                for uuid in partial.uuids.iter() {
+                    let mail_dir = partial.data.sub_dir(&*layout.mail_path(uuid))?;
+                    link_to_blob(blobs, &hash, &mail_dir)?;
+                }
+            }
+
+            let mut mails: Vec<FsQueuedMail> = Vec::with_capacity(partial.uuids.len());
+            for uuid in partial.uuids.iter() {
+                let mail_path = layout.mail_path(uuid);
+                let symlink_value = layout.symlink_target(&mail_path);
+                if let Err(e) = queue.symlink(&*mail_path, symlink_value) {
+                    // Either all the mails are queued or none of them is, so
+                    // that a client retrying does not get duplicates
+                    for mail in mails {
+                        let _ = queue.remove_file(&*layout.mail_path(&mail.id.0));
+                    }
+                    return Err(e);
+                }
+
+                mails.push(FsQueuedMail::found(FoundMail {
+                    id: QueueId::new(uuid),
+                    schedule,
+                }));
+            }
+            partial.commit();
+            Ok(mails)
         })
     }
 }
 
 impl AsyncWrite for FsEnqueuer {
     fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
-        unsafe { self.map_unchecked_mut(|s| &mut s.writer) }.poll_write(cx, buf)
+        // This is OK, as `writer` is itself pinned and `hasher` is never
+        // considered pinned
+        let this = unsafe { self.get_unchecked_mut() };
+        let res = this.writer.as_mut().poll_write(cx, buf);
+        if let (Some(hasher), Poll::Ready(Ok(written))) = (&mut this.hasher, &res) {
+            hasher.update(&buf[..*written]);
+        }
+        res
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
@@ -501,6 +1270,507 @@ impl AsyncWrite for FsEnqueuer {
         cx: &mut Context,
         bufs: &[IoSlice],
     ) -> Poll<io::Result<usize>> {
-        unsafe { self.map_unchecked_mut(|s| &mut s.writer) }.poll_write_vectored(cx, bufs)
+        // See poll_write
+        let this = unsafe { self.get_unchecked_mut() };
+        let res = this.writer.as_mut().poll_write_vectored(cx, bufs);
+        if let (Some(hasher), Poll::Ready(Ok(written))) = (&mut this.hasher, &res) {
+            let mut remaining = *written;
+            for buf in bufs {
+                let len = std::cmp::min(remaining, buf.len());
+                hasher.update(&buf[..len]);
+                remaining -= len;
+            }
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use smtp_queue::{QueuedMail, Storage, StorageEnqueuer};
+
+    fn schedule() -> ScheduleInfo {
+        ScheduleInfo {
+            at: chrono::Utc::now(),
+            last_attempt: None,
+        }
+    }
+
+    fn meta(metadata: &str) -> MailMetadata<String> {
+        MailMetadata {
+            from: None,
+            to: Vec::new(),
+            metadata: metadata.to_owned(),
+        }
+    }
+
+    /// Opens a storage in `path`, creating its directories if needed
+    fn storage<U>(path: &Path, config: FsStorageConfig) -> FsStorage<U> {
+        for dir in &[DATA_DIR, QUEUE_DIR, INFLIGHT_DIR, CLEANUP_DIR] {
+            match fs::create_dir(path.join(dir)) {
+                Err(e) if e.kind() != io::ErrorKind::AlreadyExists => panic!("{}", e),
+                _ => (),
+            }
+        }
+        smol::run(FsStorage::with_config(Arc::new(path.to_owned()), config)).unwrap()
+    }
+
+    /// Names of the entries of `dir`, sorted, with the shards flattened
+    fn entries(dir: &Path) -> Vec<String> {
+        let mut res = WalkDir::new(dir)
+            .min_depth(1)
+            .into_iter()
+            .map(|e| e.unwrap())
+            .filter(|e| !e.file_type().is_dir() || e.depth() > 1 || e.file_name().len() > 2)
+            .map(|e| {
+                let path = e.path().strip_prefix(dir).unwrap();
+                path.to_string_lossy().into_owned()
+            })
+            .collect::<Vec<_>>();
+        res.sort();
+        res
+    }
+
+    #[test]
+    fn commit_is_all_or_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path(), FsStorageConfig::default());
+        smol::run(async {
+            let metas = vec![meta("a"), meta("b"), meta("c")];
+            let mut enqueuer = storage.enqueue(metas, schedule()).await.unwrap();
+            enqueuer.write_all(b"Hello").await.unwrap();
+            // Make queuing the last mail fail
+            let last = enqueuer.mails.uuids[2].clone();
+            fs::write(dir.path().join(QUEUE_DIR).join(&last), b"").unwrap();
+            assert!(enqueuer.commit().await.is_err());
+            assert_eq!(entries(&dir.path().join(QUEUE_DIR)), vec![last.clone()]);
+            assert!(entries(&dir.path().join(DATA_DIR)).is_empty());
+
+            fs::remove_file(dir.path().join(QUEUE_DIR).join(&last)).unwrap();
+            let metas = vec![meta("a"), meta("b")];
+            let mut enqueuer = storage.enqueue(metas, schedule()).await.unwrap();
+            enqueuer.write_all(b"Hello").await.unwrap();
+            let mails = enqueuer.commit().await.unwrap();
+            let mut ids = mails
+                .iter()
+                .map(|m| (*m.id().0).clone())
+                .collect::<Vec<_>>();
+            ids.sort();
+            assert_eq!(entries(&dir.path().join(QUEUE_DIR)), ids);
+        });
+    }
+
+    /// Drops and cleans up `mail`, returning the remaining links to the blob
+    /// `hash`, if it still exists
+    fn drop_mail(
+        storage: &FsStorage<String>,
+        dir: &Path,
+        mail: FsQueuedMail,
+        hash: &str,
+    ) -> Option<u64> {
+        smol::run(async {
+            let mail = storage
+                .drop(mail)
+                .await
+                .map_err(|(_, e)| e)
+                .unwrap()
+                .unwrap();
+            assert!(storage.cleanup(mail).await.map_err(|(_, e)| e).unwrap());
+        });
+        match fs::metadata(dir.join(BLOBS_DIR).join(hash)) {
+            Ok(m) => Some(std::os::unix::fs::MetadataExt::nlink(&m)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    fn enqueue(
+        storage: &FsStorage<String>,
+        metas: Vec<&str>,
+        contents: &[u8],
+    ) -> Vec<FsQueuedMail> {
+        smol::run(async {
+            let metas = metas.into_iter().map(meta).collect();
+            let mut enqueuer = storage.enqueue(metas, schedule()).await.unwrap();
+            enqueuer.write_all(contents).await.unwrap();
+            enqueuer.commit().await.unwrap()
+        })
+    }
+
+    #[test]
+    fn blobs_are_shared_until_the_last_mail_is_cleaned_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = FsStorageConfig {
+            deduplicate_contents: true,
+            ..FsStorageConfig::default()
+        };
+        let storage = storage(dir.path(), config);
+        let mut mails = enqueue(&storage, vec!["a", "b"], b"Hello");
+        mails.extend(enqueue(&storage, vec!["c"], b"Hello"));
+        let other = enqueue(&storage, vec!["d"], b"Other").pop().unwrap();
+        let blobs = entries(&dir.path().join(BLOBS_DIR));
+        assert_eq!(blobs.len(), 2);
+
+        let hash = blobs
+            .into_iter()
+            .find(|h| fs::read(dir.path().join(BLOBS_DIR).join(h)).unwrap() == b"Hello")
+            .unwrap();
+        let mut expected_links = vec![Some(3), Some(2), None];
+        for mail in mails {
+            let contents = dir
+                .path()
+                .join(DATA_DIR)
+                .join(&*mail.id().0)
+                .join(CONTENTS_FILE);
+            assert_eq!(fs::read(contents).unwrap(), b"Hello");
+            assert_eq!(
+                drop_mail(&storage, dir.path(), mail, &hash),
+                expected_links.remove(0)
+            );
+        }
+        assert_eq!(entries(&dir.path().join(BLOBS_DIR)).len(), 1);
+        let other_id = (*other.id().0).clone();
+        drop_mail(&storage, dir.path(), other, &hash);
+        assert!(entries(&dir.path().join(BLOBS_DIR)).is_empty());
+        assert!(!dir.path().join(DATA_DIR).join(other_id).exists());
+    }
+
+    #[test]
+    fn blob_is_collected_after_crash_between_link_and_rename() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = FsStorageConfig {
+            deduplicate_contents: true,
+            ..FsStorageConfig::default()
+        };
+        let storage = storage(dir.path(), config);
+        let mail = enqueue(&storage, vec!["a"], b"Hello").pop().unwrap();
+        let hash = entries(&dir.path().join(BLOBS_DIR)).pop().unwrap();
+
+        // Put the mail back in the state link_to_blob leaves it in when
+        // interrupted before renaming the link to the blob over its contents
+        let mail_dir = dir.path().join(DATA_DIR).join(&*mail.id().0);
+        fs::remove_file(mail_dir.join(CONTENTS_FILE)).unwrap();
+        fs::write(mail_dir.join(CONTENTS_FILE), b"Hello").unwrap();
+        fs::hard_link(
+            dir.path().join(BLOBS_DIR).join(&hash),
+            mail_dir.join(TMP_CONTENTS_FILE),
+        )
+        .unwrap();
+
+        assert_eq!(drop_mail(&storage, dir.path(), mail, &hash), None);
+        assert!(!mail_dir.exists());
+    }
+
+    /// Reads back the metadata and contents of a queued mail
+    fn read_back<U>(storage: &FsStorage<U>, mail: FsQueuedMail) -> (MailMetadata<U>, Vec<u8>)
+    where
+        U: 'static + Send + Sync + for<'a> serde::Deserialize<'a> + serde::Serialize,
+    {
+        smol::run(async {
+            let mail = storage
+                .send_start(mail)
+                .await
+                .map_err(|(_, e)| e)
+                .unwrap()
+                .unwrap();
+            let (meta, mut reader) = storage.read_inflight(&mail).await.unwrap();
+            let mut contents = Vec::new();
+            reader.read_to_end(&mut contents).await.unwrap();
+            (meta, contents)
+        })
+    }
+
+    #[test]
+    fn compressed_mails_round_trip() {
+        let tests = vec![None, Some(Compression::Gzip), Some(Compression::Zstd)];
+        for compression in tests {
+            println!("Test: {:?}", compression);
+            let dir = tempfile::tempdir().unwrap();
+            let config = FsStorageConfig {
+                compression,
+                ..FsStorageConfig::default()
+            };
+            let storage = storage(dir.path(), config);
+            let contents = b"Subject: test\r\n\r\nHello Hello Hello Hello\r\n".repeat(100);
+            let mut mails = enqueue(&storage, vec!["a", "b"], &contents);
+            assert_eq!(mails.len(), 2);
+            for (mail, expected) in mails.drain(..).zip(&["a", "b"]) {
+                let (meta, read) = read_back(&storage, mail);
+                assert_eq!(meta.metadata, *expected);
+                assert_eq!(read, contents);
+            }
+        }
+    }
+
+    #[test]
+    fn user_metadata_round_trips() {
+        // Maps with non-string keys are not supported by serde when flattened
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path(), FsStorageConfig::default());
+        let mut user = std::collections::BTreeMap::new();
+        user.insert(42u32, "answer".to_owned());
+        let metas = vec![MailMetadata {
+            from: None,
+            to: Vec::new(),
+            metadata: user.clone(),
+        }];
+        let mail = smol::run(async {
+            let mut enqueuer = storage.enqueue(metas, schedule()).await.unwrap();
+            enqueuer.write_all(b"Hello").await.unwrap();
+            enqueuer.commit().await.unwrap().pop().unwrap()
+        });
+        let (meta, contents) = read_back(&storage, mail);
+        assert_eq!(meta.metadata, user);
+        assert_eq!(contents, b"Hello");
+    }
+
+    #[test]
+    fn reads_mails_written_before_storage_information() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage: FsStorage<String> = storage(dir.path(), FsStorageConfig::default());
+        let uuid = Uuid::new_v4().to_hyphenated().to_string();
+        let mail_dir = dir.path().join(DATA_DIR).join(&uuid);
+        fs::create_dir(&mail_dir).unwrap();
+        fs::write(
+            mail_dir.join(SCHEDULE_FILE),
+            serde_json::to_vec(&schedule()).unwrap(),
+        )
+        .unwrap();
+        let metadata = serde_json::to_vec(&meta("old")).unwrap();
+        fs::write(mail_dir.join(METADATA_FILE), metadata).unwrap();
+        fs::write(mail_dir.join(CONTENTS_FILE), b"Hello").unwrap();
+        let target = format!("{}/{}", DATA_DIR_FROM_OTHER_QUEUE, uuid);
+        std::os::unix::fs::symlink(target, dir.path().join(QUEUE_DIR).join(&uuid)).unwrap();
+
+        let mut mails = smol::run(async {
+            storage
+                .list_queue()
+                .await
+                .map(|r| r.map_err(|(e, _)| e).unwrap())
+                .collect::<Vec<_>>()
+                .await
+        });
+        assert_eq!(mails.len(), 1);
+        let (meta, contents) = read_back(&storage, mails.pop().unwrap());
+        assert_eq!(meta.metadata, "old");
+        assert_eq!(contents, b"Hello");
+    }
+
+    fn sharded_config() -> FsStorageConfig {
+        FsStorageConfig {
+            layout: FsLayout::Sharded,
+            ..FsStorageConfig::default()
+        }
+    }
+
+    fn list_queue(storage: &FsStorage<String>) -> Vec<FsQueuedMail> {
+        smol::run(async {
+            storage
+                .list_queue()
+                .await
+                .map(|r| r.map_err(|(e, _)| e).unwrap())
+                .collect::<Vec<_>>()
+                .await
+        })
+    }
+
+    #[test]
+    fn reads_mails_of_both_layouts() {
+        for layout in &[FsLayout::Flat, FsLayout::Sharded] {
+            println!("Test: {:?}", layout);
+            let dir = tempfile::tempdir().unwrap();
+            let flat = storage(dir.path(), FsStorageConfig::default());
+            enqueue(&flat, vec!["flat"], b"Flat");
+            let sharded = storage(dir.path(), sharded_config());
+            let uuid = (*enqueue(&sharded, vec!["sharded"], b"Sharded")[0].id().0).clone();
+            assert!(dir
+                .path()
+                .join(QUEUE_DIR)
+                .join(&uuid[..2])
+                .join(&uuid)
+                .exists());
+
+            let storage = match layout {
+                FsLayout::Flat => flat,
+                FsLayout::Sharded => sharded,
+            };
+            let mut read = list_queue(&storage)
+                .into_iter()
+                .map(|mail| {
+                    let (meta, contents) = read_back(&storage, mail);
+                    (meta.metadata, contents)
+                })
+                .collect::<Vec<_>>();
+            read.sort();
+            assert_eq!(read, vec![
+                ("flat".to_owned(), b"Flat".to_vec()),
+                ("sharded".to_owned(), b"Sharded".to_vec()),
+            ]);
+
+            let inflight =
+                smol::run(async { storage.find_inflight().await.collect::<Vec<_>>().await });
+            assert_eq!(inflight.len(), 2);
+            for mail in inflight {
+                let mail = mail.map_err(|(e, _)| e).unwrap();
+                smol::run(async {
+                    let mail = storage.send_done(mail).await.map_err(|(_, e)| e).unwrap();
+                    let cleaned = storage.cleanup(mail.unwrap()).await;
+                    assert!(cleaned.map_err(|(_, e)| e).unwrap());
+                });
+            }
+            assert!(entries(&dir.path().join(DATA_DIR)).is_empty());
+            assert!(entries(&dir.path().join(CLEANUP_DIR)).is_empty());
+        }
+    }
+
+    #[test]
+    fn migrate_layout_moves_mails_in_all_states() {
+        let dir = tempfile::tempdir().unwrap();
+        let flat = storage(dir.path(), FsStorageConfig::default());
+        let mut mails = enqueue(&flat, vec!["queued", "inflight", "cleanup"], b"Hello");
+        let ids = mails
+            .iter()
+            .map(|m| (*m.id().0).clone())
+            .collect::<Vec<_>>();
+        smol::run(async {
+            let cleanup = mails.pop().unwrap();
+            flat.drop(cleanup).await.map_err(|(_, e)| e).unwrap();
+            let inflight = mails.pop().unwrap();
+            flat.send_start(inflight).await.map_err(|(_, e)| e).unwrap();
+        });
+
+        let sharded = storage(dir.path(), sharded_config());
+        assert_eq!(smol::run(sharded.migrate_layout()).unwrap(), 3);
+        assert_eq!(smol::run(sharded.migrate_layout()).unwrap(), 0);
+        let sharded_path = |id: &String| format!("{}/{}", &id[..2], id);
+        assert_eq!(entries(&dir.path().join(QUEUE_DIR)), vec![sharded_path(
+            &ids[0]
+        )]);
+        assert_eq!(entries(&dir.path().join(INFLIGHT_DIR)), vec![sharded_path(
+            &ids[1]
+        )]);
+        assert_eq!(entries(&dir.path().join(CLEANUP_DIR)), vec![sharded_path(
+            &ids[2]
+        )]);
+
+        let mut queued = list_queue(&sharded);
+        assert_eq!(queued.len(), 1);
+        let (meta, contents) = read_back(&sharded, queued.pop().unwrap());
+        assert_eq!((&*meta.metadata, &*contents), ("queued", &b"Hello"[..]));
+        smol::run(async {
+            let mut pending = sharded
+                .find_pending_cleanup()
+                .await
+                .collect::<Vec<_>>()
+                .await;
+            let mail = pending.pop().unwrap().map_err(|(e, _)| e).unwrap();
+            assert!(sharded.cleanup(mail).await.map_err(|(_, e)| e).unwrap());
+        });
+        assert!(!dir
+            .path()
+            .join(DATA_DIR)
+            .join(sharded_path(&ids[2]))
+            .exists());
+    }
+
+    #[test]
+    fn migrate_layout_finishes_interrupted_migrations() {
+        let dir = tempfile::tempdir().unwrap();
+        let flat = storage(dir.path(), FsStorageConfig::default());
+        let id = (*enqueue(&flat, vec!["a"], b"Hello")[0].id().0).clone();
+
+        // Interrupt the migration right before the temporary symlink is
+        // renamed over the moved one
+        let (data, queue) = (dir.path().join(DATA_DIR), dir.path().join(QUEUE_DIR));
+        fs::create_dir(data.join(&id[..2])).unwrap();
+        fs::rename(data.join(&id), data.join(&id[..2]).join(&id)).unwrap();
+        fs::create_dir(queue.join(&id[..2])).unwrap();
+        fs::rename(queue.join(&id), queue.join(&id[..2]).join(&id)).unwrap();
+        let target = FsLayout::Sharded.symlink_target(&FsLayout::Sharded.mail_path(&id));
+        let tmp = format!("{}{}", id, TMP_SYMLINK_SUFFIX);
+        std::os::unix::fs::symlink(target, queue.join(&id[..2]).join(tmp)).unwrap();
+
+        let sharded = storage(dir.path(), sharded_config());
+        assert_eq!(list_queue(&sharded).len(), 1);
+        assert_eq!(smol::run(sharded.migrate_layout()).unwrap(), 0);
+        assert_eq!(entries(&queue), vec![format!("{}/{}", &id[..2], id)]);
+        let (meta, contents) = read_back(&sharded, list_queue(&sharded).pop().unwrap());
+        assert_eq!((&*meta.metadata, &*contents), ("a", &b"Hello"[..]));
+    }
+
+    #[test]
+    fn cleanup_refuses_symlinks_outside_the_queue() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path(), FsStorageConfig::default());
+        let id = (*enqueue(&storage, vec!["a"], b"Hello")[0].id().0).clone();
+        let cleanup = dir.path().join(CLEANUP_DIR).join(&id);
+        fs::rename(dir.path().join(QUEUE_DIR).join(&id), &cleanup).unwrap();
+        fs::remove_file(&cleanup).unwrap();
+        std::os::unix::fs::symlink("../../outside", &cleanup).unwrap();
+
+        smol::run(async {
+            let mut pending = storage
+                .find_pending_cleanup()
+                .await
+                .collect::<Vec<_>>()
+                .await;
+            let mail = pending.pop().unwrap().map_err(|(e, _)| e).unwrap();
+            let err = storage.cleanup(mail).await.map_err(|(_, e)| e).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        });
+        assert!(dir
+            .path()
+            .join(DATA_DIR)
+            .join(&id)
+            .join(CONTENTS_FILE)
+            .exists());
+        assert!(cleanup.symlink_metadata().is_ok());
+    }
+
+    #[test]
+    fn quota_is_released_when_enqueuing_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = FsStorageConfig {
+            max_bytes: Some(10),
+            max_mails: Some(2),
+            ..FsStorageConfig::default()
+        };
+        let storage = storage(dir.path(), config);
+        let usage = |s: &FsStorage<String>| {
+            let usage = s.usage.as_ref().unwrap();
+            (usage.bytes(), usage.mails())
+        };
+        smol::run(async {
+            // Too big for the queue
+            let mut enqueuer = storage.enqueue(vec![meta("a")], schedule()).await.unwrap();
+            let err = enqueuer.write_all(&[b'a'; 20]).await.unwrap_err();
+            assert!(QueueFull::is(&err));
+            drop(enqueuer);
+            assert_eq!(usage(&storage), (0, 0));
+
+            // Abandoned before being committed
+            let mut enqueuer = storage.enqueue(vec![meta("b")], schedule()).await.unwrap();
+            enqueuer.write_all(b"Hello").await.unwrap();
+            enqueuer.flush().await.unwrap();
+            assert_eq!(usage(&storage), (5, 1));
+            drop(enqueuer);
+            assert_eq!(usage(&storage), (0, 0));
+        });
+        assert!(entries(&dir.path().join(DATA_DIR)).is_empty());
+
+        let mut mails = enqueue(&storage, vec!["c", "d"], b"Hello");
+        assert_eq!(usage(&storage), (10, 2));
+        let err = smol::run(storage.enqueue(vec![meta("e")], schedule()))
+            .err()
+            .unwrap();
+        assert!(QueueFull::is(&err));
+        drop_mail(&storage, dir.path(), mails.pop().unwrap(), "");
+        assert_eq!(usage(&storage), (5, 1));
+        enqueue(&storage, vec!["e"], b"Hi");
+        assert_eq!(usage(&storage), (7, 2));
     }
 }
@@ -0,0 +1,200 @@
+use std::{
+    io,
+    mem::MaybeUninit,
+    os::unix::io::AsRawFd,
+    path::Path,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use futures::prelude::*;
+use openat::Dir;
+use smtp_queue::QueueFull;
+use uuid::Uuid;
+use walkdir::WalkDir;
+
+use crate::CONTENTS_FILE;
+
+/// Size of the mails currently in the storage
+///
+/// The contents of a mail are counted once per mail that references them,
+/// even if they are shared on disk, so that each mail can be accounted for
+/// independently when it is cleaned up.
+#[derive(Default)]
+pub(crate) struct QueueUsage {
+    bytes: AtomicU64,
+    mails: AtomicU64,
+}
+
+impl QueueUsage {
+    /// Computes the usage of the mails found in `data`, which includes the
+    /// mails that were not completely enqueued
+    pub(crate) fn scan(data: &Path) -> io::Result<QueueUsage> {
+        let usage = QueueUsage::default();
+        for e in WalkDir::new(data).min_depth(1).max_depth(2) {
+            let e = e?;
+            let is_mail =
+                e.file_type().is_dir() && Uuid::parse_str(&e.file_name().to_string_lossy()).is_ok();
+            if !is_mail {
+                continue;
+            }
+            let len = match std::fs::metadata(e.path().join(CONTENTS_FILE)) {
+                Ok(m) => m.len(),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+                Err(e) => return Err(e),
+            };
+            usage.add(len, 1);
+        }
+        Ok(usage)
+    }
+
+    pub(crate) fn add(&self, bytes: u64, mails: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.mails.fetch_add(mails, Ordering::Relaxed);
+    }
+
+    pub(crate) fn remove(&self, bytes: u64, mails: u64) {
+        let sub = |x: u64| move |v: u64| Some(v.saturating_sub(x));
+        let _ = self
+            .bytes
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, sub(bytes));
+        let _ = self
+            .mails
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, sub(mails));
+    }
+
+    pub(crate) fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn mails(&self) -> u64 {
+        self.mails.load(Ordering::Relaxed)
+    }
+}
+
+/// Returns the space available to unprivileged users on the filesystem
+/// holding `dir`
+pub(crate) fn free_bytes(dir: &Dir) -> io::Result<u64> {
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    // This is safe, as `stat` is only read after `fstatvfs` filled it
+    let stat = unsafe {
+        if libc::fstatvfs(dir.as_raw_fd(), stat.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        stat.assume_init()
+    };
+    Ok((stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64))
+}
+
+/// Usage of mails that are being enqueued, which is counted as soon as the
+/// contents are written so that concurrent mails cannot go over the limits
+/// together
+///
+/// It must be released if the mails end up not being queued.
+pub(crate) struct Reservation {
+    usage: Arc<QueueUsage>,
+    bytes: AtomicU64,
+    mails: u64,
+}
+
+impl Reservation {
+    pub(crate) fn new(usage: Arc<QueueUsage>, mails: u64) -> Reservation {
+        usage.add(0, mails);
+        Reservation {
+            usage,
+            bytes: AtomicU64::new(0),
+            mails,
+        }
+    }
+
+    fn add_bytes(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.usage.add(bytes, 0);
+    }
+
+    /// Removes all that was counted for these mails from the usage
+    pub(crate) fn release(&self) {
+        let bytes = self.bytes.swap(0, Ordering::Relaxed);
+        self.usage.remove(bytes, self.mails);
+    }
+}
+
+/// Writer that accounts for the bytes written to the contents of the mails
+/// of `reservation`, and refuses writing more than `max_bytes` in the storage
+pub(crate) struct CountingWriter<W> {
+    inner: W,
+    reservation: Arc<Reservation>,
+    max_bytes: Option<u64>,
+}
+
+impl<W> CountingWriter<W> {
+    pub(crate) fn new(
+        inner: W,
+        reservation: Arc<Reservation>,
+        max_bytes: Option<u64>,
+    ) -> CountingWriter<W> {
+        CountingWriter {
+            inner,
+            reservation,
+            max_bytes,
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for CountingWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mails = self.reservation.mails;
+        if let Some(max_bytes) = self.max_bytes {
+            let needed = (buf.len() as u64).saturating_mul(mails);
+            if self.reservation.usage.bytes().saturating_add(needed) > max_bytes {
+                return Poll::Ready(Err(QueueFull.into()));
+            }
+        }
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = res {
+            self.reservation.add_bytes(written as u64 * mails);
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::{executor, io::Cursor};
+
+    #[test]
+    fn counting_writer_enforces_max_bytes() {
+        executor::block_on(async {
+            let usage = Arc::new(QueueUsage::default());
+            usage.add(10, 1);
+            let reservation = Arc::new(Reservation::new(usage.clone(), 2));
+            let mut w = CountingWriter::new(Cursor::new(Vec::new()), reservation.clone(), Some(30));
+            w.write_all(b"0123456789").await.unwrap();
+            assert_eq!((usage.bytes(), usage.mails()), (30, 3));
+            let err = w.write_all(b"0").await.unwrap_err();
+            assert!(QueueFull::is(&err));
+            assert_eq!(w.inner.into_inner(), b"0123456789");
+
+            reservation.release();
+            assert_eq!((usage.bytes(), usage.mails()), (10, 1));
+        })
+    }
+}
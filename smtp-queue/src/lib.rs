@@ -1,4 +1,15 @@
-use std::{marker::PhantomData, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    marker::PhantomData,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
 
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
@@ -88,6 +99,28 @@ pub trait Config<U>: 'static + Send + Sync {
         Duration::from_secs(3600)
     }
 
+    // How long a transport sender is kept open after its last use, waiting for
+    // another mail to the same destination to be due
+    fn sender_idle_timeout(&self) -> Duration {
+        Duration::from_secs(60)
+    }
+
+    /// Computes header fields to prepend to a mail each time it is sent, eg. a
+    /// DKIM signature, from its contents read from `mail`
+    ///
+    /// When this returns `Some`, the contents are read again from the start to
+    /// be sent after the returned header fields, that may be empty. So
+    /// implementations that read from `mail` must not return `None`. An
+    /// `InvalidData` error means that the mail cannot be sent and bounces it,
+    /// while other errors make it be retried.
+    async fn prepend_headers(
+        &self,
+        _meta: &MailMetadata<U>,
+        _mail: &mut (dyn Send + Unpin + AsyncRead),
+    ) -> Result<Option<Vec<u8>>, io::Error> {
+        Ok(None)
+    }
+
     fn io_error_next_retry_delay(&self, d: Duration) -> Duration {
         if d < Duration::from_secs(30) {
             Duration::from_secs(60)
@@ -97,6 +130,37 @@ pub trait Config<U>: 'static + Send + Sync {
     }
 }
 
+/// Error returned by a [`Storage`](Storage) that cannot accept any more mail
+/// without going over its configured limits.
+///
+/// It is returned wrapped in an `io::Error`, use
+/// [`QueueFull::is`](QueueFull::is) to recognize it.
+#[derive(Debug)]
+pub struct QueueFull;
+
+impl QueueFull {
+    pub fn is(err: &io::Error) -> bool {
+        match err.get_ref() {
+            Some(e) => e.is::<QueueFull>(),
+            None => false,
+        }
+    }
+}
+
+impl std::fmt::Display for QueueFull {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "the queue is full")
+    }
+}
+
+impl std::error::Error for QueueFull {}
+
+impl From<QueueFull> for io::Error {
+    fn from(e: QueueFull) -> io::Error {
+        io::Error::new(io::ErrorKind::Other, e)
+    }
+}
+
 #[async_trait]
 pub trait Storage<U>: 'static + Send + Sync {
     type QueuedMail: QueuedMail;
@@ -121,9 +185,22 @@ pub trait Storage<U>: 'static + Send + Sync {
         mail: &Self::InflightMail,
     ) -> Result<(MailMetadata<U>, Self::Reader), io::Error>;
 
+    /// Checks whether there is room left for at least one more mail, returning
+    /// a [`QueueFull`](QueueFull) error if not.
+    ///
+    /// This is only a hint, `enqueue` and writing to the enqueuer can still
+    /// fail with `QueueFull`.
+    async fn check_space(&self) -> Result<(), io::Error> {
+        Ok(())
+    }
+
+    /// Enqueues one mail per element of `metas`, all of them sharing the
+    /// contents that will be written to the returned enqueuer.
+    ///
+    /// `metas` is never empty.
     async fn enqueue(
         &self,
-        meta: MailMetadata<U>,
+        metas: Vec<MailMetadata<U>>,
         s: ScheduleInfo,
     ) -> Result<Self::Enqueuer, io::Error>;
 
@@ -173,37 +250,137 @@ pub trait PendingCleanupMail: Send + Sync {
 }
 
 #[async_trait]
-pub trait StorageEnqueuer<QueuedMail>: Send + AsyncWrite {
-    async fn commit(self) -> Result<QueuedMail, io::Error>;
+pub trait StorageEnqueuer<QueuedMail>: Send + Unpin + AsyncWrite {
+    /// Returns the queued mails, in the same order as the `metas` that were
+    /// passed to [`Storage::enqueue`](Storage::enqueue)
+    ///
+    /// This must queue either all the mails or none of them, so that a
+    /// client retrying after an error does not get some of them twice.
+    async fn commit(self) -> Result<Vec<QueuedMail>, io::Error>;
 }
 
 pub enum TransportFailure {
     Local(io::Error),
     RemoteTransient(ReplyCode, io::Error),
     RemotePermanent(ReplyCode, io::Error),
+
+    /// The mail was accepted for some of its recipients only, eg. by an LMTP
+    /// server
+    ///
+    /// This gives the result for each recipient, in the order of `meta.to`.
+    /// The recipients that failed permanently are bounced, and the other
+    /// failed ones are retried later.
+    PerRecipient(Vec<Result<(), TransportFailure>>),
+}
+
+impl TransportFailure {
+    /// Combines the results for each recipient of a mail into the result for
+    /// the whole mail
+    pub fn combine(mut results: Vec<Result<(), TransportFailure>>) -> Result<(), TransportFailure> {
+        if results.iter().all(|r| r.is_ok()) {
+            Ok(())
+        } else if results.len() == 1 {
+            results.pop().unwrap()
+        } else {
+            Err(TransportFailure::PerRecipient(results))
+        }
+    }
 }
 
+/// A transport hands mails over to their next hop.
+///
+/// Sending goes through a [`TransportSender`](TransportSender), that is
+/// opened for a given `Destination` and can then be reused by the queue for
+/// all the mails that are due for this same destination. For instance, an
+/// SMTP transport would use the next hop as a destination, and a sender would
+/// be one SMTP session with it.
 #[async_trait]
 pub trait Transport<U>: 'static + Send + Sync {
-    async fn send<Reader>(
+    type Destination: 'static + Send + Sync + Clone + Eq + Hash;
+    type Sender: TransportSender<U>;
+
+    async fn destination(
         &self,
         meta: &MailMetadata<U>,
+    ) -> Result<Self::Destination, TransportFailure>;
+
+    async fn connect(&self, dest: &Self::Destination) -> Result<Self::Sender, TransportFailure>;
+}
+
+#[async_trait]
+pub trait TransportSender<U>: 'static + Send {
+    /// Note: after this returned a `TransportFailure::Local` error, or a
+    /// `RemoteTransient` one with a 421 code, the sender will be dropped and
+    /// not reused.
+    async fn send<Reader>(
+        &mut self,
+        meta: &MailMetadata<U>,
         mail: Reader,
     ) -> Result<(), TransportFailure>
     where
-        Reader: AsyncRead;
+        Reader: Send + AsyncRead;
+
+    /// Whether this sender can no longer send mails, eg. because its session
+    /// with the destination was closed, in which case it is dropped instead
+    /// of being reused for the next mails
+    fn is_broken(&self) -> bool {
+        false
+    }
+}
+
+/// Splits `meta` into one `MailMetadata` per recipient domain, keeping the
+/// order in which the domains first appear in `meta.to`
+fn split_by_domain<U: Clone>(meta: MailMetadata<U>) -> Vec<MailMetadata<U>> {
+    let mut res: Vec<(Option<String>, MailMetadata<U>)> = Vec::new();
+    for to in meta.to {
+        // Domains are case-insensitive
+        let domain = to.hostname.as_ref().map(|h| h.raw().to_lowercase());
+        match res.iter_mut().find(|(d, _)| *d == domain) {
+            Some((_, m)) => m.to.push(to),
+            None => res.push((domain, MailMetadata {
+                from: meta.from.clone(),
+                to: vec![to],
+                metadata: meta.metadata.clone(),
+            })),
+        }
+    }
+    if res.is_empty() {
+        // No recipient at all, keep the mail as-is so that it still gets
+        // handled (and bounced) by the queue
+        return vec![MailMetadata {
+            from: meta.from,
+            to: Vec::new(),
+            metadata: meta.metadata,
+        }];
+    }
+    res.into_iter().map(|(_, m)| m).collect()
 }
 
 const INTERVAL_ON_TOO_BIG_DURATION: Duration = Duration::from_secs(4 * 3600);
 
-struct QueueImpl<C, S, T> {
+struct IdleSender<S> {
+    id: u64,
+    sender: S,
+}
+
+type IdleSenders<D, S> = HashMap<D, Vec<IdleSender<S>>>;
+
+struct QueueImpl<U, C, S, T>
+where
+    T: Transport<U>,
+{
     config: C,
     storage: S,
     transport: T,
+    idle_senders: Mutex<IdleSenders<T::Destination, T::Sender>>,
+    next_idle_sender_id: AtomicU64,
 }
 
-pub struct Queue<U, C, S, T> {
-    q: Arc<QueueImpl<C, S, T>>,
+pub struct Queue<U, C, S, T>
+where
+    T: Transport<U>,
+{
+    q: Arc<QueueImpl<U, C, S, T>>,
     phantom: PhantomData<U>,
 }
 
@@ -227,6 +404,16 @@ macro_rules! io_retry_loop {
     }};
 }
 
+/// Maps an error met while prepending headers to a mail, an `InvalidData`
+/// error meaning that it cannot be sent at all
+fn prepend_failure(e: io::Error) -> TransportFailure {
+    if e.kind() == io::ErrorKind::InvalidData {
+        TransportFailure::RemotePermanent(ReplyCode::TRANSACTION_FAILED, e)
+    } else {
+        TransportFailure::Local(e)
+    }
+}
+
 macro_rules! io_retry_loop_raw {
     ($this:ident, $id:expr, $e:expr) => {{
         let mut delay = Duration::from_secs(0);
@@ -259,6 +446,81 @@ where
             self.q.config.log_pending_cleanup_mail_vanished(id).await;
         }
     }
+
+    fn take_idle_sender(&self, dest: &T::Destination) -> Option<T::Sender> {
+        let mut idle_senders = self.q.idle_senders.lock().unwrap();
+        let senders = idle_senders.get_mut(dest)?;
+        let sender = senders.pop().map(|s| s.sender);
+        if senders.is_empty() {
+            idle_senders.remove(dest);
+        }
+        sender
+    }
+
+    fn return_idle_sender(&self, dest: T::Destination, sender: T::Sender) {
+        let id = self.q.next_idle_sender_id.fetch_add(1, Ordering::Relaxed);
+        self.q
+            .idle_senders
+            .lock()
+            .unwrap()
+            .entry(dest.clone())
+            .or_default()
+            .push(IdleSender { id, sender });
+
+        // Close the sender if it has not been reused by the time it expires
+        let this = self.clone();
+        smol::Task::spawn(async move {
+            smol::Timer::new(this.q.config.sender_idle_timeout()).await;
+            let expired = {
+                let mut idle_senders = this.q.idle_senders.lock().unwrap();
+                match idle_senders.get_mut(&dest) {
+                    None => None,
+                    Some(senders) => {
+                        let expired = senders
+                            .iter()
+                            .position(|s| s.id == id)
+                            .map(|i| senders.remove(i));
+                        if senders.is_empty() {
+                            idle_senders.remove(&dest);
+                        }
+                        expired
+                    }
+                }
+            };
+            // Drop the sender outside of the lock
+            std::mem::drop(expired);
+        })
+        .detach();
+    }
+
+    async fn send_through_transport<Reader>(
+        &self,
+        meta: &MailMetadata<U>,
+        mail: Reader,
+    ) -> Result<(), TransportFailure>
+    where
+        Reader: Send + AsyncRead,
+    {
+        let dest = self.q.transport.destination(meta).await?;
+        let mut sender = match self.take_idle_sender(&dest) {
+            Some(sender) => sender,
+            None => self.q.transport.connect(&dest).await?,
+        };
+        let res = sender.send(meta, mail).await;
+        // A 421 reply means that the server is closing the session
+        let broken = sender.is_broken()
+            || match res {
+                Err(TransportFailure::Local(_)) => true,
+                Err(TransportFailure::RemoteTransient(c, _)) => {
+                    c == ReplyCode::SERVICE_NOT_AVAILABLE
+                }
+                _ => false,
+            };
+        if !broken {
+            self.return_idle_sender(dest, sender);
+        }
+        res
+    }
 }
 
 impl<U, C, S, T> Queue<U, C, S, T>
@@ -274,6 +536,8 @@ where
                 config,
                 storage,
                 transport,
+                idle_senders: Mutex::new(HashMap::new()),
+                next_idle_sender_id: AtomicU64::new(0),
             }),
             phantom: PhantomData,
         };
@@ -288,6 +552,16 @@ where
         this
     }
 
+    /// Checks whether the storage has room left for a new mail, so that a
+    /// server can refuse it before receiving its contents.
+    ///
+    /// See [`QueueFull::is`](QueueFull::is) to recognize a full queue.
+    pub async fn check_space(&self) -> Result<(), io::Error> {
+        self.q.storage.check_space().await
+    }
+
+    /// Fails with a [`QueueFull`](QueueFull) error if the storage cannot
+    /// accept this mail without going over its limits.
     pub async fn enqueue(
         &self,
         meta: MailMetadata<U>,
@@ -295,7 +569,26 @@ where
     ) -> Result<Enqueuer<U, C, S, T>, io::Error> {
         Ok(Enqueuer {
             queue: self.clone(),
-            enqueuer: Some(self.q.storage.enqueue(meta, s).await?),
+            enqueuer: Some(self.q.storage.enqueue(vec![meta], s).await?),
+        })
+    }
+
+    /// Enqueues the mail as one queue entry per recipient domain, so that each
+    /// domain gets its own schedule and bounces, and a slow domain does not
+    /// delay delivery to the others.
+    ///
+    /// The storage shares the contents between all these entries.
+    pub async fn enqueue_split_by_domain(
+        &self,
+        meta: MailMetadata<U>,
+        s: ScheduleInfo,
+    ) -> Result<Enqueuer<U, C, S, T>, io::Error>
+    where
+        U: Clone,
+    {
+        Ok(Enqueuer {
+            queue: self.clone(),
+            enqueuer: Some(self.q.storage.enqueue(split_by_domain(meta), s).await?),
         })
     }
 
@@ -369,25 +662,8 @@ where
                 Ok(()) => return,
                 Err(m) => mail = m,
             }
-            let this_attempt = Utc::now();
-            match self.q.config.next_interval(mail.schedule()).await {
-                Some(next_interval) => {
-                    let next_interval = match chrono::Duration::from_std(next_interval) {
-                        Ok(i) => i,
-                        Err(_) => {
-                            let new_next_interval = INTERVAL_ON_TOO_BIG_DURATION;
-                            self.q
-                                .config
-                                .log_too_big_duration(mail.id(), next_interval, new_next_interval)
-                                .await;
-                            chrono::Duration::from_std(next_interval).unwrap()
-                        }
-                    };
-                    let next_attempt = this_attempt + next_interval;
-                    let schedule = ScheduleInfo {
-                        at: next_attempt,
-                        last_attempt: Some(this_attempt),
-                    };
+            match self.next_schedule(mail.id(), mail.schedule()).await {
+                Some(schedule) => {
                     io_retry_loop_raw!(
                         self,
                         mail.id(),
@@ -412,8 +688,31 @@ where
         }
     }
 
+    /// Computes the schedule of the next attempt, after the one planned with
+    /// `schedule` just failed, or returns `None` if the mail should be dropped
+    async fn next_schedule(&self, id: QueueId, schedule: ScheduleInfo) -> Option<ScheduleInfo> {
+        let this_attempt = Utc::now();
+        let next_interval = self.q.config.next_interval(schedule).await?;
+        let next_interval = match chrono::Duration::from_std(next_interval) {
+            Ok(i) => i,
+            Err(_) => {
+                let new_next_interval = INTERVAL_ON_TOO_BIG_DURATION;
+                self.q
+                    .config
+                    .log_too_big_duration(id, next_interval, new_next_interval)
+                    .await;
+                chrono::Duration::from_std(next_interval).unwrap()
+            }
+        };
+        Some(ScheduleInfo {
+            at: this_attempt + next_interval,
+            last_attempt: Some(this_attempt),
+        })
+    }
+
     async fn try_send(&self, mail: S::QueuedMail) -> Result<(), S::QueuedMail> {
         let id = mail.id();
+        let schedule = mail.schedule();
         let inflight = io_retry_loop!(self, mail, |m| self.q.storage.send_start(m).await);
         let inflight = match inflight {
             Some(inflight) => inflight,
@@ -433,17 +732,23 @@ where
             Err(e) => Err((i, e)),
         });
 
-        match self.q.transport.send(&meta, reader).await {
+        let mut reader = Box::pin(reader);
+        let res = match self.q.config.prepend_headers(&meta, &mut reader).await {
+            Ok(None) => self.send_through_transport(&meta, reader).await,
+            // The hook read some of the contents, so they are read again from
+            // the start to be sent
+            Ok(Some(headers)) => match self.q.storage.read_inflight(&inflight).await {
+                Ok((_, reader)) => {
+                    let mail = io::Cursor::new(headers).chain(reader);
+                    self.send_through_transport(&meta, mail).await
+                }
+                Err(e) => Err(prepend_failure(e)),
+            },
+            Err(e) => Err(prepend_failure(e)),
+        };
+        match res {
             Ok(()) => {
-                let pcm = io_retry_loop!(self, inflight, |i| self.q.storage.send_done(i).await);
-                match pcm {
-                    Some(pcm) => {
-                        self.cleanup(pcm).await;
-                    }
-                    None => {
-                        self.q.config.log_queued_mail_vanished(id).await;
-                    }
-                };
+                self.send_done(inflight).await;
                 return Ok(());
             }
             Err(TransportFailure::RemotePermanent(c, e)) => {
@@ -460,6 +765,11 @@ where
             Err(TransportFailure::RemoteTransient(c, e)) => {
                 self.q.config.log_transient_error(inflight.id(), c, e).await;
             }
+            Err(TransportFailure::PerRecipient(results)) => {
+                self.split_partial(&inflight, meta, schedule, results).await;
+                self.send_done(inflight).await;
+                return Ok(());
+            }
         }
         // The above match falls through only in cases where we ought to retry
         let id = inflight.id();
@@ -472,9 +782,131 @@ where
             }
         }
     }
+
+    // This is not inlined in `split_partial`, as the compiler could then not
+    // prove that the future of `send` is `Send`
+    fn spawn_send(&self, mail: S::QueuedMail) {
+        let this = self.clone();
+        smol::Task::spawn(async move { this.send(mail).await }).detach();
+    }
+
+    async fn send_done(&self, inflight: S::InflightMail) {
+        let id = inflight.id();
+        let pcm = io_retry_loop!(self, inflight, |i| self.q.storage.send_done(i).await);
+        match pcm {
+            Some(pcm) => {
+                self.cleanup(pcm).await;
+            }
+            None => {
+                self.q.config.log_queued_mail_vanished(id).await;
+            }
+        };
+    }
+
+    /// Handles a mail that was accepted for some of its recipients only
+    ///
+    /// The recipients to retry are enqueued again as a new mail, and the ones
+    /// that failed permanently are bounced, so that `inflight` can then be
+    /// considered as sent. As the mail was already delivered to some
+    /// recipients, the recipients to retry are bounced too if they cannot be
+    /// enqueued again, rather than sending the whole mail again.
+    async fn split_partial(
+        &self,
+        inflight: &S::InflightMail,
+        meta: MailMetadata<U>,
+        schedule: ScheduleInfo,
+        results: Vec<Result<(), TransportFailure>>,
+    ) {
+        let id = inflight.id();
+        let mut results = results.into_iter();
+        let mut retried = Vec::new();
+        let mut bounced = Vec::new();
+        for to in meta.to.iter() {
+            match results.next() {
+                Some(Ok(())) => (),
+                Some(Err(TransportFailure::RemotePermanent(c, e))) => {
+                    self.q.config.log_permanent_error(id.clone(), c, &e).await;
+                    bounced.push((to.clone(), c, e));
+                }
+                Some(Err(TransportFailure::RemoteTransient(c, e))) => {
+                    let err = io::Error::new(e.kind(), e.to_string());
+                    self.q.config.log_transient_error(id.clone(), c, e).await;
+                    retried.push((to.clone(), c, err));
+                }
+                Some(Err(TransportFailure::Local(e))) => {
+                    let err = io::Error::new(e.kind(), e.to_string());
+                    self.q.config.log_io_error(e, Some(id.clone())).await;
+                    retried.push((to.clone(), ReplyCode::LOCAL_ERROR, err));
+                }
+                // The transport did not give a proper result for this
+                // recipient, so it cannot be considered as sent
+                Some(Err(TransportFailure::PerRecipient(_))) | None => {
+                    let err = io::Error::new(io::ErrorKind::Other, "no result from the transport");
+                    retried.push((to.clone(), ReplyCode::LOCAL_ERROR, err));
+                }
+            }
+        }
+
+        if !retried.is_empty() {
+            match self.next_schedule(id.clone(), schedule).await {
+                Some(schedule) => {
+                    let to = retried.iter().map(|(to, _, _)| to.clone()).collect();
+                    if let Err(e) = self.enqueue_retried(inflight, to, schedule).await {
+                        let err = format!("failed to queue the mail again: {}", e);
+                        self.q.config.log_io_error(e, Some(id.clone())).await;
+                        bounced.extend(retried.into_iter().map(|(to, _, _)| {
+                            let e = io::Error::new(io::ErrorKind::Other, err.clone());
+                            (to, ReplyCode::LOCAL_ERROR, e)
+                        }));
+                    }
+                }
+                // The mail expired for the recipients that still failed
+                None => bounced.extend(retried),
+            }
+        }
+
+        if let Some((_, code, _)) = bounced.first() {
+            let code = *code;
+            let err = bounced
+                .iter()
+                .map(|(to, _, e)| match to.hostname {
+                    Some(ref host) => format!("<{}@{}>: {}", to.localpart.raw(), host.raw(), e),
+                    None => format!("<{}>: {}", to.localpart.raw(), e),
+                })
+                .collect::<Vec<_>>()
+                .join("; ");
+            let meta = MailMetadata {
+                from: meta.from,
+                to: bounced.into_iter().map(|(to, _, _)| to).collect(),
+                metadata: meta.metadata,
+            };
+            let err = io::Error::new(io::ErrorKind::Other, err);
+            self.q.config.bounce(id, meta, code, err).await;
+        }
+    }
+
+    /// Enqueues `inflight` again for the recipients `to` only
+    async fn enqueue_retried(
+        &self,
+        inflight: &S::InflightMail,
+        to: Vec<Email>,
+        schedule: ScheduleInfo,
+    ) -> Result<(), io::Error> {
+        let (mut meta, reader) = self.q.storage.read_inflight(inflight).await?;
+        meta.to = to;
+        let mut enqueuer = self.q.storage.enqueue(vec![meta], schedule).await?;
+        io::copy(reader, &mut enqueuer).await?;
+        for mail in enqueuer.commit().await? {
+            self.spawn_send(mail);
+        }
+        Ok(())
+    }
 }
 
-impl<U, C, S, T> Clone for Queue<U, C, S, T> {
+impl<U, C, S, T> Clone for Queue<U, C, S, T>
+where
+    T: Transport<U>,
+{
     fn clone(&self) -> Self {
         Self {
             q: self.q.clone(),
@@ -486,6 +918,7 @@ impl<U, C, S, T> Clone for Queue<U, C, S, T> {
 pub struct Enqueuer<U, C, S, T>
 where
     S: Storage<U>,
+    T: Transport<U>,
 {
     queue: Queue<U, C, S, T>,
     enqueuer: Option<S::Enqueuer>,
@@ -498,10 +931,363 @@ where
     S: Storage<U>,
     T: Transport<U>,
 {
+    /// Queues the mail, or all the mails it was split into, for sending
+    ///
+    /// When this fails, none of them is queued.
     pub async fn commit(self) -> Result<(), io::Error> {
         let mut this = self;
-        let mail = this.enqueuer.take().unwrap().commit().await?;
-        smol::Task::spawn(async move { this.queue.send(mail).await }).detach();
+        let mails = this.enqueuer.take().unwrap().commit().await?;
+        for mail in mails {
+            let queue = this.queue.clone();
+            smol::Task::spawn(async move { queue.send(mail).await }).detach();
+        }
         Ok(())
     }
 }
+
+// The enqueuer is never structurally pinned, `U` only being there as a marker
+impl<U, C, S, T> Unpin for Enqueuer<U, C, S, T>
+where
+    S: Storage<U>,
+    T: Transport<U>,
+{
+}
+
+impl<U, C, S, T> AsyncWrite for Enqueuer<U, C, S, T>
+where
+    S: Storage<U>,
+    T: Transport<U>,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(self.get_mut().enqueuer.as_mut().unwrap()).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(self.get_mut().enqueuer.as_mut().unwrap()).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(self.get_mut().enqueuer.as_mut().unwrap()).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::AtomicUsize;
+
+    use futures::channel::mpsc;
+
+    fn email(s: &str) -> Email {
+        Email::parse_bracketed(s.as_bytes()).unwrap().to_owned()
+    }
+
+    fn meta(to: &[&str]) -> MailMetadata<u32> {
+        MailMetadata {
+            from: Some(email("<sender@example.org>")),
+            to: to.iter().map(|to| email(to)).collect(),
+            metadata: 42,
+        }
+    }
+
+    #[test]
+    fn split_by_domain_groups_recipients() {
+        let tests: &[(&[&str], &[&[&str]])] = &[
+            (&["<foo@example.org>"], &[&["<foo@example.org>"]]),
+            (
+                &[
+                    "<foo@example.org>",
+                    "<bar@example.net>",
+                    "<baz@Example.ORG>",
+                    "<postmaster>",
+                    "<qux@example.net>",
+                    "<foo@[192.0.2.1]>",
+                ],
+                &[
+                    &["<foo@example.org>", "<baz@Example.ORG>"],
+                    &["<bar@example.net>", "<qux@example.net>"],
+                    &["<postmaster>"],
+                    &["<foo@[192.0.2.1]>"],
+                ],
+            ),
+            (&[], &[&[]]),
+        ];
+        for &(inp, out) in tests {
+            println!("Test: {:?}", inp);
+            let res = split_by_domain(meta(inp));
+            assert_eq!(res.len(), out.len());
+            for (m, to) in res.iter().zip(out.iter()) {
+                assert_eq!(m.from, Some(email("<sender@example.org>")));
+                assert_eq!(m.to, meta(to).to);
+                assert_eq!(m.metadata, 42);
+            }
+        }
+    }
+
+    struct MemMail(ScheduleInfo);
+
+    impl QueuedMail for MemMail {
+        fn id(&self) -> QueueId {
+            QueueId::new("mail")
+        }
+
+        fn schedule(&self) -> ScheduleInfo {
+            self.0
+        }
+    }
+
+    impl InflightMail for MemMail {
+        fn id(&self) -> QueueId {
+            QueueId::new("mail")
+        }
+    }
+
+    impl PendingCleanupMail for MemMail {
+        fn id(&self) -> QueueId {
+            QueueId::new("mail")
+        }
+    }
+
+    #[async_trait]
+    impl StorageEnqueuer<MemMail> for io::Sink {
+        async fn commit(self) -> Result<Vec<MemMail>, io::Error> {
+            let schedule = ScheduleInfo {
+                at: Utc::now(),
+                last_attempt: None,
+            };
+            Ok(vec![MemMail(schedule)])
+        }
+    }
+
+    /// Storage of a single mail kept in memory, counting how many times it is
+    /// read, and failing with `read_error` from the second time on if set
+    struct MemStorage {
+        contents: &'static [u8],
+        reads: Arc<AtomicUsize>,
+        read_error: Option<io::ErrorKind>,
+    }
+
+    type MemLister = stream::Empty<Result<MemMail, (io::Error, Option<QueueId>)>>;
+
+    #[async_trait]
+    impl Storage<u32> for MemStorage {
+        type Enqueuer = io::Sink;
+        type InflightLister = MemLister;
+        type InflightMail = MemMail;
+        type PendingCleanupLister = MemLister;
+        type PendingCleanupMail = MemMail;
+        type QueueLister = MemLister;
+        type QueuedMail = MemMail;
+        type Reader = io::Cursor<&'static [u8]>;
+
+        async fn list_queue(&self) -> MemLister {
+            stream::empty()
+        }
+
+        async fn find_inflight(&self) -> MemLister {
+            stream::empty()
+        }
+
+        async fn find_pending_cleanup(&self) -> MemLister {
+            stream::empty()
+        }
+
+        async fn read_inflight(
+            &self,
+            _mail: &MemMail,
+        ) -> Result<(MailMetadata<u32>, Self::Reader), io::Error> {
+            let reads = self.reads.fetch_add(1, Ordering::Relaxed);
+            match self.read_error {
+                Some(kind) if reads > 0 => Err(io::Error::new(kind, "failed reading")),
+                _ => Ok((meta(&["<foo@example.org>"]), io::Cursor::new(self.contents))),
+            }
+        }
+
+        async fn enqueue(
+            &self,
+            _metas: Vec<MailMetadata<u32>>,
+            _s: ScheduleInfo,
+        ) -> Result<io::Sink, io::Error> {
+            Ok(io::sink())
+        }
+
+        async fn reschedule(&self, mail: &mut MemMail, s: ScheduleInfo) -> Result<(), io::Error> {
+            mail.0 = s;
+            Ok(())
+        }
+
+        async fn send_start(&self, mail: MemMail) -> Result<Option<MemMail>, (MemMail, io::Error)> {
+            Ok(Some(mail))
+        }
+
+        async fn send_done(&self, mail: MemMail) -> Result<Option<MemMail>, (MemMail, io::Error)> {
+            Ok(Some(mail))
+        }
+
+        async fn send_cancel(
+            &self,
+            mail: MemMail,
+        ) -> Result<Option<MemMail>, (MemMail, io::Error)> {
+            Ok(Some(mail))
+        }
+
+        async fn drop(&self, mail: MemMail) -> Result<Option<MemMail>, (MemMail, io::Error)> {
+            Ok(Some(mail))
+        }
+
+        async fn cleanup(&self, _mail: MemMail) -> Result<bool, (MemMail, io::Error)> {
+            Ok(true)
+        }
+    }
+
+    #[derive(Debug, Eq, PartialEq)]
+    enum Event {
+        Sent(Vec<u8>),
+        Bounced(ReplyCode),
+        IoError(io::ErrorKind),
+    }
+
+    /// Configuration prepending a header to the mails it reads if `prepend`
+    /// is set, and reporting what happens to them
+    struct PrependConfig {
+        prepend: bool,
+        events: mpsc::UnboundedSender<Event>,
+    }
+
+    #[async_trait]
+    impl Config<u32> for PrependConfig {
+        async fn next_interval(&self, _s: ScheduleInfo) -> Option<Duration> {
+            Some(Duration::from_secs(3600))
+        }
+
+        async fn bounce(
+            &self,
+            _id: QueueId,
+            _meta: MailMetadata<u32>,
+            code: ReplyCode,
+            _err: io::Error,
+        ) {
+            self.events.unbounded_send(Event::Bounced(code)).unwrap();
+        }
+
+        async fn log_permanent_error(&self, _id: QueueId, _c: ReplyCode, _err: &io::Error) {}
+
+        async fn log_transient_error(&self, _id: QueueId, _c: ReplyCode, _err: io::Error) {}
+
+        async fn log_io_error(&self, err: io::Error, _id: Option<QueueId>) {
+            println!("io error: {:?}", err);
+            self.events
+                .unbounded_send(Event::IoError(err.kind()))
+                .unwrap();
+        }
+
+        async fn log_queued_mail_vanished(&self, _id: QueueId) {}
+
+        async fn log_inflight_mail_vanished(&self, _id: QueueId) {}
+
+        async fn log_pending_cleanup_mail_vanished(&self, _id: QueueId) {}
+
+        async fn log_too_big_duration(&self, _id: QueueId, _too_big: Duration, _new: Duration) {}
+
+        async fn prepend_headers(
+            &self,
+            _meta: &MailMetadata<u32>,
+            mail: &mut (dyn Send + Unpin + AsyncRead),
+        ) -> Result<Option<Vec<u8>>, io::Error> {
+            if !self.prepend {
+                return Ok(None);
+            }
+            mail.read_to_end(&mut Vec::new()).await?;
+            Ok(Some(b"X-Read: yes\r\n".to_vec()))
+        }
+    }
+
+    /// Transport reporting the contents of the mails it delivers
+    #[derive(Clone)]
+    struct EventTransport(mpsc::UnboundedSender<Event>);
+
+    #[async_trait]
+    impl Transport<u32> for EventTransport {
+        type Destination = ();
+        type Sender = EventTransport;
+
+        async fn destination(&self, _meta: &MailMetadata<u32>) -> Result<(), TransportFailure> {
+            Ok(())
+        }
+
+        async fn connect(&self, _dest: &()) -> Result<EventTransport, TransportFailure> {
+            Ok(self.clone())
+        }
+    }
+
+    #[async_trait]
+    impl TransportSender<u32> for EventTransport {
+        async fn send<Reader>(
+            &mut self,
+            _meta: &MailMetadata<u32>,
+            mail: Reader,
+        ) -> Result<(), TransportFailure>
+        where
+            Reader: Send + AsyncRead,
+        {
+            pin_mut!(mail);
+            let mut contents = Vec::new();
+            mail.read_to_end(&mut contents)
+                .await
+                .map_err(TransportFailure::Local)?;
+            self.0.unbounded_send(Event::Sent(contents)).unwrap();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn prepend_headers_rereads_only_read_mails() {
+        let tests = vec![
+            (false, None, 1, Event::Sent(b"Hello\r\n".to_vec())),
+            (
+                true,
+                None,
+                2,
+                Event::Sent(b"X-Read: yes\r\nHello\r\n".to_vec()),
+            ),
+            (
+                true,
+                Some(io::ErrorKind::InvalidData),
+                2,
+                Event::Bounced(ReplyCode::TRANSACTION_FAILED),
+            ),
+            (
+                true,
+                Some(io::ErrorKind::Other),
+                2,
+                Event::IoError(io::ErrorKind::Other),
+            ),
+        ];
+        for (prepend, read_error, reads, event) in tests {
+            println!("Test: {:?}", (prepend, read_error));
+            let (events, mut received) = mpsc::unbounded();
+            let storage = MemStorage {
+                contents: b"Hello\r\n",
+                reads: Arc::new(AtomicUsize::new(0)),
+                read_error,
+            };
+            let read_count = storage.reads.clone();
+            let config = PrependConfig {
+                prepend,
+                events: events.clone(),
+            };
+            smol::run(async move {
+                let queue = Queue::new(config, storage, EventTransport(events)).await;
+                let schedule = ScheduleInfo {
+                    at: Utc::now(),
+                    last_attempt: None,
+                };
+                let enqueuer = queue.enqueue(meta(&["<foo@example.org>"]), schedule).await;
+                enqueuer.unwrap().commit().await.unwrap();
+                assert_eq!(received.next().await, Some(event));
+            });
+            assert_eq!(read_count.load(Ordering::Relaxed), reads);
+        }
+    }
+}
@@ -7,7 +7,7 @@ use duplexify::Duplex;
 use futures::{executor, io, AsyncRead, AsyncReadExt};
 
 use smtp_message::{Email, EscapedDataReader, Reply, ReplyCode};
-use smtp_server::{interact, ConnectionMetadata, Decision, MailMetadata};
+use smtp_server::{interact, ConnectionInfo, ConnectionMetadata, Decision, MailMetadata};
 
 struct SimpleConfig;
 
@@ -96,5 +96,10 @@ fn main() -> io::Result<()> {
     let reader = io::AllowStdIo::new(std::io::stdin());
     let writer = io::AllowStdIo::new(std::io::stdout());
     let io = Duplex::new(reader, writer);
-    executor::block_on(interact(io, (), &mut SimpleConfig))
+    executor::block_on(interact(
+        io,
+        ConnectionInfo::default(),
+        (),
+        &mut SimpleConfig,
+    ))
 }
@@ -14,7 +14,7 @@ use futures_test::io::AsyncReadTestExt;
 use libfuzzer_sys::fuzz_target;
 
 use smtp_message::{Email, EscapedDataReader, Reply, ReplyCode};
-use smtp_server::{interact, ConnectionMetadata, Decision, MailMetadata};
+use smtp_server::{interact, ConnectionInfo, ConnectionMetadata, Decision, MailMetadata};
 
 struct FuzzConfig;
 
@@ -111,5 +111,6 @@ fuzz_target!(|data: &[u8]| {
     let reader = Cursor::new(&data[2..]).limited(chunk_size as usize);
     let writer = io::sink();
     let io = Duplex::new(reader, writer);
-    let _ignore_errors = executor::block_on(interact(io, (), &mut FuzzConfig));
+    let _ignore_errors =
+        executor::block_on(interact(io, ConnectionInfo::default(), (), &mut FuzzConfig));
 });
@@ -1,10 +1,10 @@
-#![feature(io_slice_advance)]
 #![type_length_limit = "200000000"]
 
 use std::{
     borrow::Cow,
     cmp,
     io::{self, IoSlice},
+    net::{SocketAddr, TcpStream},
     ops::Range,
 };
 
@@ -14,9 +14,17 @@ use futures::{
     pin_mut,
 };
 use smtp_message::{
-    next_crlf, nom, Command, Email, EnhancedReplyCode, EscapedDataReader, Hostname, MaybeUtf8,
-    NextCrLfState, Reply, ReplyCode,
+    next_crlf, nom, Capabilities, Command, Email, EnhancedReplyCode, EscapedDataReader, Hostname,
+    MaybeUtf8, NextCrLfState, Reply, ReplyCode,
 };
+use smtp_queue::QueueFull;
+
+mod proxy;
+mod received;
+mod xclient;
+
+pub use proxy::IpNetwork;
+pub use received::{Protocol, ReceivedHeader};
 
 pub const RDBUF_SIZE: usize = 16 * 1024;
 const MINIMUM_FREE_BUFSPACE: usize = 128;
@@ -33,13 +41,67 @@ pub struct MailMetadata<U> {
     pub to: Vec<Email>,
 }
 
+#[derive(Clone)]
 pub struct HelloInfo {
     pub is_ehlo: bool,
     pub hostname: Hostname,
 }
 
+/// Information about the underlying connection, as given to `interact`
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionInfo {
+    pub remote_addr: Option<SocketAddr>,
+    pub local_addr: Option<SocketAddr>,
+
+    /// Address of the proxy the connection came through, if it was proxied
+    /// with the PROXY protocol
+    ///
+    /// `remote_addr` and `local_addr` are then the ones of the original
+    /// connection, as given by the proxy.
+    pub proxy_addr: Option<SocketAddr>,
+
+    /// Name of the listener that accepted the connection, for servers that
+    /// handle connections differently depending on how they were received
+    pub listener: Option<String>,
+
+    /// Whether the connection is TLS-protected, eg. because it was accepted
+    /// on an implicit TLS port
+    pub is_tls: bool,
+
+    /// Login the client is authenticated as, if any
+    pub login: Option<String>,
+}
+
+impl ConnectionInfo {
+    /// Fills the remote and local addresses from those of `stream`
+    ///
+    /// Asynchronous wrappers around `TcpStream` usually give access to it,
+    /// eg. through `get_ref()`.
+    pub fn from_tcp(stream: &TcpStream) -> io::Result<ConnectionInfo> {
+        Ok(ConnectionInfo {
+            remote_addr: Some(stream.peer_addr()?),
+            local_addr: Some(stream.local_addr()?),
+            proxy_addr: None,
+            listener: None,
+            is_tls: false,
+            login: None,
+        })
+    }
+
+    pub fn with_listener<S: Into<String>>(mut self, listener: S) -> ConnectionInfo {
+        self.listener = Some(listener.into());
+        self
+    }
+
+    pub fn with_tls(mut self, is_tls: bool) -> ConnectionInfo {
+        self.is_tls = is_tls;
+        self
+    }
+}
+
 pub struct ConnectionMetadata<U> {
     pub user: U,
+    pub info: ConnectionInfo,
     pub hello: Option<HelloInfo>,
 }
 
@@ -91,6 +153,10 @@ pub trait Config: Send + Sync {
     /// Note: the EscapedDataReader has an inner buffer size of
     /// [`RDBUF_SIZE`](RDBUF_SIZE), which means that reads should not happen
     /// with more than this buffer size.
+    ///
+    /// If [`prepend_received`](Config::prepend_received) is set, `stream`
+    /// starts with the [`ReceivedHeader`](ReceivedHeader) built from `meta`
+    /// and `conn_meta`.
     async fn handle_mail<'a, R>(
         &self,
         stream: &mut EscapedDataReader<'a, R>,
@@ -100,8 +166,80 @@ pub trait Config: Send + Sync {
     where
         R: Send + Unpin + AsyncRead;
 
+    /// Same as `handle_mail`, but returns one decision per recipient, in the
+    /// order of `meta.to`
+    ///
+    /// This is only called in LMTP mode, where a reply is sent for each
+    /// recipient after the end of the data. The default implementation
+    /// applies the decision of `handle_mail` to all the recipients.
+    async fn handle_mail_per_recipient<'a, R>(
+        &self,
+        stream: &mut EscapedDataReader<'a, R>,
+        meta: MailMetadata<Self::MailUserMeta>,
+        conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> Vec<Decision>
+    where
+        R: Send + Unpin + AsyncRead,
+    {
+        let recipients = meta.to.len();
+        match self.handle_mail(stream, meta, conn_meta).await {
+            Decision::Accept => (0..recipients).map(|_| Decision::Accept).collect(),
+            Decision::Reject(r) => (0..recipients)
+                .map(|_| Decision::Reject(r.clone()))
+                .collect(),
+        }
+    }
+
     fn hostname(&self) -> Cow<'static, str>;
 
+    /// Whether to prepend the [`ReceivedHeader`](ReceivedHeader) of the mail
+    /// to the stream given to `handle_mail`
+    ///
+    /// It records the id returned by [`queue_id`](Config::queue_id) and the
+    /// time the `DATA` command was accepted.
+    fn prepend_received(&self) -> bool {
+        false
+    }
+
+    /// Queue ID the mail is recorded with in the prepended `Received:`
+    /// header, eg. one generated by `new_mail`
+    #[allow(unused_variables)]
+    fn queue_id(&self, meta: &MailMetadata<Self::MailUserMeta>) -> Option<String> {
+        None
+    }
+
+    /// Whether to speak LMTP (RFC 2033) instead of SMTP
+    ///
+    /// The client then has to greet with `LHLO` instead of `HELO` or `EHLO`,
+    /// and mails are handled with `handle_mail_per_recipient`.
+    fn is_lmtp(&self) -> bool {
+        false
+    }
+
+    /// Networks of the proxies that connect using the PROXY protocol
+    ///
+    /// Connections from these networks must start with a PROXY protocol
+    /// (version 1 or 2) header, that gives the address of the original
+    /// client. They are closed if it is missing or malformed.
+    fn trusted_proxies(&self) -> &[IpNetwork] {
+        &[]
+    }
+
+    /// Whether the peer is trusted to use the Postfix `XCLIENT` and
+    /// `XFORWARD` commands, that override the client address, HELO name
+    /// and login recorded in `conn_meta.info`
+    ///
+    /// This is called once, before the welcome banner, and the commands are
+    /// only advertised in the EHLO reply to trusted peers. A HELO name given
+    /// by `XCLIENT` goes through `filter_hello` when the client says hello
+    /// again after it, and one given by `XFORWARD` right away. The values
+    /// given by `XFORWARD` only last until the end of the next mail
+    /// transaction, or until `RSET`.
+    #[allow(unused_variables)]
+    fn allow_xclient(&self, conn_meta: &ConnectionMetadata<Self::ConnectionUserMeta>) -> bool {
+        false
+    }
+
     fn banner(&self) -> Cow<'static, str> {
         "Service ready".into()
     }
@@ -157,17 +295,12 @@ pub trait Config: Send + Sync {
             banner += " ";
             banner += additional_banner;
         }
-        Reply {
-            code: ReplyCode::OKAY,
-            ecode: None,
-            text: vec![
-                MaybeUtf8::Utf8(banner),
-                MaybeUtf8::Utf8("8BITMIME".into()),
-                MaybeUtf8::Utf8("ENHANCEDSTATUSCODES".into()),
-                MaybeUtf8::Utf8("PIPELINING".into()),
-                MaybeUtf8::Utf8("SMTPUTF8".into()),
-            ],
-        }
+        Capabilities::<&str>::new()
+            .with_eightbitmime()
+            .with_enhanced_status_codes()
+            .with_pipelining()
+            .with_smtputf8()
+            .to_reply(MaybeUtf8::Utf8(banner))
     }
 
     fn mail_okay(&self) -> Reply<Cow<'static, str>> {
@@ -240,6 +373,14 @@ pub trait Config: Send + Sync {
         }
     }
 
+    fn invalid_arguments(&self) -> Reply<Cow<'static, str>> {
+        Reply {
+            code: ReplyCode::SYNTAX_ERROR,
+            ecode: Some(EnhancedReplyCode::PERMANENT_INVALID_COMMAND_ARGUMENTS.into()),
+            text: vec![MaybeUtf8::Utf8("Invalid command arguments".into())],
+        }
+    }
+
     fn line_too_long(&self) -> Reply<Cow<'static, str>> {
         Reply {
             code: ReplyCode::COMMAND_UNRECOGNIZED,
@@ -255,6 +396,41 @@ pub trait Config: Send + Sync {
             text: vec![MaybeUtf8::Utf8("System incorrectly configured".into())],
         }
     }
+
+    /// Reply for the recipients `handle_mail_per_recipient` did not return a
+    /// decision for
+    fn handle_mail_missing_decision(&self) -> Reply<Cow<'static, str>> {
+        self.handle_mail_did_not_call_complete()
+    }
+
+    /// Reply to reject a mail with when there is no room left to store it,
+    /// eg. from `filter_from` or `handle_mail` when the queue is full
+    fn insufficient_storage(&self) -> Reply<Cow<'static, str>> {
+        Reply {
+            code: ReplyCode::INSUFFICIENT_STORAGE,
+            ecode: Some(EnhancedReplyCode::TRANSIENT_SYSTEM_FULL.into()),
+            text: vec![MaybeUtf8::Utf8("Insufficient system storage".into())],
+        }
+    }
+
+    /// Reply to reject a mail with when storing it failed with `err`, eg.
+    /// from `filter_from` when checking the space left in the queue failed,
+    /// or from `handle_mail` when enqueuing the mail failed
+    ///
+    /// A full queue (see [`QueueFull`](smtp_queue::QueueFull)) is reported
+    /// with `insufficient_storage`, and any other error as a transient local
+    /// error.
+    fn storage_error(&self, err: &io::Error) -> Reply<Cow<'static, str>> {
+        if QueueFull::is(err) {
+            self.insufficient_storage()
+        } else {
+            Reply {
+                code: ReplyCode::LOCAL_ERROR,
+                ecode: Some(EnhancedReplyCode::TRANSIENT_SYSTEM_OTHER.into()),
+                text: vec![MaybeUtf8::Utf8("Local error in processing".into())],
+            }
+        }
+    }
 }
 
 // TODO: upstream in AsyncWriteExt?
@@ -266,12 +442,70 @@ where
     let mut len = bufs.iter().map(|b| b.len()).sum::<usize>();
     while len > 0 {
         let toskip = w.write_vectored(bufs).await?;
-        bufs = IoSlice::advance(bufs, toskip);
+        IoSlice::advance_slices(&mut bufs, toskip);
         len -= toskip;
     }
     Ok(())
 }
 
+fn ehlo_reply<Cfg: Config>(
+    cfg: &Cfg,
+    conn_meta: &ConnectionMetadata<Cfg::ConnectionUserMeta>,
+    xclient_allowed: bool,
+) -> Reply<Cow<'static, str>> {
+    let mut reply = cfg.ehlo_okay(conn_meta);
+    if xclient_allowed {
+        let xclient = format!("XCLIENT {}", xclient::XCLIENT_ATTRIBUTES.join(" "));
+        let xforward = format!("XFORWARD {}", xclient::XFORWARD_ATTRIBUTES.join(" "));
+        reply.text.push(MaybeUtf8::Utf8(xclient.into()));
+        reply.text.push(MaybeUtf8::Utf8(xforward.into()));
+    }
+    reply
+}
+
+/// Runs `filter_hello` on the HELO name given by XCLIENT, once the client
+/// said hello again after it, or by XFORWARD, and sets it as the
+/// connection's hello if it is accepted
+async fn filter_xclient_hello<Cfg: Config>(
+    cfg: &Cfg,
+    is_ehlo: bool,
+    hello: &HelloInfo,
+    conn_meta: &mut ConnectionMetadata<Cfg::ConnectionUserMeta>,
+) -> Decision {
+    let mut hostname = match hello.hostname {
+        Hostname::Utf8Domain {
+            ref raw,
+            ref punycode,
+        } => Hostname::Utf8Domain {
+            raw: &raw[..],
+            punycode: punycode.clone(),
+        },
+        Hostname::AsciiDomain { ref raw } => Hostname::AsciiDomain { raw: &raw[..] },
+        Hostname::Ipv6 { ref raw, ip } => Hostname::Ipv6 { raw: &raw[..], ip },
+        Hostname::Ipv4 { ref raw, ip } => Hostname::Ipv4 { raw: &raw[..], ip },
+    };
+    let decision = cfg.filter_hello(is_ehlo, &mut hostname, conn_meta).await;
+    if let Decision::Accept = decision {
+        conn_meta.hello = Some(HelloInfo {
+            is_ehlo: hello.is_ehlo,
+            hostname: hostname.to_owned(),
+        });
+    }
+    decision
+}
+
+/// Restores the connection information saved before the first XFORWARD
+/// command of a mail transaction, once it is over
+fn reset_xforward<U>(
+    saved: &mut Option<(ConnectionInfo, Option<HelloInfo>)>,
+    conn_meta: &mut ConnectionMetadata<U>,
+) {
+    if let Some((info, hello)) = saved.take() {
+        conn_meta.info = info;
+        conn_meta.hello = hello;
+    }
+}
+
 macro_rules! send_reply {
     ($writer:expr, $reply:expr) => {
         write_vectored_all(&mut $writer, &mut $reply.as_io_slices().collect::<Vec<_>>())
@@ -306,6 +540,7 @@ where
 
 pub async fn interact<IO, Cfg>(
     io: IO,
+    info: ConnectionInfo,
     metadata: Cfg::ConnectionUserMeta,
     cfg: &Cfg,
 ) -> io::Result<()>
@@ -325,10 +560,36 @@ where
     // .collect() (present in `send_reply()`)
     let mut conn_meta = ConnectionMetadata {
         user: metadata,
+        info,
         hello: None,
     };
     let mut mail_meta = None;
 
+    if let Some(addr) = conn_meta.info.remote_addr {
+        if cfg.trusted_proxies().iter().any(|n| n.contains(addr.ip())) {
+            match proxy::read_header(&mut io, rdbuf, &mut unhandled).await? {
+                proxy::ProxyHeader::Local => (),
+                proxy::ProxyHeader::Proxied {
+                    remote_addr,
+                    local_addr,
+                } => {
+                    conn_meta.info.proxy_addr = Some(addr);
+                    conn_meta.info.remote_addr = Some(remote_addr);
+                    conn_meta.info.local_addr = Some(local_addr);
+                }
+            }
+        }
+    }
+
+    let is_lmtp = cfg.is_lmtp();
+    let xclient_allowed = cfg.allow_xclient(&conn_meta);
+    // Hello given by XCLIENT, that the client's next hello is accepted as
+    // instead of its own if the filter lets it through
+    let mut xclient_hello: Option<HelloInfo> = None;
+    // Connection information from before the XFORWARD commands, that only
+    // apply to the current mail transaction
+    let mut xforward_saved: Option<(ConnectionInfo, Option<HelloInfo>)> = None;
+
     send_reply!(io, cfg.welcome_banner()).await?;
 
     loop {
@@ -392,29 +653,64 @@ where
         match cmd {
             None => (),
 
+            Some(Command::Ehlo { .. }) | Some(Command::Helo { .. }) if is_lmtp => {
+                send_reply!(io, cfg.command_unrecognized()).await?;
+            }
+
+            Some(Command::Lhlo { .. }) if !is_lmtp => {
+                send_reply!(io, cfg.command_unrecognized()).await?;
+            }
+
             // TODO: find some way to unify with the below branch
-            Some(Command::Ehlo { mut hostname }) => match conn_meta.hello {
-                Some(_) => {
-                    send_reply!(io, cfg.already_did_hello()).await?;
-                }
-                None => match cfg.filter_hello(true, &mut hostname, &mut conn_meta).await {
-                    Decision::Reject(r) => {
-                        send_reply!(io, r).await?;
+            Some(Command::Ehlo { mut hostname }) | Some(Command::Lhlo { mut hostname }) => {
+                match conn_meta.hello {
+                    Some(_) => {
+                        send_reply!(io, cfg.already_did_hello()).await?;
                     }
-                    Decision::Accept => {
-                        conn_meta.hello = Some(HelloInfo {
-                            is_ehlo: true,
-                            hostname: hostname.to_owned(),
-                        });
-                        send_reply!(io, cfg.ehlo_okay(&conn_meta)).await?;
+                    None if xclient_hello.is_some() => {
+                        let hello = xclient_hello.as_ref().unwrap();
+                        match filter_xclient_hello(cfg, true, hello, &mut conn_meta).await {
+                            Decision::Reject(r) => {
+                                send_reply!(io, r).await?;
+                            }
+                            Decision::Accept => {
+                                xclient_hello = None;
+                                send_reply!(io, ehlo_reply(cfg, &conn_meta, xclient_allowed))
+                                    .await?;
+                            }
+                        }
                     }
-                },
-            },
+                    None => match cfg.filter_hello(true, &mut hostname, &mut conn_meta).await {
+                        Decision::Reject(r) => {
+                            send_reply!(io, r).await?;
+                        }
+                        Decision::Accept => {
+                            conn_meta.hello = Some(HelloInfo {
+                                is_ehlo: true,
+                                hostname: hostname.to_owned(),
+                            });
+                            send_reply!(io, ehlo_reply(cfg, &conn_meta, xclient_allowed)).await?;
+                        }
+                    },
+                }
+            }
 
             Some(Command::Helo { mut hostname }) => match conn_meta.hello {
                 Some(_) => {
                     send_reply!(io, cfg.already_did_hello()).await?;
                 }
+                None if xclient_hello.is_some() => {
+                    let hello = xclient_hello.as_ref().unwrap();
+                    match filter_xclient_hello(cfg, false, hello, &mut conn_meta).await {
+                        Decision::Reject(r) => {
+                            send_reply!(io, r).await?;
+                        }
+                        Decision::Accept => {
+                            xclient_hello = None;
+                            send_reply!(io, cfg.helo_okay(&conn_meta)).await?;
+                        }
+                    }
+                }
                 None => match cfg.filter_hello(false, &mut hostname, &mut conn_meta).await {
                     Decision::Reject(r) => {
                         send_reply!(io, r).await?;
@@ -504,26 +800,56 @@ where
                         }
                         Decision::Accept => {
                             send_reply!(io, cfg.data_okay()).await?;
+                            // LMTP sends one reply per recipient, SMTP a single one
+                            let replies = if is_lmtp { mail_meta_unw.to.len() } else { 1 };
+                            let received = if cfg.prepend_received() {
+                                let hostname = cfg.hostname();
+                                let id = cfg.queue_id(&mail_meta_unw);
+                                let mut header =
+                                    ReceivedHeader::new(&hostname, &mail_meta_unw, &conn_meta);
+                                header.id = id.as_deref();
+                                header.to_string().into_bytes()
+                            } else {
+                                Vec::new()
+                            };
                             let mut reader =
-                                EscapedDataReader::new(rdbuf, unhandled.clone(), &mut io);
-                            let decision = cfg
-                                .handle_mail(&mut reader, mail_meta_unw, &mut conn_meta)
-                                .await;
+                                EscapedDataReader::new(rdbuf, unhandled.clone(), &mut io)
+                                    .with_prefix(received);
+                            let decisions = if is_lmtp {
+                                cfg.handle_mail_per_recipient(
+                                    &mut reader,
+                                    mail_meta_unw,
+                                    &mut conn_meta,
+                                )
+                                .await
+                            } else {
+                                vec![
+                                    cfg.handle_mail(&mut reader, mail_meta_unw, &mut conn_meta)
+                                        .await,
+                                ]
+                            };
                             if let Some(u) = reader.get_unhandled() {
                                 unhandled = u;
-                                match decision {
-                                    Decision::Accept => {
-                                        send_reply!(io, cfg.mail_accepted()).await?;
-                                    }
-                                    Decision::Reject(r) => {
-                                        send_reply!(io, r).await?;
-                                        // Other mail systems (at least postfix,
-                                        // OpenSMTPD and gmail) appear to drop
-                                        // the state on an unsuccessful DATA
-                                        // command (eg. too long,
-                                        // non-RFC5322-compliant, etc.).
-                                        // Couldn't find the RFC reference
-                                        // anywhere, though.
+                                let mut decisions = decisions.into_iter();
+                                for _ in 0..replies {
+                                    match decisions.next() {
+                                        Some(Decision::Accept) => {
+                                            send_reply!(io, cfg.mail_accepted()).await?;
+                                        }
+                                        Some(Decision::Reject(r)) => {
+                                            send_reply!(io, r).await?;
+                                            // Other mail systems (at least
+                                            // postfix, OpenSMTPD and gmail)
+                                            // appear to drop the state on an
+                                            // unsuccessful DATA command (eg.
+                                            // too long, non-RFC5322-compliant,
+                                            // etc.). Couldn't find the RFC
+                                            // reference anywhere, though.
+                                        }
+                                        None => {
+                                            send_reply!(io, cfg.handle_mail_missing_decision())
+                                                .await?;
+                                        }
                                     }
                                 }
                             } else {
@@ -540,13 +866,81 @@ where
                                 }
                                 reader.complete();
                                 unhandled = reader.get_unhandled().unwrap();
-                                send_reply!(io, cfg.handle_mail_did_not_call_complete()).await?;
+                                for _ in 0..replies {
+                                    send_reply!(io, cfg.handle_mail_did_not_call_complete())
+                                        .await?;
+                                }
                             }
+                            reset_xforward(&mut xforward_saved, &mut conn_meta);
                         }
                     }
                 }
             },
 
+            Some(Command::Xclient { attributes }) if xclient_allowed => {
+                if mail_meta.is_some() {
+                    send_reply!(io, cfg.bad_sequence()).await?;
+                } else {
+                    match xclient::Attributes::parse(&attributes, xclient::XCLIENT_ATTRIBUTES) {
+                        None => {
+                            send_reply!(io, cfg.invalid_arguments()).await?;
+                        }
+                        Some(attributes) => {
+                            // The session restarts, and the client has to say
+                            // hello again, which will not override the HELO
+                            // name given here
+                            conn_meta.hello = None;
+                            xclient_hello = None;
+                            xforward_saved = None;
+                            attributes.apply(&mut conn_meta.info, &mut xclient_hello);
+                            send_reply!(io, cfg.welcome_banner()).await?;
+                        }
+                    }
+                }
+            }
+
+            Some(Command::Xforward { attributes }) if xclient_allowed => {
+                if conn_meta.hello.is_none() || mail_meta.is_some() {
+                    send_reply!(io, cfg.bad_sequence()).await?;
+                } else {
+                    match xclient::Attributes::parse(&attributes, xclient::XFORWARD_ATTRIBUTES) {
+                        None => {
+                            send_reply!(io, cfg.invalid_arguments()).await?;
+                        }
+                        Some(attributes) => {
+                            let saved = (conn_meta.info.clone(), conn_meta.hello.clone());
+                            let mut hello = conn_meta.hello.take();
+                            attributes.apply(&mut conn_meta.info, &mut hello);
+                            // The hello is kept by `apply`, as it was checked above
+                            let hello = hello.unwrap();
+                            match filter_xclient_hello(cfg, hello.is_ehlo, &hello, &mut conn_meta)
+                                .await
+                            {
+                                Decision::Reject(r) => {
+                                    conn_meta.info = saved.0;
+                                    conn_meta.hello = saved.1;
+                                    send_reply!(io, r).await?;
+                                }
+                                Decision::Accept => {
+                                    xforward_saved.get_or_insert(saved);
+                                    send_reply!(
+                                        io,
+                                        cfg.okay(EnhancedReplyCode::SUCCESS_UNDEFINED.into())
+                                    )
+                                    .await?;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+
+            Some(Command::Rset) => {
+                mail_meta = None;
+                reset_xforward(&mut xforward_saved, &mut conn_meta);
+                send_reply!(io, cfg.okay(EnhancedReplyCode::SUCCESS_UNDEFINED.into())).await?;
+            }
+
             Some(_) => {
                 // TODO: this probably shouldn't be required
                 send_reply!(io, cfg.command_unimplemented()).await?;
@@ -559,7 +953,9 @@ where
 mod tests {
     use super::*;
     use std::{
-        self, str,
+        self,
+        net::{IpAddr, Ipv4Addr},
+        str,
         sync::{Arc, Mutex},
     };
 
@@ -580,6 +976,7 @@ mod tests {
 
     struct TestConfig {
         mails: Arc<Mutex<Vec<(Option<Email>, Vec<Email>, Vec<u8>)>>>,
+        lmtp: bool,
     }
 
     #[async_trait]
@@ -591,8 +988,43 @@ mod tests {
             "test.example.org".into()
         }
 
+        fn trusted_proxies(&self) -> &[IpNetwork] {
+            const TRUSTED_PROXIES: &[IpNetwork] = &[IpNetwork::new(
+                IpAddr::V4(Ipv4Addr::new(192, 0, 2, 254)),
+                31,
+            )];
+            TRUSTED_PROXIES
+        }
+
+        fn allow_xclient(&self, conn_meta: &ConnectionMetadata<()>) -> bool {
+            let trusted = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 253));
+            conn_meta.info.remote_addr.map(|a| a.ip()) == Some(trusted)
+        }
+
+        fn is_lmtp(&self) -> bool {
+            self.lmtp
+        }
+
         async fn new_mail(&self, _conn_meta: &mut ConnectionMetadata<()>) {}
 
+        async fn filter_hello(
+            &self,
+            _is_ehlo: bool,
+            _hostname: &mut Hostname<&str>,
+            conn_meta: &mut ConnectionMetadata<()>,
+        ) -> Decision {
+            let banned = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 66));
+            if conn_meta.info.remote_addr.map(|a| a.ip()) == Some(banned) {
+                Decision::Reject(Reply {
+                    code: ReplyCode::POLICY_REASON,
+                    ecode: None,
+                    text: vec!["Address banned".into()],
+                })
+            } else {
+                Decision::Accept
+            }
+        }
+
         async fn filter_from(
             &self,
             addr: &mut Option<Email<&str>>,
@@ -607,6 +1039,8 @@ mod tests {
                     ecode: None,
                     text: vec!["User 'bad' banned".into()],
                 })
+            } else if *addr == Some(Email::parse_bracketed(b"<full@quux.example.org>").unwrap()) {
+                Decision::Reject(self.insufficient_storage())
             } else {
                 Decision::Accept
             }
@@ -659,6 +1093,8 @@ mod tests {
                     ecode: None,
                     text: vec!["Don't you dare say 'World'!".into()],
                 })
+            } else if mail_text.windows(4).position(|x| x == b"Full").is_some() {
+                Decision::Reject(self.insufficient_storage())
             } else {
                 self.mails
                     .lock()
@@ -667,6 +1103,35 @@ mod tests {
                 Decision::Accept
             }
         }
+
+        async fn handle_mail_per_recipient<'a, R>(
+            &self,
+            reader: &mut EscapedDataReader<'a, R>,
+            meta: MailMetadata<()>,
+            conn_meta: &mut ConnectionMetadata<()>,
+        ) -> Vec<Decision>
+        where
+            R: Send + Unpin + AsyncRead,
+        {
+            let full = meta
+                .to
+                .iter()
+                .map(|to| *to.localpart.raw() == "full")
+                .collect::<Vec<_>>();
+            match self.handle_mail(reader, meta, conn_meta).await {
+                Decision::Accept => full
+                    .into_iter()
+                    .map(|full| match full {
+                        true => Decision::Reject(self.insufficient_storage()),
+                        false => Decision::Accept,
+                    })
+                    .collect(),
+                Decision::Reject(r) => full
+                    .into_iter()
+                    .map(|_| Decision::Reject(r.clone()))
+                    .collect(),
+            }
+        }
     }
 
     #[test]
@@ -749,6 +1214,23 @@ mod tests {
                     b"Hello\r\n.\r\n",
                 )],
             ),
+            (
+                b"HELO test\r\n\
+                  MAIL FROM:<full@quux.example.org>\r\n\
+                  MAIL FROM:<foo@bar.example.org>\r\n\
+                  RCPT TO:<foo2@bar.example.org>\r\n\
+                  DATA\r\n\
+                  Full queue\r\n\
+                  .\r\n",
+                b"220 test.example.org Service ready\r\n\
+                  250 test.example.org\r\n\
+                  452 4.3.1 Insufficient system storage\r\n\
+                  250 2.0.0 Okay\r\n\
+                  250 2.1.5 Okay\r\n\
+                  354 Start mail input; end with <CRLF>.<CRLF>\r\n\
+                  452 4.3.1 Insufficient system storage\r\n",
+                &[],
+            ),
             (
                 b"HELO test\r\n\
                   MAIL FROM:<foo@test.example.com>\r\n\
@@ -795,10 +1277,11 @@ mod tests {
             let resp_mail = Arc::new(Mutex::new(Vec::new()));
             let cfg = TestConfig {
                 mails: resp_mail.clone(),
+                lmtp: false,
             };
             let mut resp = Vec::new();
             let io = Duplex::new(Cursor::new(inp), Cursor::new(&mut resp));
-            executor::block_on(interact(io, (), &cfg)).unwrap();
+            executor::block_on(interact(io, ConnectionInfo::default(), (), &cfg)).unwrap();
 
             println!("Expecting: {:?}", show_bytes(out));
             println!("Got      : {:?}", show_bytes(&resp));
@@ -828,6 +1311,271 @@ mod tests {
         }
     }
 
+    #[test]
+    fn proxy_protocol() {
+        let trusted = "192.0.2.254:1234";
+        let untrusted = "203.0.113.1:1234";
+        let tests: &[(&str, &[u8], Result<&[u8], io::ErrorKind>)] = &[
+            (
+                "192.0.2.255:1234",
+                b"PROXY TCP4 198.51.100.66 192.0.2.1 4242 25\r\n\
+                  HELO test\r\n",
+                Ok(b"220 test.example.org Service ready\r\n\
+                     550 Address banned\r\n"),
+            ),
+            (
+                "[::ffff:192.0.2.254]:1234",
+                b"PROXY TCP4 198.51.100.66 192.0.2.1 4242 25\r\n\
+                  HELO test\r\n",
+                Ok(b"220 test.example.org Service ready\r\n\
+                     550 Address banned\r\n"),
+            ),
+            (
+                trusted,
+                b"PROXY TCP4 198.51.100.66 192.0.2.1 4242 25\r\n\
+                  HELO test\r\n",
+                Ok(b"220 test.example.org Service ready\r\n\
+                     550 Address banned\r\n"),
+            ),
+            (
+                trusted,
+                b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0C\
+                  \xC6\x33\x64\x42\xC0\x00\x02\x01\x10\x92\x00\x19\
+                  HELO test\r\n",
+                Ok(b"220 test.example.org Service ready\r\n\
+                     550 Address banned\r\n"),
+            ),
+            (
+                trusted,
+                b"PROXY TCP4 198.51.100.1 192.0.2.1 4242 25\r\n\
+                  HELO test\r\n",
+                Ok(b"220 test.example.org Service ready\r\n\
+                     250 test.example.org\r\n"),
+            ),
+            (trusted, b"HELO test\r\n", Err(io::ErrorKind::InvalidData)),
+            (
+                trusted,
+                b"PROXY TCP4 198.51.100",
+                Err(io::ErrorKind::ConnectionAborted),
+            ),
+            (
+                untrusted,
+                b"PROXY TCP4 198.51.100.66 192.0.2.1 4242 25\r\n\
+                  HELO test\r\n",
+                Ok(b"220 test.example.org Service ready\r\n\
+                     500 5.5.1 Command not recognized\r\n\
+                     250 test.example.org\r\n"),
+            ),
+        ];
+        for &(addr, inp, ref out) in tests {
+            println!("\nSending from {}: {:?}", addr, show_bytes(inp));
+            let cfg = TestConfig {
+                mails: Arc::new(Mutex::new(Vec::new())),
+                lmtp: false,
+            };
+            let info = ConnectionInfo {
+                remote_addr: Some(addr.parse().unwrap()),
+                ..ConnectionInfo::default()
+            };
+            let mut resp = Vec::new();
+            let io = Duplex::new(Cursor::new(inp), Cursor::new(&mut resp));
+            let res = executor::block_on(interact(io, info, (), &cfg));
+            println!("Got: {:?}", show_bytes(&resp));
+            match out {
+                Ok(out) => {
+                    res.unwrap();
+                    assert_eq!(resp, *out);
+                }
+                Err(kind) => {
+                    assert_eq!(res.unwrap_err().kind(), *kind);
+                    assert!(resp.is_empty());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn xclient() {
+        let trusted = "192.0.2.253:1234";
+        let untrusted = "203.0.113.1:1234";
+        let tests: &[(&str, &[u8], &[u8])] = &[
+            (
+                trusted,
+                b"EHLO proxy\r\n\
+                  XCLIENT ADDR=198.51.100.66 PORT=4242\r\n\
+                  HELO test\r\n",
+                b"220 test.example.org Service ready\r\n\
+                  250-test.example.org\r\n\
+                  250-8BITMIME\r\n\
+                  250-ENHANCEDSTATUSCODES\r\n\
+                  250-PIPELINING\r\n\
+                  250-SMTPUTF8\r\n\
+                  250-XCLIENT NAME ADDR PORT PROTO HELO LOGIN DESTADDR DESTPORT\r\n\
+                  250 XFORWARD NAME ADDR PORT PROTO HELO IDENT SOURCE\r\n\
+                  220 test.example.org Service ready\r\n\
+                  550 Address banned\r\n",
+            ),
+            (
+                trusted,
+                b"XCLIENT FOO=bar\r\n\
+                  XCLIENT ADDR=192.0.2.256\r\n\
+                  XCLIENT ADDR=198.51.100.1 HELO=client.example.org PROTO=SMTP\r\n\
+                  HELO proxy\r\n\
+                  MAIL FROM:<>\r\n\
+                  XCLIENT ADDR=192.0.2.1\r\n",
+                b"220 test.example.org Service ready\r\n\
+                  501 5.5.4 Invalid command arguments\r\n\
+                  501 5.5.4 Invalid command arguments\r\n\
+                  220 test.example.org Service ready\r\n\
+                  250 test.example.org\r\n\
+                  250 2.0.0 Okay\r\n\
+                  503 5.5.1 Bad sequence of commands\r\n",
+            ),
+            (
+                trusted,
+                b"XCLIENT ADDR=198.51.100.66 HELO=client.example.org\r\n\
+                  MAIL FROM:<>\r\n\
+                  EHLO proxy\r\n\
+                  HELO proxy\r\n\
+                  MAIL FROM:<>\r\n",
+                b"220 test.example.org Service ready\r\n\
+                  220 test.example.org Service ready\r\n\
+                  503 5.5.1 Bad sequence of commands\r\n\
+                  550 Address banned\r\n\
+                  550 Address banned\r\n\
+                  503 5.5.1 Bad sequence of commands\r\n",
+            ),
+            (
+                trusted,
+                b"XFORWARD ADDR=198.51.100.1\r\n\
+                  HELO proxy\r\n\
+                  XFORWARD ADDR=198.51.100.1 NAME=client.example.org\r\n\
+                  XFORWARD LOGIN=foo\r\n",
+                b"220 test.example.org Service ready\r\n\
+                  503 5.5.1 Bad sequence of commands\r\n\
+                  250 test.example.org\r\n\
+                  250 2.0.0 Okay\r\n\
+                  501 5.5.4 Invalid command arguments\r\n",
+            ),
+            (
+                trusted,
+                b"HELO proxy\r\n\
+                  XFORWARD ADDR=198.51.100.66 HELO=client.example.org\r\n\
+                  XFORWARD ADDR=198.51.100.1\r\n\
+                  MAIL FROM:<>\r\n\
+                  XFORWARD ADDR=198.51.100.1\r\n\
+                  RSET\r\n\
+                  MAIL FROM:<>\r\n",
+                b"220 test.example.org Service ready\r\n\
+                  250 test.example.org\r\n\
+                  550 Address banned\r\n\
+                  250 2.0.0 Okay\r\n\
+                  250 2.0.0 Okay\r\n\
+                  503 5.5.1 Bad sequence of commands\r\n\
+                  250 2.0.0 Okay\r\n\
+                  250 2.0.0 Okay\r\n",
+            ),
+            (
+                untrusted,
+                b"EHLO test\r\n\
+                  XCLIENT ADDR=198.51.100.66\r\n",
+                b"220 test.example.org Service ready\r\n\
+                  250-test.example.org\r\n\
+                  250-8BITMIME\r\n\
+                  250-ENHANCEDSTATUSCODES\r\n\
+                  250-PIPELINING\r\n\
+                  250 SMTPUTF8\r\n\
+                  502 5.5.1 Command not implemented\r\n",
+            ),
+        ];
+        for &(addr, inp, out) in tests {
+            println!("\nSending from {}: {:?}", addr, show_bytes(inp));
+            let cfg = TestConfig {
+                mails: Arc::new(Mutex::new(Vec::new())),
+                lmtp: false,
+            };
+            let info = ConnectionInfo {
+                remote_addr: Some(addr.parse().unwrap()),
+                ..ConnectionInfo::default()
+            };
+            let mut resp = Vec::new();
+            let io = Duplex::new(Cursor::new(inp), Cursor::new(&mut resp));
+            executor::block_on(interact(io, info, (), &cfg)).unwrap();
+            println!("Got: {:?}", show_bytes(&resp));
+            assert_eq!(resp, out);
+        }
+    }
+
+    #[test]
+    fn lmtp() {
+        let tests: &[(bool, &[u8], &[u8])] = &[
+            (
+                true,
+                b"LHLO test\r\n\
+                  MAIL FROM:<>\r\n\
+                  RCPT TO:<foo@bar.example.org>\r\n\
+                  RCPT TO:<baz@quux.example.org>\r\n\
+                  RCPT TO:<full@bar.example.org>\r\n\
+                  DATA\r\n\
+                  Hello\r\n\
+                  .\r\n\
+                  MAIL FROM:<>\r\n\
+                  RCPT TO:<foo@bar.example.org>\r\n\
+                  RCPT TO:<foo2@bar.example.org>\r\n\
+                  DATA\r\n\
+                  Hello World\r\n\
+                  .\r\n",
+                b"220 test.example.org Service ready\r\n\
+                  250-test.example.org\r\n\
+                  250-8BITMIME\r\n\
+                  250-ENHANCEDSTATUSCODES\r\n\
+                  250-PIPELINING\r\n\
+                  250 SMTPUTF8\r\n\
+                  250 2.0.0 Okay\r\n\
+                  250 2.1.5 Okay\r\n\
+                  550 No user 'baz'\r\n\
+                  250 2.1.5 Okay\r\n\
+                  354 Start mail input; end with <CRLF>.<CRLF>\r\n\
+                  250 2.0.0 Okay\r\n\
+                  452 4.3.1 Insufficient system storage\r\n\
+                  250 2.0.0 Okay\r\n\
+                  250 2.1.5 Okay\r\n\
+                  250 2.1.5 Okay\r\n\
+                  354 Start mail input; end with <CRLF>.<CRLF>\r\n\
+                  550 Don't you dare say 'World'!\r\n\
+                  550 Don't you dare say 'World'!\r\n",
+            ),
+            (
+                true,
+                b"EHLO test\r\n\
+                  HELO test\r\n\
+                  MAIL FROM:<>\r\n",
+                b"220 test.example.org Service ready\r\n\
+                  500 5.5.1 Command not recognized\r\n\
+                  500 5.5.1 Command not recognized\r\n\
+                  503 5.5.1 Bad sequence of commands\r\n",
+            ),
+            (
+                false,
+                b"LHLO test\r\n",
+                b"220 test.example.org Service ready\r\n\
+                  500 5.5.1 Command not recognized\r\n",
+            ),
+        ];
+        for &(lmtp, inp, out) in tests {
+            println!("\nSending with lmtp={}: {:?}", lmtp, show_bytes(inp));
+            let cfg = TestConfig {
+                mails: Arc::new(Mutex::new(Vec::new())),
+                lmtp,
+            };
+            let mut resp = Vec::new();
+            let io = Duplex::new(Cursor::new(inp), Cursor::new(&mut resp));
+            executor::block_on(interact(io, ConnectionInfo::default(), (), &cfg)).unwrap();
+            println!("Got: {:?}", show_bytes(&resp));
+            assert_eq!(resp, out);
+        }
+    }
+
     // Fuzzer-found
     #[test]
     fn interrupted_data() {
@@ -837,11 +1585,12 @@ mod tests {
                            hello";
         let cfg = TestConfig {
             mails: Arc::new(Mutex::new(Vec::new())),
+            lmtp: false,
         };
         let mut resp = Vec::new();
         let io = Duplex::new(Cursor::new(txt), Cursor::new(&mut resp));
         assert_eq!(
-            executor::block_on(interact(io, (), &cfg))
+            executor::block_on(interact(io, ConnectionInfo::default(), (), &cfg))
                 .unwrap_err()
                 .kind(),
             io::ErrorKind::ConnectionAborted,
@@ -905,9 +1654,374 @@ mod tests {
               \r\n\r\n\r\n\r\n\r\n\r\n\r\n\r\n\r\n\r\n\r\n\n\r\n\n\r\n\r\n\r\n\r\n\r\n\n\r\n\r\n";
         let cfg = TestConfig {
             mails: Arc::new(Mutex::new(Vec::new())),
+            lmtp: false,
         };
         let mut resp = Vec::new();
         let io = Duplex::new(Cursor::new(txt), Cursor::new(&mut resp));
-        executor::block_on(interact(io, (), &cfg)).unwrap();
+        executor::block_on(interact(io, ConnectionInfo::default(), (), &cfg)).unwrap();
+    }
+
+    #[test]
+    fn connection_info_from_tcp() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, remote_addr) = listener.accept().unwrap();
+        let info = ConnectionInfo::from_tcp(&server)
+            .unwrap()
+            .with_listener("smtp");
+        assert_eq!(info.remote_addr, Some(remote_addr));
+        assert_eq!(info.remote_addr, client.local_addr().ok());
+        assert_eq!(info.local_addr, listener.local_addr().ok());
+        assert_eq!(info.listener.as_deref(), Some("smtp"));
+        assert!(!info.is_tls);
+    }
+
+    struct QueueConfig {
+        storage: smtp_queue_fs::FsStorage<()>,
+    }
+
+    #[async_trait]
+    impl Config for QueueConfig {
+        type ConnectionUserMeta = ();
+        type MailUserMeta = ();
+
+        fn hostname(&self) -> Cow<'static, str> {
+            "test.example.org".into()
+        }
+
+        async fn new_mail(&self, _conn_meta: &mut ConnectionMetadata<()>) {}
+
+        async fn filter_from(
+            &self,
+            _from: &mut Option<Email<&str>>,
+            _meta: &mut MailMetadata<()>,
+            _conn_meta: &mut ConnectionMetadata<()>,
+        ) -> Decision {
+            match smtp_queue::Storage::<()>::check_space(&self.storage).await {
+                Ok(()) => Decision::Accept,
+                Err(e) => Decision::Reject(self.storage_error(&e)),
+            }
+        }
+
+        async fn filter_to(
+            &self,
+            _to: &mut Email<&str>,
+            _meta: &mut MailMetadata<()>,
+            _conn_meta: &mut ConnectionMetadata<()>,
+        ) -> Decision {
+            Decision::Accept
+        }
+
+        async fn handle_mail<'a, R>(
+            &self,
+            reader: &mut EscapedDataReader<'a, R>,
+            meta: MailMetadata<()>,
+            _conn_meta: &mut ConnectionMetadata<()>,
+        ) -> Decision
+        where
+            R: Send + Unpin + AsyncRead,
+        {
+            use smtp_queue::{Storage, StorageEnqueuer};
+
+            let meta = smtp_queue::MailMetadata {
+                from: meta.from,
+                to: meta.to,
+                metadata: (),
+            };
+            let schedule = smtp_queue::ScheduleInfo {
+                at: chrono::Utc::now(),
+                last_attempt: None,
+            };
+            let res = async {
+                let mut enqueuer = self.storage.enqueue(vec![meta], schedule).await?;
+                futures::io::copy(&mut *reader, &mut enqueuer).await?;
+                enqueuer.commit().await
+            }
+            .await;
+            // Skip what was not stored, to keep reading the commands
+            if futures::io::copy(&mut *reader, &mut futures::io::sink())
+                .await
+                .is_err()
+            {
+                return Decision::Reject(self.handle_mail_did_not_call_complete());
+            }
+            reader.complete();
+            match res {
+                Ok(_) => Decision::Accept,
+                Err(e) => Decision::Reject(self.storage_error(&e)),
+            }
+        }
+    }
+
+    #[test]
+    fn full_queue_is_insufficient_storage() {
+        let dir = tempfile::tempdir().unwrap();
+        for d in &["data", "queue", "inflight", "cleanup"] {
+            std::fs::create_dir(dir.path().join(d)).unwrap();
+        }
+        let config = smtp_queue_fs::FsStorageConfig {
+            max_bytes: Some(20),
+            max_mails: Some(1),
+            ..Default::default()
+        };
+        let path = Arc::new(dir.path().to_owned());
+        let storage = executor::block_on(smtp_queue_fs::FsStorage::with_config(path, config));
+        let cfg = QueueConfig {
+            storage: storage.unwrap(),
+        };
+
+        let inp: &[u8] = b"HELO test\r\n\
+            MAIL FROM:<foo@example.org>\r\n\
+            RCPT TO:<bar@example.org>\r\n\
+            DATA\r\n\
+            This mail is too big for the queue\r\n\
+            .\r\n\
+            MAIL FROM:<foo@example.org>\r\n\
+            RCPT TO:<bar@example.org>\r\n\
+            DATA\r\n\
+            Hello\r\n\
+            .\r\n\
+            MAIL FROM:<foo@example.org>\r\n";
+        let out: &[u8] = b"220 test.example.org Service ready\r\n\
+            250 test.example.org\r\n\
+            250 2.0.0 Okay\r\n\
+            250 2.1.5 Okay\r\n\
+            354 Start mail input; end with <CRLF>.<CRLF>\r\n\
+            452 4.3.1 Insufficient system storage\r\n\
+            250 2.0.0 Okay\r\n\
+            250 2.1.5 Okay\r\n\
+            354 Start mail input; end with <CRLF>.<CRLF>\r\n\
+            250 2.0.0 Okay\r\n\
+            452 4.3.1 Insufficient system storage\r\n";
+        let mut resp = Vec::new();
+        let io = Duplex::new(Cursor::new(inp), Cursor::new(&mut resp));
+        executor::block_on(interact(io, ConnectionInfo::default(), (), &cfg)).unwrap();
+        println!("Expecting: {:?}", show_bytes(out));
+        println!("Got      : {:?}", show_bytes(&resp));
+        assert_eq!(resp, out);
+        assert_eq!(
+            std::fs::read_dir(dir.path().join("queue")).unwrap().count(),
+            1
+        );
+    }
+
+    struct ReceivedConfig {
+        next_id: std::sync::atomic::AtomicUsize,
+        mails: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    #[async_trait]
+    impl Config for ReceivedConfig {
+        type ConnectionUserMeta = ();
+        type MailUserMeta = String;
+
+        fn hostname(&self) -> Cow<'static, str> {
+            "test.example.org".into()
+        }
+
+        fn prepend_received(&self) -> bool {
+            true
+        }
+
+        fn allow_xclient(&self, _conn_meta: &ConnectionMetadata<()>) -> bool {
+            true
+        }
+
+        fn queue_id(&self, meta: &MailMetadata<String>) -> Option<String> {
+            Some(meta.user.clone())
+        }
+
+        async fn new_mail(&self, _conn_meta: &mut ConnectionMetadata<()>) -> String {
+            let id = self
+                .next_id
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            format!("id{}", id)
+        }
+
+        async fn filter_from(
+            &self,
+            _from: &mut Option<Email<&str>>,
+            _meta: &mut MailMetadata<String>,
+            _conn_meta: &mut ConnectionMetadata<()>,
+        ) -> Decision {
+            Decision::Accept
+        }
+
+        async fn filter_to(
+            &self,
+            _to: &mut Email<&str>,
+            _meta: &mut MailMetadata<String>,
+            _conn_meta: &mut ConnectionMetadata<()>,
+        ) -> Decision {
+            Decision::Accept
+        }
+
+        async fn handle_mail<'a, R>(
+            &self,
+            reader: &mut EscapedDataReader<'a, R>,
+            _meta: MailMetadata<String>,
+            _conn_meta: &mut ConnectionMetadata<()>,
+        ) -> Decision
+        where
+            R: Send + Unpin + AsyncRead,
+        {
+            let mut mail_text = Vec::new();
+            if reader.read_to_end(&mut mail_text).await.is_err() {
+                return Decision::Reject(self.handle_mail_did_not_call_complete());
+            }
+            reader.complete();
+            self.mails.lock().unwrap().push(mail_text);
+            Decision::Accept
+        }
+    }
+
+    #[test]
+    fn prepends_received_header() {
+        let info = |addr: &str, is_tls, login: Option<&str>| ConnectionInfo {
+            remote_addr: Some(addr.parse().unwrap()),
+            is_tls,
+            login: login.map(|l| l.to_owned()),
+            ..ConnectionInfo::default()
+        };
+        let tests: Vec<(ConnectionInfo, &[u8], &[&str])> = vec![
+            (
+                info("192.0.2.1:1234", true, Some("foo")),
+                b"EHLO client.example.org\r\n\
+                  MAIL FROM:<>\r\n\
+                  RCPT TO:<foo@example.org>\r\n\
+                  DATA\r\n\
+                  Hello\r\n\
+                  .\r\n\
+                  MAIL FROM:<>\r\n\
+                  RCPT TO:<foo@example.org>\r\n\
+                  RCPT TO:<bar@example.org>\r\n\
+                  DATA\r\n\
+                  Hello\r\n\
+                  .\r\n",
+                &[
+                    "Received: from client.example.org ([192.0.2.1])\r\n\tby test.example.org \
+                     with ESMTPSA id id0\r\n\tfor <foo@example.org>;\r\n\t",
+                    "Received: from client.example.org ([192.0.2.1])\r\n\tby test.example.org \
+                     with ESMTPSA id id1;\r\n\t",
+                ],
+            ),
+            (
+                info("[2001:db8::1]:1234", true, None),
+                b"EHLO client.example.org\r\n\
+                  MAIL FROM:<>\r\n\
+                  RCPT TO:<foo@example.org>\r\n\
+                  DATA\r\n\
+                  Hello\r\n\
+                  .\r\n",
+                &[
+                    "Received: from client.example.org ([IPv6:2001:db8::1])\r\n\tby \
+                     test.example.org with ESMTPS id id0\r\n\tfor <foo@example.org>;\r\n\t",
+                ],
+            ),
+            (
+                info("192.0.2.1:1234", false, None),
+                b"EHLO client.example.org\r\n\
+                  MAIL FROM:<>\r\n\
+                  RCPT TO:<foo@example.org>\r\n\
+                  DATA\r\n\
+                  Hello\r\n\
+                  .\r\n",
+                &[
+                    "Received: from client.example.org ([192.0.2.1])\r\n\tby test.example.org \
+                     with ESMTP id id0\r\n\tfor <foo@example.org>;\r\n\t",
+                ],
+            ),
+            (
+                info("192.0.2.1:1234", false, None),
+                b"HELO client.example.org\r\n\
+                  MAIL FROM:<>\r\n\
+                  RCPT TO:<foo@example.org>\r\n\
+                  DATA\r\n\
+                  Hello\r\n\
+                  .\r\n",
+                &[
+                    "Received: from client.example.org ([192.0.2.1])\r\n\tby test.example.org \
+                     with SMTP id id0\r\n\tfor <foo@example.org>;\r\n\t",
+                ],
+            ),
+        ];
+        for (info, inp, headers) in tests {
+            println!("Test: {:?}", (&info, show_bytes(inp)));
+            let cfg = ReceivedConfig {
+                next_id: std::sync::atomic::AtomicUsize::new(0),
+                mails: Arc::new(Mutex::new(Vec::new())),
+            };
+            let mut resp = Vec::new();
+            let io = Duplex::new(Cursor::new(inp), Cursor::new(&mut resp));
+            let before = chrono::Utc::now().timestamp();
+            executor::block_on(interact(io, info, (), &cfg)).unwrap();
+            let after = chrono::Utc::now().timestamp();
+            println!("Got: {:?}", show_bytes(&resp));
+
+            let mails = cfg.mails.lock().unwrap();
+            assert_eq!(mails.len(), headers.len());
+            for (mail, header) in mails.iter().zip(headers.iter()) {
+                let mail = str::from_utf8(mail).unwrap();
+                println!("Mail: {:?}", mail);
+                assert!(mail.starts_with(header));
+                let (date, rest) =
+                    mail[header.len()..].split_at(mail[header.len()..].find("\r\n").unwrap());
+                assert_eq!(rest, "\r\nHello\r\n.\r\n");
+                let date = chrono::DateTime::parse_from_rfc2822(date)
+                    .unwrap()
+                    .timestamp();
+                assert!(before <= date && date <= after);
+            }
+        }
+    }
+
+    #[test]
+    fn xforward_lasts_one_transaction() {
+        let inp: &[u8] = b"EHLO proxy.example.org\r\n\
+            XFORWARD ADDR=198.51.100.1 HELO=client.example.org\r\n\
+            MAIL FROM:<>\r\n\
+            RCPT TO:<foo@example.org>\r\n\
+            DATA\r\n\
+            Hello\r\n\
+            .\r\n\
+            MAIL FROM:<>\r\n\
+            RCPT TO:<foo@example.org>\r\n\
+            DATA\r\n\
+            Hello\r\n\
+            .\r\n\
+            XFORWARD ADDR=198.51.100.2 HELO=other.example.org\r\n\
+            RSET\r\n\
+            MAIL FROM:<>\r\n\
+            RCPT TO:<foo@example.org>\r\n\
+            DATA\r\n\
+            Hello\r\n\
+            .\r\n";
+        let headers: &[&str] = &[
+            "Received: from client.example.org ([198.51.100.1])\r\n\tby test.example.org with \
+             ESMTP id id0\r\n",
+            "Received: from proxy.example.org ([192.0.2.253])\r\n\tby test.example.org with ESMTP \
+             id id1\r\n",
+            "Received: from proxy.example.org ([192.0.2.253])\r\n\tby test.example.org with ESMTP \
+             id id2\r\n",
+        ];
+        let cfg = ReceivedConfig {
+            next_id: std::sync::atomic::AtomicUsize::new(0),
+            mails: Arc::new(Mutex::new(Vec::new())),
+        };
+        let info = ConnectionInfo {
+            remote_addr: Some("192.0.2.253:1234".parse().unwrap()),
+            ..ConnectionInfo::default()
+        };
+        let mut resp = Vec::new();
+        let io = Duplex::new(Cursor::new(inp), Cursor::new(&mut resp));
+        executor::block_on(interact(io, info, (), &cfg)).unwrap();
+        println!("Got: {:?}", show_bytes(&resp));
+
+        let mails = cfg.mails.lock().unwrap();
+        assert_eq!(mails.len(), headers.len());
+        for (mail, header) in mails.iter().zip(headers.iter()) {
+            println!("Mail: {:?}", show_bytes(mail));
+            assert!(mail.starts_with(header.as_bytes()));
+        }
     }
 }
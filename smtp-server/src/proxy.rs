@@ -0,0 +1,381 @@
+use std::{
+    cmp,
+    convert::TryInto,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    ops::Range,
+    str,
+};
+
+use futures::io::{AsyncRead, AsyncReadExt};
+
+// See https://www.haproxy.org/download/2.2/doc/proxy-protocol.txt for the
+// specification of both versions of the PROXY protocol
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LEN: usize = 107;
+
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_HEADER_LEN: usize = 16;
+
+/// Addresses conveyed by a PROXY protocol header
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) enum ProxyHeader {
+    /// The connection was opened by the proxy itself (eg. for health
+    /// checks), or for an unsupported protocol, so the addresses of the
+    /// connection itself should be used
+    Local,
+    Proxied {
+        remote_addr: SocketAddr,
+        local_addr: SocketAddr,
+    },
+}
+
+/// Network of IP addresses, eg. that proxies connect from
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNetwork {
+    /// Network of the addresses that share their first `prefix_len` bits
+    /// with `addr`, as written `addr/prefix_len` in CIDR notation
+    pub const fn new(addr: IpAddr, prefix_len: u8) -> IpNetwork {
+        IpNetwork { addr, prefix_len }
+    }
+
+    /// Whether `addr` is in this network
+    ///
+    /// IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`), that dual-stack
+    /// sockets give for IPv4 peers, are in the IPv4 networks of the address
+    /// they map.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, canonical_ip(addr)) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                same_prefix(&net.octets(), &addr.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                same_prefix(&net.octets(), &addr.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl From<IpAddr> for IpNetwork {
+    fn from(addr: IpAddr) -> IpNetwork {
+        let prefix_len = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        IpNetwork::new(addr, prefix_len)
+    }
+}
+
+fn same_prefix(a: &[u8], b: &[u8], prefix_len: u8) -> bool {
+    let bits = cmp::min(prefix_len as usize, a.len() * 8);
+    let (bytes, rem) = (bits / 8, bits % 8);
+    a[..bytes] == b[..bytes] && (rem == 0 || (a[bytes] ^ b[bytes]) >> (8 - rem) == 0)
+}
+
+/// Returns the IPv4 address an IPv4-mapped IPv6 address stands for, and any
+/// other address as-is
+pub(crate) fn canonical_ip(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(ip) => match ip.segments() {
+            [0, 0, 0, 0, 0, 0xffff, ..] => {
+                let o = ip.octets();
+                IpAddr::V4(Ipv4Addr::new(o[12], o[13], o[14], o[15]))
+            }
+            _ => addr,
+        },
+        IpAddr::V4(_) => addr,
+    }
+}
+
+fn malformed(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Parses the PROXY protocol header at the beginning of `buf`, returning
+/// `Ok(None)` if more data is needed, and otherwise the header along with
+/// its length.
+pub(crate) fn parse(buf: &[u8]) -> io::Result<Option<(usize, ProxyHeader)>> {
+    let len = std::cmp::min(buf.len(), V2_SIGNATURE.len());
+    if buf[..len] == V2_SIGNATURE[..len] {
+        if buf.len() < V2_SIGNATURE.len() {
+            return Ok(None);
+        }
+        return parse_v2(buf);
+    }
+    let len = std::cmp::min(buf.len(), V1_PREFIX.len());
+    if buf[..len] == V1_PREFIX[..len] {
+        return parse_v1(buf);
+    }
+    Err(malformed(
+        "connection did not start with a PROXY protocol header",
+    ))
+}
+
+fn parse_v1(buf: &[u8]) -> io::Result<Option<(usize, ProxyHeader)>> {
+    let end = match buf.windows(2).position(|w| w == b"\r\n") {
+        Some(end) if end + 2 <= V1_MAX_LEN => end,
+        Some(_) => return Err(malformed("PROXY protocol v1 header too long")),
+        None if buf.len() >= V1_MAX_LEN => {
+            return Err(malformed("PROXY protocol v1 header too long"));
+        }
+        None => return Ok(None),
+    };
+    let line = str::from_utf8(&buf[V1_PREFIX.len()..end])
+        .map_err(|_| malformed("PROXY protocol v1 header is not ASCII"))?;
+    let mut fields = line.split(' ');
+    let family = fields.next();
+    if family == Some("UNKNOWN") {
+        // Anything can follow, and must be ignored
+        return Ok(Some((end + 2, ProxyHeader::Local)));
+    }
+    let fields = fields.collect::<Vec<&str>>();
+    let (src, dst, sport, dport) = match fields[..] {
+        [src, dst, sport, dport] => (src, dst, sport, dport),
+        _ => return Err(malformed("PROXY protocol v1 header has wrong field count")),
+    };
+    let parse_ip = |ip: &str| -> io::Result<IpAddr> {
+        let ip = match family {
+            Some("TCP4") => ip.parse::<Ipv4Addr>().map(IpAddr::V4),
+            Some("TCP6") => ip.parse::<Ipv6Addr>().map(IpAddr::V6),
+            _ => return Err(malformed("PROXY protocol v1 header has unknown protocol")),
+        };
+        ip.map_err(|_| malformed("PROXY protocol v1 header has invalid address"))
+    };
+    let parse_port = |port: &str| -> io::Result<u16> {
+        let valid = !port.is_empty()
+            && port.bytes().all(|c| c.is_ascii_digit())
+            && (port == "0" || !port.starts_with('0'));
+        match valid {
+            true => port
+                .parse()
+                .map_err(|_| malformed("PROXY protocol v1 header has invalid port")),
+            false => Err(malformed("PROXY protocol v1 header has invalid port")),
+        }
+    };
+    Ok(Some((end + 2, ProxyHeader::Proxied {
+        remote_addr: SocketAddr::new(parse_ip(src)?, parse_port(sport)?),
+        local_addr: SocketAddr::new(parse_ip(dst)?, parse_port(dport)?),
+    })))
+}
+
+fn parse_v2(buf: &[u8]) -> io::Result<Option<(usize, ProxyHeader)>> {
+    if buf.len() < V2_HEADER_LEN {
+        return Ok(None);
+    }
+    let version = buf[12] >> 4;
+    let command = buf[12] & 0xF;
+    let family = buf[13];
+    let len = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    if version != 2 {
+        return Err(malformed("PROXY protocol v2 header has unknown version"));
+    }
+    if buf.len() < V2_HEADER_LEN + len {
+        return Ok(None);
+    }
+    let total_len = V2_HEADER_LEN + len;
+    let addrs = &buf[V2_HEADER_LEN..total_len];
+    match command {
+        // LOCAL, the addresses must be ignored
+        0x0 => return Ok(Some((total_len, ProxyHeader::Local))),
+        // PROXY
+        0x1 => (),
+        _ => return Err(malformed("PROXY protocol v2 header has unknown command")),
+    }
+    let (remote_addr, local_addr) = match family {
+        // TCP over IPv4
+        0x11 if len >= 12 => {
+            let src: [u8; 4] = addrs[0..4].try_into().unwrap();
+            let dst: [u8; 4] = addrs[4..8].try_into().unwrap();
+            (
+                SocketAddr::new(IpAddr::from(src), u16::from_be_bytes([addrs[8], addrs[9]])),
+                SocketAddr::new(
+                    IpAddr::from(dst),
+                    u16::from_be_bytes([addrs[10], addrs[11]]),
+                ),
+            )
+        }
+        // TCP over IPv6
+        0x21 if len >= 36 => {
+            let src: [u8; 16] = addrs[0..16].try_into().unwrap();
+            let dst: [u8; 16] = addrs[16..32].try_into().unwrap();
+            (
+                SocketAddr::new(
+                    IpAddr::from(src),
+                    u16::from_be_bytes([addrs[32], addrs[33]]),
+                ),
+                SocketAddr::new(
+                    IpAddr::from(dst),
+                    u16::from_be_bytes([addrs[34], addrs[35]]),
+                ),
+            )
+        }
+        0x11 | 0x21 => return Err(malformed("PROXY protocol v2 header is too short")),
+        // Unspecified, UDP or UNIX sockets, the addresses must be ignored
+        _ => return Ok(Some((total_len, ProxyHeader::Local))),
+    };
+    Ok(Some((total_len, ProxyHeader::Proxied {
+        remote_addr,
+        local_addr,
+    })))
+}
+
+/// Reads the PROXY protocol header at the beginning of `r`, leaving the data
+/// that was read after it in `buf[unhandled]`
+pub(crate) async fn read_header<R>(
+    r: &mut R,
+    buf: &mut [u8],
+    unhandled: &mut Range<usize>,
+) -> io::Result<ProxyHeader>
+where
+    R: Unpin + AsyncRead,
+{
+    *unhandled = 0..0;
+    loop {
+        if unhandled.end == buf.len() {
+            return Err(malformed("PROXY protocol header too long"));
+        }
+        let read = r.read(&mut buf[unhandled.end..]).await?;
+        if read == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "connection shutdown while waiting for the PROXY protocol header",
+            ));
+        }
+        unhandled.end += read;
+        if let Some((len, header)) = parse(&buf[..unhandled.end])? {
+            unhandled.start = len;
+            return Ok(header);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proxied(remote_addr: &str, local_addr: &str) -> ProxyHeader {
+        ProxyHeader::Proxied {
+            remote_addr: remote_addr.parse().unwrap(),
+            local_addr: local_addr.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn valid_headers() {
+        let tests: &[(&[u8], usize, ProxyHeader)] = &[
+            (
+                b"PROXY TCP4 192.0.2.1 198.51.100.2 56324 25\r\nEHLO",
+                44,
+                proxied("192.0.2.1:56324", "198.51.100.2:25"),
+            ),
+            (
+                b"PROXY TCP6 2001:db8::1 2001:db8::2 0 25\r\n",
+                41,
+                proxied("[2001:db8::1]:0", "[2001:db8::2]:25"),
+            ),
+            (
+                b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\n",
+                35,
+                ProxyHeader::Local,
+            ),
+            (b"PROXY UNKNOWN\r\n", 15, ProxyHeader::Local),
+            (
+                b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0C\
+                  \xC0\x00\x02\x01\xC6\x33\x64\x02\xDC\x04\x00\x19EHLO",
+                28,
+                proxied("192.0.2.1:56324", "198.51.100.2:25"),
+            ),
+            (
+                b"\r\n\r\n\0\r\nQUIT\n\x21\x21\x00\x27\
+                  \x20\x01\x0d\xb8\0\0\0\0\0\0\0\0\0\0\0\x01\
+                  \x20\x01\x0d\xb8\0\0\0\0\0\0\0\0\0\0\0\x02\
+                  \xDC\x04\x00\x19\
+                  \x04\x00\x00",
+                55,
+                proxied("[2001:db8::1]:56324", "[2001:db8::2]:25"),
+            ),
+            (
+                b"\r\n\r\n\0\r\nQUIT\n\x20\x00\x00\x00",
+                16,
+                ProxyHeader::Local,
+            ),
+            (
+                b"\r\n\r\n\0\r\nQUIT\n\x21\x31\x00\x04abcd",
+                20,
+                ProxyHeader::Local,
+            ),
+        ];
+        for (inp, len, header) in tests {
+            println!("Parsing {:?}", inp);
+            assert_eq!(parse(inp).unwrap(), Some((*len, header.clone())));
+            for i in 0..*len {
+                assert_eq!(parse(&inp[..i]).unwrap(), None);
+            }
+        }
+    }
+
+    #[test]
+    fn ip_network_contains() {
+        let tests: &[(&str, u8, &str, bool)] = &[
+            ("192.0.2.0", 24, "192.0.2.42", true),
+            ("192.0.2.0", 24, "192.0.3.42", false),
+            ("192.0.2.128", 25, "192.0.2.127", false),
+            ("192.0.2.128", 25, "192.0.2.255", true),
+            ("192.0.2.1", 32, "192.0.2.1", true),
+            ("192.0.2.1", 32, "192.0.2.2", false),
+            ("192.0.2.1", 40, "192.0.2.1", true),
+            ("0.0.0.0", 0, "203.0.113.1", true),
+            ("192.0.2.0", 24, "::ffff:192.0.2.42", true),
+            ("192.0.2.0", 24, "::ffff:192.0.3.42", false),
+            ("192.0.2.0", 24, "::192.0.2.42", false),
+            ("192.0.2.0", 24, "2001:db8::1", false),
+            ("2001:db8::", 32, "2001:db8:1::1", true),
+            ("2001:db8::", 32, "2001:db9::1", false),
+            ("2001:db8::", 33, "2001:db8:8000::1", false),
+            ("2001:db8::", 32, "192.0.2.1", false),
+        ];
+        for &(net, prefix_len, addr, expected) in tests {
+            println!("Test: {}/{} contains {}", net, prefix_len, addr);
+            let net = IpNetwork::new(net.parse().unwrap(), prefix_len);
+            assert_eq!(net.contains(addr.parse().unwrap()), expected);
+        }
+        let host = IpNetwork::from("192.0.2.1".parse::<IpAddr>().unwrap());
+        assert!(host.contains("::ffff:192.0.2.1".parse().unwrap()));
+        assert!(!host.contains("192.0.2.2".parse().unwrap()));
+    }
+
+    #[test]
+    fn malformed_headers() {
+        let tests: &[&[u8]] = &[
+            b"EHLO foo\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.2 56324\r\n",
+            b"PROXY TCP4 2001:db8::1 198.51.100.2 56324 25\r\n",
+            b"PROXY TCP6 192.0.2.1 198.51.100.2 56324 25\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.2 056324 25\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.2 +5 25\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.2 65536 25\r\n",
+            b"PROXY UDP4 192.0.2.1 198.51.100.2 56324 25\r\n",
+            b"PROXY TCP4  192.0.2.1 198.51.100.2 56324 25\r\n",
+            b"\r\n\r\n\0\r\nQUIT\n\x11\x11\x00\x0C\
+              \xC0\x00\x02\x01\xC6\x33\x64\x02\xDC\x04\x00\x19",
+            b"\r\n\r\n\0\r\nQUIT\n\x22\x11\x00\x0C\
+              \xC0\x00\x02\x01\xC6\x33\x64\x02\xDC\x04\x00\x19",
+            b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x04\xC0\x00\x02\x01",
+        ];
+        for inp in tests {
+            println!("Parsing {:?}", inp);
+            assert!(parse(inp).is_err());
+        }
+
+        let too_long = [b"PROXY UNKNOWN ".as_ref(), &[b'a'; 100], b"\r\n"].concat();
+        assert!(parse(&too_long).is_err());
+        assert!(parse(&too_long[..V1_MAX_LEN]).is_err());
+    }
+}
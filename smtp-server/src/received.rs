@@ -0,0 +1,235 @@
+use std::{fmt, net::IpAddr};
+
+use chrono::{DateTime, FixedOffset, Utc};
+use smtp_message::{Email, Hostname};
+
+use crate::{ConnectionMetadata, HelloInfo, MailMetadata};
+
+/// The protocol a mail was received with, as registered for the `with`
+/// clause of the `Received:` header (RFC 3848)
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Protocol {
+    Smtp,
+    Esmtp,
+    Esmtpa,
+    Esmtps,
+    Esmtpsa,
+}
+
+impl Protocol {
+    pub fn new(is_ehlo: bool, is_tls: bool, is_authenticated: bool) -> Protocol {
+        match (is_ehlo, is_tls, is_authenticated) {
+            (false, _, _) => Protocol::Smtp,
+            (true, false, false) => Protocol::Esmtp,
+            (true, false, true) => Protocol::Esmtpa,
+            (true, true, false) => Protocol::Esmtps,
+            (true, true, true) => Protocol::Esmtpsa,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Protocol::Smtp => "SMTP",
+            Protocol::Esmtp => "ESMTP",
+            Protocol::Esmtpa => "ESMTPA",
+            Protocol::Esmtps => "ESMTPS",
+            Protocol::Esmtpsa => "ESMTPSA",
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The `Received:` trace header that RFC 5321 section 4.4 requires to be
+/// prepended to each mail that is received
+///
+/// Its `Display` implementation writes the whole header, including the
+/// trailing CRLF, so that it can be written as-is before the contents of the
+/// mail. The server does so itself when `Config::prepend_received` is set.
+pub struct ReceivedHeader<'a> {
+    /// Hostname the client gave in its `HELO` or `EHLO`
+    pub hello: Option<&'a Hostname>,
+
+    /// Address of the client
+    pub peer_addr: Option<IpAddr>,
+
+    /// Hostname of the server, usually `Config::hostname`
+    pub hostname: &'a str,
+
+    pub protocol: Protocol,
+
+    /// Queue ID the mail has been given by the server
+    pub id: Option<&'a str>,
+
+    /// Recipient of the mail, only set if there is a single one so as not
+    /// to disclose the other recipients
+    pub recipient: Option<&'a Email>,
+
+    pub date: DateTime<FixedOffset>,
+}
+
+impl<'a> ReceivedHeader<'a> {
+    /// Builds the header for a mail received now
+    ///
+    /// The queue ID has to be set afterwards, if known.
+    pub fn new<U, V>(
+        hostname: &'a str,
+        meta: &'a MailMetadata<U>,
+        conn_meta: &'a ConnectionMetadata<V>,
+    ) -> ReceivedHeader<'a> {
+        let is_ehlo = conn_meta.hello.as_ref().map(|h| h.is_ehlo) == Some(true);
+        ReceivedHeader {
+            hello: conn_meta.hello.as_ref().map(|h: &HelloInfo| &h.hostname),
+            peer_addr: conn_meta.info.remote_addr.map(|a| a.ip()),
+            hostname,
+            protocol: Protocol::new(
+                is_ehlo,
+                conn_meta.info.is_tls,
+                conn_meta.info.login.is_some(),
+            ),
+            id: None,
+            recipient: match &meta.to[..] {
+                [to] => Some(to),
+                _ => None,
+            },
+            date: Utc::now().into(),
+        }
+    }
+}
+
+impl<'a> fmt::Display for ReceivedHeader<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Received:")?;
+        let has_from = match (self.hello, self.peer_addr) {
+            (None, None) => false,
+            (Some(hello), None) => {
+                write!(f, " from {}", hello.raw())?;
+                true
+            }
+            (None, Some(addr)) => {
+                write!(f, " from {}", AddressLiteral(addr))?;
+                true
+            }
+            (Some(hello), Some(addr)) => {
+                write!(f, " from {} ({})", hello.raw(), AddressLiteral(addr))?;
+                true
+            }
+        };
+        if has_from {
+            f.write_str("\r\n\t")?;
+        } else {
+            f.write_str(" ")?;
+        }
+        write!(f, "by {} with {}", self.hostname, self.protocol)?;
+        if let Some(id) = self.id {
+            write!(f, " id {}", id)?;
+        }
+        if let Some(to) = self.recipient {
+            write!(f, "\r\n\tfor <{}", to.localpart.raw())?;
+            if let Some(ref hostname) = to.hostname {
+                write!(f, "@{}", hostname.raw())?;
+            }
+            f.write_str(">")?;
+        }
+        let date = self.date.format("%a, %d %b %Y %H:%M:%S %z");
+        write!(f, ";\r\n\t{}\r\n", date)
+    }
+}
+
+struct AddressLiteral(IpAddr);
+
+impl fmt::Display for AddressLiteral {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            IpAddr::V4(ip) => write!(f, "[{}]", ip),
+            IpAddr::V6(ip) => write!(f, "[IPv6:{}]", ip),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date() -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc2822("Tue, 1 Jul 2003 10:52:37 +0200").unwrap()
+    }
+
+    #[test]
+    fn protocol() {
+        let tests = &[
+            ((false, false, false), "SMTP"),
+            ((false, true, true), "SMTP"),
+            ((true, false, false), "ESMTP"),
+            ((true, false, true), "ESMTPA"),
+            ((true, true, false), "ESMTPS"),
+            ((true, true, true), "ESMTPSA"),
+        ];
+        for &((is_ehlo, is_tls, is_authenticated), expected) in tests {
+            println!("Test: {:?}", (is_ehlo, is_tls, is_authenticated));
+            let protocol = Protocol::new(is_ehlo, is_tls, is_authenticated);
+            assert_eq!(protocol.as_str(), expected);
+        }
+    }
+
+    #[test]
+    fn received_header() {
+        let hello = Hostname::parse_until(b">")(b"client.example.org>")
+            .unwrap()
+            .1;
+        let to = Email::parse_bracketed(b"<foo@bar.example.org>")
+            .unwrap()
+            .to_owned();
+        let tests: &[(ReceivedHeader, &[u8])] = &[
+            (
+                ReceivedHeader {
+                    hello: Some(&hello),
+                    peer_addr: Some("192.0.2.1".parse().unwrap()),
+                    hostname: "mx.example.org",
+                    protocol: Protocol::Esmtps,
+                    id: Some("abcd"),
+                    recipient: Some(&to),
+                    date: date(),
+                },
+                b"Received: from client.example.org ([192.0.2.1])\r\n\
+                 \tby mx.example.org with ESMTPS id abcd\r\n\
+                 \tfor <foo@bar.example.org>;\r\n\
+                 \tTue, 01 Jul 2003 10:52:37 +0200\r\n",
+            ),
+            (
+                ReceivedHeader {
+                    hello: None,
+                    peer_addr: Some("2001:db8::1".parse().unwrap()),
+                    hostname: "mx.example.org",
+                    protocol: Protocol::Smtp,
+                    id: None,
+                    recipient: None,
+                    date: date(),
+                },
+                b"Received: from [IPv6:2001:db8::1]\r\n\
+                 \tby mx.example.org with SMTP;\r\n\
+                 \tTue, 01 Jul 2003 10:52:37 +0200\r\n",
+            ),
+            (
+                ReceivedHeader {
+                    hello: None,
+                    peer_addr: None,
+                    hostname: "mx.example.org",
+                    protocol: Protocol::Esmtpsa,
+                    id: Some("abcd"),
+                    recipient: None,
+                    date: date(),
+                },
+                b"Received: by mx.example.org with ESMTPSA id abcd;\r\n\
+                 \tTue, 01 Jul 2003 10:52:37 +0200\r\n",
+            ),
+        ];
+        for (header, expected) in tests {
+            assert_eq!(header.to_string().as_bytes(), *expected);
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+use smtp_message::{Hostname, MaybeUtf8, ParameterName, Parameters};
+
+use crate::{ConnectionInfo, HelloInfo};
+
+// See http://www.postfix.org/XCLIENT_README.html and
+// http://www.postfix.org/XFORWARD_README.html
+
+pub(crate) const XCLIENT_ATTRIBUTES: &[&str] = &[
+    "NAME", "ADDR", "PORT", "PROTO", "HELO", "LOGIN", "DESTADDR", "DESTPORT",
+];
+
+pub(crate) const XFORWARD_ATTRIBUTES: &[&str] =
+    &["NAME", "ADDR", "PORT", "PROTO", "HELO", "IDENT", "SOURCE"];
+
+/// Decodes an xtext-encoded value (RFC 3461 section 4)
+fn xtext_decode(s: &str) -> Option<String> {
+    let mut res = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(c) = bytes.next() {
+        match c {
+            b'+' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                let hex = std::str::from_utf8(&hex).ok()?;
+                res.push(u8::from_str_radix(hex, 16).ok()?);
+            }
+            b'!'..=b'~' if c != b'=' => res.push(c),
+            _ => return None,
+        }
+    }
+    String::from_utf8(res).ok()
+}
+
+/// Parses a value that may be unavailable, in which case the value that was
+/// previously known must be forgotten
+fn parse_available<T, F>(value: &str, parse: F) -> Option<Option<T>>
+where
+    F: FnOnce(&str) -> Option<T>,
+{
+    match value.to_ascii_uppercase().as_str() {
+        "[UNAVAILABLE]" | "[TEMPUNAVAIL]" => Some(None),
+        _ => parse(value).map(Some),
+    }
+}
+
+fn parse_addr(addr: &str) -> Option<IpAddr> {
+    if addr.len() > 5 && addr[..5].eq_ignore_ascii_case("IPV6:") {
+        addr[5..].parse::<Ipv6Addr>().ok().map(IpAddr::V6)
+    } else {
+        addr.parse().ok()
+    }
+}
+
+fn parse_hostname(hostname: &str) -> Option<Hostname> {
+    let buf = format!("{}\r", hostname);
+    let (rem, hostname) = Hostname::<&str>::parse_until(b"\r")(buf.as_bytes()).ok()?;
+    match rem {
+        b"\r" => Some(hostname.to_owned()),
+        _ => None,
+    }
+}
+
+fn override_addr(
+    current: Option<SocketAddr>,
+    addr: Option<Option<IpAddr>>,
+    port: Option<Option<u16>>,
+) -> Option<SocketAddr> {
+    if addr.is_none() && port.is_none() {
+        return current;
+    }
+    let addr = addr.unwrap_or_else(|| current.map(|a| a.ip()))?;
+    let port = port.unwrap_or_else(|| current.map(|a| a.port()));
+    Some(SocketAddr::new(addr, port.unwrap_or(0)))
+}
+
+/// The attributes given by an `XCLIENT` or `XFORWARD` command
+///
+/// For each attribute, `None` means it was not given, and `Some(None)` that
+/// it was given as unavailable.
+#[derive(Default)]
+pub(crate) struct Attributes {
+    addr: Option<Option<IpAddr>>,
+    port: Option<Option<u16>>,
+    dest_addr: Option<Option<IpAddr>>,
+    dest_port: Option<Option<u16>>,
+    helo: Option<Option<Hostname>>,
+    is_ehlo: Option<bool>,
+    login: Option<Option<String>>,
+}
+
+impl Attributes {
+    /// Parses `attributes`, returning `None` if one of them is invalid or not
+    /// in `allowed`
+    pub(crate) fn parse(attributes: &Parameters<&str>, allowed: &[&str]) -> Option<Attributes> {
+        let mut res = Attributes::default();
+        let mut empty = true;
+        for (name, value) in attributes.iter() {
+            let ParameterName::Other(name) = name;
+            let name = name.to_ascii_uppercase();
+            let value = match value {
+                Some(MaybeUtf8::Ascii(value)) => xtext_decode(value)?,
+                _ => return None,
+            };
+            if !allowed.contains(&name.as_str()) {
+                return None;
+            }
+            empty = false;
+            match name.as_str() {
+                "ADDR" => res.addr = Some(parse_available(&value, parse_addr)?),
+                "PORT" => res.port = Some(parse_available(&value, |p| p.parse().ok())?),
+                "DESTADDR" => res.dest_addr = Some(parse_available(&value, parse_addr)?),
+                "DESTPORT" => res.dest_port = Some(parse_available(&value, |p| p.parse().ok())?),
+                "HELO" => res.helo = Some(parse_available(&value, parse_hostname)?),
+                "LOGIN" => res.login = Some(parse_available(&value, |l| Some(l.to_owned()))?),
+                "PROTO" => match value.to_ascii_uppercase().as_str() {
+                    "SMTP" => res.is_ehlo = Some(false),
+                    "ESMTP" => res.is_ehlo = Some(true),
+                    "[UNAVAILABLE]" | "[TEMPUNAVAIL]" => (),
+                    _ => return None,
+                },
+                // Only used for logging by Postfix, and not recorded here
+                _ => (),
+            }
+        }
+        if empty {
+            return None;
+        }
+        Some(res)
+    }
+
+    /// Overrides the information of the connection with these attributes
+    pub(crate) fn apply(self, info: &mut ConnectionInfo, hello: &mut Option<HelloInfo>) {
+        info.remote_addr = override_addr(info.remote_addr, self.addr, self.port);
+        info.local_addr = override_addr(info.local_addr, self.dest_addr, self.dest_port);
+        if let Some(login) = self.login {
+            info.login = login;
+        }
+        let is_ehlo = self
+            .is_ehlo
+            .or_else(|| hello.as_ref().map(|h| h.is_ehlo))
+            .unwrap_or(true);
+        match self.helo {
+            Some(Some(hostname)) => *hello = Some(HelloInfo { is_ehlo, hostname }),
+            // An unavailable HELO name just keeps the one the client gave
+            Some(None) | None => {
+                if let Some(ref mut hello) = hello {
+                    hello.is_ehlo = is_ehlo;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xtext() {
+        let tests: &[(&str, Option<&str>)] = &[
+            ("foo.example.org", Some("foo.example.org")),
+            ("foo+2Bbar+3d", Some("foo+bar=")),
+            ("foo+2", None),
+            ("foo+zz", None),
+            ("foo=bar", None),
+        ];
+        for (inp, out) in tests {
+            assert_eq!(xtext_decode(inp).as_deref(), *out);
+        }
+    }
+
+    #[test]
+    fn override_addrs() {
+        let addr = |s: &str| Some(s.parse::<SocketAddr>().unwrap());
+        let ip = |s: &str| Some(Some(s.parse::<IpAddr>().unwrap()));
+        let current = addr("192.0.2.1:25");
+        let tests = &[
+            (None, None, current),
+            (ip("192.0.2.2"), None, addr("192.0.2.2:25")),
+            (None, Some(Some(2525)), addr("192.0.2.1:2525")),
+            (ip("2001:db8::1"), Some(None), addr("[2001:db8::1]:0")),
+            (Some(None), Some(Some(25)), None),
+        ];
+        for &(a, p, res) in tests {
+            assert_eq!(override_addr(current, a, p), res);
+        }
+        assert_eq!(
+            override_addr(None, None, Some(Some(25))),
+            None,
+            "a port without an address is ignored"
+        );
+    }
+}